@@ -0,0 +1,33 @@
+use crate::{
+    types::{IpfId, IpsId},
+    BoxResult,
+};
+use cid::Cid;
+use futures::future::LocalBoxFuture;
+
+/// What the backends return. Futures aren't `Send`, so a browser can back them with `fetch`.
+pub type BackendFuture<'a, T> = LocalBoxFuture<'a, BoxResult<T>>;
+
+/// Where `Reader` downloads content from, an IPFS node or gateway.
+pub trait IpfsBackend {
+    /// The file at `cid`.
+    fn cat<'a>(&'a self, cid: &'a Cid) -> BackendFuture<'a, Vec<u8>>;
+}
+
+/// An IPF as the chain stores it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IpfRecord {
+    /// Name and CID of the IPF's content, see `metadata::decode_ipf_metadata`
+    pub metadata: Vec<u8>,
+    /// sha2-256 digest of the IPF's content
+    pub data: [u8; 32],
+}
+
+/// Where `Reader` looks IPSs and their IPFs up, a chain node or an indexer.
+pub trait ChainBackend {
+    /// The IPFs of the IPS `ips_id` in the order they were appended.
+    fn ipf_ids(&self, ips_id: IpsId) -> BackendFuture<'_, Vec<IpfId>>;
+
+    /// The IPF `ipf_id`, if it exists.
+    fn ipf(&self, ipf_id: IpfId) -> BackendFuture<'_, Option<IpfRecord>>;
+}
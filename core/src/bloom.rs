@@ -0,0 +1,72 @@
+use crate::{types::GitHash, BoxResult};
+use codec::{Decode, Encode};
+
+/// Bits set aside per object, which with `HASHES` probes gives about 1% false positives.
+const BITS_PER_OBJECT: usize = 10;
+
+/// How many bits each object sets.
+const HASHES: u32 = 7;
+
+/// Probes checked at most, whatever a filter claims. Checking fewer bits than were set never
+/// turns a member into a non-member.
+const MAX_HASHES: u32 = 32;
+
+/// A bloom filter over the objects of a RepoData, stored alongside them.
+///
+/// Readers that keep the objects section on disk ask it first, so looking up an object the
+/// RepoData doesn't have rarely needs the section at all. Only a "no" is certain.
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct ObjectFilter {
+    /// How many bits each object sets
+    hashes: u32,
+    bits: Vec<u8>,
+}
+
+impl ObjectFilter {
+    /// A filter holding `git_hashes`, of which there are `len`.
+    pub fn new<'a>(git_hashes: impl Iterator<Item = &'a GitHash>, len: usize) -> Self {
+        let mut filter = Self {
+            hashes: HASHES,
+            bits: vec![0; (len * BITS_PER_OBJECT / 8).max(8)],
+        };
+
+        for git_hash in git_hashes {
+            // A hash that can't be probed is reported as possibly present by `may_contain`
+            if let Ok(probes) = filter.probes(git_hash) {
+                for bit in probes {
+                    filter.bits[bit / 8] |= 1 << (bit % 8);
+                }
+            }
+        }
+
+        filter
+    }
+
+    /// Whether `git_hash` may be in the set; `false` means it certainly isn't.
+    pub fn may_contain(&self, git_hash: &GitHash) -> bool {
+        match self.probes(git_hash) {
+            Ok(mut probes) if !self.bits.is_empty() => {
+                probes.all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+            }
+            _ => true,
+        }
+    }
+
+    /// The bits `git_hash` sets. Git hashes are already uniformly distributed, so two words of
+    /// the hash itself stand in for the two hash functions of double hashing.
+    fn probes(&self, git_hash: &GitHash) -> BoxResult<impl Iterator<Item = usize>> {
+        let raw = git_hash.to_raw()?;
+        let word = |at: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&raw[at..at + 8]);
+            u64::from_le_bytes(bytes)
+        };
+
+        let first = word(0);
+        let step = word(8) | 1;
+        let len = self.bits.len() as u64 * 8;
+
+        Ok((0..self.hashes.min(MAX_HASHES) as u64)
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(step)) % len) as usize))
+    }
+}
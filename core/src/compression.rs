@@ -0,0 +1,129 @@
+use crate::{error, BoxResult, VerificationError};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// zstd level objects are compressed at, its default trade-off.
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// Why zstd objects fail in builds without the `zstd` feature, like those for the browser.
+#[cfg(not(feature = "zstd"))]
+static ZSTD_MISSING: &str = "This build of inv4_git_core was made without zstd support";
+
+/// Brotli quality and window objects are compressed with.
+const BROTLI_QUALITY: u32 = 9;
+const BROTLI_WINDOW: u32 = 22;
+
+/// How the data of an object is stored in its MultiObject, `push.compression`.
+///
+/// Every algorithm can be fetched whatever the setting, so repositories pushed by clients
+/// configured differently read the same.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "zstd")]
+    Zstd,
+    #[serde(rename = "brotli")]
+    Brotli,
+}
+
+impl Compression {
+    /// The byte standing for this algorithm in a MultiObject.
+    pub fn tag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Brotli => 2,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> BoxResult<Self> {
+        match tag {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            2 => Ok(Compression::Brotli),
+            _ => error!(format!(
+                "Unknown object compression {}, update git-remote-inv4 to fetch it",
+                tag
+            )),
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> BoxResult<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd::stream::encode_all(data, ZSTD_LEVEL)?),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => error!(ZSTD_MISSING),
+            Compression::Brotli => {
+                let mut compressed = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(
+                        &mut compressed,
+                        4096,
+                        BROTLI_QUALITY,
+                        BROTLI_WINDOW,
+                    );
+                    writer.write_all(data)?;
+                }
+                Ok(compressed)
+            }
+        }
+    }
+
+    /// Inflates `data`, failing if it takes more than `limit` bytes, so a small hostile object
+    /// can't exhaust memory.
+    pub fn decompress(self, data: Vec<u8>, limit: usize) -> BoxResult<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        match self {
+            Compression::None => return Ok(data),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                zstd::stream::read::Decoder::new(data.as_slice())?
+                    .take(limit as u64 + 1)
+                    .read_to_end(&mut decompressed)?;
+            }
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => error!(ZSTD_MISSING),
+            Compression::Brotli => {
+                brotli::Decompressor::new(data.as_slice(), 4096)
+                    .take(limit as u64 + 1)
+                    .read_to_end(&mut decompressed)?;
+            }
+        }
+
+        if decompressed.len() > limit {
+            error!(VerificationError(format!(
+                "Compressed object inflates past {} bytes",
+                limit
+            )))
+        }
+
+        Ok(decompressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompression_stops_at_the_limit() {
+        let data = vec![0u8; 64 * 1024];
+
+        for compression in [Compression::Zstd, Compression::Brotli] {
+            let compressed = compression.compress(&data).unwrap();
+
+            assert_eq!(
+                compression
+                    .decompress(compressed.clone(), data.len())
+                    .unwrap(),
+                data
+            );
+            assert!(compression.decompress(compressed, data.len() - 1).is_err());
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! The formats git-remote-inv4 stores repositories in on chain and on IPFS, and a reader for
+//! them over whatever IPFS and chain backends the caller supplies.
+//!
+//! Without its default features this crate leaves out libgit2 and zstd, so browsers can read
+//! repositories client-side:
+//!
+//! ```text
+//! cargo build -p inv4-git-core --no-default-features --target wasm32-unknown-unknown
+//! ```
+
+use codec::Input;
+use std::{error::Error, fmt};
+
+pub mod backend;
+pub mod bloom;
+pub mod compression;
+pub mod metadata;
+pub mod objects;
+pub mod reader;
+pub mod repo_data;
+pub mod types;
+
+pub type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+/// Content from chain or IPFS that doesn't match what it claims to be.
+#[derive(Debug)]
+pub struct VerificationError(pub String);
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for VerificationError {}
+
+/// Replays a byte that was already read before the rest of `input`.
+pub(crate) struct PrefixedInput<'a, I: Input> {
+    pub(crate) prefix: Option<u8>,
+    pub(crate) input: &'a mut I,
+}
+
+impl<'a, I: Input> Input for PrefixedInput<'a, I> {
+    fn remaining_len(&mut self) -> Result<Option<usize>, codec::Error> {
+        Ok(self
+            .input
+            .remaining_len()?
+            .map(|len| len + self.prefix.is_some() as usize))
+    }
+
+    fn read(&mut self, into: &mut [u8]) -> Result<(), codec::Error> {
+        if into.is_empty() {
+            return Ok(());
+        }
+
+        match self.prefix.take() {
+            Some(byte) => {
+                into[0] = byte;
+                self.input.read(&mut into[1..])
+            }
+            None => self.input.read(into),
+        }
+    }
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! error {
+    ($x:expr) => {{
+        return Err($x.into());
+    }};
+}
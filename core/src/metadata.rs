@@ -0,0 +1,148 @@
+use crate::{error, types::IpfsCidBytes, BoxResult, VerificationError};
+use cid::{multihash::MultihashGeneric, Cid};
+
+/// Multihash code of sha2-256.
+pub const SHA2_256: u64 = 0x12;
+/// Multihash code of blake2b-256.
+pub const BLAKE2B_256: u64 = 0xb220;
+
+/// Separates the object name from the full CID bytes in IPF metadata.
+pub const METADATA_CID_SEPARATOR: u8 = 0;
+
+/// Separates the CID bytes from the license tag in IPF metadata.
+pub const METADATA_LICENSE_SEPARATOR: u8 = 0;
+
+/// Starts the metadata of every named IPF git-remote-inv4 mints, so scans can tell them from
+/// the other assets an IPS holds. Names are read from after their last `/`, so readers see
+/// the same names with or without it.
+pub const GIT_IPF_TAG: &[u8] = b"git/";
+
+/// The name of the RepoData IPF, replaced on every push.
+pub static REPO_DATA_NAME: &str = "RepoData";
+
+/// The name of the manifest IPF, which replaces the previous one on every push like RepoData.
+pub static MANIFEST_NAME: &str = "Manifest";
+
+/// Rebuilds a CIDv0 from a sha2-256 digest stored on chain.
+pub fn generate_cid(digest: [u8; 32]) -> BoxResult<Cid> {
+    Ok(Cid::new_v0(MultihashGeneric::<64>::wrap(
+        SHA2_256, &digest,
+    )?)?)
+}
+
+/// Splits IPF metadata into the object name and the CID of its content.
+pub fn decode_ipf_metadata(metadata: &[u8], digest: [u8; 32]) -> BoxResult<(String, Cid)> {
+    match metadata.iter().position(|b| *b == METADATA_CID_SEPARATOR) {
+        Some(pos) => {
+            let name = lookup_name(&String::from_utf8(metadata[..pos].to_vec())?).to_owned();
+            let cid = IpfsCidBytes::try_from(&metadata[pos + 1..])?.to_cid()?;
+
+            if cid.hash().digest() != digest {
+                error!(VerificationError(format!(
+                    "IPF metadata for {} lists CID {} which does not match the on-chain digest 0x{}",
+                    name,
+                    cid,
+                    hex::encode(digest)
+                )))
+            }
+
+            Ok((name, cid))
+        }
+        None => Ok((
+            lookup_name(&String::from_utf8(metadata.to_vec())?).to_owned(),
+            generate_cid(digest)?,
+        )),
+    }
+}
+
+/// Reads the license tag from IPF metadata, an SPDX expression or the CID of custom terms.
+pub fn ipf_metadata_license(metadata: &[u8]) -> BoxResult<Option<String>> {
+    let pos = match metadata.iter().position(|b| *b == METADATA_CID_SEPARATOR) {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+
+    // CIDs carry their own length, so whatever follows is the license
+    let mut rest = &metadata[pos + 1..];
+    Cid::read_bytes(&mut rest)?;
+
+    match rest.split_first() {
+        Some((&METADATA_LICENSE_SEPARATOR, license)) => {
+            Ok(Some(String::from_utf8(license.to_vec())?))
+        }
+        Some(_) => error!("IPF metadata has unexpected bytes after its CID"),
+        None => Ok(None),
+    }
+}
+
+/// Reads only the object name from IPF metadata.
+pub fn ipf_metadata_name(metadata: &[u8]) -> BoxResult<String> {
+    let end = metadata
+        .iter()
+        .position(|b| *b == METADATA_CID_SEPARATOR)
+        .unwrap_or(metadata.len());
+
+    Ok(lookup_name(&String::from_utf8(metadata[..end].to_vec())?).to_owned())
+}
+
+/// Whether `metadata` is that of an IPF git-remote-inv4 minted under a name, rather than of
+/// a push receipt or an asset of something else sharing the IPS.
+///
+/// IPFs minted before `GIT_IPF_TAG` count if their name is one git-remote-inv4 uses: RepoData,
+/// the manifest, a git object hash, or the decimal xxh3 id MultiObjects are named by. Foreign
+/// metadata that isn't even UTF-8 is skipped without an error.
+pub fn is_git_ipf(metadata: &[u8]) -> bool {
+    if metadata.starts_with(GIT_IPF_TAG) {
+        return true;
+    }
+
+    let end = metadata
+        .iter()
+        .position(|b| *b == METADATA_CID_SEPARATOR)
+        .unwrap_or(metadata.len());
+
+    match std::str::from_utf8(&metadata[..end]) {
+        Ok(name) => {
+            let name = lookup_name(name);
+            name == REPO_DATA_NAME
+                || name == MANIFEST_NAME
+                || (matches!(name.len(), 40 | 64) && name.bytes().all(|b| b.is_ascii_hexdigit()))
+                || (name.bytes().all(|b| b.is_ascii_digit()) && name.parse::<u64>().is_ok())
+        }
+        Err(_) => false,
+    }
+}
+
+/// The name IPFs are looked up by: whatever follows the last `/`, so objects minted with a
+/// `chain.metadata_template` are found under their hash like those minted before.
+fn lookup_name(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_multi_object_names_are_git_ipfs() {
+        let mut metadata = b"12345678901234567890".to_vec();
+        assert!(is_git_ipf(&metadata));
+
+        metadata.push(METADATA_CID_SEPARATOR);
+        metadata.extend_from_slice(b"cid bytes");
+        assert!(is_git_ipf(&metadata));
+
+        assert!(is_git_ipf(b"3/commit/42"));
+        assert!(!is_git_ipf(b"99999999999999999999"));
+    }
+
+    #[test]
+    fn only_git_remote_inv4_names_are_git_ipfs() {
+        assert!(is_git_ipf(b"git/anything"));
+        assert!(is_git_ipf(b"RepoData"));
+        assert!(is_git_ipf(b"0123456789abcdef0123456789abcdef01234567"));
+        assert!(!is_git_ipf(b""));
+        assert!(!is_git_ipf(b"Receipt"));
+        assert!(!is_git_ipf(&[0xff, 0xfe]));
+    }
+}
@@ -0,0 +1,529 @@
+use crate::{
+    compression::Compression, error, types::GitHash, BoxResult, PrefixedInput, VerificationError,
+};
+use codec::{Compact, Decode, Encode, Input, Output};
+#[cfg(feature = "git2")]
+use git2::{Blob, Commit, ObjectType, Odb, Oid, Repository, Tag, Tree};
+use log::debug;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+};
+#[cfg(feature = "git2")]
+use twox_hash::xxh3;
+
+#[derive(Clone, Debug)]
+pub struct MultiObject {
+    pub hash: String,
+    pub git_hashes: Vec<GitHash>,
+    pub objects: BTreeMap<GitHash, GitObject>,
+}
+
+/// First byte of a versioned MultiObject encoding.
+///
+/// Unversioned MultiObjects start with the compact length of their hash, which never starts
+/// with `0xff`, so the two can't be confused.
+pub const MULTI_OBJECT_MARKER: u8 = 0xff;
+
+/// Current MultiObject format: like version 1, with an optional signature of its uploader after
+/// every object. Only written if an object is signed.
+pub const MULTI_OBJECT_VERSION: u8 = 2;
+
+/// Like the unversioned one, with the compression of every object before its data. Only written
+/// if an object is compressed, so clients predating it can read what is pushed without
+/// compression.
+pub const MULTI_OBJECT_VERSION_UNSIGNED: u8 = 1;
+
+/// Most bytes the compressed objects of one MultiObject may inflate to together.
+///
+/// Objects that large are streamed as chunks since pushes stopped holding them in memory, so
+/// only a hostile MultiObject gets near it.
+pub const MAX_INFLATED_BYTES: usize = 1 << 30;
+
+impl Encode for MultiObject {
+    fn encode_to<T: Output + ?Sized>(&self, dest: &mut T) {
+        self.encode_compressed_to(dest, |object| object.compression, &BTreeMap::new(), None);
+    }
+}
+
+impl Decode for MultiObject {
+    fn decode<I: Input>(input: &mut I) -> Result<Self, codec::Error> {
+        let first = input.read_byte()?;
+        let versioned = first == MULTI_OBJECT_MARKER;
+
+        let signed = if versioned {
+            match input.read_byte()? {
+                MULTI_OBJECT_VERSION => true,
+                MULTI_OBJECT_VERSION_UNSIGNED => false,
+                _ => {
+                    return Err(
+                        "Unknown MultiObject version, update git-remote-inv4 to fetch it".into(),
+                    )
+                }
+            }
+        } else {
+            false
+        };
+
+        let mut input = PrefixedInput {
+            prefix: if versioned { None } else { Some(first) },
+            input,
+        };
+
+        let hash = String::decode(&mut input)?;
+        let git_hashes = Vec::<GitHash>::decode(&mut input)?;
+
+        let mut objects = BTreeMap::new();
+        let mut inflated = 0;
+        for _ in 0..Compact::<u32>::decode(&mut input)?.0 {
+            let key = GitHash::decode(&mut input)?;
+            let git_hash = GitHash::decode(&mut input)?;
+
+            let compression = if versioned {
+                Compression::from_tag(input.read_byte()?).map_err(|e| {
+                    debug!("Object {}: {}", git_hash, e);
+                    codec::Error::from("Unknown object compression, update git-remote-inv4")
+                })?
+            } else {
+                Compression::None
+            };
+            let data = compression
+                .decompress(
+                    Vec::<u8>::decode(&mut input)?,
+                    MAX_INFLATED_BYTES - inflated,
+                )
+                .map_err(|e| {
+                    debug!("Could not decompress object {}: {}", git_hash, e);
+                    codec::Error::from("Could not decompress an object")
+                })?;
+            if compression != Compression::None {
+                inflated += data.len();
+            }
+
+            let metadata = GitObjectMetadata::decode(&mut input)?;
+            let signature = if signed {
+                Option::<ObjectSignature>::decode(&mut input)?
+            } else {
+                None
+            };
+
+            objects.insert(
+                key,
+                GitObject {
+                    git_hash,
+                    data,
+                    metadata,
+                    compression,
+                    signature,
+                    streamed: None,
+                },
+            );
+        }
+
+        Ok(Self {
+            hash,
+            git_hashes,
+            objects,
+        })
+    }
+}
+
+impl MultiObject {
+    pub fn add(&mut self, object: GitObject) {
+        let hash = object.git_hash.clone();
+        self.objects.insert(hash.clone(), object);
+        self.git_hashes.push(hash);
+    }
+
+    /// The git type `{type}` stands for in the name this MultiObject is minted under: the
+    /// commits a push is made of if it has any, or else the tags, trees or blobs it holds.
+    pub fn object_type(&self) -> &'static str {
+        [
+            ObjectKind::Commit,
+            ObjectKind::Tag,
+            ObjectKind::Tree,
+            ObjectKind::Blob,
+        ]
+        .into_iter()
+        .find(|&kind| {
+            self.objects
+                .values()
+                .any(|object| object.metadata.kind() == kind)
+        })
+        .map(ObjectKind::name)
+        .unwrap_or("object")
+    }
+
+    /// The MultiObject of `git_hashes` in that order, read from `repo`, as it was minted before.
+    #[cfg(feature = "git2")]
+    pub fn rebuild(git_hashes: &[GitHash], repo: &Repository) -> BoxResult<Self> {
+        let odb = repo.odb()?;
+        let mut multi_object = Self {
+            hash: String::new(),
+            git_hashes: vec![],
+            objects: BTreeMap::new(),
+        };
+
+        for git_hash in git_hashes {
+            let obj = repo.find_object(git_hash.to_oid()?, None)?;
+            let not_a = |kind: &str| format!("Object {} is not a {}", git_hash, kind);
+
+            multi_object.add(match obj.kind() {
+                Some(ObjectType::Commit) => GitObject::from_git_commit(
+                    obj.as_commit().ok_or_else(|| not_a("commit"))?,
+                    &odb,
+                )?,
+                Some(ObjectType::Tree) => {
+                    GitObject::from_git_tree(obj.as_tree().ok_or_else(|| not_a("tree"))?, &odb)?
+                }
+                Some(ObjectType::Blob) => {
+                    GitObject::from_git_blob(obj.as_blob().ok_or_else(|| not_a("blob"))?, &odb)?
+                }
+                Some(ObjectType::Tag) => {
+                    GitObject::from_git_tag(obj.as_tag().ok_or_else(|| not_a("tag"))?, &odb)?
+                }
+                _ => error!(format!("Don't know how to rebuild object {}", git_hash)),
+            });
+        }
+
+        multi_object.hash = xxh3::hash64(multi_object.git_hashes.encode().as_slice()).to_string();
+        Ok(multi_object)
+    }
+
+    /// The encoding of this MultiObject as a push uploads it: the data of every object
+    /// compressed with `compression` whatever it was read with, the blobs in `chunks` stored
+    /// as those chunks, and every object signed by `signer` if given.
+    ///
+    /// Unlike a chunked or signed copy of the MultiObject, this copies no object's data but
+    /// the compressed ones.
+    pub fn encode_upload(
+        &self,
+        compression: Compression,
+        chunks: &BTreeMap<GitHash, Vec<BlobChunk>>,
+        signer: Option<&dyn Fn(&GitObject) -> ObjectSignature>,
+    ) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        self.encode_compressed_to(&mut encoded, |_| compression, chunks, signer);
+        encoded
+    }
+
+    /// Objects whose data doesn't get smaller are stored uncompressed.
+    fn encode_compressed_to<T: Output + ?Sized>(
+        &self,
+        dest: &mut T,
+        compression_of: impl Fn(&GitObject) -> Compression,
+        chunks: &BTreeMap<GitHash, Vec<BlobChunk>>,
+        signer: Option<&dyn Fn(&GitObject) -> ObjectSignature>,
+    ) {
+        let stored: Vec<(Compression, Cow<[u8]>)> = self
+            .objects
+            .values()
+            .map(|object| match compression_of(object) {
+                _ if chunks.contains_key(&object.git_hash) => {
+                    (Compression::None, Cow::Borrowed(&[][..]))
+                }
+                Compression::None => (Compression::None, Cow::Borrowed(object.data.as_slice())),
+                compression => match compression.compress(&object.data) {
+                    Ok(compressed) if compressed.len() < object.data.len() => {
+                        (compression, Cow::Owned(compressed))
+                    }
+                    Ok(_) => (Compression::None, Cow::Borrowed(object.data.as_slice())),
+                    Err(e) => {
+                        debug!("Storing object {} uncompressed: {}", object.git_hash, e);
+                        (Compression::None, Cow::Borrowed(object.data.as_slice()))
+                    }
+                },
+            })
+            .collect();
+        let signed = signer.is_some()
+            || self
+                .objects
+                .values()
+                .any(|object| object.signature.is_some());
+        let versioned = signed
+            || stored
+                .iter()
+                .any(|(compression, _)| *compression != Compression::None);
+
+        if versioned {
+            dest.push_byte(MULTI_OBJECT_MARKER);
+            dest.push_byte(if signed {
+                MULTI_OBJECT_VERSION
+            } else {
+                MULTI_OBJECT_VERSION_UNSIGNED
+            });
+        }
+
+        self.hash.encode_to(dest);
+        self.git_hashes.encode_to(dest);
+
+        Compact(self.objects.len() as u32).encode_to(dest);
+        for ((key, object), (compression, data)) in self.objects.iter().zip(stored) {
+            key.encode_to(dest);
+            object.git_hash.encode_to(dest);
+            if versioned {
+                dest.push_byte(compression.tag());
+            }
+            data.encode_to(dest);
+            match chunks.get(&object.git_hash) {
+                Some(chunks) => GitObjectMetadata::ChunkedBlob {
+                    chunks: chunks.clone(),
+                }
+                .encode_to(dest),
+                None => object.metadata.encode_to(dest),
+            }
+            if signed {
+                match signer {
+                    Some(sign) => Some(sign(object)).encode_to(dest),
+                    None => object.signature.encode_to(dest),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GitObject {
+    /// The git hash of the underlying git object
+    pub git_hash: GitHash,
+    /// A link to the raw form of the object
+    pub data: Vec<u8>,
+    /// Object-type-specific metadata
+    pub metadata: GitObjectMetadata,
+    /// How `data` is stored in the MultiObject it was read from; `data` itself is never
+    /// compressed
+    pub compression: Compression,
+    /// Signature of the member who uploaded the object, since MultiObject version 2
+    pub signature: Option<ObjectSignature>,
+    /// Set for blobs too large to read into `data`, which a push streams from the object
+    /// database to IPFS as chunks instead; never encoded
+    pub streamed: Option<StreamedBlob>,
+}
+
+/// A blob a push reads from the object database a chunk at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamedBlob {
+    /// Length of the blob
+    pub size: u64,
+    /// blake2-256 of the blob, known once it was streamed
+    pub checksum: Option<[u8; 32]>,
+}
+
+/// A detached sr25519 signature over the git hash of an object, made by whoever uploaded it.
+///
+/// It proves who contributed the object whichever account signed the extrinsic minting it, as
+/// when a DAO batches the uploads of its members.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct ObjectSignature {
+    /// Public key of the signer
+    pub signer: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+/// What an object signature covers, so it can't be passed off as a signature of anything else.
+pub fn object_signing_payload(git_hash: &GitHash) -> Vec<u8> {
+    let mut payload = b"inv4-object:".to_vec();
+    git_hash.encode_to(&mut payload);
+    payload
+}
+
+#[derive(Clone, Debug, Encode, Decode)]
+pub enum GitObjectMetadata {
+    #[allow(missing_docs)]
+    Commit {
+        parent_git_hashes: BTreeSet<GitHash>,
+        tree_git_hash: GitHash,
+    },
+    #[allow(missing_docs)]
+    Tag { target_git_hash: GitHash },
+    #[allow(missing_docs)]
+    Tree { entry_git_hashes: BTreeSet<GitHash> },
+    #[allow(missing_docs)]
+    Blob,
+    /// A blob stored as content-defined chunks outside the MultiObject, so versions of a large
+    /// file share the chunks they have in common. Its `data` is empty.
+    ChunkedBlob { chunks: Vec<BlobChunk> },
+}
+
+/// One chunk of a `ChunkedBlob`, in order.
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct BlobChunk {
+    /// The chunk's CID in binary form
+    pub cid: Vec<u8>,
+    /// Length of the chunk
+    pub size: u32,
+}
+
+impl GitObjectMetadata {
+    pub fn kind(&self) -> ObjectKind {
+        match self {
+            GitObjectMetadata::Blob | GitObjectMetadata::ChunkedBlob { .. } => ObjectKind::Blob,
+            GitObjectMetadata::Commit { .. } => ObjectKind::Commit,
+            GitObjectMetadata::Tag { .. } => ObjectKind::Tag,
+            GitObjectMetadata::Tree { .. } => ObjectKind::Tree,
+        }
+    }
+
+    #[cfg(feature = "git2")]
+    pub fn object_type(&self) -> ObjectType {
+        self.kind().object_type()
+    }
+}
+
+/// The type and size of a git object, recorded in RepoData so fetch knows them before
+/// downloading the object's MultiObject.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Encode, Decode)]
+pub struct ObjectHeader {
+    pub kind: ObjectKind,
+    /// Length of the object's raw data
+    pub size: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Encode, Decode)]
+pub enum ObjectKind {
+    Commit,
+    Tag,
+    Tree,
+    Blob,
+}
+
+impl ObjectKind {
+    /// The name git gives objects of this kind.
+    pub fn name(self) -> &'static str {
+        match self {
+            ObjectKind::Commit => "commit",
+            ObjectKind::Tag => "tag",
+            ObjectKind::Tree => "tree",
+            ObjectKind::Blob => "blob",
+        }
+    }
+
+    #[cfg(feature = "git2")]
+    pub fn object_type(self) -> ObjectType {
+        match self {
+            ObjectKind::Commit => ObjectType::Commit,
+            ObjectKind::Tag => ObjectType::Tag,
+            ObjectKind::Tree => ObjectType::Tree,
+            ObjectKind::Blob => ObjectType::Blob,
+        }
+    }
+}
+
+impl GitObject {
+    pub fn header(&self) -> ObjectHeader {
+        ObjectHeader {
+            kind: self.metadata.kind(),
+            size: match &self.metadata {
+                GitObjectMetadata::ChunkedBlob { chunks } => {
+                    chunks.iter().map(|chunk| chunk.size as u64).sum()
+                }
+                _ => match self.streamed {
+                    Some(streamed) => streamed.size,
+                    None => self.data.len() as u64,
+                },
+            },
+        }
+    }
+
+    /// Fails if this object doesn't match the header RepoData has for it.
+    pub fn check_header(&self, expected: &ObjectHeader) -> BoxResult<()> {
+        let actual = self.header();
+
+        if actual != *expected {
+            error!(VerificationError(format!(
+                "Object {} is a {} of {} bytes, but RepoData says it's a {} of {} bytes",
+                self.git_hash,
+                actual.kind.name(),
+                actual.size,
+                expected.kind.name(),
+                expected.size
+            )))
+        }
+
+        Ok(())
+    }
+
+    /// A blob of `size` bytes left in the object database until a push streams it.
+    #[cfg(feature = "git2")]
+    pub fn streamed_blob(oid: Oid, size: u64) -> Self {
+        Self {
+            git_hash: oid.into(),
+            data: Vec::new(),
+            metadata: GitObjectMetadata::Blob,
+            compression: Compression::None,
+            signature: None,
+            streamed: Some(StreamedBlob {
+                size,
+                checksum: None,
+            }),
+        }
+    }
+
+    #[cfg(feature = "git2")]
+    pub fn from_git_blob(blob: &Blob, odb: &Odb) -> BoxResult<Self> {
+        let odb_obj = odb.read(blob.id())?;
+
+        Ok(Self {
+            git_hash: blob.id().into(),
+            data: odb_obj.data().to_vec(),
+            metadata: GitObjectMetadata::Blob,
+            compression: Compression::None,
+            signature: None,
+            streamed: None,
+        })
+    }
+
+    #[cfg(feature = "git2")]
+    pub fn from_git_commit(commit: &Commit, odb: &Odb) -> BoxResult<Self> {
+        let odb_obj = odb.read(commit.id())?;
+
+        let parent_git_hashes: BTreeSet<GitHash> = commit.parent_ids().map(GitHash::from).collect();
+
+        let tree_git_hash = commit.tree()?.id().into();
+
+        Ok(Self {
+            git_hash: commit.id().into(),
+            data: odb_obj.data().to_vec(),
+            metadata: GitObjectMetadata::Commit {
+                parent_git_hashes,
+                tree_git_hash,
+            },
+            compression: Compression::None,
+            signature: None,
+            streamed: None,
+        })
+    }
+
+    #[cfg(feature = "git2")]
+    pub fn from_git_tag(tag: &Tag, odb: &Odb) -> BoxResult<Self> {
+        let odb_obj = odb.read(tag.id())?;
+
+        Ok(Self {
+            git_hash: tag.id().into(),
+            data: odb_obj.data().to_vec(),
+            metadata: GitObjectMetadata::Tag {
+                target_git_hash: tag.target_id().into(),
+            },
+            compression: Compression::None,
+            signature: None,
+            streamed: None,
+        })
+    }
+
+    #[cfg(feature = "git2")]
+    pub fn from_git_tree(tree: &Tree, odb: &Odb) -> BoxResult<Self> {
+        let odb_obj = odb.read(tree.id())?;
+
+        let entry_git_hashes: BTreeSet<GitHash> =
+            tree.iter().map(|entry| entry.id().into()).collect();
+
+        Ok(Self {
+            git_hash: tree.id().into(),
+            data: odb_obj.data().to_vec(),
+            metadata: GitObjectMetadata::Tree { entry_git_hashes },
+            compression: Compression::None,
+            signature: None,
+            streamed: None,
+        })
+    }
+}
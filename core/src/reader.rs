@@ -0,0 +1,253 @@
+use crate::{
+    backend::{ChainBackend, IpfsBackend},
+    error,
+    metadata::{decode_ipf_metadata, ipf_metadata_name, is_git_ipf, REPO_DATA_NAME},
+    objects::{GitObject, GitObjectMetadata, MultiObject},
+    repo_data::RepoData,
+    types::{GitHash, IpsId},
+    BoxResult, VerificationError,
+};
+use cid::Cid;
+use codec::Decode;
+use std::collections::HashMap;
+
+/// Reads the repository an IPS holds through the backends the caller supplies.
+///
+/// Signatures of RepoData and objects are not checked, the caller verifies them if it has an
+/// sr25519 implementation at hand.
+pub struct Reader<'a> {
+    pub ipfs: &'a dyn IpfsBackend,
+    pub chain: &'a dyn ChainBackend,
+    pub ips_id: IpsId,
+}
+
+impl Reader<'_> {
+    /// The CID of the git IPF named `name`, the latest one if it was minted more than once.
+    pub async fn find_ipf(&self, name: &str) -> BoxResult<Option<Cid>> {
+        for ipf_id in self.chain.ipf_ids(self.ips_id).await?.into_iter().rev() {
+            let ipf = self
+                .chain
+                .ipf(ipf_id)
+                .await?
+                .ok_or("Internal error: IPF listed from IPS does not exist")?;
+
+            if is_git_ipf(&ipf.metadata) && ipf_metadata_name(&ipf.metadata)? == name {
+                return Ok(Some(decode_ipf_metadata(&ipf.metadata, ipf.data)?.1));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The RepoData of the repository, `None` if nothing was pushed yet.
+    pub async fn repo_data(&self) -> BoxResult<Option<RepoData>> {
+        let cid = match self.find_ipf(REPO_DATA_NAME).await? {
+            Some(cid) => cid,
+            None => return Ok(None),
+        };
+
+        let encoded = self.ipfs.cat(&cid).await?;
+        Ok(Some(RepoData::decode(&mut encoded.as_slice())?))
+    }
+
+    /// The MultiObject `hash`, with its chunked blobs reassembled.
+    pub async fn multi_object(&self, hash: &str) -> BoxResult<MultiObject> {
+        let cid = self
+            .find_ipf(hash)
+            .await?
+            .ok_or_else(|| format!("MultiObject {} is not in IPS {}", hash, self.ips_id))?;
+
+        let encoded = self.ipfs.cat(&cid).await?;
+        let mut multi_object = MultiObject::decode(&mut encoded.as_slice())
+            .map_err(|e| format!("Could not decode MultiObject {}: {}", hash, e))?;
+
+        if multi_object.hash != hash {
+            error!(VerificationError(format!(
+                "The IPF of MultiObject {} holds MultiObject {}",
+                hash, multi_object.hash
+            )))
+        }
+
+        self.reassemble(&mut multi_object).await?;
+        Ok(multi_object)
+    }
+
+    /// The object `git_hash` of the repository `repo_data` describes.
+    pub async fn object(&self, repo_data: &RepoData, git_hash: &GitHash) -> BoxResult<GitObject> {
+        let multi_object_hash = repo_data
+            .objects
+            .get(git_hash)
+            .ok_or_else(|| format!("Object {} is not in IPS {}", git_hash, self.ips_id))?;
+
+        let object = self
+            .multi_object(multi_object_hash)
+            .await?
+            .objects
+            .remove(git_hash)
+            .ok_or_else(|| {
+                VerificationError(format!(
+                    "MultiObject {} does not hold object {}",
+                    multi_object_hash, git_hash
+                ))
+            })?;
+
+        if let Some(header) = repo_data.object_headers.get(git_hash) {
+            object.check_header(header)?;
+        }
+
+        Ok(object)
+    }
+
+    /// Turns every chunked blob in `multi_object` back into a blob holding its data.
+    async fn reassemble(&self, multi_object: &mut MultiObject) -> BoxResult<()> {
+        // Chunks repeat within a MultiObject as well, as in files that share a header
+        let mut fetched: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+
+        for object in multi_object.objects.values_mut() {
+            let chunks = match &object.metadata {
+                GitObjectMetadata::ChunkedBlob { chunks } => chunks,
+                _ => continue,
+            };
+
+            let mut data = Vec::with_capacity(chunks.iter().map(|chunk| chunk.size as usize).sum());
+
+            for chunk in chunks {
+                if !fetched.contains_key(&chunk.cid) {
+                    let cid = Cid::try_from(chunk.cid.as_slice())?;
+                    let content = self.ipfs.cat(&cid).await?;
+
+                    if content.len() != chunk.size as usize {
+                        error!(VerificationError(format!(
+                            "Chunk {} of blob {} holds {} bytes, expected {}",
+                            cid,
+                            object.git_hash,
+                            content.len(),
+                            chunk.size
+                        )))
+                    }
+                    fetched.insert(chunk.cid.clone(), content);
+                }
+
+                data.extend_from_slice(&fetched[&chunk.cid]);
+            }
+
+            object.data = data;
+            object.metadata = GitObjectMetadata::Blob;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::{BackendFuture, IpfRecord},
+        compression::Compression,
+        metadata::{generate_cid, METADATA_CID_SEPARATOR},
+        objects::{BlobChunk, ObjectHeader, ObjectKind},
+        types::{HashAlgorithm, IpfId},
+    };
+    use cid::multihash::{Code, MultihashDigest};
+    use codec::Encode;
+    use std::collections::BTreeMap;
+
+    /// IPFS and chain held in memory, as a browser would hold what it fetched.
+    #[derive(Default)]
+    struct Memory {
+        files: HashMap<Cid, Vec<u8>>,
+        ipfs: Vec<IpfRecord>,
+    }
+
+    impl Memory {
+        fn add(&mut self, content: Vec<u8>) -> Cid {
+            let digest: [u8; 32] = Code::Sha2_256.digest(&content).digest().try_into().unwrap();
+            let cid = generate_cid(digest).unwrap();
+            self.files.insert(cid, content);
+            cid
+        }
+
+        fn mint(&mut self, name: &str, content: Vec<u8>) {
+            let cid = self.add(content);
+            let mut metadata = format!("git/{}", name).into_bytes();
+            metadata.push(METADATA_CID_SEPARATOR);
+            metadata.extend_from_slice(&cid.to_bytes());
+
+            self.ipfs.push(IpfRecord {
+                metadata,
+                data: cid.hash().digest().try_into().unwrap(),
+            });
+        }
+    }
+
+    impl IpfsBackend for Memory {
+        fn cat<'a>(&'a self, cid: &'a Cid) -> BackendFuture<'a, Vec<u8>> {
+            Box::pin(async move { Ok(self.files.get(cid).ok_or("No such file")?.clone()) })
+        }
+    }
+
+    impl ChainBackend for Memory {
+        fn ipf_ids(&self, _: IpsId) -> BackendFuture<'_, Vec<IpfId>> {
+            Box::pin(async move { Ok((0..self.ipfs.len() as u64).map(IpfId).collect()) })
+        }
+
+        fn ipf(&self, ipf_id: IpfId) -> BackendFuture<'_, Option<IpfRecord>> {
+            Box::pin(async move { Ok(self.ipfs.get(ipf_id.0 as usize).cloned()) })
+        }
+    }
+
+    #[test]
+    fn objects_are_read_through_the_backends() {
+        let git_hash: GitHash = "3b18e512dba79e4c8300dd08aeb37f8e728b8dad".parse().unwrap();
+        let content = b"hello, browser\n".to_vec();
+
+        let mut memory = Memory::default();
+        let chunk_cid = memory.add(content.clone());
+
+        let mut multi_object = MultiObject {
+            hash: "42".to_owned(),
+            git_hashes: vec![],
+            objects: BTreeMap::new(),
+        };
+        multi_object.add(GitObject {
+            git_hash: git_hash.clone(),
+            data: vec![],
+            metadata: GitObjectMetadata::ChunkedBlob {
+                chunks: vec![BlobChunk {
+                    cid: chunk_cid.to_bytes(),
+                    size: content.len() as u32,
+                }],
+            },
+            compression: Compression::None,
+            signature: None,
+            streamed: None,
+        });
+        memory.mint("42", multi_object.encode());
+
+        let mut repo_data = RepoData::new(HashAlgorithm::Sha1);
+        repo_data.objects.insert(git_hash.clone(), "42".to_owned());
+        repo_data.object_headers.insert(
+            git_hash.clone(),
+            ObjectHeader {
+                kind: ObjectKind::Blob,
+                size: content.len() as u64,
+            },
+        );
+        memory.mint(REPO_DATA_NAME, repo_data.encode().unwrap());
+
+        let reader = Reader {
+            ipfs: &memory,
+            chain: &memory,
+            ips_id: IpsId(0),
+        };
+        futures::executor::block_on(async {
+            let repo_data = reader.repo_data().await.unwrap().unwrap();
+            let object = reader.object(&repo_data, &git_hash).await.unwrap();
+
+            assert_eq!(object.data, content);
+            assert!(matches!(object.metadata, GitObjectMetadata::Blob));
+            assert!(reader.multi_object("43").await.is_err());
+        });
+    }
+}
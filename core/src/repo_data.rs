@@ -0,0 +1,635 @@
+use crate::{
+    bloom::ObjectFilter,
+    error,
+    objects::ObjectHeader,
+    types::{GitHash, HashAlgorithm},
+    BoxResult, PrefixedInput,
+};
+use codec::{Compact, Decode, Encode, Input, Output};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone)]
+pub struct RepoData {
+    /// The object format of the repository
+    pub hash_algorithm: HashAlgorithm,
+    /// All refs this repository knows; a {name -> git hash} map
+    pub refs: BTreeMap<String, GitHash>,
+    /// Refs standing for another ref, like HEAD; a {name -> target} map
+    pub symrefs: BTreeMap<String, String>,
+    /// The last updates of each ref, oldest first
+    pub reflogs: BTreeMap<String, Vec<RefLogEntry>>,
+    /// All objects this repository contains; a {git hash -> MultiObject hash} map
+    pub objects: BTreeMap<GitHash, String>,
+    /// Type and size of the objects pushed since RepoData was versioned; a {git hash -> header}
+    /// map
+    pub object_headers: BTreeMap<GitHash, ObjectHeader>,
+    /// Objects in `objects` that no ref reached anymore after a ref was deleted. Only a hint, as
+    /// concurrent pushes are merged without walking history, so check before acting on it
+    pub tombstones: BTreeSet<GitHash>,
+    /// Commits pushed from shallow or grafted clones without their parents. Fetches stop at
+    /// them and make the fetching clone shallow there
+    pub shallow: BTreeSet<GitHash>,
+    /// What the annotated tags in `objects` point at, so fetches walk past them without
+    /// downloading them first; a {tag -> target} map
+    pub tag_targets: BTreeMap<GitHash, GitHash>,
+    /// Signature of the pusher over this RepoData and the one it replaced
+    pub signature: Option<RepoDataSignature>,
+}
+
+/// One update of a ref, as kept in its reflog.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefLogEntry {
+    /// `None` when the update created the ref
+    pub old: Option<GitHash>,
+    /// `None` when the update deleted the ref
+    pub new: Option<GitHash>,
+    /// Public key of the account that pushed it
+    pub pusher: [u8; 32],
+    /// The best block when the update was pushed; the RepoData lands in a later one
+    pub block: u32,
+}
+
+/// A detached sr25519 signature chaining a RepoData to the one it replaced.
+///
+/// It is part of the RepoData's content, so the chain of repository states can be checked from
+/// IPFS alone, without trusting the extrinsics that put them on chain.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct RepoDataSignature {
+    /// blake2-256 of the encoded RepoData this one replaced; `None` for the first one
+    pub previous: Option<[u8; 32]>,
+    /// Public key of the signer
+    pub signer: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+/// First byte of a versioned RepoData encoding.
+///
+/// Unversioned RepoData starts with the compact length of its refs map, and a compact `u32`
+/// never starts with `0xff`, so the two can't be confused.
+pub const REPO_DATA_MARKER: u8 = 0xff;
+
+/// RepoData format: the hash algorithm followed by refs and symbolic refs, then the reflogs, a
+/// bloom filter of the objects, the objects, object headers, tombstones, shallow commits and tag
+/// targets with raw hashes, each prefixed with its length in bytes so readers after the refs can
+/// skip them, then an optional signature over the blake2-256 of all that.
+///
+/// Unversioned RepoData, with hex string hashes and nothing but refs and objects, came before it.
+pub const REPO_DATA_VERSION: u8 = 1;
+
+/// RepoData as it was encoded before formats were versioned, with hex string hashes.
+#[derive(Decode)]
+struct LegacyRepoData {
+    refs: BTreeMap<String, GitHash>,
+    objects: BTreeMap<GitHash, String>,
+}
+
+impl RepoData {
+    pub fn new(hash_algorithm: HashAlgorithm) -> Self {
+        Self {
+            hash_algorithm,
+            refs: Default::default(),
+            symrefs: Default::default(),
+            reflogs: Default::default(),
+            objects: Default::default(),
+            object_headers: Default::default(),
+            tombstones: Default::default(),
+            shallow: Default::default(),
+            tag_targets: Default::default(),
+            signature: None,
+        }
+    }
+
+    /// The encoding stored on IPFS. Fails if one of the hashes held isn't hex.
+    pub fn encode(&self) -> BoxResult<Vec<u8>> {
+        let mut encoded = Vec::new();
+        self.encode_unsigned_to(&mut encoded)?;
+        self.signature.encode_to(&mut encoded);
+        Ok(encoded)
+    }
+
+    /// Everything but the signature, which is what gets signed.
+    fn encode_unsigned_to<T: Output + ?Sized>(&self, dest: &mut T) -> BoxResult<()> {
+        dest.push_byte(REPO_DATA_MARKER);
+        dest.push_byte(REPO_DATA_VERSION);
+        self.hash_algorithm.encode_to(dest);
+
+        Compact(self.refs.len() as u32).encode_to(dest);
+        for (name, git_hash) in &self.refs {
+            name.encode_to(dest);
+            dest.write(&self.raw(git_hash)?);
+        }
+        self.symrefs.encode_to(dest);
+
+        let mut reflogs = Vec::new();
+        Compact(self.reflogs.len() as u32).encode_to(&mut reflogs);
+        for (name, entries) in &self.reflogs {
+            name.encode_to(&mut reflogs);
+            Compact(entries.len() as u32).encode_to(&mut reflogs);
+            for entry in entries {
+                self.encode_optional_git_hash_to(&entry.old, &mut reflogs)?;
+                self.encode_optional_git_hash_to(&entry.new, &mut reflogs)?;
+                reflogs.extend_from_slice(&entry.pusher);
+                entry.block.encode_to(&mut reflogs);
+            }
+        }
+        encode_section_to(&reflogs, dest);
+
+        let filter = ObjectFilter::new(self.objects.keys(), self.objects.len());
+        encode_section_to(&filter.encode(), dest);
+
+        let mut objects = Vec::new();
+        Compact(self.objects.len() as u32).encode_to(&mut objects);
+        for (git_hash, multi_object_hash) in &self.objects {
+            objects.extend_from_slice(&self.raw(git_hash)?);
+            multi_object_hash.encode_to(&mut objects);
+        }
+        encode_section_to(&objects, dest);
+
+        let mut object_headers = Vec::new();
+        Compact(self.object_headers.len() as u32).encode_to(&mut object_headers);
+        for (git_hash, header) in &self.object_headers {
+            object_headers.extend_from_slice(&self.raw(git_hash)?);
+            header.encode_to(&mut object_headers);
+        }
+        encode_section_to(&object_headers, dest);
+
+        let mut tombstones = Vec::new();
+        Compact(self.tombstones.len() as u32).encode_to(&mut tombstones);
+        for git_hash in &self.tombstones {
+            tombstones.extend_from_slice(&self.raw(git_hash)?);
+        }
+        encode_section_to(&tombstones, dest);
+
+        let mut shallow = Vec::new();
+        Compact(self.shallow.len() as u32).encode_to(&mut shallow);
+        for git_hash in &self.shallow {
+            shallow.extend_from_slice(&self.raw(git_hash)?);
+        }
+        encode_section_to(&shallow, dest);
+
+        let mut tag_targets = Vec::new();
+        Compact(self.tag_targets.len() as u32).encode_to(&mut tag_targets);
+        for (tag, target) in &self.tag_targets {
+            tag_targets.extend_from_slice(&self.raw(tag)?);
+            tag_targets.extend_from_slice(&self.raw(target)?);
+        }
+        encode_section_to(&tag_targets, dest);
+
+        Ok(())
+    }
+
+    /// The raw bytes of `git_hash`, which has to be named with the object format of this
+    /// RepoData, as readers take every hash to be that long.
+    fn raw(&self, git_hash: &GitHash) -> BoxResult<Vec<u8>> {
+        if git_hash.algorithm() != self.hash_algorithm {
+            error!(format!(
+                "{} is not a {} object id",
+                git_hash,
+                self.hash_algorithm.name()
+            ))
+        }
+
+        git_hash.to_raw()
+    }
+
+    fn encode_optional_git_hash_to(
+        &self,
+        git_hash: &Option<GitHash>,
+        dest: &mut Vec<u8>,
+    ) -> BoxResult<()> {
+        match git_hash {
+            Some(git_hash) => {
+                dest.push(1);
+                dest.extend_from_slice(&self.raw(git_hash)?);
+            }
+            None => dest.push(0),
+        }
+
+        Ok(())
+    }
+
+    /// The encoding the signature covers.
+    pub fn unsigned_encoding(&self) -> BoxResult<Vec<u8>> {
+        let mut unsigned = Vec::new();
+        self.encode_unsigned_to(&mut unsigned)?;
+        Ok(unsigned)
+    }
+
+    /// Makes sure objects named with `local` can be pushed into this repository.
+    ///
+    /// An empty repository takes on the local object format.
+    pub fn adopt_hash_algorithm(&mut self, local: HashAlgorithm) -> BoxResult<()> {
+        if self.refs.is_empty() && self.objects.is_empty() {
+            self.hash_algorithm = local;
+        } else if self.hash_algorithm != local {
+            error!(format!(
+                "The on-chain repository uses {} object ids but the local one uses {}. Mixing object formats is not possible.",
+                self.hash_algorithm.name(),
+                local.name()
+            ))
+        }
+
+        Ok(())
+    }
+
+    /// Applies the ref updates this RepoData has over `base` on top of `theirs`, another update
+    /// of `base`, and takes over their objects.
+    ///
+    /// Fails if both changed the same ref to different commits.
+    pub fn merge(&mut self, base: &BTreeMap<String, GitHash>, theirs: &RepoData) -> BoxResult<()> {
+        if self.hash_algorithm != theirs.hash_algorithm {
+            error!("Can't merge RepoData with different object formats")
+        }
+
+        let their_updates = RefUpdate::between(base, &theirs.refs);
+        let our_updates = RefUpdate::between(base, &self.refs);
+
+        for ours in &our_updates {
+            let diverged = their_updates
+                .iter()
+                .any(|their| their.name == ours.name && their.new != ours.new);
+
+            if diverged {
+                error!(format!(
+                    "both pushes changed {}. Fetch their changes and push again.",
+                    ours.name
+                ))
+            }
+        }
+
+        let mut refs = theirs.refs.clone();
+        for update in our_updates {
+            match update.new {
+                Some(git_hash) => refs.insert(update.name, git_hash),
+                None => refs.remove(&update.name),
+            };
+        }
+        self.refs = refs;
+
+        for (name, target) in &theirs.symrefs {
+            self.symrefs
+                .entry(name.clone())
+                .or_insert_with(|| target.clone());
+        }
+        self.symrefs
+            .retain(|_, target| self.refs.contains_key(target.as_str()));
+        // Reflogs are only appended to once merged, so theirs already extend ours
+        self.reflogs = theirs.reflogs.clone();
+
+        for (git_hash, multi_object_hash) in &theirs.objects {
+            self.objects
+                .entry(git_hash.clone())
+                .or_insert_with(|| multi_object_hash.clone());
+        }
+        for (git_hash, header) in &theirs.object_headers {
+            self.object_headers
+                .entry(git_hash.clone())
+                .or_insert(*header);
+        }
+        self.tombstones.extend(theirs.tombstones.iter().cloned());
+        self.shallow.extend(theirs.shallow.iter().cloned());
+        self.tag_targets.extend(
+            theirs
+                .tag_targets
+                .iter()
+                .map(|(tag, target)| (tag.clone(), target.clone())),
+        );
+
+        Ok(())
+    }
+
+    /// The ref HEAD stands for, if it stands for one that exists.
+    pub fn head(&self) -> Option<&str> {
+        self.symrefs
+            .get("HEAD")
+            .map(String::as_str)
+            .filter(|target| self.refs.contains_key(*target))
+    }
+
+    /// Finds the full name of `name` in this RepoData, trying branches and then tags.
+    pub fn resolve_ref(&self, name: &str) -> BoxResult<String> {
+        [
+            name.to_owned(),
+            format!("refs/heads/{}", name),
+            format!("refs/tags/{}", name),
+        ]
+        .into_iter()
+        .find(|candidate| self.refs.contains_key(candidate))
+        .ok_or_else(|| format!("No ref called {} in the IPS", name).into())
+    }
+}
+
+/// What a RepoData signature covers: the blake2-256 of the unsigned encoding, `digest`,
+/// followed by the previous RepoData's hash.
+pub fn signing_payload(digest: [u8; 32], previous: &Option<[u8; 32]>) -> Vec<u8> {
+    let mut payload = digest.to_vec();
+    previous.encode_to(&mut payload);
+    payload
+}
+
+/// Writes a section of a RepoData, prefixed with its length.
+fn encode_section_to<T: Output + ?Sized>(section: &[u8], dest: &mut T) {
+    Compact(section.len() as u64).encode_to(dest);
+    dest.write(section);
+}
+
+impl Decode for RepoData {
+    fn decode<I: Input>(input: &mut I) -> Result<Self, codec::Error> {
+        let first = input.read_byte()?;
+
+        if first != REPO_DATA_MARKER {
+            let legacy = LegacyRepoData::decode(&mut PrefixedInput {
+                prefix: Some(first),
+                input,
+            })?;
+
+            return Ok(Self {
+                hash_algorithm: HashAlgorithm::Sha1,
+                refs: legacy.refs,
+                symrefs: Default::default(),
+                reflogs: Default::default(),
+                objects: legacy.objects,
+                object_headers: Default::default(),
+                tombstones: Default::default(),
+                shallow: Default::default(),
+                tag_targets: Default::default(),
+                signature: None,
+            });
+        }
+
+        if input.read_byte()? != REPO_DATA_VERSION {
+            return Err("Unsupported RepoData format version, try updating git-remote-inv4".into());
+        }
+        let hash_algorithm = HashAlgorithm::decode(input)?;
+
+        let read_git_hash = |input: &mut I| -> Result<GitHash, codec::Error> {
+            let mut raw = vec![0u8; hash_algorithm.raw_len()];
+            input.read(&mut raw)?;
+            Ok(GitHash::from_raw(&raw))
+        };
+        let read_optional_git_hash = |input: &mut I| -> Result<Option<GitHash>, codec::Error> {
+            match input.read_byte()? {
+                0 => Ok(None),
+                _ => Ok(Some(read_git_hash(input)?)),
+            }
+        };
+        // Lengths only matter to readers skipping sections
+        let skip_section_length = |input: &mut I| -> Result<(), codec::Error> {
+            Compact::<u64>::decode(input)?;
+            Ok(())
+        };
+
+        let mut refs = BTreeMap::new();
+        for _ in 0..Compact::<u32>::decode(input)?.0 {
+            let name = String::decode(input)?;
+            refs.insert(name, read_git_hash(input)?);
+        }
+        let symrefs = BTreeMap::decode(input)?;
+
+        skip_section_length(input)?;
+        let mut reflogs = BTreeMap::new();
+        for _ in 0..Compact::<u32>::decode(input)?.0 {
+            let name = String::decode(input)?;
+            let mut entries = Vec::new();
+            for _ in 0..Compact::<u32>::decode(input)?.0 {
+                let old = read_optional_git_hash(input)?;
+                let new = read_optional_git_hash(input)?;
+                let pusher = <[u8; 32]>::decode(input)?;
+                entries.push(RefLogEntry {
+                    old,
+                    new,
+                    pusher,
+                    block: u32::decode(input)?,
+                });
+            }
+            reflogs.insert(name, entries);
+        }
+
+        // The filter is derived from the objects, which are read in full below
+        skip_section_length(input)?;
+        ObjectFilter::decode(input)?;
+
+        skip_section_length(input)?;
+        let mut objects = BTreeMap::new();
+        for _ in 0..Compact::<u32>::decode(input)?.0 {
+            let git_hash = read_git_hash(input)?;
+            objects.insert(git_hash, String::decode(input)?);
+        }
+
+        skip_section_length(input)?;
+        let mut object_headers = BTreeMap::new();
+        for _ in 0..Compact::<u32>::decode(input)?.0 {
+            let git_hash = read_git_hash(input)?;
+            object_headers.insert(git_hash, ObjectHeader::decode(input)?);
+        }
+
+        skip_section_length(input)?;
+        let mut tombstones = BTreeSet::new();
+        for _ in 0..Compact::<u32>::decode(input)?.0 {
+            tombstones.insert(read_git_hash(input)?);
+        }
+
+        skip_section_length(input)?;
+        let mut shallow = BTreeSet::new();
+        for _ in 0..Compact::<u32>::decode(input)?.0 {
+            shallow.insert(read_git_hash(input)?);
+        }
+
+        skip_section_length(input)?;
+        let mut tag_targets = BTreeMap::new();
+        for _ in 0..Compact::<u32>::decode(input)?.0 {
+            let tag = read_git_hash(input)?;
+            tag_targets.insert(tag, read_git_hash(input)?);
+        }
+
+        let signature = Option::<RepoDataSignature>::decode(input)?;
+
+        Ok(Self {
+            hash_algorithm,
+            refs,
+            symrefs,
+            reflogs,
+            objects,
+            object_headers,
+            tombstones,
+            shallow,
+            tag_targets,
+            signature,
+        })
+    }
+}
+
+/// A ref that differs between two RepoData versions; `None` means the ref doesn't exist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefUpdate {
+    pub name: String,
+    pub old: Option<GitHash>,
+    pub new: Option<GitHash>,
+}
+
+impl RefUpdate {
+    /// The refs that were created, moved or deleted going from `old` to `new`.
+    pub fn between(
+        old: &BTreeMap<String, GitHash>,
+        new: &BTreeMap<String, GitHash>,
+    ) -> Vec<RefUpdate> {
+        let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let old_git_hash = old.get(name);
+                let new_git_hash = new.get(name);
+
+                (old_git_hash != new_git_hash).then(|| RefUpdate {
+                    name: name.clone(),
+                    old: old_git_hash.cloned(),
+                    new: new_git_hash.cloned(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "old": self.old.as_ref().map(ToString::to_string),
+            "new": self.new.as_ref().map(ToString::to_string),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::ObjectKind;
+
+    fn git_hash(byte: u8) -> GitHash {
+        GitHash::from_raw(&[byte; 20])
+    }
+
+    #[test]
+    fn repo_data_round_trips() {
+        let mut repo_data = RepoData::new(HashAlgorithm::Sha1);
+        repo_data
+            .refs
+            .insert("refs/heads/main".to_owned(), git_hash(1));
+        repo_data
+            .refs
+            .insert("refs/tags/v1".to_owned(), git_hash(2));
+        repo_data
+            .symrefs
+            .insert("HEAD".to_owned(), "refs/heads/main".to_owned());
+        repo_data.reflogs.insert(
+            "refs/heads/main".to_owned(),
+            vec![
+                RefLogEntry {
+                    old: None,
+                    new: Some(git_hash(3)),
+                    pusher: [7; 32],
+                    block: 10,
+                },
+                RefLogEntry {
+                    old: Some(git_hash(3)),
+                    new: Some(git_hash(1)),
+                    pusher: [8; 32],
+                    block: 12,
+                },
+            ],
+        );
+        for byte in 1..=4 {
+            repo_data
+                .objects
+                .insert(git_hash(byte), format!("{}", byte * 100));
+        }
+        repo_data.object_headers.insert(
+            git_hash(1),
+            ObjectHeader {
+                kind: ObjectKind::Commit,
+                size: 230,
+            },
+        );
+        repo_data.tombstones.insert(git_hash(3));
+        repo_data.shallow.insert(git_hash(1));
+        repo_data.tag_targets.insert(git_hash(2), git_hash(1));
+
+        let encoded = repo_data.encode().unwrap();
+        let decoded = RepoData::decode(&mut encoded.as_slice()).unwrap();
+
+        assert_eq!(decoded.hash_algorithm, HashAlgorithm::Sha1);
+        assert_eq!(decoded.refs, repo_data.refs);
+        assert_eq!(decoded.symrefs, repo_data.symrefs);
+        assert_eq!(decoded.reflogs, repo_data.reflogs);
+        assert_eq!(decoded.objects, repo_data.objects);
+        assert_eq!(decoded.object_headers, repo_data.object_headers);
+        assert_eq!(decoded.tombstones, repo_data.tombstones);
+        assert_eq!(decoded.shallow, repo_data.shallow);
+        assert_eq!(decoded.tag_targets, repo_data.tag_targets);
+        assert_eq!(decoded.head(), Some("refs/heads/main"));
+        assert_eq!(decoded.encode().unwrap(), encoded);
+    }
+
+    #[test]
+    fn unversioned_repo_data_still_decodes() {
+        let refs: BTreeMap<String, GitHash> = [("refs/heads/main".to_owned(), git_hash(1))].into();
+        let objects: BTreeMap<GitHash, String> = [
+            (git_hash(1), "1736712396309695476".to_owned()),
+            (git_hash(2), "1736712396309695476".to_owned()),
+        ]
+        .into();
+        let encoded = (&refs, &objects).encode();
+
+        let decoded = RepoData::decode(&mut encoded.as_slice()).unwrap();
+
+        assert_eq!(decoded.hash_algorithm, HashAlgorithm::Sha1);
+        assert_eq!(decoded.refs, refs);
+        assert_eq!(decoded.objects, objects);
+        assert!(decoded.symrefs.is_empty() && decoded.reflogs.is_empty());
+    }
+
+    #[test]
+    fn hex_hashes_survive_the_raw_encoding() {
+        let sha1: GitHash = "3b18e512dba79e4c8300dd08aeb37f8e728b8dad".parse().unwrap();
+        let sha256: GitHash = format!("{}{}", "0f".repeat(16), "a1".repeat(16))
+            .parse()
+            .unwrap();
+
+        for (algorithm, git_hash) in [(HashAlgorithm::Sha1, sha1), (HashAlgorithm::Sha256, sha256)]
+        {
+            let mut repo_data = RepoData::new(algorithm);
+            repo_data
+                .refs
+                .insert("refs/heads/main".to_owned(), git_hash.clone());
+            repo_data.objects.insert(git_hash.clone(), "42".to_owned());
+
+            let encoded = repo_data.encode().unwrap();
+            let decoded = RepoData::decode(&mut encoded.as_slice()).unwrap();
+
+            assert_eq!(
+                decoded.refs["refs/heads/main"].to_string(),
+                git_hash.to_string()
+            );
+            assert_eq!(decoded.objects.keys().collect::<Vec<_>>(), [&git_hash]);
+        }
+    }
+
+    #[test]
+    fn hashes_of_the_wrong_length_are_rejected() {
+        // A SHA-1 id in a SHA-256 RepoData would be written short and misread on the way back
+        let mut repo_data = RepoData::new(HashAlgorithm::Sha256);
+        repo_data
+            .refs
+            .insert("refs/heads/main".to_owned(), git_hash(1));
+        assert!(repo_data.encode().is_err());
+
+        // Raw hashes cut short by a truncated RepoData fail to decode instead of shifting
+        let mut repo_data = RepoData::new(HashAlgorithm::Sha1);
+        repo_data
+            .refs
+            .insert("refs/heads/main".to_owned(), git_hash(1));
+        let encoded = repo_data.encode().unwrap();
+        let cut = 2 + 1 + 1 + "refs/heads/main".encode().len() + 19;
+        assert!(RepoData::decode(&mut &encoded[..cut]).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use cid::Cid;
+use codec::{Decode, Encode};
+#[cfg(feature = "git2")]
+use git2::Oid;
+use std::{fmt, str::FromStr};
+
+use crate::{error, BoxResult};
+
+/// The hash function a repository names its objects with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Encode, Decode)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Length of a hash in bytes.
+    pub fn raw_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+
+    /// The name git uses for this algorithm in `extensions.objectFormat`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// The object format of the repository git is running us in.
+    ///
+    /// Asks git itself, as libgit2 refuses to open SHA-256 repositories at all.
+    #[cfg(feature = "git2")]
+    pub fn of_local_repository() -> BoxResult<Self> {
+        let output = std::process::Command::new("git")
+            .args(["rev-parse", "--show-object-format"])
+            .output()?;
+
+        // Git versions older than 2.29 only know SHA-1
+        if !output.status.success() {
+            return Ok(HashAlgorithm::Sha1);
+        }
+
+        String::from_utf8(output.stdout)?.trim().parse()
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha1" => Ok(HashAlgorithm::Sha1),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            other => error!(format!("Unknown git object format {:?}", other)),
+        }
+    }
+}
+
+/// A git object id in its lowercase hex form, 40 characters for SHA-1 and 64 for SHA-256.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Encode)]
+pub struct GitHash(String);
+
+impl GitHash {
+    pub fn algorithm(&self) -> HashAlgorithm {
+        if self.0.len() == 64 {
+            HashAlgorithm::Sha256
+        } else {
+            HashAlgorithm::Sha1
+        }
+    }
+
+    #[cfg(feature = "git2")]
+    pub fn to_oid(&self) -> BoxResult<Oid> {
+        if self.algorithm() != HashAlgorithm::Sha1 {
+            error!(format!(
+                "{} is a SHA-256 object id, which libgit2 can't represent",
+                self
+            ))
+        }
+
+        Ok(Oid::from_str(&self.0)?)
+    }
+
+    /// The raw bytes of the hash.
+    pub fn to_raw(&self) -> BoxResult<Vec<u8>> {
+        Ok(hex::decode(&self.0)?)
+    }
+
+    pub fn from_raw(raw: &[u8]) -> Self {
+        Self(hex::encode(raw))
+    }
+}
+
+#[cfg(feature = "git2")]
+impl From<Oid> for GitHash {
+    fn from(oid: Oid) -> Self {
+        Self(oid.to_string())
+    }
+}
+
+impl FromStr for GitHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !matches!(s.len(), 40 | 64) || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        {
+            return Err(format!("{:?} is not a git hash", s));
+        }
+
+        Ok(Self(s.to_owned()))
+    }
+}
+
+/// Decodes like a `String`, refusing anything but a 40 or 64 character lowercase hex hash.
+impl Decode for GitHash {
+    fn decode<I: codec::Input>(input: &mut I) -> Result<Self, codec::Error> {
+        String::decode(input)?
+            .parse()
+            .map_err(|_| codec::Error::from("Not a git hash"))
+    }
+}
+
+impl fmt::Display for GitHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The id of an IP Set.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Encode, Decode)]
+pub struct IpsId(pub u32);
+
+impl FromStr for IpsId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+impl fmt::Display for IpsId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The id of an IP File.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Encode, Decode)]
+pub struct IpfId(pub u64);
+
+impl fmt::Display for IpfId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The binary form of a CID, as stored in IPF metadata.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Encode, Decode)]
+pub struct IpfsCidBytes(Vec<u8>);
+
+impl IpfsCidBytes {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_cid(&self) -> BoxResult<Cid> {
+        Ok(Cid::try_from(self.0.as_slice())?)
+    }
+}
+
+impl From<&Cid> for IpfsCidBytes {
+    fn from(cid: &Cid) -> Self {
+        Self(cid.to_bytes())
+    }
+}
+
+impl TryFrom<&[u8]> for IpfsCidBytes {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        // Parse once so invalid bytes are rejected up front
+        Cid::try_from(bytes)?;
+        Ok(Self(bytes.to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_hex_hashes_decode() {
+        let sha1 = "3b18e512dba79e4c8300dd08aeb37f8e728b8dad".to_owned();
+        let sha256 = "a".repeat(64);
+        for valid in [&sha1, &sha256] {
+            let decoded = GitHash::decode(&mut valid.encode().as_slice()).unwrap();
+            assert_eq!(&decoded.to_string(), valid);
+            assert_eq!(decoded.to_raw().unwrap().len(), valid.len() / 2);
+        }
+
+        let uppercase = sha1.to_uppercase();
+        let not_hex = "z".repeat(40);
+        let short = sha1[..39].to_owned();
+        for invalid in ["", &uppercase, &not_hex, &short, "1736712396309695476"] {
+            assert!(GitHash::decode(&mut invalid.encode().as_slice()).is_err());
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use crate::{
+    config::Config,
+    connect, discover,
+    fees::InvArchExtrinsicParams,
+    get_signer, invarch,
+    primitives::BoxResult,
+    timeout::{within, Operation},
+    token,
+};
+use subxt::{
+    sp_core::crypto::{set_default_ss58_version, AccountId32, Ss58AddressFormat, Ss58Codec},
+    DefaultConfig,
+};
+
+/// Makes every account displayed from now on use the SS58 prefix of the network, as set in
+/// `chain.ss58_prefix` or else the one the chain declares, so it matches what wallets show.
+pub fn install(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    config: &Config,
+) -> BoxResult<()> {
+    let prefix = match config.ss58_prefix {
+        Some(prefix) => prefix,
+        None => chain_api.constants().system().ss58_prefix()?,
+    };
+
+    set_default_ss58_version(Ss58AddressFormat::custom(prefix));
+
+    Ok(())
+}
+
+/// `account` as an SS58 address with the prefix `install` chose.
+pub fn address(account: &AccountId32) -> String {
+    account.to_ss58check()
+}
+
+/// `whoami`: prints the address of the signer, its free balance and the IP Sets it holds.
+pub async fn whoami(config: &Config) -> BoxResult<()> {
+    let chain_api = connect(config).await?;
+    let signer = get_signer(config).await?;
+    let account = signer.account_id();
+
+    let info = within(
+        Operation::ChainQuery,
+        &format!("reading account {} from chain", address(account)),
+        chain_api.storage().system().account(account, None),
+    )
+    .await?;
+
+    println!("address\t{}", address(account));
+    println!("free balance\t{}", token::format(info.data.free));
+
+    let owned = discover::owned_by(&chain_api, account).await?;
+    if owned.is_empty() {
+        println!("IP Sets\tnone");
+    } else {
+        let ips_ids: Vec<String> = owned.iter().map(|(ips_id, _)| ips_id.to_string()).collect();
+        println!("IP Sets\t{}", ips_ids.join(", "));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use crate::{
+    fees::InvArchExtrinsicParams,
+    invarch,
+    primitives::BoxResult,
+    state::inv4_dir,
+    timeout::{within, Operation},
+    types::{IpfId, IpsId},
+    util::{decode_ipf_metadata, ipf_infos, IPF_BATCH_SIZE},
+};
+use cid::Cid;
+use git2::Repository;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+};
+use subxt::DefaultConfig;
+
+/// At most this many IPFs minted since a pending mint was submitted are searched for it, so a
+/// mint that never landed doesn't make every later push read the whole chain.
+const MAX_SEARCHED: u64 = 16 * IPF_BATCH_SIZE as u64;
+
+/// A MultiObject mint that was submitted but not seen to land, a line of
+/// `.git/inv4/pending-mints.jsonl`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PendingMint {
+    ips_id: u32,
+    multi_object: String,
+    cid: String,
+    /// The id the chain was going to give the next IPF when the mint was submitted
+    first_ipf_id: u64,
+}
+
+/// Where pending mints are kept, if git-remote-inv4 runs in a repository.
+fn pending_path() -> Option<PathBuf> {
+    let repo = Repository::open_from_env().ok()?;
+    Some(inv4_dir(&repo).join("pending-mints.jsonl"))
+}
+
+fn read_pending() -> Vec<PendingMint> {
+    let f = match pending_path().and_then(|path| std::fs::File::open(path).ok()) {
+        Some(f) => f,
+        None => return Vec::new(),
+    };
+
+    BufReader::new(f)
+        .lines()
+        .filter_map(|line| serde_json::from_str(&line.ok()?).ok())
+        .collect()
+}
+
+fn write_pending(pending: &[PendingMint]) -> BoxResult<()> {
+    let path = match pending_path() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    if pending.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(path.parent().ok_or("No directory for pending mints")?)?;
+    let mut f = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)?;
+    for mint in pending {
+        writeln!(f, "{}", serde_json::to_string(mint)?)?;
+    }
+
+    Ok(())
+}
+
+/// Notes that the MultiObject `multi_object` is about to be minted for `ips_id` with `cid`, so a
+/// push retried after the mint timed out can find the IPF if it landed after all.
+pub async fn submitting(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ips_id: IpsId,
+    multi_object: &str,
+    cid: &Cid,
+) -> BoxResult<()> {
+    let first_ipf_id = within(
+        Operation::ChainQuery,
+        "reading the next IPF id from chain",
+        chain_api.storage().ipf().next_ipf_id(None),
+    )
+    .await?;
+
+    let mut pending = read_pending();
+    pending.push(PendingMint {
+        ips_id: ips_id.0,
+        multi_object: multi_object.to_owned(),
+        cid: cid.to_string(),
+        first_ipf_id,
+    });
+    write_pending(&pending)
+}
+
+/// Forgets the pending mints of `multi_object` for `ips_id`, once one landed.
+pub fn landed(ips_id: IpsId, multi_object: &str) {
+    let mut pending = read_pending();
+    pending.retain(|mint| !(mint.ips_id == ips_id.0 && mint.multi_object == multi_object));
+
+    if let Err(e) = write_pending(&pending) {
+        debug!("Could not update the pending mints: {}", e);
+    }
+}
+
+/// The IPF an earlier, timed out mint of `multi_object` for `ips_id` created after all, and the
+/// CID it holds, so minting it again can be skipped.
+///
+/// Only IPFs minted since that mint was submitted are searched, for one whose metadata names
+/// `multi_object` and whose data is the CID that was submitted.
+pub async fn find(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ips_id: IpsId,
+    multi_object: &str,
+) -> BoxResult<Option<(IpfId, Cid)>> {
+    let pending: Vec<PendingMint> = read_pending()
+        .into_iter()
+        .filter(|mint| mint.ips_id == ips_id.0 && mint.multi_object == multi_object)
+        .collect();
+    let first = match pending.iter().map(|mint| mint.first_ipf_id).min() {
+        Some(first) => first,
+        None => return Ok(None),
+    };
+
+    let next = within(
+        Operation::ChainQuery,
+        "reading the next IPF id from chain",
+        chain_api.storage().ipf().next_ipf_id(None),
+    )
+    .await?;
+    let ids: Vec<u64> = (first..next.min(first + MAX_SEARCHED)).collect();
+
+    for batch in ids.chunks(IPF_BATCH_SIZE) {
+        let infos = within(
+            Operation::ChainQuery,
+            "reading recently minted IPFs from chain",
+            ipf_infos(chain_api, batch),
+        )
+        .await?;
+
+        for (&id, info) in batch.iter().zip(infos) {
+            let info = match info {
+                Some(info) => info,
+                None => continue,
+            };
+            let (name, cid) = match decode_ipf_metadata(&info.metadata.0, info.data.0) {
+                Ok(decoded) => decoded,
+                Err(_) => continue,
+            };
+            if name == multi_object
+                && pending
+                    .iter()
+                    .any(|mint| id >= mint.first_ipf_id && mint.cid == cid.to_string())
+            {
+                return Ok(Some((IpfId(id), cid)));
+            }
+        }
+    }
+
+    Ok(None)
+}
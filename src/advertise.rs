@@ -0,0 +1,72 @@
+use crate::primitives::BoxResult;
+use git2::Repository;
+
+/// Which refs of an IPS are listed to git, and so cloned and fetched.
+///
+/// Read from `remote.<name>.inv4Advertise`, or `inv4.advertise` for all remotes, as a comma
+/// separated list of patterns like `refs/heads/*, refs/tags/v*, !refs/tags/*-rc*`. A ref is
+/// advertised if it matches any pattern, or there are only `!` patterns, and matches none of
+/// the `!` patterns. Without either setting every ref is advertised.
+#[derive(Debug, Clone, Default)]
+pub struct RefFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl RefFilter {
+    pub fn load(repo: &Repository, remote: &str) -> BoxResult<Self> {
+        let config = repo.config()?;
+
+        let value = match config.get_string(&format!("remote.{}.inv4advertise", remote)) {
+            Ok(value) => value,
+            Err(_) => match config.get_string("inv4.advertise") {
+                Ok(value) => value,
+                Err(_) => return Ok(Self::default()),
+            },
+        };
+
+        let mut filter = Self::default();
+        for pattern in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match pattern.strip_prefix('!') {
+                Some(excluded) => filter.exclude.push(excluded.to_owned()),
+                None => filter.include.push(pattern.to_owned()),
+            }
+        }
+
+        Ok(filter)
+    }
+
+    pub fn allows(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| glob_match(p, name)))
+            && !self.exclude.iter().any(|p| glob_match(p, name))
+    }
+}
+
+/// Matches `name` against `pattern`, in which `*` stands for any run of characters, slashes
+/// included.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+
+    // Without a `*` the pattern has to match exactly
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let last = match parts.split_last() {
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(i) => rest = &rest[i + part.len()..],
+                    None => return false,
+                }
+            }
+            last
+        }
+        None => return rest.is_empty(),
+    };
+
+    rest.len() >= last.len() && rest.ends_with(last)
+}
@@ -0,0 +1,98 @@
+use crate::{error, primitives::BoxResult};
+use std::{
+    env,
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    process::{Command, Stdio},
+    sync::Mutex,
+};
+
+/// Passphrases entered during this helper session, keyed by prompt.
+static CACHE: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// Asks the user for a passphrase the way git asks for credentials.
+///
+/// `GIT_ASKPASS`, `core.askPass` and `SSH_ASKPASS` are tried in that order,
+/// falling back to a no-echo prompt on the controlling terminal. Answers are
+/// cached for as long as the helper process lives, so a push with many refs
+/// only asks once.
+pub fn passphrase(prompt: &str) -> BoxResult<String> {
+    let mut cache = CACHE.lock().map_err(|_| "Passphrase cache is poisoned")?;
+
+    if let Some((_, passphrase)) = cache.iter().find(|(p, _)| p == prompt) {
+        return Ok(passphrase.clone());
+    }
+
+    let passphrase = match askpass_program() {
+        Some(program) => run_askpass(&program, prompt)?,
+        None => rpassword::prompt_password(prompt)?,
+    };
+
+    cache.push((prompt.to_owned(), passphrase.clone()));
+
+    Ok(passphrase)
+}
+
+/// Asks the user a yes or no question, through the askpass program if there is one and on
+/// the controlling terminal otherwise, since a helper's stdin belongs to git. Only "y" and
+/// "yes" count as yes.
+pub fn confirm(prompt: &str) -> BoxResult<bool> {
+    let answer = match askpass_program() {
+        Some(program) => run_askpass(&program, prompt)?,
+        None => {
+            let mut tty = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("/dev/tty")
+                .map_err(|e| format!("No terminal to ask on: {}", e))?;
+            tty.write_all(prompt.as_bytes())?;
+            tty.flush()?;
+
+            let mut answer = String::new();
+            BufReader::new(tty).read_line(&mut answer)?;
+            answer
+        }
+    };
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+fn askpass_program() -> Option<String> {
+    let non_empty = |v: String| if v.trim().is_empty() { None } else { Some(v) };
+
+    env::var("GIT_ASKPASS")
+        .ok()
+        .and_then(non_empty)
+        .or_else(|| {
+            Command::new("git")
+                .args(["config", "--get", "core.askPass"])
+                .stderr(Stdio::null())
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .and_then(|output| String::from_utf8(output.stdout).ok())
+                .map(|v| v.trim().to_owned())
+                .and_then(non_empty)
+        })
+        .or_else(|| env::var("SSH_ASKPASS").ok().and_then(non_empty))
+}
+
+fn run_askpass(program: &str, prompt: &str) -> BoxResult<String> {
+    let output = Command::new(program)
+        .arg(prompt)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Could not run askpass program {}: {}", program, e))?;
+
+    if !output.status.success() {
+        error!(format!(
+            "Askpass program {} did not return a passphrase",
+            program
+        ))
+    }
+
+    Ok(String::from_utf8(output.stdout)?
+        .trim_end_matches(&['\r', '\n'][..])
+        .to_owned())
+}
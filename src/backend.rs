@@ -0,0 +1,145 @@
+use crate::{
+    primitives::{BoxResult, ObjectKind},
+    types::GitHash,
+};
+use git2::{Odb, Repository};
+use std::{fs::File, path::Path};
+
+/// The object database fetched objects are written to.
+///
+/// libgit2's ODB is used by default, builds with the `gix-backend` feature write through
+/// gitoxide instead.
+pub trait ObjectStore {
+    /// Whether the object named `git_hash` is present.
+    fn contains(&self, git_hash: &GitHash) -> bool;
+
+    /// Writes an object, returning the hash it is stored under.
+    fn write(&self, kind: ObjectKind, data: &[u8]) -> BoxResult<GitHash>;
+}
+
+/// Which object files git flushes to disk once written, as `core.fsync` and the older
+/// `core.fsyncObjectFiles` say, so fetches leave objects as durable as git's own.
+///
+/// Without either, git flushes packs but not loose objects.
+#[derive(Clone, Copy, Debug)]
+pub struct Fsync {
+    pub loose: bool,
+    pub pack: bool,
+}
+
+impl Fsync {
+    pub fn load(repo: &Repository) -> Self {
+        let mut fsync = Self {
+            loose: false,
+            pack: true,
+        };
+        let config = match repo.config() {
+            Ok(config) => config,
+            Err(_) => return fsync,
+        };
+
+        if let Ok(components) = config.get_string("core.fsync") {
+            for component in components.split(',').map(str::trim) {
+                let (enable, name) = match component.strip_prefix('-') {
+                    Some(name) => (false, name),
+                    None => (true, component),
+                };
+                match name {
+                    "none" => {
+                        fsync.loose = false;
+                        fsync.pack = false;
+                    }
+                    "loose-object" => fsync.loose = enable,
+                    "pack" | "pack-metadata" => fsync.pack = enable,
+                    "objects" | "committed" | "added" | "all" => {
+                        fsync.loose = enable;
+                        fsync.pack = enable;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if config.get_bool("core.fsyncObjectFiles").unwrap_or(false) {
+            fsync.loose = true;
+        }
+
+        fsync
+    }
+}
+
+/// Flushes the file or directory at `path` to disk.
+pub fn sync_path(path: &Path) -> BoxResult<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// The names of the files in a repository's `objects/pack`.
+#[cfg(not(feature = "gix-backend"))]
+pub fn pack_files(pack_dir: &Path) -> BoxResult<std::collections::BTreeSet<String>> {
+    let mut names = std::collections::BTreeSet::new();
+
+    for entry in std::fs::read_dir(pack_dir)? {
+        names.insert(entry?.file_name().to_string_lossy().into_owned());
+    }
+
+    Ok(names)
+}
+
+impl ObjectStore for Odb<'_> {
+    fn contains(&self, git_hash: &GitHash) -> bool {
+        git_hash.to_oid().is_ok_and(|oid| self.exists(oid))
+    }
+
+    fn write(&self, kind: ObjectKind, data: &[u8]) -> BoxResult<GitHash> {
+        Ok(Odb::write(self, kind.object_type(), data)?.into())
+    }
+}
+
+/// A repository opened with gitoxide.
+///
+/// gitoxide writes every loose object to a temporary file it renames into place, which this
+/// flushes to disk after if `core.fsync` asks for it.
+#[cfg(feature = "gix-backend")]
+pub struct GixStore {
+    repo: gix::Repository,
+    objects: std::path::PathBuf,
+    fsync: Fsync,
+}
+
+#[cfg(feature = "gix-backend")]
+impl GixStore {
+    pub fn open(path: &Path, fsync: Fsync) -> BoxResult<Self> {
+        Ok(Self {
+            repo: gix::open(path)?,
+            objects: path.join("objects"),
+            fsync,
+        })
+    }
+}
+
+#[cfg(feature = "gix-backend")]
+impl ObjectStore for GixStore {
+    fn contains(&self, git_hash: &GitHash) -> bool {
+        gix::ObjectId::from_hex(git_hash.to_string().as_bytes())
+            .map_or(false, |id| self.repo.has_object(id))
+    }
+
+    fn write(&self, kind: ObjectKind, data: &[u8]) -> BoxResult<GitHash> {
+        let kind = match kind {
+            ObjectKind::Commit => gix::object::Kind::Commit,
+            ObjectKind::Tag => gix::object::Kind::Tag,
+            ObjectKind::Tree => gix::object::Kind::Tree,
+            ObjectKind::Blob => gix::object::Kind::Blob,
+        };
+
+        let hex = self.repo.write_buf(kind, data)?.to_string();
+
+        if self.fsync.loose {
+            let fan_out = self.objects.join(&hex[..2]);
+            sync_path(&fan_out.join(&hex[2..]))?;
+            sync_path(&fan_out)?;
+        }
+
+        Ok(hex.parse()?)
+    }
+}
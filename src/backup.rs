@@ -0,0 +1,103 @@
+use crate::{
+    config::Config,
+    fees::InvArchExtrinsicParams,
+    invarch,
+    ipfs::{AddOptions, Ipfs, IpfsOps},
+    primitives::BoxResult,
+    proxy,
+    types::IpsId,
+    util::block_number,
+};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use subxt::{sp_core::H256, DefaultConfig};
+
+/// A second place every pushed RepoData is uploaded to, so losing the only pinned copy
+/// doesn't make the repository unrecoverable.
+///
+/// Copies are keyed by IPS id and the number of the block the RepoData was minted in.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Backup {
+    /// Add and pin it on another IPFS node, given by its API address like `http://host:5001`
+    Ipfs { url: String },
+    /// PUT it to `<url>/<ips_id>/<block>.repodata`, e.g. an S3 bucket or WebDAV share
+    Http {
+        url: String,
+        /// Value of the Authorization header, if the store wants one
+        #[serde(default)]
+        authorization: Option<String>,
+    },
+    /// Write it to `<path>/<ips_id>/<block>.repodata`
+    Dir { path: PathBuf },
+}
+
+impl Backup {
+    /// Uploads an encoded RepoData minted in `block_hash`. A failed upload only produces a
+    /// warning, the push itself already succeeded.
+    pub async fn store(
+        &self,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+        block_hash: H256,
+        encoded: &[u8],
+        config: &Config,
+    ) {
+        let result = match block_number(chain_api, block_hash).await {
+            Ok(block) => self.upload(ips_id, block, encoded, config).await,
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(location) => eprintln!("Backed up RepoData to {}", location),
+            Err(e) => eprintln!("Warning: could not back up RepoData: {}", e),
+        }
+    }
+
+    async fn upload(
+        &self,
+        ips_id: IpsId,
+        block: u32,
+        encoded: &[u8],
+        config: &Config,
+    ) -> BoxResult<String> {
+        let name = format!("{}/{}.repodata", ips_id, block);
+        debug!("Backing up RepoData as {} to {:?}", name, self);
+
+        match self {
+            Backup::Ipfs { url } => {
+                let options = AddOptions {
+                    pin: true,
+                    ..Default::default()
+                };
+                let cid = Ipfs::from_url(&proxy::route(url, config).await?)?
+                    .add(encoded.to_vec(), options)
+                    .await?;
+
+                Ok(format!("{} as {} ({})", url, cid, name))
+            }
+            Backup::Http { url, authorization } => {
+                let target = format!("{}/{}", url.trim_end_matches('/'), name);
+
+                let mut request = proxy::http_client(config)?
+                    .put(&target)
+                    .body(encoded.to_vec());
+                if let Some(authorization) = authorization {
+                    request = request.header("Authorization", authorization);
+                }
+                request.send().await?.error_for_status()?;
+
+                Ok(target)
+            }
+            Backup::Dir { path } => {
+                let target = path.join(&name);
+
+                std::fs::create_dir_all(target.parent().expect("name has a directory"))?;
+                std::fs::write(&target, encoded)?;
+
+                Ok(target.display().to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use crate::{
+    config::Config,
+    connect, error,
+    ipfs::Ipfs,
+    open_repository,
+    primitives::{BoxResult, PushSession, RepoDataOps},
+    push_ref,
+    quota::IGNORE_QUOTA_PUSH_OPTION,
+    set_repo,
+    state::{network_id, RemoteState},
+    types::{HashAlgorithm, IpsId},
+    util::flag,
+};
+use git2::Repository;
+use std::path::Path;
+use temp_dir::TempDir;
+use tokio::process::Command;
+
+static USAGE: &str = "Usage: git-remote-inv4 bundle create --ips <id> [--ref <name>] -o <file>
+       git-remote-inv4 bundle push --ips <id> [--ref <name>] [--force] -i <file>";
+
+/// Converts between the history in an IPS and `git bundle` files.
+pub async fn run(config: &Config, args: &[String]) -> BoxResult<()> {
+    match args.first().map(String::as_str) {
+        Some("create") => create(config, &args[1..]).await,
+        Some("push") => push_bundle(config, &args[1..]).await,
+        _ => error!(USAGE),
+    }
+}
+
+/// Writes the refs of an IPS, or just `--ref`, with all their history to a bundle.
+async fn create(config: &Config, args: &[String]) -> BoxResult<()> {
+    let ips_id: IpsId = config
+        .ips_arg(args)?
+        .ok_or(format!("Missing IPS id. {}", USAGE))?;
+    let output = flag(args, "-o").ok_or(format!("Missing output file. {}", USAGE))?;
+
+    let chain_api = connect(config).await?;
+    let repo_data = set_repo(ips_id, chain_api.clone()).await?;
+    let mut ipfs = Ipfs::default();
+
+    let names: Vec<String> = match flag(args, "--ref") {
+        Some(name) => vec![repo_data.resolve_ref(name)?],
+        None => repo_data.refs.keys().cloned().collect(),
+    };
+
+    if names.is_empty() {
+        error!(format!("IPS {} has no refs to bundle", ips_id))
+    }
+
+    let temp_dir = TempDir::new()?;
+    let mut repo = Repository::init_bare(temp_dir.path())?;
+
+    for name in &names {
+        let git_hash = &repo_data.refs[name];
+
+        eprintln!("Bundling {} at {}", name, git_hash);
+        repo_data
+            .fetch_to_ref_from_str(git_hash, name, &mut repo, &mut ipfs, &chain_api, ips_id)
+            .await?;
+
+        // A bundle has to carry every ref it was asked for, tags included
+        repo.reference(name, git_hash.to_oid()?, true, "inv4-git bundle")?;
+    }
+
+    let mut bundle_args = names.clone();
+    if let Some(head) = head(&names) {
+        repo.set_head(head)?;
+        bundle_args.push("HEAD".to_owned());
+    }
+
+    let status = git(temp_dir.path(), &["bundle", "create", output])
+        .args(&bundle_args)
+        .status()
+        .await?;
+    if !status.success() {
+        error!("git bundle create failed")
+    }
+
+    eprintln!("Wrote {} refs of IPS {} to {}", names.len(), ips_id, output);
+
+    Ok(())
+}
+
+/// Pushes the refs in a bundle, or just `--ref`, to an IPS the same way `git push` would.
+async fn push_bundle(config: &Config, args: &[String]) -> BoxResult<()> {
+    let ips_id: IpsId = config
+        .ips_arg(args)?
+        .ok_or(format!("Missing IPS id. {}", USAGE))?;
+    let input = flag(args, "-i").ok_or(format!("Missing input file. {}", USAGE))?;
+    let force = args.iter().any(|arg| arg == "--force");
+    let enforce_quota = !args
+        .iter()
+        .any(|arg| arg.strip_prefix("--") == Some(IGNORE_QUOTA_PUSH_OPTION));
+
+    let temp_dir = TempDir::new()?;
+    let repo = Repository::init_bare(temp_dir.path())?;
+
+    let status = git(
+        temp_dir.path(),
+        &["fetch", "--quiet", input, "refs/*:refs/*"],
+    )
+    .status()
+    .await?;
+    if !status.success() {
+        error!(format!("Could not read the bundle {}", input))
+    }
+
+    let names: Vec<String> = match flag(args, "--ref") {
+        Some(name) => vec![repo
+            .resolve_reference_from_short_name(name)?
+            .name()
+            .ok_or("Ref name is not UTF-8")?
+            .to_owned()],
+        None => repo
+            .references()?
+            .filter_map(|reference| reference.ok()?.name().map(str::to_owned))
+            .collect(),
+    };
+
+    let chain_api = connect(config).await?;
+    let mut remote_repo = set_repo(ips_id, chain_api.clone()).await?;
+
+    // Keep the fee ledger with the repository we're run from, if there is one
+    let state = RemoteState::new(
+        &open_repository().unwrap_or(repo),
+        "bundle",
+        network_id(&chain_api),
+        ips_id,
+    );
+
+    let mut repo = Repository::open_bare(temp_dir.path())?;
+    let mut ipfs = Ipfs::default();
+    let mut session = PushSession::default();
+
+    for name in names {
+        push_ref(
+            &chain_api,
+            &mut remote_repo,
+            ips_id,
+            None,
+            &mut repo,
+            &state,
+            &mut ipfs,
+            &name,
+            &name,
+            force,
+            config,
+            false,
+            enforce_quota,
+            None,
+            HashAlgorithm::Sha1,
+            &mut session,
+        )
+        .await
+        .map_err(|e| format!("Pushing {} failed: {}", name, e))?;
+
+        eprintln!("Pushed {} to IPS {}", name, ips_id);
+    }
+
+    Ok(())
+}
+
+/// The branch a clone of the bundle checks out: main, master or the first one.
+fn head(names: &[String]) -> Option<&str> {
+    if let Some(name) = ["refs/heads/main", "refs/heads/master"]
+        .into_iter()
+        .find(|name| names.iter().any(|n| n == *name))
+    {
+        return Some(name);
+    }
+
+    names
+        .iter()
+        .map(String::as_str)
+        .find(|name| name.starts_with("refs/heads/"))
+}
+
+fn git(git_dir: &Path, args: &[&str]) -> Command {
+    let mut cmd = Command::new("git");
+    cmd.arg("--git-dir").arg(git_dir).args(args);
+    cmd
+}
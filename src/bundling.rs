@@ -0,0 +1,153 @@
+use crate::{
+    config::Config,
+    fees::{self, InvArchExtrinsicParams},
+    invarch,
+    primitives::{BoxResult, MultiObject},
+    types::IpsId,
+    util::{encode_ipf_metadata, generate_cid, object_metadata_name},
+};
+use serde::{Deserialize, Serialize};
+use subxt::{sp_core::H256, DefaultConfig, PairSigner};
+
+/// Whether a push mints each object as its own IPF or bundles them, `bundling.mode`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Decide per push from its object count, its size and the fees of the extra mints
+    #[default]
+    #[serde(rename = "auto")]
+    Auto,
+    /// Always bundle, as `push.ordering` splits the push
+    #[serde(rename = "bundle")]
+    Bundle,
+    /// Always mint every object as its own IPF
+    #[serde(rename = "per-object")]
+    PerObject,
+}
+
+/// Choosing between one IPF per object and bundled MultiObjects for each push.
+///
+/// Per-object IPFs let fetches download exactly the objects they miss, but every one is a
+/// mint of its own, so they only pay off for pushes of a few large objects.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Bundling {
+    #[serde(default)]
+    pub mode: Mode,
+    /// `auto` bundles pushes of more objects
+    #[serde(default = "default_per_object_max_objects")]
+    pub per_object_max_objects: usize,
+    /// `auto` bundles pushes whose objects take fewer bytes together
+    #[serde(default = "default_per_object_min_bytes")]
+    pub per_object_min_bytes: u64,
+    /// The most `auto` pays for the extra mints of a push, in the smallest unit of the asset
+    /// fees are paid in; none to leave fees out of the decision
+    #[serde(default)]
+    pub per_object_max_fee: Option<u64>,
+}
+
+fn default_per_object_max_objects() -> usize {
+    8
+}
+
+fn default_per_object_min_bytes() -> u64 {
+    256 * 1024
+}
+
+impl Default for Bundling {
+    fn default() -> Self {
+        Self {
+            mode: Mode::default(),
+            per_object_max_objects: default_per_object_max_objects(),
+            per_object_min_bytes: default_per_object_min_bytes(),
+            per_object_max_fee: None,
+        }
+    }
+}
+
+impl Bundling {
+    /// Whether the objects of `multi_object` are minted one IPF each.
+    ///
+    /// In `auto` mode pushes of up to `per_object_max_objects` objects taking at least
+    /// `per_object_min_bytes` are, as long as the mints beyond the first cost at most
+    /// `per_object_max_fee`.
+    pub async fn per_object(
+        &self,
+        multi_object: &MultiObject,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+        ips_id: IpsId,
+        config: &Config,
+    ) -> BoxResult<bool> {
+        let count = multi_object.objects.len();
+        match self.mode {
+            Mode::Bundle => return Ok(false),
+            Mode::PerObject => return Ok(count > 1),
+            Mode::Auto if !self.by_size(multi_object) => return Ok(false),
+            Mode::Auto => {}
+        }
+
+        let max_fee = match self.per_object_max_fee {
+            Some(max_fee) => u128::from(max_fee),
+            None => return Ok(true),
+        };
+
+        let extra =
+            mint_fee(multi_object, chain_api, signer, ips_id, config).await? * (count as u128 - 1);
+        if extra > max_fee {
+            eprintln!(
+                "Bundling {} objects, minting them one IPF each would cost {} more in fees",
+                count, extra
+            );
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Whether `multi_object` is few and large enough objects for `auto` to mint one by one.
+    fn by_size(&self, multi_object: &MultiObject) -> bool {
+        let count = multi_object.objects.len();
+        let bytes: u64 = multi_object
+            .objects
+            .values()
+            .map(|object| object.header().size)
+            .sum();
+
+        count > 1 && count <= self.per_object_max_objects && bytes >= self.per_object_min_bytes
+    }
+}
+
+/// The fee of minting one IPF for an object of `multi_object`.
+///
+/// Mints only differ in the length of their metadata, so a mint named after the MultiObject
+/// with a placeholder CID stands in for each of them.
+async fn mint_fee(
+    multi_object: &MultiObject,
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+    ips_id: IpsId,
+    config: &Config,
+) -> BoxResult<u128> {
+    let name = object_metadata_name(
+        config.metadata_template.as_deref(),
+        ips_id,
+        multi_object.object_type(),
+        &multi_object.hash,
+    );
+    let metadata = encode_ipf_metadata(
+        &name,
+        &generate_cid([0; 32])?,
+        config.cid_format,
+        config.license.as_deref(),
+    );
+
+    let tx = chain_api.tx().ipf().mint(metadata, H256::zero())?;
+    fees::estimate(
+        chain_api,
+        tx.create_signed(signer, Default::default())
+            .await?
+            .encoded()
+            .to_vec(),
+    )
+    .await
+}
@@ -0,0 +1,81 @@
+use crate::{
+    fees::InvArchExtrinsicParams,
+    invarch,
+    ipfs::Ipfs,
+    primitives::{BoxResult, RepoData, RepoDataOps},
+    state::RemoteState,
+    types::{HashAlgorithm, IpfId, IpfsCidBytes},
+    util::find_ipf,
+};
+use codec::{Decode, Encode};
+use log::debug;
+use std::path::PathBuf;
+use subxt::DefaultConfig;
+
+/// The last RepoData read for a remote, with the IPF it was read from.
+#[derive(Decode)]
+struct CachedRepoData {
+    ipf_id: IpfId,
+    cid: IpfsCidBytes,
+    repo_data: RepoData,
+}
+
+impl CachedRepoData {
+    /// The fields in order, as `Decode` reads them back.
+    fn encode(&self) -> BoxResult<Vec<u8>> {
+        let mut encoded = (self.ipf_id, &self.cid).encode();
+        encoded.extend(self.repo_data.encode()?);
+        Ok(encoded)
+    }
+}
+
+fn cache_path(state: &RemoteState) -> PathBuf {
+    state.dir().join("repo-data.scale")
+}
+
+/// The current RepoData of the remote, like `set_repo`.
+///
+/// Only the IPF id of the current RepoData is looked up on chain; the RepoData itself is
+/// downloaded from IPFS and decoded only if that IPF changed since the last call.
+pub async fn repo_data(
+    state: &RemoteState,
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+) -> BoxResult<RepoData> {
+    let (ipf_id, cid) = match find_ipf(chain_api, state.ips_id, "RepoData").await? {
+        Some(found) => found,
+        None => return Ok(RepoData::new(HashAlgorithm::Sha1)),
+    };
+    let cid_bytes = IpfsCidBytes::from(&cid);
+
+    let path = cache_path(state);
+    if let Ok(bytes) = std::fs::read(&path) {
+        match CachedRepoData::decode(&mut bytes.as_slice()) {
+            Ok(cached) if cached.ipf_id == ipf_id && cached.cid == cid_bytes => {
+                debug!("RepoData IPF {} unchanged, using the cached copy", ipf_id);
+                return Ok(cached.repo_data);
+            }
+            Ok(cached) => debug!(
+                "RepoData moved from IPF {} to {}, downloading it",
+                cached.ipf_id, ipf_id
+            ),
+            Err(e) => debug!("Ignoring unreadable RepoData cache: {}", e),
+        }
+    }
+
+    let repo_data = RepoData::from_ipfs(cid, &mut Ipfs::default()).await?;
+
+    let cached = CachedRepoData {
+        ipf_id,
+        cid: cid_bytes,
+        repo_data,
+    };
+    let written = cached.encode().and_then(|encoded| {
+        std::fs::create_dir_all(state.dir())?;
+        Ok(std::fs::write(&path, encoded)?)
+    });
+    if let Err(e) = written {
+        debug!("Could not cache RepoData at {}: {}", path.display(), e);
+    }
+
+    Ok(cached.repo_data)
+}
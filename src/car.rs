@@ -0,0 +1,588 @@
+use crate::{
+    chunking::chunk_cids,
+    config::Config,
+    connect, error,
+    exit::ExitCode,
+    ipfs::{Ipfs, IpfsOps, DEFAULT_API},
+    primitives::{BoxResult, MultiObject, RepoData, RepoDataOps, SUBMODULE_TIP_MARKER},
+    timeout::{within, Operation},
+    types::IpsId,
+    util::{
+        decode_ipf_metadata, find_ipf, flag, ipf_infos, ipfs_cat, ips_ipf_ids, is_git_ipf,
+        BLAKE2B_256, IPF_BATCH_SIZE, SHA2_256,
+    },
+};
+use cid::Cid;
+use codec::Decode;
+use log::debug;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fs::File,
+    io::{Cursor, Seek, SeekFrom, Write},
+};
+use subxt::sp_core::hashing::{blake2_256, sha2_256};
+
+static EXPORT_USAGE: &str = "Usage: git-remote-inv4 export-car --ips <id> -o <file.car>";
+static IMPORT_USAGE: &str = "Usage: git-remote-inv4 import-car <file.car>";
+
+/// Start of every CARv2 file: a length-prefixed DAG-CBOR `{"version": 2}`.
+const CARV2_PRAGMA: [u8; 11] = [
+    0x0a, 0xa1, 0x67, b'v', b'e', b'r', b's', b'i', b'o', b'n', 0x02,
+];
+
+/// Length of the CARv2 header following the pragma: characteristics, then the data offset,
+/// data size and index offset.
+const CARV2_HEADER_LEN: u64 = 40;
+
+/// Multicodec of DAG-PB, which `ipfs add` builds files from.
+const DAG_PB: u64 = 0x70;
+
+/// Multicodec of raw leaves.
+const RAW: u64 = 0x55;
+
+/// `export-car`: writes the current RepoData of an IPS and every MultiObject it lists, with all
+/// their blocks and the chunks of chunked blobs, to a CARv2 file. The RepoData comes first
+/// among the roots.
+pub async fn export(config: &Config, args: &[String]) -> BoxResult<()> {
+    let ips_id: IpsId = config
+        .ips_arg(args)?
+        .ok_or(format!("Missing IPS id. {}", EXPORT_USAGE))?;
+    let output = flag(args, "-o").ok_or(format!("Missing output file. {}", EXPORT_USAGE))?;
+
+    let chain_api = connect(config).await?;
+    let mut ipfs = Ipfs::default();
+
+    let (_, repo_data_cid) = find_ipf(&chain_api, ips_id, "RepoData")
+        .await?
+        .ok_or(format!("IPS {} holds no RepoData", ips_id))?;
+    let repo_data = RepoData::from_ipfs(repo_data_cid, &mut ipfs).await?;
+
+    let multi_object_hashes: BTreeSet<&String> = repo_data
+        .objects
+        .values()
+        .filter(|hash| *hash != SUBMODULE_TIP_MARKER)
+        .collect();
+
+    // MultiObject IPFs are named after their hash
+    let ipf_ids = ips_ipf_ids(&chain_api, ips_id).await?;
+    let mut named = BTreeMap::new();
+
+    for batch in ipf_ids.chunks(IPF_BATCH_SIZE) {
+        let ipf_infos = within(
+            Operation::ChainQuery,
+            &format!("reading IPFs of IPS {} from chain", ips_id),
+            ipf_infos(&chain_api, batch),
+        )
+        .await?;
+
+        for (id, ipf_info) in batch.iter().zip(ipf_infos) {
+            let ipf_info = ipf_info.ok_or("Internal error: IPF listed from IPS does not exist")?;
+            if !is_git_ipf(&ipf_info.metadata.0) {
+                continue;
+            }
+
+            match decode_ipf_metadata(&ipf_info.metadata.0, ipf_info.data.0) {
+                Ok((name, cid)) => {
+                    named.insert(name, cid);
+                }
+                Err(e) => debug!("Skipping IPF {}: {}", id, e),
+            }
+        }
+    }
+
+    let mut roots = vec![repo_data_cid];
+    for hash in multi_object_hashes {
+        match named.get(hash) {
+            Some(cid) => roots.push(*cid),
+            None => error!(ExitCode::Verification.wrap(format!(
+                "MultiObject {} listed in RepoData is not in IPS {}, the archive would be incomplete",
+                hash, ips_id
+            ))),
+        }
+    }
+
+    let multi_objects = roots.len() - 1;
+
+    // Chunked blobs live outside their MultiObject's DAG, so their chunks are roots too
+    let mut chunks = Vec::new();
+    for cid in &roots[1..] {
+        let multi_object = MultiObject::decode(&mut ipfs_cat(&ipfs, cid).await?.as_slice())?;
+        for cid in chunk_cids(&multi_object)? {
+            if !chunks.contains(&cid) {
+                chunks.push(cid);
+            }
+        }
+    }
+    roots.extend(chunks);
+
+    eprintln!(
+        "Exporting RepoData and {} MultiObjects to {}...",
+        multi_objects, output
+    );
+
+    let mut file = File::create(output)?;
+    file.write_all(&CARV2_PRAGMA)?;
+    file.write_all(&[0; CARV2_HEADER_LEN as usize])?;
+
+    let data_offset = CARV2_PRAGMA.len() as u64 + CARV2_HEADER_LEN;
+
+    let header = carv1_header(&roots);
+    write_varint(&mut file, header.len() as u64)?;
+    file.write_all(&header)?;
+
+    let mut written = HashSet::new();
+    let mut pending: Vec<Cid> = roots.iter().rev().copied().collect();
+
+    while let Some(cid) = pending.pop() {
+        if !written.insert(cid) {
+            continue;
+        }
+
+        let block = within(
+            Operation::IpfsCat,
+            &format!("reading block {} from IPFS", cid),
+            ipfs.block_get(&cid.to_string()),
+        )
+        .await?;
+        verify_block(&cid, &block)?;
+
+        let cid_bytes = cid.to_bytes();
+        write_varint(&mut file, (cid_bytes.len() + block.len()) as u64)?;
+        file.write_all(&cid_bytes)?;
+        file.write_all(&block)?;
+
+        // Children go on top of the stack in order, so blocks are written depth first
+        pending.extend(links(&cid, &block)?.into_iter().rev());
+    }
+
+    let data_size = file.stream_position()? - data_offset;
+
+    let mut header = [0u8; CARV2_HEADER_LEN as usize];
+    header[16..24].copy_from_slice(&data_offset.to_le_bytes());
+    header[24..32].copy_from_slice(&data_size.to_le_bytes());
+    // No index, `ipfs dag import` doesn't need one
+    file.seek(SeekFrom::Start(CARV2_PRAGMA.len() as u64))?;
+    file.write_all(&header)?;
+
+    eprintln!(
+        "Wrote {} blocks ({} bytes) to {}",
+        written.len(),
+        data_size,
+        output
+    );
+
+    Ok(())
+}
+
+/// `import-car`: checks every block of a CAR file against its CID and imports it into the
+/// local IPFS node, pinning its roots, like `ipfs dag import`.
+pub async fn import(args: &[String]) -> BoxResult<()> {
+    let input = args.first().ok_or(IMPORT_USAGE)?;
+    let car = std::fs::read(input)?;
+
+    let (roots, blocks) = verify_car(&car)?;
+    eprintln!(
+        "{} holds {} blocks under {} roots, importing...",
+        input,
+        blocks,
+        roots.len()
+    );
+
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(car).file_name("repo.car"),
+    );
+
+    // The local node is never reached through the network proxy
+    let response = within(
+        Operation::IpfsAdd,
+        &format!("importing {} into IPFS", input),
+        reqwest::Client::builder()
+            .no_proxy()
+            .build()?
+            .post(format!("{}/dag/import?pin-roots=true", DEFAULT_API))
+            .multipart(form)
+            .send(),
+    )
+    .await
+    .map_err(|e| ExitCode::Ipfs.wrap(e))?;
+
+    if !response.status().is_success() {
+        error!(ExitCode::Ipfs.wrap(format!(
+            "IPFS refused the import: {}",
+            response.text().await.unwrap_or_default()
+        )))
+    }
+
+    let ipfs = Ipfs::default();
+    let mut size = 0;
+    for root in &roots {
+        size += ipfs.stat(&root.to_string()).await?;
+        println!("{}", root);
+    }
+    eprintln!("Imported {} bytes", size);
+
+    Ok(())
+}
+
+/// The CARv1 header naming `roots`, in DAG-CBOR: `{"roots": [<cid>...], "version": 1}`.
+fn carv1_header(roots: &[Cid]) -> Vec<u8> {
+    let mut header = vec![0xa2];
+
+    cbor_head(&mut header, 3, 5);
+    header.extend(b"roots");
+
+    cbor_head(&mut header, 4, roots.len() as u64);
+    for root in roots {
+        let bytes = root.to_bytes();
+        // Tag 42, a CID as bytes behind the identity multibase prefix
+        header.extend([0xd8, 0x2a]);
+        cbor_head(&mut header, 2, bytes.len() as u64 + 1);
+        header.push(0);
+        header.extend(bytes);
+    }
+
+    cbor_head(&mut header, 3, 7);
+    header.extend(b"version");
+    header.push(0x01);
+
+    header
+}
+
+/// Writes the head of a CBOR item with `major` type and `value` as its argument.
+fn cbor_head(out: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+
+    match value {
+        0..=23 => out.push(major | value as u8),
+        24..=0xff => out.extend([major | 24, value as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend((value as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend((value as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend(value.to_be_bytes());
+        }
+    }
+}
+
+/// Checks every block of a CARv1 or CARv2 file against its CID, returning the roots and the
+/// number of blocks.
+fn verify_car(car: &[u8]) -> BoxResult<(Vec<Cid>, usize)> {
+    let data = if car.starts_with(&CARV2_PRAGMA) {
+        let header = car
+            .get(CARV2_PRAGMA.len()..CARV2_PRAGMA.len() + CARV2_HEADER_LEN as usize)
+            .ok_or("CARv2 header is cut off")?;
+        let offset = u64::from_le_bytes(header[16..24].try_into()?) as usize;
+        let size = u64::from_le_bytes(header[24..32].try_into()?) as usize;
+
+        span(car, offset, size).ok_or("CARv2 data is cut off")?
+    } else {
+        car
+    };
+
+    let mut position = 0;
+    let header_len = read_varint(data, &mut position)? as usize;
+    let header = span(data, position, header_len).ok_or("CAR header is cut off")?;
+    position += header_len;
+
+    let roots = header_roots(header)?;
+
+    let mut blocks = 0;
+    while position < data.len() {
+        let len = read_varint(data, &mut position)? as usize;
+        let section = span(data, position, len).ok_or("CAR block is cut off")?;
+        position += len;
+
+        let mut cursor = Cursor::new(section);
+        let cid = Cid::read_bytes(&mut cursor)?;
+        verify_block(&cid, &section[cursor.position() as usize..])?;
+        blocks += 1;
+    }
+
+    Ok((roots, blocks))
+}
+
+/// The roots a DAG-CBOR CARv1 header names.
+fn header_roots(header: &[u8]) -> BoxResult<Vec<Cid>> {
+    let mut position = 0;
+    let mut roots = Vec::new();
+
+    let (major, entries) = cbor_read_head(header, &mut position)?;
+    if major != 5 {
+        error!("CAR header is not a map")
+    }
+
+    for _ in 0..entries {
+        let (major, len) = cbor_read_head(header, &mut position)?;
+        let key = cbor_bytes(header, &mut position, major, len)?;
+
+        match key {
+            b"roots" => {
+                let (major, count) = cbor_read_head(header, &mut position)?;
+                if major != 4 {
+                    error!("CAR roots are not a list")
+                }
+
+                for _ in 0..count {
+                    // Tag 42, a CID as bytes behind the identity multibase prefix
+                    if cbor_read_head(header, &mut position)? != (6, 42) {
+                        error!("CAR root is not a CID")
+                    }
+                    let (major, len) = cbor_read_head(header, &mut position)?;
+                    let bytes = cbor_bytes(header, &mut position, major, len)?;
+                    roots.push(Cid::try_from(bytes.get(1..).ok_or("CAR root is empty")?)?);
+                }
+            }
+            // The version is a plain integer
+            _ => {
+                cbor_read_head(header, &mut position)?;
+            }
+        }
+    }
+
+    Ok(roots)
+}
+
+/// Reads the head of a CBOR item, returning its major type and argument.
+fn cbor_read_head(bytes: &[u8], position: &mut usize) -> BoxResult<(u8, u64)> {
+    let initial = *bytes.get(*position).ok_or("CAR header is cut off")?;
+    *position += 1;
+
+    let len = match initial & 0x1f {
+        value @ 0..=23 => return Ok((initial >> 5, value as u64)),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        _ => error!("CAR header uses indefinite lengths"),
+    };
+
+    let argument = span(bytes, *position, len)
+        .ok_or("CAR header is cut off")?
+        .iter()
+        .fold(0u64, |value, byte| value << 8 | u64::from(*byte));
+    *position += len;
+
+    Ok((initial >> 5, argument))
+}
+
+/// The content of a CBOR byte or text string whose head was read.
+fn cbor_bytes<'a>(
+    bytes: &'a [u8],
+    position: &mut usize,
+    major: u8,
+    len: u64,
+) -> BoxResult<&'a [u8]> {
+    if major != 2 && major != 3 {
+        error!("CAR header holds an unexpected item")
+    }
+
+    let content = span(bytes, *position, len as usize).ok_or("CAR header is cut off")?;
+    *position += len as usize;
+
+    Ok(content)
+}
+
+/// Fails unless `block` hashes to the digest in `cid`.
+fn verify_block(cid: &Cid, block: &[u8]) -> BoxResult<()> {
+    let digest = match cid.hash().code() {
+        SHA2_256 => sha2_256(block),
+        BLAKE2B_256 => blake2_256(block),
+        code => error!(format!(
+            "Block {} uses multihash 0x{:x}, which can't be checked",
+            cid, code
+        )),
+    };
+
+    if digest[..] != *cid.hash().digest() {
+        error!(ExitCode::Verification.wrap(format!("Block {} doesn't match its CID", cid)))
+    }
+
+    Ok(())
+}
+
+/// The CIDs a block links to; only DAG-PB blocks have links.
+fn links(cid: &Cid, block: &[u8]) -> BoxResult<Vec<Cid>> {
+    match cid.codec() {
+        DAG_PB => {}
+        RAW => return Ok(Vec::new()),
+        codec => error!(format!(
+            "Block {} is encoded with codec 0x{:x}, which can't be walked",
+            cid, codec
+        )),
+    }
+
+    let mut links = Vec::new();
+
+    // PBNode: field 2 holds the links, each a PBLink whose field 1 is the CID
+    for (field, value) in protobuf_fields(block)? {
+        if field != 2 {
+            continue;
+        }
+        for (link_field, link_value) in protobuf_fields(value)? {
+            if link_field == 1 {
+                links.push(Cid::try_from(link_value)?);
+            }
+        }
+    }
+
+    Ok(links)
+}
+
+/// The length-delimited fields of a protobuf message, other wire types are skipped.
+fn protobuf_fields(message: &[u8]) -> BoxResult<Vec<(u64, &[u8])>> {
+    let mut fields = Vec::new();
+    let mut position = 0;
+
+    while position < message.len() {
+        let key = read_varint(message, &mut position)?;
+
+        match key & 0x7 {
+            0 => {
+                read_varint(message, &mut position)?;
+            }
+            1 => position += 8,
+            2 => {
+                let len = read_varint(message, &mut position)? as usize;
+                let value = span(message, position, len).ok_or("DAG-PB block is cut off")?;
+                fields.push((key >> 3, value));
+                position += len;
+            }
+            5 => position += 4,
+            wire_type => error!(format!("Unknown protobuf wire type {}", wire_type)),
+        }
+    }
+
+    Ok(fields)
+}
+
+/// The `len` bytes of `bytes` at `start`, `None` if they run past its end. Lengths come from
+/// the file being read, so the end is checked for overflow too.
+fn span(bytes: &[u8], start: usize, len: usize) -> Option<&[u8]> {
+    bytes.get(start..start.checked_add(len)?)
+}
+
+/// Reads an unsigned LEB128 varint from `bytes` at `position`, advancing it.
+fn read_varint(bytes: &[u8], position: &mut usize) -> BoxResult<u64> {
+    let mut value = 0u64;
+
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.get(*position).ok_or("Varint is cut off")?;
+        *position += 1;
+
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    error!("Varint is too long")
+}
+
+fn write_varint(out: &mut impl Write, mut value: u64) -> BoxResult<()> {
+    let mut bytes = Vec::new();
+
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            break;
+        }
+        bytes.push(byte | 0x80);
+    }
+
+    out.write_all(&bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::multihash::MultihashGeneric;
+
+    fn raw_block(data: &[u8]) -> (Cid, Vec<u8>) {
+        let hash = MultihashGeneric::<64>::wrap(SHA2_256, &sha2_256(data)).unwrap();
+        (Cid::new_v1(RAW, hash), data.to_vec())
+    }
+
+    fn carv1(roots: &[Cid], blocks: &[(Cid, Vec<u8>)]) -> Vec<u8> {
+        let mut car = Vec::new();
+        let header = carv1_header(roots);
+        write_varint(&mut car, header.len() as u64).unwrap();
+        car.extend(header);
+
+        for (cid, block) in blocks {
+            let cid_bytes = cid.to_bytes();
+            write_varint(&mut car, (cid_bytes.len() + block.len()) as u64).unwrap();
+            car.extend(cid_bytes);
+            car.extend(block);
+        }
+
+        car
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX as u64, u64::MAX] {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, value).unwrap();
+
+            let mut position = 0;
+            assert_eq!(read_varint(&bytes, &mut position).unwrap(), value);
+            assert_eq!(position, bytes.len());
+        }
+
+        assert!(read_varint(&[0x80], &mut 0).is_err());
+    }
+
+    #[test]
+    fn header_roots_read_back_what_carv1_header_wrote() {
+        let roots: Vec<Cid> = (0..30u8).map(|i| raw_block(&[i]).0).collect();
+
+        assert_eq!(header_roots(&carv1_header(&roots)).unwrap(), roots);
+        assert_eq!(header_roots(&carv1_header(&[])).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn verify_car_checks_every_block() {
+        let blocks = vec![raw_block(b"RepoData"), raw_block(b"MultiObject")];
+        let roots = vec![blocks[0].0];
+
+        let car = carv1(&roots, &blocks);
+        assert_eq!(verify_car(&car).unwrap(), (roots.clone(), 2));
+
+        let mut tampered = blocks.clone();
+        tampered[1].1[0] ^= 1;
+        assert!(verify_car(&carv1(&roots, &tampered)).is_err());
+    }
+
+    #[test]
+    fn lengths_past_the_end_are_refused_without_overflowing() {
+        let mut car = Vec::new();
+        write_varint(&mut car, u64::MAX).unwrap();
+        assert!(verify_car(&car).is_err());
+
+        let mut car = carv1(&[], &[]);
+        write_varint(&mut car, u64::MAX).unwrap();
+        assert!(verify_car(&car).is_err());
+
+        let mut carv2 = CARV2_PRAGMA.to_vec();
+        let mut header = [0u8; CARV2_HEADER_LEN as usize];
+        header[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        header[24..32].copy_from_slice(&u64::MAX.to_le_bytes());
+        carv2.extend(header);
+        assert!(verify_car(&carv2).is_err());
+
+        // A length-delimited protobuf field claiming more bytes than can exist
+        let mut message = vec![0x12];
+        write_varint(&mut message, u64::MAX).unwrap();
+        assert!(protobuf_fields(&message).is_err());
+    }
+}
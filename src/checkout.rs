@@ -0,0 +1,39 @@
+use crate::{
+    config::Config,
+    connect,
+    ipfs::Ipfs,
+    primitives::{BoxResult, RepoDataOps},
+    set_repo,
+    types::IpsId,
+    util::flag,
+};
+use std::path::Path;
+
+static USAGE: &str = "Usage: git-remote-inv4 checkout-dir --ips <id> --ref <name> -o <dir>";
+
+/// Writes the files of a ref in an IPS to a plain directory, for consumers that have no use
+/// for a git repository.
+pub async fn run(config: &Config, args: &[String]) -> BoxResult<()> {
+    let ips_id: IpsId = config
+        .ips_arg(args)?
+        .ok_or(format!("Missing IPS id. {}", USAGE))?;
+    let name = flag(args, "--ref").ok_or(format!("Missing ref. {}", USAGE))?;
+    let output = flag(args, "-o").ok_or(format!("Missing output directory. {}", USAGE))?;
+
+    let chain_api = connect(config).await?;
+    let repo_data = set_repo(ips_id, chain_api.clone()).await?;
+
+    let files = repo_data
+        .checkout_to_dir(
+            name,
+            Path::new(output),
+            &mut Ipfs::default(),
+            &chain_api,
+            ips_id,
+        )
+        .await?;
+
+    eprintln!("Wrote {} files of {} to {}", files, name, output);
+
+    Ok(())
+}
@@ -0,0 +1,285 @@
+use crate::{
+    config::Config,
+    error,
+    exit::ExitCode,
+    ipfs::Ipfs,
+    primitives::{BlobChunk, BoxResult, GitObjectMetadata, MultiObject},
+    types::GitHash,
+    util::{ipfs_add, ipfs_cat},
+};
+use blake2::{
+    digest::{consts::U32, Digest},
+    Blake2b,
+};
+use cid::Cid;
+use fastcdc::v2020::FastCDC;
+use git2::Repository;
+use log::debug;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Read,
+};
+
+/// FastCDC cuts chunks between these sizes, around the average. Boundaries depend only on
+/// nearby content, so an edit to a large file changes the chunks around it and no others.
+const MIN_CHUNK: u32 = 64 * 1024;
+const AVG_CHUNK: u32 = 256 * 1024;
+const MAX_CHUNK: u32 = 1024 * 1024;
+
+/// The chunks every blob of `multi_object` over `threshold` bytes is stored as.
+///
+/// Each chunk is added to IPFS on its own, unpinned like the MultiObject until it's minted.
+/// Chunks a previous version of the blob already had get the same CID, so the node stores
+/// them once. Returns {blob -> chunks} and the CIDs of the chunks, for
+/// `MultiObject::encode_upload`.
+pub async fn chunk_blobs(
+    multi_object: &MultiObject,
+    threshold: u64,
+    ipfs: &mut Ipfs,
+    config: &Config,
+) -> BoxResult<(BTreeMap<GitHash, Vec<BlobChunk>>, Vec<Cid>)> {
+    let mut chunked = BTreeMap::new();
+    let mut chunk_cids = Vec::new();
+    let mut seen = HashSet::new();
+
+    for object in multi_object.objects.values() {
+        if !matches!(object.metadata, GitObjectMetadata::Blob)
+            || object.data.len() as u64 <= threshold
+        {
+            continue;
+        }
+
+        let mut chunks = Vec::new();
+        for chunk in FastCDC::new(&object.data, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK) {
+            let data = object.data[chunk.offset..chunk.offset + chunk.length].to_vec();
+            let (cid, _) = ipfs_add(ipfs, data, config.cid_format).await?;
+
+            if seen.insert(cid) {
+                chunk_cids.push(cid);
+            }
+            chunks.push(BlobChunk {
+                cid: cid.to_bytes(),
+                size: chunk.length as u32,
+            });
+        }
+
+        debug!(
+            "Storing blob {} ({} bytes) as {} chunks",
+            object.git_hash,
+            object.data.len(),
+            chunks.len()
+        );
+
+        chunked.insert(object.git_hash.clone(), chunks);
+    }
+
+    Ok((chunked, chunk_cids))
+}
+
+/// Stores the blobs of `multi_object` too large to read into memory as chunks, reading them
+/// from `repo`'s object database a chunk at a time.
+///
+/// Chunks are cut exactly as `chunk_blobs` cuts them, so they are shared with versions of the
+/// blob pushed either way, and added to IPFS unpinned like them.
+pub async fn stream_blobs(
+    multi_object: &mut MultiObject,
+    repo: &Repository,
+    ipfs: &mut Ipfs,
+    config: &Config,
+) -> BoxResult<()> {
+    let odb = repo.odb()?;
+
+    for object in multi_object.objects.values_mut() {
+        let mut streamed = match object.streamed {
+            Some(streamed) if streamed.checksum.is_none() => streamed,
+            _ => continue,
+        };
+        let oid = object.git_hash.to_oid()?;
+
+        let mut hasher = Blake2b::<U32>::new();
+        let mut chunks = Vec::new();
+
+        // libgit2 only streams loose objects, packed ones are read whole once
+        let packed;
+        let reader: Box<dyn Read + '_> = match odb.reader(oid) {
+            Ok((reader, _, _)) => Box::new(reader),
+            Err(e) => {
+                debug!("Reading blob {} whole: {}", oid, e);
+                packed = odb.read(oid)?;
+                Box::new(packed.data())
+            }
+        };
+
+        let mut cutter = Cutter::new(reader);
+        while let Some(data) = cutter.next_chunk()? {
+            hasher.update(&data);
+
+            let size = data.len() as u32;
+            let (cid, _) = ipfs_add(ipfs, data, config.cid_format).await?;
+            chunks.push(BlobChunk {
+                cid: cid.to_bytes(),
+                size,
+            });
+        }
+
+        let size: u64 = chunks.iter().map(|chunk| chunk.size as u64).sum();
+        if size != streamed.size {
+            error!(ExitCode::Verification.wrap(format!(
+                "Blob {} holds {} bytes, but its header says {}",
+                oid, size, streamed.size
+            )))
+        }
+
+        debug!(
+            "Streamed blob {} ({} bytes) as {} chunks",
+            oid,
+            size,
+            chunks.len()
+        );
+
+        streamed.checksum = Some(hasher.finalize().into());
+        object.streamed = Some(streamed);
+        object.metadata = GitObjectMetadata::ChunkedBlob { chunks };
+    }
+
+    Ok(())
+}
+
+/// Cuts what a reader yields into the chunks FastCDC cuts it into in memory, holding at most two
+/// chunks at a time: the first chunk of a window is the same once the window holds MAX_CHUNK.
+struct Cutter<R: Read> {
+    reader: R,
+    window: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Cutter<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            window: Vec::with_capacity(2 * MAX_CHUNK as usize),
+            eof: false,
+        }
+    }
+
+    fn next_chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        while !self.eof && self.window.len() < MAX_CHUNK as usize {
+            let start = self.window.len();
+            self.window.resize(start + MAX_CHUNK as usize, 0);
+            let read = self.reader.read(&mut self.window[start..])?;
+            self.window.truncate(start + read);
+            self.eof = read == 0;
+        }
+        if self.window.is_empty() {
+            return Ok(None);
+        }
+
+        let length = FastCDC::new(&self.window, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK)
+            .next()
+            .map_or(self.window.len(), |chunk| chunk.length);
+
+        Ok(Some(self.window.drain(..length).collect()))
+    }
+}
+
+/// Turns every chunked blob in `multi_object` back into a blob holding its data.
+pub async fn reassemble(multi_object: &mut MultiObject, ipfs: &Ipfs) -> BoxResult<()> {
+    // Chunks repeat within a MultiObject as well, as in files that share a header
+    let mut fetched: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+
+    for object in multi_object.objects.values_mut() {
+        let chunks = match &object.metadata {
+            GitObjectMetadata::ChunkedBlob { chunks } => chunks,
+            _ => continue,
+        };
+
+        let mut data = Vec::with_capacity(chunks.iter().map(|chunk| chunk.size as usize).sum());
+
+        for chunk in chunks {
+            if !fetched.contains_key(&chunk.cid) {
+                let cid = Cid::try_from(chunk.cid.as_slice())?;
+                let content = ipfs_cat(ipfs, &cid).await?;
+
+                if content.len() != chunk.size as usize {
+                    error!(ExitCode::Verification.wrap(format!(
+                        "Chunk {} of blob {} holds {} bytes, expected {}",
+                        cid,
+                        object.git_hash,
+                        content.len(),
+                        chunk.size
+                    )))
+                }
+                fetched.insert(chunk.cid.clone(), content);
+            }
+
+            data.extend_from_slice(&fetched[&chunk.cid]);
+        }
+
+        object.data = data;
+        object.metadata = GitObjectMetadata::Blob;
+    }
+
+    Ok(())
+}
+
+/// The CIDs of the chunks `multi_object` stores outside itself.
+pub fn chunk_cids(multi_object: &MultiObject) -> BoxResult<Vec<Cid>> {
+    let mut cids = Vec::new();
+
+    for object in multi_object.objects.values() {
+        if let GitObjectMetadata::ChunkedBlob { chunks } = &object.metadata {
+            for chunk in chunks {
+                let cid = Cid::try_from(chunk.cid.as_slice())?;
+                if !cids.contains(&cid) {
+                    cids.push(cid);
+                }
+            }
+        }
+    }
+
+    Ok(cids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads at most a few bytes at a time, as streams may.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(4093);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn streamed_chunks_are_cut_like_in_memory_ones() {
+        // Pseudo-random, so FastCDC finds boundaries before MAX_CHUNK
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let data: Vec<u8> = (0..5 * MAX_CHUNK + 12345)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+
+        let in_memory: Vec<&[u8]> = FastCDC::new(&data, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK)
+            .map(|chunk| &data[chunk.offset..chunk.offset + chunk.length])
+            .collect();
+
+        let mut cutter = Cutter::new(Trickle(&data));
+        let mut streamed = Vec::new();
+        while let Some(chunk) = cutter.next_chunk().unwrap() {
+            streamed.push(chunk);
+        }
+
+        assert!(in_memory.len() > 5);
+        assert_eq!(streamed, in_memory);
+    }
+}
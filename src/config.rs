@@ -0,0 +1,880 @@
+use crate::{
+    backup::Backup,
+    bundling::Bundling,
+    compression::Compression,
+    error,
+    exit::ExitCode,
+    maintenance::Maintenance,
+    networks::{self, Network},
+    prefetch::Prefetch,
+    primitives::{BoxResult, PushOrdering},
+    proxy,
+    quota::Quota,
+    reclaim::Reclaim,
+    reconstruct::MissingRepoData,
+    timeout::Timeouts,
+    types::IpsId,
+    util::{flag, validate_license, validate_metadata_template, CidFormat},
+};
+use dirs::config_dir;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf};
+use toml::Value;
+
+/// Prefix of the environment variables overriding config keys, as in `INV4_GIT_CHAIN_ENDPOINT`.
+static ENV_PREFIX: &str = "INV4_GIT_";
+
+/// Selects a `[profile.<name>]` when the remote URL doesn't, as in `inv4://5?profile=<name>`.
+static PROFILE_VAR: &str = "INV4_GIT_PROFILE";
+
+/// Every key that can be overridden from the environment.
+static OVERRIDABLE_KEYS: &[&str] = &[
+    "chain.endpoint",
+    "chain.treasury_pays",
+    "chain.verify_objects",
+    "chain.fee_ledger",
+    "chain.push_receipts",
+    "chain.manifest",
+    "chain.fee_asset",
+    "chain.license",
+    "chain.metadata_template",
+    "chain.ss58_prefix",
+    "chain.skip_metadata_check",
+    "ipfs.mfs_mirror",
+    "ipfs.cid_format.version",
+    "ipfs.cid_format.hash",
+    "signer.passphrase",
+    "signer.sign_repo_data",
+    "signer.sign_objects",
+    "limits.max_objects",
+    "limits.max_bytes",
+    "limits.max_blob_bytes",
+    "network.proxy",
+    "fetch.prune",
+    "fetch.accept_rewrite",
+    "fetch.verify",
+    "fetch.missing_repo_data",
+    "push.ordering",
+    "push.chunk_blobs_over",
+    "push.reflog_len",
+    "push.compression",
+    "push.reclaim_deposits",
+    "push.object_index",
+    "bundling.mode",
+    "bundling.per_object_max_objects",
+    "bundling.per_object_min_bytes",
+    "bundling.per_object_max_fee",
+    "maintenance.min_objects",
+    "maintenance.commit_graph",
+    "maintenance.bitmap",
+    "prefetch.generations",
+    "prefetch.concurrency",
+    "prefetch.max_bytes",
+    "timeouts.chain_query",
+    "timeouts.extrinsic",
+    "timeouts.ipfs_add",
+    "timeouts.ipfs_cat",
+];
+
+/// The effective configuration, as the rest of git-remote-inv4 reads it.
+///
+/// This is also the flat format config files had before they were split into sections, which
+/// is still read.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Config {
+    pub chain_endpoint: String,
+    /// Mirror pushed content into the IPFS node's MFS under `/inv4-git/<ips_id>/`
+    #[serde(default)]
+    pub mfs_mirror: bool,
+    /// Ask for the passphrase protecting the signer's seed phrase
+    #[serde(default)]
+    pub signer_passphrase: bool,
+    /// Check that the IPFs backing RepoData's objects exist before skipping them on push
+    #[serde(default)]
+    pub verify_objects: bool,
+    /// Append the fees paid for every push to `.git/inv4/<remote>/<network>-<ips_id>/ledger.tsv`
+    #[serde(default)]
+    pub fee_ledger: bool,
+    /// Mint a human readable PushReceipt IPF together with every RepoData
+    #[serde(default)]
+    pub push_receipts: bool,
+    /// Mint a manifest IPF listing every object with a checksum together with every RepoData
+    #[serde(default)]
+    pub manifest: bool,
+    /// Mint IPFs through the IPS's multisig so the IPS account owns and pays for them
+    #[serde(default)]
+    pub treasury_pays: bool,
+    /// Sign every pushed RepoData, chaining it to the one it replaces
+    #[serde(default)]
+    pub sign_repo_data: bool,
+    /// Sign every pushed object, so its uploader can be told whoever signs the extrinsics
+    #[serde(default)]
+    pub sign_objects: bool,
+    /// Asset to pay fees in on chains with `ChargeAssetTxPayment`, the native token if unset
+    #[serde(default)]
+    pub fee_asset: Option<u32>,
+    /// License tag added to the metadata of every minted IPF, an SPDX expression or the CID of
+    /// custom terms
+    #[serde(default)]
+    pub license: Option<String>,
+    /// Name MultiObject IPFs are minted under, as in `"{repo}/{type}/{hash}"` with the IPS id,
+    /// the git type of the object and its hash; the bare hash if unset
+    #[serde(default)]
+    pub metadata_template: Option<String>,
+    /// SS58 prefix accounts are displayed with, the chain's own if unset
+    #[serde(default)]
+    pub ss58_prefix: Option<u16>,
+    /// Don't check once per process that the node's runtime matches the metadata this build
+    /// was generated from, for hot paths in automation that know it does
+    #[serde(default)]
+    pub skip_metadata_check: bool,
+    /// CID version and hash function used when adding content to IPFS
+    #[serde(default)]
+    pub cid_format: CidFormat,
+    /// Limits on what a single push may add
+    #[serde(default)]
+    pub quota: Quota,
+    /// Upload a copy of every pushed RepoData here too
+    #[serde(default)]
+    pub backup: Option<Backup>,
+    /// Optimizing the local repository after large fetches
+    #[serde(default)]
+    pub maintenance: Maintenance,
+    /// Downloading MultiObjects a fetch is likely to need ahead of time
+    #[serde(default)]
+    pub prefetch: Prefetch,
+    /// Proxy for the chain and remote IPFS APIs, `none` to ignore the proxy variables
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// Hosts reached without the proxy
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    /// How long chain and IPFS operations may take
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// Delete remote-tracking refs of refs deleted on chain when fetching, unless git's
+    /// `remote.<name>.prune` or `fetch.prune` say otherwise
+    #[serde(default)]
+    pub prune: bool,
+    /// Fetch refs whose history was rewritten on chain, instead of failing once their old tips
+    /// are backed up
+    #[serde(default)]
+    pub accept_rewrite: bool,
+    /// Re-hash what a fetch wrote and check it reaches its whole history and matches RepoData
+    #[serde(default)]
+    pub verify_fetch: bool,
+    /// What to do with an IPS that holds git objects but no RepoData
+    #[serde(default)]
+    pub missing_repo_data: MissingRepoData,
+    /// Whether a push mints its objects at once or leaves first
+    #[serde(default)]
+    pub push_ordering: PushOrdering,
+    /// When a push mints every object as its own IPF instead of bundling them
+    #[serde(default)]
+    pub bundling: Bundling,
+    /// Blobs larger than this many bytes are pushed as content-defined chunks, none to store
+    /// every blob up to 16 MiB whole
+    #[serde(default)]
+    pub chunk_blobs_over: Option<u64>,
+    /// How many updates of each ref RepoData keeps in its reflog, 0 to record none
+    #[serde(default = "default_reflog_len")]
+    pub reflog_len: usize,
+    /// How the data of pushed objects is compressed in their MultiObjects
+    #[serde(default)]
+    pub compression: Compression,
+    /// Whether the IPFs a push replaces are burned to get their deposits back
+    #[serde(default)]
+    pub reclaim_deposits: Reclaim,
+    /// Whether pushes mint an index of which IPF holds each MultiObject, for faster fetches
+    #[serde(default = "default_object_index")]
+    pub object_index: bool,
+    /// Networks `chain_endpoint` can name, over the built-in ones
+    #[serde(default)]
+    pub networks: BTreeMap<String, Network>,
+    /// The network `chain_endpoint` was named after, if it was
+    #[serde(skip)]
+    pub network: Option<(String, Network)>,
+    /// The profile these settings were taken from
+    #[serde(skip)]
+    pub profile: Option<String>,
+    /// Username the signer's credential is stored under, so every profile has its own
+    #[serde(skip)]
+    pub signer_key: Option<String>,
+    /// The IPS subcommands act on without `--ips`
+    #[serde(skip)]
+    pub default_ips: Option<IpsId>,
+}
+
+/// The config file, `INV4-Git/config.toml` in the OS's config directory.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    chain: ChainSection,
+    #[serde(default)]
+    ipfs: IpfsSection,
+    #[serde(default)]
+    signer: SignerSection,
+    #[serde(default)]
+    limits: Quota,
+    #[serde(default)]
+    pinning: PinningSection,
+    #[serde(default)]
+    network: NetworkSection,
+    #[serde(default)]
+    fetch: FetchSection,
+    #[serde(default)]
+    push: PushSection,
+    #[serde(default)]
+    bundling: Bundling,
+    #[serde(default)]
+    maintenance: Maintenance,
+    #[serde(default)]
+    prefetch: Prefetch,
+    #[serde(default)]
+    timeouts: Timeouts,
+    #[serde(default)]
+    networks: BTreeMap<String, Network>,
+    #[serde(default)]
+    profile: BTreeMap<String, Profile>,
+}
+
+/// Settings for one account and network, `[profile.<name>]`, applied over the sections.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    /// Replaces `chain.endpoint`, a URL or the name of a network
+    endpoint: Option<String>,
+    /// The credential to sign with; defaults to the profile's name
+    key: Option<String>,
+    /// Replaces `chain.treasury_pays`, so a DAO profile mints through the IPS multisig
+    treasury_pays: Option<bool>,
+    /// Replaces `signer.passphrase`
+    passphrase: Option<bool>,
+    /// Replaces `chain.fee_asset`, as networks take different assets
+    fee_asset: Option<u32>,
+    /// The IPS subcommands act on without `--ips`
+    ips: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+struct ChainSection {
+    /// WebSocket URL of the node to submit to, or the name of a known network
+    #[serde(default = "default_endpoint")]
+    endpoint: String,
+    #[serde(default)]
+    treasury_pays: bool,
+    #[serde(default)]
+    verify_objects: bool,
+    #[serde(default)]
+    fee_ledger: bool,
+    #[serde(default)]
+    push_receipts: bool,
+    #[serde(default)]
+    manifest: bool,
+    fee_asset: Option<u32>,
+    license: Option<String>,
+    metadata_template: Option<String>,
+    ss58_prefix: Option<u16>,
+    #[serde(default)]
+    skip_metadata_check: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct IpfsSection {
+    #[serde(default)]
+    mfs_mirror: bool,
+    #[serde(default)]
+    cid_format: CidFormat,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct SignerSection {
+    #[serde(default)]
+    passphrase: bool,
+    #[serde(default)]
+    sign_repo_data: bool,
+    #[serde(default)]
+    sign_objects: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct PinningSection {
+    #[serde(default)]
+    backup: Option<Backup>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct NetworkSection {
+    #[serde(default)]
+    proxy: Option<String>,
+    #[serde(default)]
+    no_proxy: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FetchSection {
+    #[serde(default)]
+    prune: bool,
+    #[serde(default)]
+    accept_rewrite: bool,
+    #[serde(default)]
+    verify: bool,
+    #[serde(default)]
+    missing_repo_data: MissingRepoData,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+struct PushSection {
+    #[serde(default)]
+    ordering: PushOrdering,
+    #[serde(default)]
+    chunk_blobs_over: Option<u64>,
+    #[serde(default = "default_reflog_len")]
+    reflog_len: usize,
+    #[serde(default)]
+    compression: Compression,
+    #[serde(default)]
+    reclaim_deposits: Reclaim,
+    #[serde(default = "default_object_index")]
+    object_index: bool,
+}
+
+fn default_endpoint() -> String {
+    String::from("ws://127.0.0.1:9944")
+}
+
+fn default_reflog_len() -> usize {
+    16
+}
+
+fn default_object_index() -> bool {
+    true
+}
+
+impl Default for PushSection {
+    fn default() -> Self {
+        Self {
+            ordering: PushOrdering::default(),
+            chunk_blobs_over: None,
+            reflog_len: default_reflog_len(),
+            compression: Compression::default(),
+            reclaim_deposits: Reclaim::default(),
+            object_index: default_object_index(),
+        }
+    }
+}
+
+impl Default for ChainSection {
+    fn default() -> Self {
+        Self {
+            endpoint: default_endpoint(),
+            treasury_pays: false,
+            verify_objects: false,
+            fee_ledger: false,
+            push_receipts: false,
+            manifest: false,
+            fee_asset: None,
+            license: None,
+            metadata_template: None,
+            ss58_prefix: None,
+            skip_metadata_check: false,
+        }
+    }
+}
+
+impl From<ConfigFile> for Config {
+    fn from(file: ConfigFile) -> Self {
+        Self {
+            chain_endpoint: file.chain.endpoint,
+            mfs_mirror: file.ipfs.mfs_mirror,
+            signer_passphrase: file.signer.passphrase,
+            verify_objects: file.chain.verify_objects,
+            fee_ledger: file.chain.fee_ledger,
+            push_receipts: file.chain.push_receipts,
+            manifest: file.chain.manifest,
+            treasury_pays: file.chain.treasury_pays,
+            sign_repo_data: file.signer.sign_repo_data,
+            sign_objects: file.signer.sign_objects,
+            fee_asset: file.chain.fee_asset,
+            license: file.chain.license,
+            metadata_template: file.chain.metadata_template,
+            ss58_prefix: file.chain.ss58_prefix,
+            skip_metadata_check: file.chain.skip_metadata_check,
+            cid_format: file.ipfs.cid_format,
+            quota: file.limits,
+            backup: file.pinning.backup,
+            maintenance: file.maintenance,
+            prefetch: file.prefetch,
+            proxy: file.network.proxy,
+            no_proxy: file.network.no_proxy,
+            timeouts: file.timeouts,
+            prune: file.fetch.prune,
+            accept_rewrite: file.fetch.accept_rewrite,
+            verify_fetch: file.fetch.verify,
+            missing_repo_data: file.fetch.missing_repo_data,
+            push_ordering: file.push.ordering,
+            bundling: file.bundling,
+            chunk_blobs_over: file.push.chunk_blobs_over,
+            reflog_len: file.push.reflog_len,
+            compression: file.push.compression,
+            reclaim_deposits: file.push.reclaim_deposits,
+            object_index: file.push.object_index,
+            networks: file.networks,
+            network: None,
+            profile: None,
+            signer_key: None,
+            default_ips: None,
+        }
+    }
+}
+
+impl From<Config> for ConfigFile {
+    fn from(config: Config) -> Self {
+        Self {
+            chain: ChainSection {
+                endpoint: config.chain_endpoint,
+                treasury_pays: config.treasury_pays,
+                verify_objects: config.verify_objects,
+                fee_ledger: config.fee_ledger,
+                push_receipts: config.push_receipts,
+                manifest: config.manifest,
+                fee_asset: config.fee_asset,
+                license: config.license,
+                metadata_template: config.metadata_template,
+                ss58_prefix: config.ss58_prefix,
+                skip_metadata_check: config.skip_metadata_check,
+            },
+            ipfs: IpfsSection {
+                mfs_mirror: config.mfs_mirror,
+                cid_format: config.cid_format,
+            },
+            signer: SignerSection {
+                passphrase: config.signer_passphrase,
+                sign_repo_data: config.sign_repo_data,
+                sign_objects: config.sign_objects,
+            },
+            limits: config.quota,
+            pinning: PinningSection {
+                backup: config.backup,
+            },
+            network: NetworkSection {
+                proxy: config.proxy,
+                no_proxy: config.no_proxy,
+            },
+            fetch: FetchSection {
+                prune: config.prune,
+                accept_rewrite: config.accept_rewrite,
+                verify: config.verify_fetch,
+                missing_repo_data: config.missing_repo_data,
+            },
+            push: PushSection {
+                ordering: config.push_ordering,
+                chunk_blobs_over: config.chunk_blobs_over,
+                reflog_len: config.reflog_len,
+                compression: config.compression,
+                reclaim_deposits: config.reclaim_deposits,
+                object_index: config.object_index,
+            },
+            bundling: config.bundling,
+            maintenance: config.maintenance,
+            prefetch: config.prefetch,
+            timeouts: config.timeouts,
+            networks: config.networks,
+            profile: BTreeMap::new(),
+        }
+    }
+}
+
+/// A config file as read, with its environment overrides applied.
+struct Resolved {
+    file: ConfigFile,
+    contents: Option<String>,
+    /// The overridden keys and the variables or profile that set them
+    overrides: Vec<(&'static str, String)>,
+    /// The selected profile
+    profile: Option<(String, Profile)>,
+}
+
+fn config_file_path() -> PathBuf {
+    let mut config_file_path =
+        config_dir().expect("Operating system's configs directory not found");
+    config_file_path.push("INV4-Git/config.toml");
+    config_file_path
+}
+
+fn is_ws_url(endpoint: &str) -> bool {
+    endpoint.starts_with("ws://") || endpoint.starts_with("wss://")
+}
+
+fn env_var(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase())
+}
+
+impl Config {
+    /// Loads the config file, creating it with the defaults if it doesn't exist yet.
+    ///
+    /// The profile named by `INV4_GIT_PROFILE` is applied over the sections, and
+    /// `INV4_GIT_<SECTION>_<KEY>` environment variables override both.
+    pub fn load() -> BoxResult<Self> {
+        Self::load_profile(None)
+    }
+
+    /// Like `load`, with `profile` instead of the one `INV4_GIT_PROFILE` names.
+    pub fn load_profile(profile: Option<&str>) -> BoxResult<Self> {
+        let resolved = resolve(profile).map_err(|e| ExitCode::Config.wrap(e))?;
+        resolved.validate().map_err(|e| ExitCode::Config.wrap(e))?;
+
+        let profile = resolved.profile;
+        let mut config: Config = resolved.file.into();
+
+        if !is_ws_url(&config.chain_endpoint) {
+            let name = std::mem::take(&mut config.chain_endpoint);
+            let network =
+                networks::find(&name, &config.networks).map_err(|e| ExitCode::Config.wrap(e))?;
+            config.chain_endpoint = network.endpoints[0].clone();
+            config.ss58_prefix = config.ss58_prefix.or(Some(network.ss58_prefix));
+            config.network = Some((name, network));
+        }
+
+        if let Some((name, profile)) = profile {
+            config.signer_key = Some(profile.key.unwrap_or_else(|| name.clone()));
+            config.default_ips = profile.ips.map(IpsId);
+            config.profile = Some(name);
+        }
+
+        config.timeouts.install();
+        config.prefetch.install();
+
+        Ok(config)
+    }
+
+    /// The IPS given with `--ips`, or else the profile's.
+    pub fn ips_arg(&self, args: &[String]) -> BoxResult<Option<IpsId>> {
+        match flag(args, "--ips") {
+            Some(ips_id) => Ok(Some(ips_id.parse()?)),
+            None => Ok(self.default_ips),
+        }
+    }
+}
+
+fn resolve(profile: Option<&str>) -> BoxResult<Resolved> {
+    let config_file_path = config_file_path();
+
+    std::fs::create_dir_all(config_file_path.parent().unwrap()).unwrap();
+
+    let (file, contents) = if config_file_path.exists() {
+        let contents = std::fs::read_to_string(&config_file_path)?;
+
+        let file = parse(&contents)
+            .map_err(|e| format!("Invalid config {}: {}", config_file_path.display(), e))?;
+
+        (file, Some(contents))
+    } else {
+        let file = ConfigFile::default();
+        std::fs::write(&config_file_path, toml::to_string(&file)?)?;
+
+        (file, None)
+    };
+
+    let mut value = Value::try_from(&file)?;
+    let mut overrides = Vec::new();
+
+    let profile = match profile
+        .map(str::to_owned)
+        .or_else(|| std::env::var(PROFILE_VAR).ok().filter(|p| !p.is_empty()))
+    {
+        Some(name) => match file.profile.get(&name) {
+            Some(profile) => Some((name, profile.clone())),
+            None => error!(format!(
+                "There is no [profile.{}] in the config. Known profiles: {}",
+                name,
+                file.profile.keys().cloned().collect::<Vec<_>>().join(", ")
+            )),
+        },
+        None => None,
+    };
+
+    if let Some((name, profile)) = &profile {
+        let applied = [
+            (
+                "chain.endpoint",
+                profile.endpoint.clone().map(Value::String),
+            ),
+            (
+                "chain.treasury_pays",
+                profile.treasury_pays.map(Value::Boolean),
+            ),
+            ("signer.passphrase", profile.passphrase.map(Value::Boolean)),
+            (
+                "chain.fee_asset",
+                profile.fee_asset.map(|asset| Value::Integer(asset.into())),
+            ),
+        ];
+
+        for (key, new) in applied {
+            if let Some(new) = new {
+                set(&mut value, key, new);
+                overrides.push((key, format!("[profile.{}]", name)));
+            }
+        }
+    }
+
+    for key in OVERRIDABLE_KEYS {
+        let var = env_var(key);
+
+        if let Ok(raw) = std::env::var(&var) {
+            // Values are TOML, bare words are taken as strings
+            let parsed = format!("v = {}", raw)
+                .parse::<Value>()
+                .ok()
+                .and_then(|table| table.get("v").cloned())
+                .unwrap_or(Value::String(raw));
+
+            set(&mut value, key, parsed);
+            overrides.retain(|(k, _)| k != key);
+            overrides.push((*key, var));
+        }
+    }
+
+    let file = if overrides.is_empty() {
+        file
+    } else {
+        value.try_into().map_err(|e| {
+            format!(
+                "Invalid value in {}: {}",
+                overrides
+                    .iter()
+                    .map(|(_, var)| var.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                e
+            )
+        })?
+    };
+
+    Ok(Resolved {
+        file,
+        contents,
+        overrides,
+        profile,
+    })
+}
+
+/// Reads a sectioned config file, or one in the older flat format.
+fn parse(contents: &str) -> Result<ConfigFile, toml::de::Error> {
+    let flat = contents
+        .parse::<Value>()
+        .map(|value| value.get("chain_endpoint").is_some())
+        .unwrap_or(false);
+
+    if flat {
+        Ok(toml::from_str::<Config>(contents)?.into())
+    } else {
+        toml::from_str(contents)
+    }
+}
+
+/// Sets the dotted `key` in `value`, creating the tables on the way.
+fn set(value: &mut Value, key: &str, new: Value) {
+    let mut parts = key.split('.').peekable();
+    let mut current = value;
+
+    while let Some(part) = parts.next() {
+        let table = match current {
+            Value::Table(table) => table,
+            _ => return,
+        };
+
+        if parts.peek().is_none() {
+            table.insert(part.to_owned(), new);
+            return;
+        }
+
+        current = table
+            .entry(part.to_owned())
+            .or_insert_with(|| Value::Table(Default::default()));
+    }
+}
+
+impl Resolved {
+    /// Where the value of `key` came from, for error messages.
+    fn origin(&self, key: &str) -> String {
+        if let Some((_, var)) = self.overrides.iter().find(|(k, _)| *k == key) {
+            return format!("{} (set by {})", key, var);
+        }
+
+        match self.contents.as_deref().and_then(|c| locate(c, key)) {
+            Some(line) => format!("{} (config.toml line {})", key, line),
+            None => key.to_owned(),
+        }
+    }
+
+    fn validate(&self) -> BoxResult<()> {
+        let file = &self.file;
+        let mut problems = Vec::new();
+
+        if !is_ws_url(&file.chain.endpoint) {
+            if let Err(e) = networks::find(&file.chain.endpoint, &file.networks) {
+                problems.push(format!("{}: {}", self.origin("chain.endpoint"), e));
+            }
+        }
+
+        for (name, network) in &file.networks {
+            let key = format!("networks.{}", name);
+            match network
+                .endpoints
+                .iter()
+                .find(|endpoint| !is_ws_url(endpoint))
+            {
+                Some(endpoint) => problems.push(format!(
+                    "{}: expected ws:// or wss:// endpoints, got {:?}",
+                    self.origin(&key),
+                    endpoint
+                )),
+                None if network.endpoints.is_empty() => {
+                    problems.push(format!("{}: has no endpoints", self.origin(&key)))
+                }
+                None => {}
+            }
+        }
+
+        if let Err(e) = file.ipfs.cid_format.validate() {
+            problems.push(format!("{}: {}", self.origin("ipfs.cid_format.hash"), e));
+        }
+
+        let limits = [
+            (
+                "limits.max_objects",
+                file.limits.max_objects.map(|n| n as u64),
+            ),
+            ("limits.max_bytes", file.limits.max_bytes),
+            ("limits.max_blob_bytes", file.limits.max_blob_bytes),
+        ];
+        for (key, limit) in limits {
+            if limit == Some(0) {
+                problems.push(format!(
+                    "{}: 0 would reject every push, leave it out to disable the limit",
+                    self.origin(key)
+                ));
+            }
+        }
+
+        if file.bundling.per_object_max_objects == 0 {
+            problems.push(format!(
+                "{}: 0 would never mint per object, set bundling.mode = \"bundle\" instead",
+                self.origin("bundling.per_object_max_objects")
+            ));
+        }
+
+        match &file.pinning.backup {
+            Some(Backup::Ipfs { url }) | Some(Backup::Http { url, .. })
+                if !url.starts_with("http://") && !url.starts_with("https://") =>
+            {
+                problems.push(format!(
+                    "{}: expected an http:// or https:// URL, got {:?}",
+                    self.origin("pinning.backup"),
+                    url
+                ));
+            }
+            Some(Backup::Dir { path }) if path.as_os_str().is_empty() => {
+                problems.push(format!("{}: path is empty", self.origin("pinning.backup")));
+            }
+            _ => {}
+        }
+
+        if let Some(license) = &file.chain.license {
+            if let Err(e) = validate_license(license) {
+                problems.push(format!("{}: {}", self.origin("chain.license"), e));
+            }
+        }
+
+        if let Some(template) = &file.chain.metadata_template {
+            if let Err(e) = validate_metadata_template(template) {
+                problems.push(format!("{}: {}", self.origin("chain.metadata_template"), e));
+            }
+        }
+
+        if let Some(proxy) = &file.network.proxy {
+            if let Err(e) = proxy::validate(proxy) {
+                problems.push(format!("{}: {}", self.origin("network.proxy"), e));
+            }
+        }
+
+        match problems.len() {
+            0 => Ok(()),
+            1 => error!(format!("Invalid config: {}", problems[0])),
+            _ => error!(format!("Invalid config:\n  {}", problems.join("\n  "))),
+        }
+    }
+}
+
+/// The line `key` is set on in `contents`, looking for it in its `[section]`.
+fn locate(contents: &str, key: &str) -> Option<usize> {
+    let (section, name) = key.rsplit_once('.').unwrap_or(("", key));
+    let mut current = "";
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            current = header.trim();
+
+            // A key set as a table of its own, like `[pinning.backup]`
+            if current == key {
+                return Some(i + 1);
+            }
+            continue;
+        }
+
+        let sets_name = line
+            .strip_prefix(name)
+            .is_some_and(|rest| rest.trim_start().starts_with('='));
+        if current == section && sets_name {
+            return Some(i + 1);
+        }
+    }
+
+    None
+}
+
+/// `config show [--resolved] [--profile <name>]`: prints the config file, or with `--resolved`
+/// the effective configuration including defaults, the profile and environment overrides.
+pub fn run(args: &[String]) -> BoxResult<()> {
+    match args.first().map(String::as_str) {
+        Some("show") => {}
+        _ => error!("Usage: git-remote-inv4 config show [--resolved] [--profile <name>]"),
+    }
+
+    let resolved = resolve(flag(args, "--profile"))?;
+
+    if args.iter().any(|arg| arg == "--resolved") {
+        print!("{}", toml::to_string(&resolved.file)?);
+
+        for (key, var) in &resolved.overrides {
+            eprintln!("{} is overridden by {}", key, var);
+        }
+    } else {
+        eprintln!("# {}", config_file_path().display());
+        print!(
+            "{}",
+            match &resolved.contents {
+                Some(contents) => contents.clone(),
+                None => toml::to_string(&resolved.file)?,
+            }
+        );
+    }
+
+    if let Err(e) = resolved.validate() {
+        eprintln!("{}", e);
+    }
+
+    Ok(())
+}
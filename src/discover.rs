@@ -0,0 +1,202 @@
+use crate::{
+    config::Config,
+    connect, error,
+    fees::InvArchExtrinsicParams,
+    invarch,
+    ipfs::Ipfs,
+    primitives::BoxResult,
+    spool::SpooledRepoData,
+    timeout::{within, Operation},
+    types::{IpfId, IpsId},
+    util::{find_ipf, flag, ips_child_ids, IPF_BATCH_SIZE},
+};
+use log::debug;
+use subxt::{rpc::BlockNumber, sp_core::crypto::AccountId32, DefaultConfig};
+
+static USAGE: &str = "Usage: git-remote-inv4 discover (--owner <account> | --parent <ips_id>)";
+
+/// `discover`: lists the IP Sets holding a repository among those owned by an account or nested
+/// in an IPS, with their refs and the block of their last push.
+pub async fn run(config: &Config, args: &[String]) -> BoxResult<()> {
+    let chain_api = connect(config).await?;
+
+    let candidates = match (flag(args, "--owner"), flag(args, "--parent")) {
+        (Some(owner), None) => {
+            let owner: AccountId32 = owner
+                .parse()
+                .map_err(|e| format!("Invalid account {}: {}", owner, e))?;
+            owned_by(&chain_api, &owner).await?
+        }
+        (None, Some(parent)) => nested_in(&chain_api, parent.parse()?).await?,
+        _ => error!(USAGE),
+    };
+
+    eprintln!(
+        "Looking for repositories in {} IP Sets...",
+        candidates.len()
+    );
+
+    let ipfs = Ipfs::default();
+    let mut found = 0;
+
+    for (ips_id, path) in candidates {
+        let (ipf_id, cid) = match find_ipf(&chain_api, ips_id, "RepoData").await? {
+            Some(repo_data) => repo_data,
+            None => {
+                debug!("IPS {} holds no RepoData", ips_id);
+                continue;
+            }
+        };
+        found += 1;
+
+        let refs = match SpooledRepoData::from_ipfs(cid, &ipfs).await {
+            Ok(repo_data) => format!("{} refs", repo_data.refs.len()),
+            Err(e) => {
+                debug!("RepoData of IPS {} is unreadable: {}", ips_id, e);
+                "unreadable RepoData".to_owned()
+            }
+        };
+
+        let last_push = match minted_at(&chain_api, ipf_id).await {
+            Ok(number) => format!("last push in block #{}", number),
+            Err(e) => {
+                debug!("Could not find the block of IPF {}: {}", ipf_id, e);
+                "last push in an unknown block".to_owned()
+            }
+        };
+
+        println!(
+            "inv4://{}\t{}\t{}\t{}",
+            path,
+            ips_name(&chain_api, ips_id).await?,
+            refs,
+            last_push
+        );
+    }
+
+    if found == 0 {
+        eprintln!("No repositories found");
+    }
+
+    Ok(())
+}
+
+/// The IP Sets `owner` holds, with the URL paths naming them.
+pub async fn owned_by(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    owner: &AccountId32,
+) -> BoxResult<Vec<(IpsId, String)>> {
+    let inv4 = chain_api.storage().inv4();
+    let next_ips_id = within(
+        Operation::ChainQuery,
+        "reading the next IPS id from chain",
+        inv4.next_ip_id(None),
+    )
+    .await?;
+
+    let ips_ids: Vec<u32> = (0..next_ips_id).collect();
+    let mut owned = Vec::new();
+
+    for batch in ips_ids.chunks(IPF_BATCH_SIZE) {
+        let holdings = within(
+            Operation::ChainQuery,
+            &format!("reading the IP Sets of {} from chain", owner),
+            futures::future::try_join_all(
+                batch.iter().map(|id| inv4.ips_by_owner(owner, id, None)),
+            ),
+        )
+        .await?;
+
+        owned.extend(
+            batch
+                .iter()
+                .zip(holdings)
+                .filter(|(_, holding)| holding.is_some())
+                .map(|(id, _)| (IpsId(*id), id.to_string())),
+        );
+    }
+
+    Ok(owned)
+}
+
+/// The IP Sets nested in `parent` at any depth, with the URL paths naming them.
+async fn nested_in(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    parent: IpsId,
+) -> BoxResult<Vec<(IpsId, String)>> {
+    let mut nested = Vec::new();
+    let mut stack = vec![(parent, parent.to_string())];
+
+    while let Some((ips_id, path)) = stack.pop() {
+        for child in ips_child_ids(chain_api, ips_id).await? {
+            let child_path = format!("{}/{}", path, child);
+            stack.push((IpsId(child), child_path.clone()));
+            nested.push((IpsId(child), child_path));
+        }
+    }
+
+    Ok(nested)
+}
+
+/// The name an IPS was created with, from its metadata.
+async fn ips_name(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ips_id: IpsId,
+) -> BoxResult<String> {
+    let inv4 = chain_api.storage().inv4();
+    let ips_info = within(
+        Operation::ChainQuery,
+        &format!("reading IPS {} from chain", ips_id),
+        inv4.ip_storage(&ips_id.0, None),
+    )
+    .await?
+    .ok_or(format!("IPS {ips_id} does not exist"))?;
+
+    let name = String::from_utf8_lossy(&ips_info.metadata.0)
+        .trim()
+        .to_owned();
+
+    Ok(if name.is_empty() {
+        "-".to_owned()
+    } else {
+        name
+    })
+}
+
+/// The first block IPF `ipf_id` exists in, found by bisecting the chain.
+///
+/// Needs a node that keeps the state of old blocks, like an archive node.
+async fn minted_at(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ipf_id: IpfId,
+) -> BoxResult<u32> {
+    let rpc = chain_api.client.rpc();
+    let ipf_storage = chain_api.storage().ipf();
+
+    let mut low = 0;
+    let mut high = rpc.header(None).await?.ok_or("No best block")?.number;
+
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let block_hash = rpc
+            .block_hash(Some(BlockNumber::from(middle)))
+            .await?
+            .ok_or(format!("Block #{} not found", middle))?;
+
+        let exists = within(
+            Operation::ChainQuery,
+            &format!("reading IPF {} at block #{} from chain", ipf_id, middle),
+            ipf_storage.ipf_storage(&ipf_id.0, Some(block_hash)),
+        )
+        .await?
+        .is_some();
+
+        if exists {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+
+    Ok(low)
+}
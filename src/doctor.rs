@@ -0,0 +1,146 @@
+use crate::{
+    config::Config,
+    connect_unchecked, error,
+    fees::InvArchExtrinsicParams,
+    get_signer, invarch,
+    ipfs::{Ipfs, IpfsOps},
+    primitives::BoxResult,
+};
+use std::process::Command;
+use subxt::DefaultConfig;
+
+/// A failed check: what went wrong and what the user can do about it.
+struct Failure {
+    problem: String,
+    fix: String,
+}
+
+fn failure(problem: impl ToString, fix: impl ToString) -> Failure {
+    Failure {
+        problem: problem.to_string(),
+        fix: fix.to_string(),
+    }
+}
+
+/// Checks everything a push or fetch depends on and prints how to fix what's broken.
+pub async fn run(config: &Config) -> BoxResult<()> {
+    let mut failed = 0;
+
+    let mut report = |name: &str, result: Result<String, Failure>| match result {
+        Ok(detail) => println!("[ ok ] {}: {}", name, detail),
+        Err(f) => {
+            failed += 1;
+            println!("[FAIL] {}: {}", name, f.problem);
+            println!("       fix: {}", f.fix);
+        }
+    };
+
+    report("git", check_git());
+    report("IPFS", check_ipfs(&Ipfs::default()).await);
+
+    match check_chain(config).await {
+        Ok(api) => {
+            report(
+                "chain",
+                Ok(format!("connected to {}", config.chain_endpoint)),
+            );
+            report("signer", check_signer(&api, config).await);
+        }
+        Err(f) => {
+            report("chain", Err(f));
+            report(
+                "signer",
+                Err(failure(
+                    "skipped, the chain is unreachable",
+                    "fix the chain endpoint first",
+                )),
+            );
+        }
+    }
+
+    if failed > 0 {
+        error!(format!("{} checks failed", failed))
+    }
+
+    println!("Everything looks good!");
+    Ok(())
+}
+
+fn check_git() -> Result<String, Failure> {
+    let output = Command::new("git")
+        .arg("--version")
+        .output()
+        .map_err(|e| failure(e, "install git and make sure it is on your PATH"))?;
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+async fn check_ipfs(ipfs: &Ipfs) -> Result<String, Failure> {
+    let version = ipfs.version().await.map_err(|e| {
+        failure(
+            format!("the IPFS API at 127.0.0.1:5001 is unreachable ({})", e),
+            "start the IPFS daemon with `ipfs daemon`",
+        )
+    })?;
+
+    Ok(format!("go-ipfs {}", version))
+}
+
+async fn check_chain(
+    config: &Config,
+) -> Result<invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>, Failure> {
+    let api = connect_unchecked(config).await.map_err(|e| {
+        failure(
+            format!("could not connect to {} ({})", config.chain_endpoint, e),
+            "start the node or set `endpoint` in the [chain] section of the INV4-Git config.toml, or INV4_GIT_CHAIN_ENDPOINT",
+        )
+    })?;
+
+    api.validate_metadata().map_err(|_| {
+        failure(
+            "the node's runtime doesn't match the metadata git-remote-inv4 was built with",
+            "update git-remote-inv4, or connect to a node running a compatible runtime",
+        )
+    })?;
+
+    Ok(api)
+}
+
+async fn check_signer(
+    api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    config: &Config,
+) -> Result<String, Failure> {
+    let signer = get_signer(config).await.map_err(|e| {
+        failure(
+            e,
+            "store your seed phrase or private key for the inv4 protocol in a git credential helper",
+        )
+    })?;
+
+    let account = api
+        .storage()
+        .system()
+        .account(signer.account_id(), None)
+        .await
+        .map_err(|e| failure(e, "check that the node is fully synced"))?;
+
+    if account.providers == 0 {
+        return Err(failure(
+            format!("account {} does not exist on chain", signer.account_id()),
+            "transfer some funds to it to pay for transaction fees",
+        ));
+    }
+
+    if account.data.free == 0 {
+        return Err(failure(
+            format!("account {} has no free balance", signer.account_id()),
+            "transfer some funds to it to pay for transaction fees",
+        ));
+    }
+
+    Ok(format!(
+        "{} with a free balance of {}",
+        signer.account_id(),
+        account.data.free
+    ))
+}
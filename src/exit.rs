@@ -0,0 +1,125 @@
+use inv4_git_core::VerificationError;
+use std::{error::Error, fmt};
+
+/// What git-remote-inv4 exits with, so scripts can tell failures apart.
+///
+/// Failures that fit none of these exit with 1, and success with 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitCode {
+    /// 2: the config file or an environment override is invalid
+    Config = 2,
+    /// 3: the chain node couldn't be reached or didn't answer in time
+    ChainUnreachable = 3,
+    /// 4: there is no key to sign with, or the chain refused the signer
+    PermissionDenied = 4,
+    /// 5: the push would drop commits on chain that aren't in the local repository, or the
+    /// fetch would drop commits of a remote-tracking ref that were rewritten on chain
+    NonFastForward = 5,
+    /// 6: IPFS couldn't store or return content
+    Ipfs = 6,
+    /// 7: content from chain or IPFS doesn't match what it claims to be
+    Verification = 7,
+}
+
+/// What can be done about a failure, which decides whether it's retried and what the user is
+/// told to try.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    /// Likely to go away by itself, so the same operation may be tried again
+    Transient,
+    /// Fails the same way until the user changes something, as the hint says
+    UserActionable,
+    /// Fails the same way whatever is tried again
+    Permanent,
+}
+
+impl ExitCode {
+    pub fn class(self) -> Class {
+        match self {
+            Self::ChainUnreachable | Self::Ipfs => Class::Transient,
+            Self::Config | Self::PermissionDenied | Self::NonFastForward => Class::UserActionable,
+            Self::Verification => Class::Permanent,
+        }
+    }
+
+    /// What to try next, printed after an error tagged with this code.
+    pub fn hint(self) -> &'static str {
+        match self {
+            Self::Config => "see what's wrong with `git-remote-inv4 config show`",
+            Self::ChainUnreachable => {
+                "check the node with `git-remote-inv4 doctor`, then try again"
+            }
+            Self::PermissionDenied => "check the key you sign with using `git-remote-inv4 whoami`",
+            Self::NonFastForward => {
+                "fetch and integrate what's on chain first, or push with --force to drop it"
+            }
+            Self::Ipfs => "check the IPFS node with `git-remote-inv4 doctor`, then try again",
+            Self::Verification => {
+                "check the IPS with `git-remote-inv4 fsck --ips <id>`, trying again won't help"
+            }
+        }
+    }
+
+    /// Tags `e` with this exit code.
+    pub fn wrap(self, e: impl Into<Box<dyn Error>>) -> Box<dyn Error> {
+        Box::new(Failure {
+            code: self,
+            source: e.into(),
+        })
+    }
+}
+
+/// An error tagged with the code to exit with once it reaches `main`.
+#[derive(Debug)]
+struct Failure {
+    code: ExitCode,
+    source: Box<dyn Error>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl Error for Failure {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.source()
+    }
+}
+
+/// The exit code `e` is tagged with, the outermost tag wins.
+fn tag(e: &(dyn Error + 'static)) -> Option<ExitCode> {
+    let mut current = Some(e);
+
+    while let Some(e) = current {
+        if let Some(failure) = e.downcast_ref::<Failure>() {
+            return Some(failure.code);
+        }
+        #[cfg(feature = "ipfs-api")]
+        if e.is::<ipfs_api::Error>() {
+            return Some(ExitCode::Ipfs);
+        }
+        if e.is::<VerificationError>() {
+            return Some(ExitCode::Verification);
+        }
+        current = e.source();
+    }
+
+    None
+}
+
+/// The code to exit with for `e`.
+pub fn code(e: &(dyn Error + 'static)) -> i32 {
+    tag(e).map_or(1, |code| code as i32)
+}
+
+/// What can be done about `e`. Untagged failures are taken to be permanent.
+pub fn classify(e: &(dyn Error + 'static)) -> Class {
+    tag(e).map_or(Class::Permanent, ExitCode::class)
+}
+
+/// What to try next about `e`, if it's tagged.
+pub fn hint(e: &(dyn Error + 'static)) -> Option<&'static str> {
+    tag(e).map(ExitCode::hint)
+}
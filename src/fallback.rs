@@ -0,0 +1,116 @@
+use crate::{
+    cache, error,
+    fees::InvArchExtrinsicParams,
+    invarch,
+    primitives::{BoxResult, RepoData, SUBMODULE_TIP_MARKER},
+    state::RemoteState,
+    types::IpsId,
+};
+use git2::Repository;
+use log::debug;
+use std::{collections::BTreeMap, sync::Mutex};
+use subxt::DefaultConfig;
+
+/// {MultiObject hash -> the fallback IPS it is read from}, filled by `borrow`.
+static SOURCES: Mutex<BTreeMap<String, IpsId>> = Mutex::new(BTreeMap::new());
+
+/// The IP Sets a remote reads objects from that its own IPS doesn't list, in the order they
+/// are consulted.
+///
+/// Read from every `remote.<name>.inv4Fallback`, or else every `inv4.fallback`. A fork listing
+/// its upstream this way only mints what the upstream lacks, as pushes see the upstream's
+/// objects as already on chain; whoever fetches from the fork needs the same setting.
+pub fn load(repo: &Repository, remote: &str) -> BoxResult<Vec<IpsId>> {
+    let config = repo.config()?;
+
+    for key in [
+        format!("remote.{}.inv4fallback", remote),
+        "inv4.fallback".to_owned(),
+    ] {
+        let mut fallbacks = Vec::new();
+        let entries = config.multivar(&key, None)?;
+        for entry in &entries {
+            let entry = entry?;
+            let value = entry.value().ok_or(format!("{} is not UTF-8", key))?;
+            match value.trim().parse::<u32>() {
+                Ok(ips_id) => fallbacks.push(IpsId(ips_id)),
+                Err(_) => error!(format!("{} = {:?} is not an IPS id", key, value)),
+            }
+        }
+        if !fallbacks.is_empty() {
+            return Ok(fallbacks);
+        }
+    }
+
+    Ok(Vec::new())
+}
+
+/// Adds the objects the RepoData of every IPS in `fallbacks` lists to `repo_data`, unless an
+/// earlier one or `repo_data` itself lists them, and notes where their MultiObjects are.
+pub async fn borrow(
+    repo_data: &mut RepoData,
+    fallbacks: &[IpsId],
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    repo: &Repository,
+    remote: &str,
+    network: &str,
+) -> BoxResult<()> {
+    for &fallback in fallbacks {
+        let state = RemoteState::new(repo, remote, network.to_owned(), fallback);
+        let upstream = cache::repo_data(&state, chain_api).await?;
+
+        if upstream.objects.is_empty() {
+            eprintln!("Warning: fallback IPS {} lists no objects", fallback);
+            continue;
+        }
+        if upstream.hash_algorithm != repo_data.hash_algorithm {
+            error!(format!(
+                "Fallback IPS {} uses {} object ids, this one {}",
+                fallback,
+                upstream.hash_algorithm.name(),
+                repo_data.hash_algorithm.name()
+            ))
+        }
+
+        let mut sources = SOURCES
+            .lock()
+            .map_err(|_| "The fallback IPS registry is poisoned")?;
+        let mut borrowed = 0;
+
+        for (git_hash, multi_object_hash) in &upstream.objects {
+            if multi_object_hash == SUBMODULE_TIP_MARKER {
+                continue;
+            }
+            // Pushes of a fork list what they borrowed, so this holds for its own RepoData too
+            sources.entry(multi_object_hash.clone()).or_insert(fallback);
+
+            if repo_data.objects.contains_key(git_hash) {
+                continue;
+            }
+            borrowed += 1;
+            repo_data
+                .objects
+                .insert(git_hash.clone(), multi_object_hash.clone());
+            if let Some(header) = upstream.object_headers.get(git_hash) {
+                repo_data.object_headers.insert(git_hash.clone(), *header);
+            }
+            if let Some(target) = upstream.tag_targets.get(git_hash) {
+                repo_data
+                    .tag_targets
+                    .insert(git_hash.clone(), target.clone());
+            }
+            if upstream.shallow.contains(git_hash) {
+                repo_data.shallow.insert(git_hash.clone());
+            }
+        }
+
+        debug!("Borrowed {} objects from IPS {}", borrowed, fallback);
+    }
+
+    Ok(())
+}
+
+/// The fallback IPS the MultiObject `hash` is read from, if it is listed by one.
+pub fn source(hash: &str) -> Option<IpsId> {
+    SOURCES.lock().ok()?.get(hash).copied()
+}
@@ -0,0 +1,104 @@
+use crate::{config::Config, error, invarch, primitives::BoxResult};
+use codec::{Compact, Encode, Output};
+use serde_json::Value;
+use std::sync::Mutex;
+use subxt::{
+    extrinsic::BaseExtrinsicParams,
+    rpc::{rpc_params, ClientT},
+    sp_core::Bytes,
+    DefaultConfig,
+};
+
+/// Extrinsic parameters whose tip is encoded for the fee payment extension of the runtime.
+pub type InvArchExtrinsicParams<T> = BaseExtrinsicParams<T, FeeTip>;
+
+/// The tip `FeeTip::default` gives, encoded for the chain connected to last.
+static DEFAULT_TIP: Mutex<FeeTip> = Mutex::new(FeeTip::Native { tip: 0 });
+
+/// The signed extension of runtimes that take fees in other assets than the native token.
+static ASSET_TX_PAYMENT: &str = "ChargeAssetTxPayment";
+
+/// The tip of an extrinsic and the asset its fees are paid in.
+///
+/// Runtimes with `ChargeTransactionPayment` only expect the tip, while `ChargeAssetTxPayment`
+/// is followed by the id of the asset paying the fees.
+#[derive(Clone, Copy, Debug)]
+pub enum FeeTip {
+    Native { tip: u128 },
+    Asset { tip: u128, asset: Option<u32> },
+}
+
+impl Default for FeeTip {
+    fn default() -> Self {
+        DEFAULT_TIP
+            .lock()
+            .map_or(Self::Native { tip: 0 }, |tip| *tip)
+    }
+}
+
+impl Encode for FeeTip {
+    fn encode_to<O: Output + ?Sized>(&self, dest: &mut O) {
+        match self {
+            Self::Native { tip } => Compact(*tip).encode_to(dest),
+            Self::Asset { tip, asset } => {
+                Compact(*tip).encode_to(dest);
+                asset.encode_to(dest);
+            }
+        }
+    }
+}
+
+/// Makes extrinsics signed from now on match the fee payment extension of the chain behind
+/// `chain_api`, paying in `config.fee_asset` if it takes other assets.
+///
+/// Fails if `fee_asset` is set but the chain only takes its native token, as extrinsics would
+/// be rejected as invalid otherwise.
+pub fn install(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    config: &Config,
+) -> BoxResult<()> {
+    let metadata = chain_api.client.metadata();
+    let asset_payment = metadata
+        .read()
+        .runtime_metadata()
+        .extrinsic
+        .signed_extensions
+        .iter()
+        .any(|extension| extension.identifier == ASSET_TX_PAYMENT);
+
+    let tip = match (asset_payment, config.fee_asset) {
+        (true, asset) => FeeTip::Asset { tip: 0, asset },
+        (false, None) => FeeTip::Native { tip: 0 },
+        (false, Some(asset)) => error!(format!(
+            "chain.fee_asset is {}, but {} only takes fees in its native token. Remove fee_asset from the [chain] section of the config.",
+            asset, config.chain_endpoint
+        )),
+    };
+
+    *DEFAULT_TIP
+        .lock()
+        .map_err(|_| "Fee settings are poisoned")? = tip;
+
+    Ok(())
+}
+
+/// The partial fee of a signed extrinsic as reported by `payment_queryInfo`.
+pub async fn estimate(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    extrinsic: Vec<u8>,
+) -> BoxResult<u128> {
+    let info: Value = chain_api
+        .client
+        .rpc()
+        .client
+        .request("payment_queryInfo", rpc_params![Bytes(extrinsic)])
+        .await?;
+
+    let fee = match &info["partialFee"] {
+        Value::String(fee) => fee.parse().ok(),
+        Value::Number(fee) => fee.as_u64().map(u128::from),
+        _ => None,
+    };
+
+    Ok(fee.ok_or(format!("Unexpected payment_queryInfo response: {}", info))?)
+}
@@ -0,0 +1,116 @@
+use crate::{
+    address,
+    config::Config,
+    connect, error,
+    exit::ExitCode,
+    ipfs::Ipfs,
+    primitives::{
+        BoxResult, GitObjectOps, MultiObject, MultiObjectOps, RepoData, RepoDataOps,
+        SUBMODULE_TIP_MARKER,
+    },
+    types::{GitHash, IpsId},
+    util::find_ipf,
+};
+use std::collections::BTreeMap;
+use subxt::sp_core::crypto::AccountId32;
+
+static USAGE: &str = "Usage: git-remote-inv4 fsck --ips <id> [--require-signed]";
+
+/// `fsck`: checks every object the RepoData of an IPS lists against the header RepoData has for
+/// it and the signature of its uploader, then prints how many objects each member signed.
+///
+/// With `--require-signed`, objects nobody signed fail the check as well.
+pub async fn run(config: &Config, args: &[String]) -> BoxResult<()> {
+    let ips_id: IpsId = config
+        .ips_arg(args)?
+        .ok_or(format!("Missing IPS id. {}", USAGE))?;
+    let require_signed = args.iter().any(|arg| arg == "--require-signed");
+
+    let chain_api = connect(config).await?;
+    let mut ipfs = Ipfs::default();
+
+    let (_, repo_data_cid) = find_ipf(&chain_api, ips_id, "RepoData")
+        .await?
+        .ok_or(format!("IPS {} holds no RepoData", ips_id))?;
+    let repo_data = RepoData::from_ipfs(repo_data_cid, &mut ipfs).await?;
+
+    // {MultiObject hash -> git hashes listed in it}
+    let mut listed: BTreeMap<&String, Vec<&GitHash>> = BTreeMap::new();
+    for (git_hash, multi_object_hash) in &repo_data.objects {
+        if multi_object_hash != SUBMODULE_TIP_MARKER {
+            listed.entry(multi_object_hash).or_default().push(git_hash);
+        }
+    }
+
+    let mut signers: BTreeMap<String, usize> = BTreeMap::new();
+    let mut unsigned = 0;
+    let mut problems = Vec::new();
+
+    for (multi_object_hash, git_hashes) in listed {
+        let multi_object =
+            match MultiObject::chain_get(multi_object_hash.clone(), &mut ipfs, &chain_api, ips_id)
+                .await
+            {
+                Ok(multi_object) => multi_object,
+                Err(e) => {
+                    problems.push(format!("MultiObject {}: {}", multi_object_hash, e));
+                    continue;
+                }
+            };
+
+        for git_hash in git_hashes {
+            let object = match multi_object.objects.get(git_hash) {
+                Some(object) => object,
+                None => {
+                    problems.push(format!(
+                        "Object {} is missing from its MultiObject {}",
+                        git_hash, multi_object_hash
+                    ));
+                    continue;
+                }
+            };
+
+            if let Some(header) = repo_data.object_headers.get(git_hash) {
+                if let Err(e) = object.check_header(header) {
+                    problems.push(e.to_string());
+                }
+            }
+
+            match &object.signature {
+                Some(signature) => match object.verify_signature() {
+                    Ok(()) => {
+                        *signers
+                            .entry(address::address(&AccountId32::from(signature.signer)))
+                            .or_default() += 1
+                    }
+                    Err(e) => problems.push(e.to_string()),
+                },
+                None if require_signed => {
+                    unsigned += 1;
+                    problems.push(format!("Object {} is not signed", git_hash));
+                }
+                None => unsigned += 1,
+            }
+        }
+    }
+
+    for (signer, count) in &signers {
+        println!("{}\t{} objects", signer, count);
+    }
+    println!("(unsigned)\t{} objects", unsigned);
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        error!(ExitCode::Verification.wrap(format!(
+            "{} problems found in IPS {}",
+            problems.len(),
+            ips_id
+        )))
+    }
+
+    eprintln!("All objects of IPS {} check out", ips_id);
+
+    Ok(())
+}
@@ -0,0 +1,193 @@
+use crate::{
+    error,
+    fees::InvArchExtrinsicParams,
+    invarch,
+    ipfs::Ipfs,
+    primitives::{BoxResult, RepoData},
+    timeout::{within, Operation},
+    types::{IpfId, IpsId},
+    util::{decode_ipf_metadata, find_ipf, ipf_infos, ipf_metadata_name, ipfs_cat, is_git_ipf},
+};
+use cid::Cid;
+use log::debug;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+use subxt::DefaultConfig;
+
+/// The name of the object index IPF, which replaces the previous one on every push like
+/// RepoData.
+pub static INDEX_NAME: &str = "ObjectIndex";
+
+/// First word of an encoded index, followed by the format version.
+const MAGIC: &str = "inv4-index";
+
+const VERSION: u32 = 1;
+
+/// Which IPF holds each MultiObject of a RepoData, so fetches find a MultiObject with one
+/// storage query instead of going through every IPF of the IPS.
+///
+/// Encoded as text, one MultiObject per line sorted by hash. Only a hint: every lookup is
+/// checked against the IPF it points at, and anything missing is looked up the slow way.
+#[derive(Clone, Debug, Default)]
+pub struct ObjectIndex {
+    /// {MultiObject hash -> IPF id}
+    pub ipfs: BTreeMap<String, IpfId>,
+}
+
+/// Indexes read this process, `None` for IPSes without one.
+static LOADED: Mutex<BTreeMap<IpsId, Option<Arc<ObjectIndex>>>> = Mutex::new(BTreeMap::new());
+
+impl ObjectIndex {
+    /// The index of the IPS with the MultiObjects minted as `added`, limited to the ones
+    /// `repo_data` lists objects in.
+    ///
+    /// MultiObjects pushed before the IPS had an index stay out of it, lookups of their
+    /// objects go through the IPS.
+    pub async fn updated(
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ipfs: &mut Ipfs,
+        previous: Option<&Cid>,
+        repo_data: &RepoData,
+        added: &[IpfId],
+    ) -> BoxResult<Self> {
+        let mut index = match previous {
+            Some(cid) => match Self::decode(&ipfs_cat(ipfs, cid).await?) {
+                Ok(index) => index,
+                Err(e) => {
+                    eprintln!("Warning: rebuilding an unreadable object index: {}", e);
+                    Self::default()
+                }
+            },
+            None => Self::default(),
+        };
+
+        let ids: Vec<u64> = added.iter().map(|id| id.0).collect();
+        let ipf_infos = within(
+            Operation::ChainQuery,
+            "reading the minted MultiObject IPFs from chain",
+            ipf_infos(chain_api, &ids),
+        )
+        .await?;
+        for (ipf_id, ipf_info) in added.iter().zip(ipf_infos) {
+            if let Some(ipf_info) = ipf_info {
+                index
+                    .ipfs
+                    .insert(ipf_metadata_name(&ipf_info.metadata.0)?, *ipf_id);
+            }
+        }
+
+        let listed: BTreeSet<&String> = repo_data.objects.values().collect();
+        index.ipfs.retain(|hash, _| listed.contains(hash));
+
+        Ok(index)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut text = format!("{} {}\n", MAGIC, VERSION);
+
+        for (hash, ipf_id) in &self.ipfs {
+            text.push_str(&format!("{}\t{}\n", hash, ipf_id));
+        }
+
+        text.into_bytes()
+    }
+
+    pub fn decode(encoded: &[u8]) -> BoxResult<Self> {
+        let text = std::str::from_utf8(encoded)?;
+        let mut lines = text.lines();
+
+        let header: Vec<&str> = lines
+            .next()
+            .ok_or("The object index is empty")?
+            .split(' ')
+            .collect();
+        match header.as_slice() {
+            [MAGIC, version] if version.parse::<u32>().ok() == Some(VERSION) => {}
+            [MAGIC, version, ..] => error!(format!(
+                "Unsupported object index version {}, try updating git-remote-inv4",
+                version
+            )),
+            _ => error!("Not an object index"),
+        }
+
+        let mut index = Self::default();
+        for line in lines {
+            match line.split_once('\t') {
+                Some((hash, ipf_id)) => {
+                    index.ipfs.insert(hash.to_owned(), IpfId(ipf_id.parse()?));
+                }
+                None => error!(format!("Malformed object index line: {}", line)),
+            }
+        }
+
+        Ok(index)
+    }
+}
+
+/// The IPF of the MultiObject `hash` in `ips_id` and its CID, if the IPS's index knows it.
+///
+/// The index is read once per process. `None` sends the caller through the IPS.
+pub async fn lookup(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ipfs: &Ipfs,
+    ips_id: IpsId,
+    hash: &str,
+) -> BoxResult<Option<(IpfId, Cid)>> {
+    let loaded = LOADED
+        .lock()
+        .map_err(|_| "The object index cache is poisoned")?
+        .get(&ips_id)
+        .cloned();
+
+    let index = match loaded {
+        Some(index) => index,
+        None => {
+            let index = match find_ipf(chain_api, ips_id, INDEX_NAME).await? {
+                Some((_, cid)) => match ObjectIndex::decode(&ipfs_cat(ipfs, &cid).await?) {
+                    Ok(index) => Some(Arc::new(index)),
+                    Err(e) => {
+                        debug!("Ignoring the object index of IPS {}: {}", ips_id, e);
+                        None
+                    }
+                },
+                None => None,
+            };
+            LOADED
+                .lock()
+                .map_err(|_| "The object index cache is poisoned")?
+                .insert(ips_id, index.clone());
+            index
+        }
+    };
+
+    let ipf_id = match index.and_then(|index| index.ipfs.get(hash).copied()) {
+        Some(ipf_id) => ipf_id,
+        None => return Ok(None),
+    };
+
+    let ipf_info = within(
+        Operation::ChainQuery,
+        &format!("reading IPF {} from chain", ipf_id),
+        chain_api.storage().ipf().ipf_storage(&ipf_id.0, None),
+    )
+    .await?;
+
+    // Burned, or reused for something else since the index was minted
+    let ipf_info = match ipf_info {
+        Some(ipf_info)
+            if is_git_ipf(&ipf_info.metadata.0)
+                && ipf_metadata_name(&ipf_info.metadata.0)? == hash =>
+        {
+            ipf_info
+        }
+        _ => {
+            debug!("The object index of IPS {} is stale for {}", ips_id, hash);
+            return Ok(None);
+        }
+    };
+
+    let (_, cid) = decode_ipf_metadata(&ipf_info.metadata.0, ipf_info.data.0)?;
+    Ok(Some((ipf_id, cid)))
+}
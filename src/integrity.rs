@@ -0,0 +1,195 @@
+use crate::{
+    error,
+    exit::ExitCode,
+    primitives::{parse_tree, BoxResult, GitObject, GitObjectMetadata, MultiObject},
+    types::{GitHash, HashAlgorithm},
+};
+use git2::Oid;
+use std::collections::{BTreeMap, BTreeSet};
+use subxt::sp_core::hashing::sha2_256;
+
+/// Checks the objects a push is about to upload, so a corrupt local object database fails the
+/// push naming the object at fault instead of minting objects no fetch can write.
+///
+/// The data of every object is hashed again, and trees, commits and tags are parsed and have to
+/// point at what their metadata says. Problems with objects reachable from a tree of the push
+/// name the path of the object as well.
+pub fn check(multi_object: &MultiObject) -> BoxResult<()> {
+    // {git hash -> (tree, entry name)} of every tree entry, to name the paths of broken objects
+    let mut parents: BTreeMap<&GitHash, (&GitHash, String)> = BTreeMap::new();
+    let mut problems = Vec::new();
+
+    for object in multi_object.objects.values() {
+        if let Err(e) = check_object(object) {
+            problems.push((&object.git_hash, e.to_string()));
+        }
+    }
+
+    let trees: Vec<(&GitHash, _)> = multi_object
+        .objects
+        .values()
+        .filter(|object| matches!(object.metadata, GitObjectMetadata::Tree { .. }))
+        .filter_map(|object| {
+            parse_tree(&object.data, object.git_hash.algorithm())
+                .ok()
+                .map(|entries| (&object.git_hash, entries))
+        })
+        .collect();
+    for (tree, entries) in &trees {
+        for entry in entries {
+            if let Some((git_hash, _)) = multi_object.objects.get_key_value(&entry.git_hash) {
+                parents.insert(git_hash, (*tree, entry.name.to_string_lossy().into_owned()));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    for (git_hash, problem) in &problems {
+        match path_of(git_hash, &parents) {
+            Some(path) => eprintln!("Object {} at {}: {}", git_hash, path, problem),
+            None => eprintln!("Object {}: {}", git_hash, problem),
+        }
+    }
+
+    error!(ExitCode::Verification.wrap(format!(
+        "{} objects about to be pushed are corrupt in the local repository, run `git fsck` to find out more",
+        problems.len()
+    )))
+}
+
+/// Fails if the data of `object` doesn't hash to its git hash or doesn't parse as its type.
+fn check_object(object: &GitObject) -> BoxResult<()> {
+    let kind = object.header().kind.object_type();
+    let algorithm = object.git_hash.algorithm();
+
+    // Chunked blobs only exist on the way to IPFS, so they are never enumerated, and streamed
+    // ones have no data to hash until they are read from the object database
+    if !matches!(object.metadata, GitObjectMetadata::ChunkedBlob { .. })
+        && object.streamed.is_none()
+    {
+        let actual = match algorithm {
+            HashAlgorithm::Sha1 => GitHash::from(Oid::hash_object(kind, &object.data)?),
+            HashAlgorithm::Sha256 => {
+                let mut raw = format!("{} {}\0", kind.str(), object.data.len()).into_bytes();
+                raw.extend_from_slice(&object.data);
+                GitHash::from_raw(&sha2_256(&raw))
+            }
+        };
+        if actual != object.git_hash {
+            error!(format!("its data hashes to {}", actual))
+        }
+    }
+
+    match &object.metadata {
+        GitObjectMetadata::Tree { entry_git_hashes } => {
+            let entries: BTreeSet<GitHash> = parse_tree(&object.data, algorithm)?
+                .into_iter()
+                .map(|entry| entry.git_hash)
+                .collect();
+            if &entries != entry_git_hashes {
+                error!("its entries differ from the ones read through libgit2")
+            }
+        }
+        GitObjectMetadata::Commit {
+            parent_git_hashes,
+            tree_git_hash,
+        } => {
+            let headers = headers(&object.data)?;
+            let tree = headers
+                .get("tree")
+                .and_then(|trees| trees.first())
+                .ok_or("the commit names no tree")?;
+            if tree.parse::<GitHash>()? != *tree_git_hash {
+                error!(format!("the commit names the tree {}", tree))
+            }
+            // Shallow and grafted clones see fewer parents than the commit names
+            let parents = headers
+                .get("parent")
+                .map(|parents| {
+                    parents
+                        .iter()
+                        .map(|parent| parent.parse::<GitHash>())
+                        .collect::<Result<BTreeSet<_>, _>>()
+                })
+                .transpose()?
+                .unwrap_or_default();
+            if !parent_git_hashes.is_subset(&parents) {
+                error!("its parents differ from the ones read through libgit2")
+            }
+            for header in ["author", "committer"] {
+                if !headers.contains_key(header) {
+                    error!(format!("the commit has no {}", header))
+                }
+            }
+        }
+        GitObjectMetadata::Tag { target_git_hash } => {
+            let headers = headers(&object.data)?;
+            let target = headers
+                .get("object")
+                .and_then(|targets| targets.first())
+                .ok_or("the tag names no object")?;
+            if target.parse::<GitHash>()? != *target_git_hash {
+                error!(format!("the tag names the object {}", target))
+            }
+        }
+        GitObjectMetadata::Blob | GitObjectMetadata::ChunkedBlob { .. } => {}
+    }
+
+    Ok(())
+}
+
+/// The headers of a commit or tag, up to the empty line before its message; a
+/// {name -> values} map. Continuation lines, as of `gpgsig`, are skipped, and values that
+/// aren't UTF-8, like names in another `encoding`, are empty.
+fn headers(data: &[u8]) -> BoxResult<BTreeMap<&str, Vec<&str>>> {
+    let mut headers: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+
+    for line in data.split(|&b| b == b'\n') {
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with(b" ") {
+            continue;
+        }
+
+        match line.iter().position(|&b| b == b' ') {
+            Some(space) => {
+                let name = std::str::from_utf8(&line[..space])
+                    .map_err(|_| "a header name is not UTF-8")?;
+                let value = std::str::from_utf8(&line[space + 1..]).unwrap_or("");
+                headers.entry(name).or_default().push(value);
+            }
+            None => error!(format!(
+                "malformed header {:?}",
+                String::from_utf8_lossy(line)
+            )),
+        }
+    }
+
+    Ok(headers)
+}
+
+/// The path of `git_hash` below the outermost tree of the push that contains it.
+fn path_of(git_hash: &GitHash, parents: &BTreeMap<&GitHash, (&GitHash, String)>) -> Option<String> {
+    let mut names = Vec::new();
+    let mut current = git_hash;
+
+    // Bounded, so a tree that contains itself in a corrupt repository can't hang the push
+    while let Some((tree, name)) = parents.get(current) {
+        if names.len() > parents.len() {
+            break;
+        }
+        names.push(name.as_str());
+        current = tree;
+    }
+
+    if names.is_empty() {
+        return None;
+    }
+
+    names.reverse();
+    Some(names.join("/"))
+}
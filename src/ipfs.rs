@@ -0,0 +1,349 @@
+use crate::primitives::BoxResult;
+use futures::future::LocalBoxFuture;
+use std::io::Write;
+
+#[cfg(not(any(feature = "ipfs-api", feature = "reqwest-ipfs")))]
+compile_error!("Enable either the `ipfs-api` or the `reqwest-ipfs` feature to talk to IPFS");
+
+/// Where the local IPFS node serves its API, as `Ipfs::default()` assumes.
+pub static DEFAULT_API: &str = "http://127.0.0.1:5001/api/v0";
+
+/// The IPFS client in use, `ipfs-api`'s by default.
+#[cfg(not(feature = "reqwest-ipfs"))]
+pub type Ipfs = ipfs_api::IpfsClient;
+
+/// The IPFS client in use, the plain reqwest one built with `reqwest-ipfs`.
+#[cfg(feature = "reqwest-ipfs")]
+pub type Ipfs = ReqwestIpfs;
+
+pub type IpfsFuture<'a, T> = LocalBoxFuture<'a, BoxResult<T>>;
+
+/// How `IpfsOps::add` stores content; `None` leaves the choice to the node.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AddOptions {
+    pub cid_version: Option<u32>,
+    pub hash: Option<&'static str>,
+    pub pin: bool,
+}
+
+/// Everything git-remote-inv4 asks of an IPFS node.
+///
+/// This keeps the rest of the crate independent of a particular client library, whose HTTP
+/// and TLS stack downstream users then have to build with ours.
+pub trait IpfsOps {
+    /// A client for the node whose API is at `url`, as in `http://127.0.0.1:5001`.
+    fn from_url(url: &str) -> BoxResult<Self>
+    where
+        Self: Sized;
+
+    /// Adds `data` as a file, returning its CID.
+    fn add(&self, data: Vec<u8>, options: AddOptions) -> IpfsFuture<'_, String>;
+
+    /// Streams the file at `cid` into `out`.
+    fn cat_to<'a>(&'a self, cid: &'a str, out: &'a mut dyn Write) -> IpfsFuture<'a, ()>;
+
+    /// The file at `cid`.
+    fn cat<'a>(&'a self, cid: &'a str) -> IpfsFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            let mut data = Vec::new();
+            self.cat_to(cid, &mut data).await?;
+            Ok(data)
+        })
+    }
+
+    /// Pins `cid` and everything below it.
+    fn pin<'a>(&'a self, cid: &'a str) -> IpfsFuture<'a, ()>;
+
+    /// Removes the recursive pin of `cid`.
+    fn unpin<'a>(&'a self, cid: &'a str) -> IpfsFuture<'a, ()>;
+
+    /// The size of `cid` with everything below it.
+    fn stat<'a>(&'a self, cid: &'a str) -> IpfsFuture<'a, u64>;
+
+    /// The raw block `cid`.
+    fn block_get<'a>(&'a self, cid: &'a str) -> IpfsFuture<'a, Vec<u8>>;
+
+    /// The version of the node.
+    fn version(&self) -> IpfsFuture<'_, String>;
+
+    /// Copies `cid` to `<dir>/<name>` in the node's MFS, replacing what was there.
+    fn mfs_copy<'a>(&'a self, cid: &'a str, dir: &'a str, name: &'a str) -> IpfsFuture<'a, ()>;
+}
+
+#[cfg(feature = "ipfs-api")]
+impl IpfsOps for ipfs_api::IpfsClient {
+    fn from_url(url: &str) -> BoxResult<Self> {
+        Ok(<Self as ipfs_api::TryFromUri>::from_str(url)?)
+    }
+
+    fn add(&self, data: Vec<u8>, options: AddOptions) -> IpfsFuture<'_, String> {
+        Box::pin(async move {
+            let add = ipfs_api::request::Add {
+                cid_version: options.cid_version,
+                hash: options.hash,
+                pin: Some(options.pin),
+                ..Default::default()
+            };
+
+            Ok(
+                ipfs_api::IpfsApi::add_with_options(self, std::io::Cursor::new(data), add)
+                    .await?
+                    .hash,
+            )
+        })
+    }
+
+    fn cat_to<'a>(&'a self, cid: &'a str, out: &'a mut dyn Write) -> IpfsFuture<'a, ()> {
+        use futures::TryStreamExt;
+
+        Box::pin(async move {
+            let mut chunks = ipfs_api::IpfsApi::cat(self, cid);
+            while let Some(chunk) = chunks.try_next().await? {
+                out.write_all(&chunk)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn pin<'a>(&'a self, cid: &'a str) -> IpfsFuture<'a, ()> {
+        Box::pin(async move {
+            ipfs_api::IpfsApi::pin_add(self, cid, true).await?;
+            Ok(())
+        })
+    }
+
+    fn unpin<'a>(&'a self, cid: &'a str) -> IpfsFuture<'a, ()> {
+        Box::pin(async move {
+            ipfs_api::IpfsApi::pin_rm(self, cid, true).await?;
+            Ok(())
+        })
+    }
+
+    fn stat<'a>(&'a self, cid: &'a str) -> IpfsFuture<'a, u64> {
+        Box::pin(async move {
+            Ok(
+                ipfs_api::IpfsApi::files_stat(self, &format!("/ipfs/{}", cid))
+                    .await?
+                    .cumulative_size,
+            )
+        })
+    }
+
+    fn block_get<'a>(&'a self, cid: &'a str) -> IpfsFuture<'a, Vec<u8>> {
+        use futures::TryStreamExt;
+
+        Box::pin(async move {
+            Ok(ipfs_api::IpfsApi::block_get(self, cid)
+                .map_ok(|chunk| chunk.to_vec())
+                .try_concat()
+                .await?)
+        })
+    }
+
+    fn version(&self) -> IpfsFuture<'_, String> {
+        Box::pin(async move { Ok(ipfs_api::IpfsApi::version(self).await?.version) })
+    }
+
+    fn mfs_copy<'a>(&'a self, cid: &'a str, dir: &'a str, name: &'a str) -> IpfsFuture<'a, ()> {
+        use ipfs_api::IpfsApi;
+
+        Box::pin(async move {
+            let path = format!("{}/{}", dir, name);
+
+            self.files_mkdir(dir, true).await?;
+            // `files cp` won't overwrite
+            if self.files_stat(&path).await.is_ok() {
+                self.files_rm(&path, false).await?;
+            }
+            self.files_cp(&format!("/ipfs/{}", cid), &path).await?;
+
+            Ok(())
+        })
+    }
+}
+
+/// A minimal client for the IPFS HTTP API on top of reqwest, which the crate needs anyway.
+#[cfg(feature = "reqwest-ipfs")]
+pub struct ReqwestIpfs {
+    /// Base URL of the API, ending in `/api/v0`
+    api: String,
+    http: reqwest::Client,
+}
+
+#[cfg(feature = "reqwest-ipfs")]
+impl Default for ReqwestIpfs {
+    fn default() -> Self {
+        Self::at(DEFAULT_API.to_owned())
+    }
+}
+
+#[cfg(feature = "reqwest-ipfs")]
+impl ReqwestIpfs {
+    fn at(api: String) -> Self {
+        // The node is local, or reached through `network.proxy` routing by the caller
+        let http = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .unwrap_or_default();
+
+        Self { api, http }
+    }
+
+    /// Calls `command` with `args`, failing with the node's message unless it succeeded.
+    async fn call(
+        &self,
+        command: &str,
+        args: &[(&str, &str)],
+        form: Option<reqwest::multipart::Form>,
+    ) -> BoxResult<reqwest::Response> {
+        use crate::exit::ExitCode;
+
+        let mut request = self
+            .http
+            .post(format!("{}/{}", self.api, command))
+            .query(args);
+        if let Some(form) = form {
+            request = request.multipart(form);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| ExitCode::Ipfs.wrap(format!("IPFS {} failed: {}", command, e)))?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            let message = serde_json::from_str::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|error| error["Message"].as_str().map(str::to_owned))
+                .unwrap_or(body);
+
+            return Err(ExitCode::Ipfs.wrap(format!(
+                "IPFS {} failed with {}: {}",
+                command, status, message
+            )));
+        }
+
+        Ok(response)
+    }
+
+    async fn call_json(
+        &self,
+        command: &str,
+        args: &[(&str, &str)],
+    ) -> BoxResult<serde_json::Value> {
+        Ok(self.call(command, args, None).await?.json().await?)
+    }
+}
+
+#[cfg(feature = "reqwest-ipfs")]
+impl IpfsOps for ReqwestIpfs {
+    fn from_url(url: &str) -> BoxResult<Self> {
+        Ok(Self::at(format!("{}/api/v0", url.trim_end_matches('/'))))
+    }
+
+    fn add(&self, data: Vec<u8>, options: AddOptions) -> IpfsFuture<'_, String> {
+        Box::pin(async move {
+            let cid_version = options.cid_version.map(|version| version.to_string());
+            let mut args = vec![("pin", if options.pin { "true" } else { "false" })];
+            if let Some(cid_version) = &cid_version {
+                args.push(("cid-version", cid_version));
+            }
+            if let Some(hash) = options.hash {
+                args.push(("hash", hash));
+            }
+
+            let form =
+                reqwest::multipart::Form::new().part("file", reqwest::multipart::Part::bytes(data));
+            let added: serde_json::Value =
+                self.call("add", &args, Some(form)).await?.json().await?;
+
+            Ok(added["Hash"]
+                .as_str()
+                .ok_or("IPFS add returned no hash")?
+                .to_owned())
+        })
+    }
+
+    fn cat_to<'a>(&'a self, cid: &'a str, out: &'a mut dyn Write) -> IpfsFuture<'a, ()> {
+        Box::pin(async move {
+            let mut response = self.call("cat", &[("arg", cid)], None).await?;
+            while let Some(chunk) = response.chunk().await? {
+                out.write_all(&chunk)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn pin<'a>(&'a self, cid: &'a str) -> IpfsFuture<'a, ()> {
+        Box::pin(async move {
+            self.call("pin/add", &[("arg", cid), ("recursive", "true")], None)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn unpin<'a>(&'a self, cid: &'a str) -> IpfsFuture<'a, ()> {
+        Box::pin(async move {
+            self.call("pin/rm", &[("arg", cid), ("recursive", "true")], None)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn stat<'a>(&'a self, cid: &'a str) -> IpfsFuture<'a, u64> {
+        Box::pin(async move {
+            let path = format!("/ipfs/{}", cid);
+            let stat = self.call_json("files/stat", &[("arg", &path)]).await?;
+
+            Ok(stat["CumulativeSize"]
+                .as_u64()
+                .ok_or("IPFS files/stat returned no size")?)
+        })
+    }
+
+    fn block_get<'a>(&'a self, cid: &'a str) -> IpfsFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            Ok(self
+                .call("block/get", &[("arg", cid)], None)
+                .await?
+                .bytes()
+                .await?
+                .to_vec())
+        })
+    }
+
+    fn version(&self) -> IpfsFuture<'_, String> {
+        Box::pin(async move {
+            let version = self.call_json("version", &[]).await?;
+
+            Ok(version["Version"]
+                .as_str()
+                .ok_or("IPFS version returned no version")?
+                .to_owned())
+        })
+    }
+
+    fn mfs_copy<'a>(&'a self, cid: &'a str, dir: &'a str, name: &'a str) -> IpfsFuture<'a, ()> {
+        Box::pin(async move {
+            let path = format!("{}/{}", dir, name);
+            let source = format!("/ipfs/{}", cid);
+
+            self.call("files/mkdir", &[("arg", dir), ("parents", "true")], None)
+                .await?;
+            // `files cp` won't overwrite
+            if self
+                .call("files/stat", &[("arg", &path)], None)
+                .await
+                .is_ok()
+            {
+                self.call("files/rm", &[("arg", &path)], None).await?;
+            }
+            self.call("files/cp", &[("arg", &source), ("arg", &path)], None)
+                .await?;
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,216 @@
+use crate::{
+    config::Config,
+    error,
+    invarch::{self, balances::events::Deposit, balances::events::Withdraw},
+    primitives::BoxResult,
+    state::RemoteState,
+    token,
+    types::IpsId,
+};
+use git2::Repository;
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::Write,
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use subxt::{sp_core::crypto::AccountId32, DefaultConfig, TransactionEvents};
+
+/// Fees of extrinsics submitted since the last `commit`, as (extrinsic, fee) pairs.
+static PENDING: Mutex<Vec<(&'static str, u128)>> = Mutex::new(Vec::new());
+
+pub fn ledger_path(state: &RemoteState) -> PathBuf {
+    state.dir().join("ledger.tsv")
+}
+
+/// Notes the fee `payer` paid for the extrinsic that emitted `events`.
+///
+/// The transaction payment pallet doesn't emit its own events on this runtime, so the fee
+/// is what was withdrawn from the payer minus what was refunded for unused weight.
+pub fn record(
+    extrinsic: &'static str,
+    events: &TransactionEvents<DefaultConfig, invarch::Event>,
+    payer: &AccountId32,
+) -> BoxResult<()> {
+    let mut fee: u128 = 0;
+
+    for withdraw in events.find::<Withdraw>() {
+        let withdraw = withdraw?;
+        if &withdraw.who == payer {
+            fee = fee.saturating_add(withdraw.amount);
+        }
+    }
+
+    for deposit in events.find::<Deposit>() {
+        let deposit = deposit?;
+        if &deposit.who == payer {
+            fee = fee.saturating_sub(deposit.amount);
+        }
+    }
+
+    PENDING
+        .lock()
+        .map_err(|_| "Fee ledger is poisoned")?
+        .push((extrinsic, fee));
+
+    Ok(())
+}
+
+/// Attributes all fees recorded since the last call to `ref_dst`.
+///
+/// The fees are appended to the remote's ledger if `fee_ledger` is enabled.
+pub fn commit(state: &RemoteState, ref_dst: &str, config: &Config) -> BoxResult<()> {
+    let fees = std::mem::take(&mut *PENDING.lock().map_err(|_| "Fee ledger is poisoned")?);
+    let total: u128 = fees.iter().map(|(_, fee)| fee).sum();
+
+    if !config.fee_ledger || fees.is_empty() {
+        return Ok(());
+    }
+
+    let path = ledger_path(state);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+
+    for (extrinsic, fee) in fees {
+        writeln!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            timestamp, state.ips_id, ref_dst, extrinsic, fee
+        )?;
+    }
+
+    eprintln!("Fees paid for {}: {}", ref_dst, token::format(total));
+
+    Ok(())
+}
+
+/// The length of the periods `costs` sums fees over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    Month,
+    Year,
+}
+
+impl Period {
+    /// The label of the period `timestamp` falls into, e.g. `2022-07` for months.
+    fn label(&self, timestamp: u64) -> String {
+        let (year, month, day) = civil_from_days((timestamp / 86400) as i64);
+
+        match self {
+            Period::Day => format!("{:04}-{:02}-{:02}", year, month, day),
+            Period::Month => format!("{:04}-{:02}", year, month),
+            Period::Year => format!("{:04}", year),
+        }
+    }
+}
+
+impl FromStr for Period {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Period::Day),
+            "month" => Ok(Period::Month),
+            "year" => Ok(Period::Year),
+            other => error!(format!(
+                "Unknown period {:?}, expected day, month or year",
+                other
+            )),
+        }
+    }
+}
+
+/// Prints the fees in the ledgers of all remotes summed up per period, remote and IPS.
+///
+/// The ledgers keep the smallest unit; sums are shown in the token of the network
+/// `chain.endpoint` names, as the ledgers are read without connecting to a node.
+pub fn print_costs(repo: &Repository, period: Period, config: &Config) -> BoxResult<()> {
+    let ledgers: Vec<(RemoteState, PathBuf)> = RemoteState::all(repo)?
+        .into_iter()
+        .map(|state| {
+            let path = ledger_path(&state);
+            (state, path)
+        })
+        .filter(|(_, path)| path.exists())
+        .collect();
+
+    if ledgers.is_empty() {
+        eprintln!(
+            "No fees recorded yet. Set `fee_ledger = true` in the [chain] section of the config to start recording."
+        );
+        return Ok(());
+    }
+
+    let mut totals: BTreeMap<(String, String, IpsId), (usize, u128)> = BTreeMap::new();
+
+    for (state, path) in &ledgers {
+        for (i, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+            let corrupt = || format!("{}:{} is corrupt", path.display(), i + 1);
+
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 5 {
+                error!(corrupt())
+            }
+
+            let timestamp: u64 = fields[0].parse().map_err(|_| corrupt())?;
+            let ips_id: IpsId = fields[1].parse().map_err(|_| corrupt())?;
+            let fee: u128 = fields[4].parse().map_err(|_| corrupt())?;
+
+            let entry = totals
+                .entry((period.label(timestamp), state.remote.clone(), ips_id))
+                .or_default();
+            entry.0 += 1;
+            entry.1 = entry.1.saturating_add(fee);
+        }
+    }
+
+    let token = token::offline(config);
+    let show = |amount: u128| match &token {
+        Some(token) => token.format(amount),
+        None => amount.to_string(),
+    };
+
+    for ((label, remote, ips_id), (count, total)) in &totals {
+        println!(
+            "{}\t{}\tIPS {}\t{} extrinsics\t{}",
+            label,
+            remote,
+            ips_id,
+            count,
+            show(*total)
+        );
+    }
+
+    println!(
+        "Total\t\t\t{} extrinsics\t{}",
+        totals.values().map(|(count, _)| count).sum::<usize>(),
+        show(
+            totals
+                .values()
+                .fold(0u128, |acc, (_, total)| acc.saturating_add(*total))
+        )
+    );
+
+    Ok(())
+}
+
+/// Converts days since the unix epoch to a (year, month, day) date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
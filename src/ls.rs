@@ -0,0 +1,99 @@
+use crate::{
+    address::address,
+    config::Config,
+    connect,
+    primitives::BoxResult,
+    set_repo,
+    timeout::{within, Operation},
+    types::{GitHash, IpsId},
+    util::{
+        decode_ipf_metadata, flag, ipf_infos, ipf_metadata_license, ips_ipf_ids, is_git_ipf,
+        IPF_BATCH_SIZE,
+    },
+};
+use subxt::sp_core::crypto::AccountId32;
+
+static USAGE: &str = "Usage: git-remote-inv4 ls --ips <id>";
+
+static REFLOG_USAGE: &str = "Usage: git-remote-inv4 reflog --ips <id> [--ref <name>]";
+
+/// `ls`: prints every IPF of an IPS with its name, CID and license.
+pub async fn run(config: &Config, args: &[String]) -> BoxResult<()> {
+    let ips_id: IpsId = config
+        .ips_arg(args)?
+        .ok_or(format!("Missing IPS id. {}", USAGE))?;
+
+    let chain_api = connect(config).await?;
+    let ipf_ids = ips_ipf_ids(&chain_api, ips_id).await?;
+
+    for batch in ipf_ids.chunks(IPF_BATCH_SIZE) {
+        let ipf_infos = within(
+            Operation::ChainQuery,
+            &format!("reading IPFs of IPS {} from chain", ips_id),
+            ipf_infos(&chain_api, batch),
+        )
+        .await?;
+
+        for (id, ipf_info) in batch.iter().zip(ipf_infos) {
+            let ipf_info = ipf_info.ok_or("Internal error: IPF listed from IPS does not exist")?;
+            let metadata = &ipf_info.metadata.0;
+
+            // Receipts are JSON rather than a name and CID
+            if metadata.first() == Some(&b'{') {
+                println!("{}\t(push receipt)\t-\t-", id);
+                continue;
+            }
+
+            if !is_git_ipf(metadata) {
+                println!("{}\t(not git)\t-\t-", id);
+                continue;
+            }
+
+            let (name, cid) = decode_ipf_metadata(metadata, ipf_info.data.0)?;
+            let license = ipf_metadata_license(metadata)
+                .ok()
+                .flatten()
+                .unwrap_or_else(|| "-".to_owned());
+
+            println!("{}\t{}\t{}\t{}", id, name, cid, license);
+        }
+    }
+
+    Ok(())
+}
+
+/// `reflog`: prints the updates the RepoData of an IPS recorded for each ref, or for `--ref`,
+/// with the block they were pushed at and who pushed them.
+pub async fn reflog(config: &Config, args: &[String]) -> BoxResult<()> {
+    let ips_id: IpsId = config
+        .ips_arg(args)?
+        .ok_or(format!("Missing IPS id. {}", REFLOG_USAGE))?;
+    let only = flag(args, "--ref");
+
+    let chain_api = connect(config).await?;
+    let repo_data = set_repo(ips_id, chain_api).await?;
+
+    let short = |git_hash: &Option<GitHash>| match git_hash {
+        Some(git_hash) => git_hash.to_string()[..7].to_owned(),
+        None => "-".to_owned(),
+    };
+
+    for (name, entries) in &repo_data.reflogs {
+        if only.is_some_and(|only| only != name) {
+            continue;
+        }
+
+        for entry in entries.iter().rev() {
+            println!(
+                "{}\t#{}\t{}\t{}..{}",
+                name,
+                entry.block,
+                address(&AccountId32::from(entry.pusher)),
+                short(&entry.old),
+                short(&entry.new)
+            );
+        }
+    }
+
+    Ok(())
+}
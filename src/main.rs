@@ -1,140 +1,295 @@
 #![allow(clippy::too_many_arguments)]
 
-use dirs::config_dir;
+use advertise::RefFilter;
+use config::Config;
+use exit::ExitCode;
+use fees::InvArchExtrinsicParams;
 use git2::Repository;
-use invarch::runtime_types::{
-    invarch_runtime::Call, pallet_inv4::pallet::AnyId, pallet_inv4::pallet::Call as IpsCall,
-};
-use ipfs_api::IpfsClient;
+use inv4_git_core::{bloom, compression, types};
+use ipfs::Ipfs;
 use log::debug;
-use primitives::{BoxResult, Config, RepoData};
-use sp_keyring::AccountKeyring::Alice;
+use maintenance::Fetched;
+use manifest::{Manifest, ManifestEntry};
+use namespace::Namespace;
+use prefetch::{MultiObjectSource, Prefetcher};
+use primitives::{
+    BoxResult, PushReceipt, PushSession, RefUpdate, RepoData, RepoDataOps,
+    RECEIPT_MESSAGE_PUSH_OPTION,
+};
+use queue::{QueuedPush, QUEUE_PUSH_OPTION};
+use quota::IGNORE_QUOTA_PUSH_OPTION;
+use reconstruct::MissingRepoData;
+use reference::Reference;
+use shallow::ALLOW_SHALLOW_PUSH_OPTION;
+use state::{network_id, RemoteState};
 use std::{
+    collections::BTreeMap,
     env::args,
-    io::{self, Read, Write},
+    io::{self, Write},
     path::Path,
     process::Stdio,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
 };
 use subxt::sp_core::Pair;
 use subxt::subxt;
-use subxt::{ClientBuilder, DefaultConfig, PairSigner, PolkadotExtrinsicParams};
+use subxt::{ClientBuilder, DefaultConfig, PairSigner};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::process::Command;
-
+use types::{GitHash, HashAlgorithm, IpfId, IpsId};
+use util::{find_ipf, ipfs_pin, ipfs_unpin, resolve_ips_path};
+use watch::RepoDataWatcher;
+
+mod address;
+mod adopt;
+mod advertise;
+mod askpass;
+mod backend;
+mod backup;
+mod bundle;
+mod bundling;
+mod cache;
+mod car;
+mod checkout;
+mod chunking;
+mod config;
+mod discover;
+mod doctor;
+mod exit;
+mod fallback;
+mod fees;
+mod fsck;
+mod index;
+mod integrity;
+mod ipfs;
+mod ledger;
+mod ls;
+mod maintenance;
+mod manifest;
+mod namespace;
+mod networks;
+mod prefetch;
 mod primitives;
+mod progress;
+mod proxy;
+mod prune;
+mod queue;
+mod quota;
+mod receipts;
+mod reclaim;
+mod reconstruct;
+mod reference;
+mod resubmit;
+mod rewrite;
+mod rpc;
+mod schema;
+mod serve;
+mod shallow;
+mod spool;
+mod state;
+mod subtree;
+mod syncd;
+mod timeout;
+mod token;
+mod treasury;
 mod util;
+mod verify;
+mod watch;
+mod webhook;
 
 #[subxt(runtime_metadata_path = "invarch_metadata.scale")]
 pub mod invarch {}
 
 pub async fn set_repo(
-    ips_id: u32,
-    api: invarch::RuntimeApi<DefaultConfig, PolkadotExtrinsicParams<DefaultConfig>>,
+    ips_id: IpsId,
+    api: invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
 ) -> BoxResult<RepoData> {
-    let mut ipfs_client = IpfsClient::default();
-    let data = api
-        .storage()
-        .inv4()
-        .ip_storage(&ips_id, None)
-        .await?
-        .ok_or(format!("Ips {ips_id} does not exist"))?
-        .data
-        .0;
-
-    for file in data {
-        if let AnyId::IpfId(id) = file {
-            let ipf_info = api
-                .storage()
-                .ipf()
-                .ipf_storage(&id, None)
-                .await?
-                .ok_or("Internal error: IPF listed from IPS does not exist")?;
-            if String::from_utf8(ipf_info.metadata.0.clone())? == *"RepoData" {
-                return RepoData::from_ipfs(ipf_info.data, &mut ipfs_client).await;
-            }
-        }
+    match find_ipf(&api, ips_id, "RepoData").await? {
+        Some((_, cid)) => RepoData::from_ipfs(cid, &mut Ipfs::default()).await,
+        None => Ok(RepoData::new(HashAlgorithm::Sha1)),
     }
-    Ok(RepoData {
-        refs: Default::default(),
-        objects: Default::default(),
-    })
 }
 
+/// Exits with the code `exit::ExitCode` tags the error with, or 1.
 #[tokio::main]
-async fn main() -> BoxResult<()> {
-    let (_, raw_url) = {
-        let mut args = args();
-        args.next();
-        (
-            args.next().ok_or("Missing alias argument.")?,
-            args.next().ok_or("Missing url argument.")?,
-        )
-    };
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {}", e);
+        if let Some(hint) = exit::hint(&*e) {
+            eprintln!("hint: {}", hint);
+        }
+        std::process::exit(exit::code(&*e));
+    }
+}
 
-    let (ips_id, subasset_id) = {
-        let mut url = Path::new(&raw_url).components();
-        url.next();
-        (
-            url.next()
-                .ok_or("Missing IPS id. Expected: 'inv4://>ips_id<'")?
-                .as_os_str()
-                .to_str()
-                .ok_or("Input was not UTF-8")?
-                .parse::<u32>()?,
-            if let Some(component) = url.next() {
-                Some(
-                    component
-                        .as_os_str()
-                        .to_str()
-                        .ok_or("Input was not UTF-8")?
-                        .parse::<u32>()?,
-                )
-            } else {
-                None
-            },
-        )
-    };
+/// What the URLs of inv4 remotes start with.
+static URL_SCHEME: &str = "inv4://";
 
-    let mut config_file_path =
-        config_dir().expect("Operating system's configs directory not found");
-    config_file_path.push("INV4-Git/config.toml");
+async fn run() -> BoxResult<()> {
+    let mut args: Vec<String> = args().skip(1).collect();
+    if let Some(pos) = args.iter().position(|arg| arg == "--skip-metadata-check") {
+        args.remove(pos);
+        SKIP_METADATA_CHECK.store(true, Ordering::Relaxed);
+    }
+    let first_arg = args.first().ok_or("Missing alias argument.")?;
 
-    std::fs::create_dir_all(config_file_path.parent().unwrap()).unwrap();
+    // git runs remote helpers as `<remote> <url>`, and a remote may be named like a subcommand
+    let as_helper = args.get(1).is_some_and(|url| url.starts_with(URL_SCHEME));
 
-    let config: Config = if config_file_path.exists() {
-        let mut contents = String::new();
-        std::fs::File::options()
-            .write(true)
-            .read(true)
-            .create(false)
-            .open(config_file_path.clone())?
-            .read_to_string(&mut contents)?;
+    // Has to work with a broken config, to show what's wrong with it
+    if first_arg == "config" && !as_helper {
+        return config::run(&args[1..]);
+    }
 
-        toml::from_str(&contents)?
-    } else {
-        let c = Config {
-            chain_endpoint: String::from("ws://127.0.0.1:9944"),
-        };
+    let config = Config::load()?;
+
+    let subcommand = if as_helper { "" } else { first_arg.as_str() };
+    match subcommand {
+        "flush" => return flush(&config).await,
+        "doctor" => return doctor::run(&config).await,
+        "whoami" => return address::whoami(&config).await,
+        "discover" => return discover::run(&config, &args[1..]).await,
+        "serve" => return serve::run(&config, &args[1..]).await,
+        "syncd" => return syncd::run(&config, &args[1..]).await,
+        "watch" => return webhook::run(&config, &args[1..]).await,
+        "bundle" => return bundle::run(&config, &args[1..]).await,
+        "checkout-dir" => return checkout::run(&config, &args[1..]).await,
+        "rpc" => return rpc::run(&config, &args[1..]).await,
+        "reconstruct" => return reconstruct::run(&config, &args[1..]).await,
+        "ls" => return ls::run(&config, &args[1..]).await,
+        "reflog" => return ls::reflog(&config, &args[1..]).await,
+        "repodata" => return schema::run(&config, &args[1..]).await,
+        "export-car" => return car::export(&config, &args[1..]).await,
+        "import-car" => return car::import(&args[1..]).await,
+        "verify-manifest" => return manifest::verify(&config, &args[1..]).await,
+        "fsck" => return fsck::run(&config, &args[1..]).await,
+        "receipt" => return receipts::run(&open_repository()?, &args[1..]),
+        "state" => return state::run(&open_repository()?, &args[1..]),
+        "subtree" => return subtree::run(&config, &args[1..]).await,
+        "networks" => return networks::run(&config, &args[1..]),
+        "costs" => {
+            return ledger::print_costs(
+                &open_repository()?,
+                args.get(1).map(String::as_str).unwrap_or("month").parse()?,
+                &config,
+            )
+        }
+        _ => {}
+    }
 
-        let mut f = std::fs::File::create(config_file_path)?;
+    let raw_url = args.get(1).ok_or("Missing url argument.")?;
 
-        f.write_all(toml::to_string(&c)?.as_bytes())?;
+    // A profile in the URL, as in `inv4://5?profile=dao`, wins over INV4_GIT_PROFILE
+    let (raw_url, query) = raw_url.split_once('?').unwrap_or((raw_url, ""));
+    let config = match query
+        .split('&')
+        .find_map(|param| param.strip_prefix("profile="))
+    {
+        Some(profile) => Config::load_profile(Some(profile))?,
+        None => config,
+    };
 
-        c
+    let subasset_id = match query
+        .split('&')
+        .find_map(|param| param.strip_prefix("subasset="))
+    {
+        Some(subasset_id) => Some(subasset_id.parse::<u32>()?),
+        None => None,
     };
 
-    let api: invarch::RuntimeApi<DefaultConfig, PolkadotExtrinsicParams<DefaultConfig>> =
-        ClientBuilder::new()
-            .set_url(config.chain_endpoint)
-            .build()
-            .await?
-            .to_runtime_api();
+    // Objects already on disk, as in `inv4://5?reference=/src/upstream&dissociate`
+    let reference = Reference::from_query(query)?;
+
+    let accept_rewrite =
+        config.accept_rewrite || query.split('&').any(|param| param == "accept-rewrite");
+
+    // Nested IP Sets follow their parent, as in `inv4://<parent>/<child>`
+    let ips_path = Path::new(raw_url)
+        .components()
+        .skip(1)
+        .map(|component| -> BoxResult<u32> {
+            Ok(component
+                .as_os_str()
+                .to_str()
+                .ok_or("Input was not UTF-8")?
+                .parse::<u32>()?)
+        })
+        .collect::<BoxResult<Vec<u32>>>()?;
+    let (&root_ips, nested_ips) = ips_path
+        .split_first()
+        .ok_or("Missing IPS id. Expected: 'inv4://>ips_id<'")?;
+
+    // libgit2 can't open SHA-256 repositories, so fail before anything is read from chain
+    let object_format = HashAlgorithm::of_local_repository()?;
+    if object_format != HashAlgorithm::Sha1 {
+        error!(format!(
+            "This repository uses {} object ids, but git-remote-inv4 can only push and fetch SHA-1 repositories",
+            object_format.name()
+        ))
+    }
+
+    let api = connect(&config).await?;
+
+    let (ips_id, path_subasset_id) = resolve_ips_path(&api, IpsId(root_ips), nested_ips).await?;
+    let subasset_id = subasset_id.or(path_subasset_id);
+
+    let state = RemoteState::new(&open_repository()?, first_arg, network_id(&api), ips_id);
+    debug!("Remote state: {}", state.dir().display());
 
-    let mut remote_repo = set_repo(ips_id, api.clone()).await?;
+    let ref_filter = RefFilter::load(&open_repository()?, first_arg)?;
+    let namespace = Namespace::load(&open_repository()?, first_arg)?;
+
+    let mut remote_repo = cache::repo_data(&state, &api).await?;
+    if remote_repo.refs.is_empty() && remote_repo.objects.is_empty() {
+        let missing = if query.split('&').any(|param| param == "recover") {
+            MissingRepoData::Recover
+        } else {
+            config.missing_repo_data
+        };
+        if let Some(recovered) = reconstruct::check_missing(&api, ips_id, missing).await? {
+            remote_repo = recovered;
+        }
+    }
+    let fallbacks = fallback::load(&open_repository()?, first_arg)?;
+    if !fallbacks.is_empty() {
+        fallback::borrow(
+            &mut remote_repo,
+            &fallbacks,
+            &api,
+            &open_repository()?,
+            first_arg,
+            &network_id(&api),
+        )
+        .await?;
+    }
     debug!("RepoData: {:#?}", remote_repo);
 
+    // A fetch or push shouldn't fail because recent pushes couldn't be checked
+    if let Err(e) = receipts::check_reorgs(
+        &api,
+        &state,
+        &open_repository()?,
+        &remote_repo,
+        ips_id,
+        subasset_id,
+        &config,
+    )
+    .await
+    {
+        eprintln!(
+            "Warning: could not check recent pushes for chain reorgs: {}",
+            e
+        );
+    }
+
+    let mut push_options: Vec<String> = Vec::new();
+    let mut push_session = PushSession::default();
+    let mut fetched = Fetched::default();
+
     loop {
-        let repo = Repository::open_from_env().unwrap();
+        let repo = open_repository()?;
 
         let mut input = String::new();
         io::stdin().read_line(&mut input)?;
@@ -149,31 +304,106 @@ async fn main() -> BoxResult<()> {
 
         match (args.next(), args.next(), args.next()) {
             (Some("push"), Some(ref_arg), None) => {
+                let queue_mode = push_options.iter().any(|o| o == QUEUE_PUSH_OPTION);
+                let enforce_quota = !push_options.iter().any(|o| o == IGNORE_QUOTA_PUSH_OPTION);
+                push_session.allow_shallow =
+                    push_options.iter().any(|o| o == ALLOW_SHALLOW_PUSH_OPTION);
+                let message = push_options
+                    .iter()
+                    .find_map(|o| o.strip_prefix(RECEIPT_MESSAGE_PUSH_OPTION));
+
+                if queue_mode {
+                    if let Some(queued_repo) = queue::latest_repo_data(&state)? {
+                        remote_repo = queued_repo;
+                    }
+                }
+
                 push(
                     &api,
                     &mut remote_repo,
                     ips_id,
                     subasset_id,
                     repo,
-                    IpfsClient::default(),
+                    &state,
+                    Ipfs::default(),
                     ref_arg,
+                    &namespace,
+                    &config,
+                    queue_mode,
+                    enforce_quota,
+                    message,
+                    object_format,
+                    &mut push_session,
                 )
                 .await
             }
+            // git only asks for advertised refs, but `--tags` shouldn't bypass the filter either
+            (Some("fetch"), Some(_), Some(name)) if !ref_filter.allows(name) => {
+                debug!("Not fetching {}, it isn't advertised", name);
+                println!();
+                Ok(())
+            }
             (Some("fetch"), Some(sha), Some(name)) => {
+                if let Some(reference) = &reference {
+                    reference.attach(&repo)?;
+                }
+
+                let ipfs = Ipfs::default();
+                let mut prefetcher = Prefetcher::new(&remote_repo, &repo, &ipfs, &api, ips_id);
+
                 fetch(
                     &remote_repo,
-                    &api,
-                    ips_id,
-                    repo,
-                    IpfsClient::default(),
+                    &mut prefetcher,
+                    &repo,
+                    &ipfs,
                     sha,
                     name,
+                    first_arg,
+                    accept_rewrite,
+                    &mut fetched,
+                    &mut io::stdout(),
                 )
                 .await
             }
-            (Some("capabilities"), None, None) => capabilities(),
-            (Some("list"), _, None) => list(&remote_repo),
+            (Some("option"), Some("push-option"), Some(value)) => {
+                push_options.push(value.to_owned());
+                println!("ok");
+                Ok(())
+            }
+            (Some("option"), Some(_), _) => {
+                println!("unsupported");
+                Ok(())
+            }
+            (Some("capabilities"), None, None) => capabilities(&mut io::stdout()),
+            // Pushes see every ref, so they can't clobber one that isn't advertised
+            (Some("list"), Some("for-push"), None) => list(
+                &remote_repo,
+                &RefFilter::default(),
+                &namespace,
+                &mut io::stdout(),
+            ),
+            (Some("list"), _, None) => {
+                list(&remote_repo, &ref_filter, &namespace, &mut io::stdout())
+            }
+            // A batch of fetches ends with a blank line
+            (None, None, None) if !fetched.tips.is_empty() => {
+                if let Some(reference) = &reference {
+                    reference.dissociate(&repo).await?;
+                }
+                if config.verify_fetch {
+                    verify::after_fetch(&repo, &remote_repo, &fetched, ips_id)?;
+                }
+                // Only fetches prune, `ls-remote` and `remote show` list too
+                prune::run(
+                    &repo,
+                    first_arg,
+                    &namespace.view(&remote_repo.refs),
+                    &config,
+                )?;
+                config.maintenance.run(&repo, &fetched).await;
+                fetched = Fetched::default();
+                Ok(())
+            }
             (None, None, None) => Ok(()),
             _ => {
                 eprintln!("unknown command\n");
@@ -183,15 +413,91 @@ async fn main() -> BoxResult<()> {
     }
 }
 
-async fn push(
-    api: &invarch::RuntimeApi<DefaultConfig, PolkadotExtrinsicParams<DefaultConfig>>,
-    remote_repo: &mut RepoData,
-    ips_id: u32,
-    subasset_id: Option<u32>,
-    mut repo: Repository,
-    mut ipfs: IpfsClient,
-    ref_arg: &str,
-) -> BoxResult<()> {
+/// The connection of this process to the chain and the endpoint it went to, shared by every
+/// subcommand and remote that asks for the same endpoint again.
+static CHAIN_API: Mutex<Option<(String, ChainApi)>> = Mutex::new(None);
+
+type ChainApi = invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>;
+
+/// Whether the runtime of the node behind `CHAIN_API` was checked against our metadata.
+static METADATA_CHECKED: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--skip-metadata-check`, which works like `chain.skip_metadata_check`.
+static SKIP_METADATA_CHECK: AtomicBool = AtomicBool::new(false);
+
+/// The chain API for `config.chain_endpoint`, checked once per process to run the runtime
+/// the chain bindings were generated for.
+async fn connect(
+    config: &Config,
+) -> BoxResult<invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>> {
+    let chain_api = connect_unchecked(config).await?;
+
+    let skip = config.skip_metadata_check || SKIP_METADATA_CHECK.load(Ordering::Relaxed);
+    if !skip && !METADATA_CHECKED.swap(true, Ordering::Relaxed) {
+        if let Err(e) = chain_api.validate_metadata() {
+            METADATA_CHECKED.store(false, Ordering::Relaxed);
+            error!(ExitCode::Verification.wrap(format!(
+                "The runtime of {} doesn't match the metadata git-remote-inv4 was built with ({:?}). Rebuild it against the chain's current metadata, or pass --skip-metadata-check.",
+                config.chain_endpoint, e
+            )))
+        }
+    }
+
+    Ok(chain_api)
+}
+
+/// Like `connect`, without checking the runtime.
+async fn connect_unchecked(
+    config: &Config,
+) -> BoxResult<invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>> {
+    let cached = match &*CHAIN_API.lock().map_err(|_| "The chain API is poisoned")? {
+        Some((endpoint, chain_api)) if *endpoint == config.chain_endpoint => {
+            Some(chain_api.clone())
+        }
+        _ => None,
+    };
+
+    let chain_api = match cached {
+        Some(chain_api) => chain_api,
+        None => {
+            let chain_api: invarch::RuntimeApi<
+                DefaultConfig,
+                InvArchExtrinsicParams<DefaultConfig>,
+            > = ClientBuilder::new()
+                .set_url(proxy::route(&config.chain_endpoint, config).await?)
+                .build()
+                .await
+                .map_err(|e| {
+                    ExitCode::ChainUnreachable.wrap(format!(
+                        "Could not connect to {}: {}",
+                        config.chain_endpoint, e
+                    ))
+                })?
+                .to_runtime_api();
+
+            // Only the first endpoint is kept, later ones are rare enough to reconnect to
+            let mut cached = CHAIN_API.lock().map_err(|_| "The chain API is poisoned")?;
+            if cached.is_none() {
+                debug!("Connected to {}", config.chain_endpoint);
+                *cached = Some((config.chain_endpoint.clone(), chain_api.clone()));
+            }
+
+            chain_api
+        }
+    };
+
+    // Cheap and profile dependent, so redone for every caller
+    fees::install(&chain_api, config)?;
+    address::install(&chain_api, config)?;
+    token::install(&chain_api, config).await;
+
+    Ok(chain_api)
+}
+
+/// Asks git's credential helpers for the seed phrase or private key of the signer.
+async fn get_signer(
+    config: &Config,
+) -> BoxResult<PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>> {
     let mut cmd = Command::new("git");
     cmd.arg("credential");
     cmd.arg("fill");
@@ -220,12 +526,23 @@ async fn push(
             .expect("child process encountered an error");
     });
 
+    // Credential helpers key on host and username, so every profile keeps its own
+    let request = match (&config.profile, &config.signer_key) {
+        (Some(profile), Some(key)) => {
+            format!("protocol=inv4\nhost={}\nusername={}\n\n", profile, key)
+        }
+        _ => "protocol=inv4\nhost=\nusername= \n\n".to_owned(),
+    };
+
     stdin
-        .write_all("protocol=inv4\nhost=\nusername= \n\n".as_bytes())
+        .write_all(request.as_bytes())
         .await
         .expect("could not write to stdin");
 
-    eprintln!("Seed Phrase or Private Key ↓");
+    match &config.profile {
+        Some(profile) => eprintln!("Seed Phrase or Private Key for profile {} ↓", profile),
+        None => eprintln!("Seed Phrase or Private Key ↓"),
+    }
 
     drop(stdin);
 
@@ -238,13 +555,40 @@ async fn push(
     }
 
     if credential.is_empty() {
-        error!("No credential")
+        error!(ExitCode::PermissionDenied.wrap("No credential"))
     }
 
-    let signer = &PairSigner::<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>::new(
-        sp_keyring::sr25519::sr25519::Pair::from_string(&credential, None).unwrap(),
-    );
+    let passphrase = if config.signer_passphrase {
+        Some(askpass::passphrase("Passphrase for the INV4 signer key: ")?)
+    } else {
+        None
+    };
 
+    Ok(PairSigner::new(
+        sp_keyring::sr25519::sr25519::Pair::from_string(&credential, passphrase.as_deref())
+            .map_err(|e| {
+                ExitCode::PermissionDenied.wrap(format!("Could not derive signer key: {:?}", e))
+            })?,
+    ))
+}
+
+async fn push(
+    api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    remote_repo: &mut RepoData,
+    ips_id: IpsId,
+    subasset_id: Option<u32>,
+    mut repo: Repository,
+    state: &RemoteState,
+    mut ipfs: Ipfs,
+    ref_arg: &str,
+    namespace: &Namespace,
+    config: &Config,
+    queue_mode: bool,
+    enforce_quota: bool,
+    message: Option<&str>,
+    object_format: HashAlgorithm,
+    session: &mut PushSession,
+) -> BoxResult<()> {
     // Separate source, destination and the force flag
     let mut refspec_iter = ref_arg.split(':');
 
@@ -267,94 +611,370 @@ async fn push(
         .ok_or_else(|| eprintln!("Could not read destination ref from refspec: {:?}", ref_arg))
         .unwrap();
 
+    let pushed = match broken_ref(&repo, src) {
+        Some(broken) => Err(broken.into()),
+        None => {
+            push_ref(
+                api,
+                remote_repo,
+                ips_id,
+                subasset_id,
+                &mut repo,
+                state,
+                &mut ipfs,
+                src,
+                &namespace.to_chain(dst),
+                force,
+                config,
+                queue_mode,
+                enforce_quota,
+                message,
+                object_format,
+                session,
+            )
+            .await
+        }
+    };
+
+    match pushed {
+        Ok(()) => println!("ok {}", dst),
+        // git reads one line per ref, so multi-line errors would garble the rest of the batch
+        Err(e) => println!(
+            "error {} \"{}\"",
+            dst,
+            e.to_string().replace(['\n', '\r'], " ").replace('"', "'")
+        ),
+    }
+
+    println!();
+    Ok(())
+}
+
+/// Why the local ref `src` can't be pushed, if it's broken: a symbolic ref pointing nowhere,
+/// or a ref whose object is missing from the object database.
+///
+/// A deletion has no source and is never broken.
+fn broken_ref(repo: &Repository, src: &str) -> Option<String> {
+    if src.is_empty() {
+        return None;
+    }
+
+    let reference = match repo.find_reference(src) {
+        Ok(reference) => reference,
+        Err(e) => return Some(format!("{} can't be read: {}", src, e.message())),
+    };
+
+    let resolved = match reference.resolve() {
+        Ok(resolved) => resolved,
+        Err(_) => {
+            return Some(format!(
+                "{} is a symbolic ref to {}, which doesn't exist",
+                src,
+                reference.symbolic_target().unwrap_or("an invalid name")
+            ))
+        }
+    };
+
+    let target = match resolved.target() {
+        Some(target) => target,
+        None => return Some(format!("{} doesn't point at an object", src)),
+    };
+    match repo.odb().and_then(|odb| odb.read_header(target)) {
+        Ok(_) => None,
+        Err(_) => Some(format!(
+            "{} points at {}, which is missing from the local repository",
+            src, target
+        )),
+    }
+}
+
+/// Pushes `src` from `repo` to `dst` in the IPS, or stages the push in `queue_mode`.
+///
+/// Objects already minted by an earlier push in `session` aren't uploaded again.
+async fn push_ref(
+    api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    remote_repo: &mut RepoData,
+    ips_id: IpsId,
+    subasset_id: Option<u32>,
+    repo: &mut Repository,
+    state: &RemoteState,
+    ipfs: &mut Ipfs,
+    src: &str,
+    dst: &str,
+    force: bool,
+    config: &Config,
+    queue_mode: bool,
+    enforce_quota: bool,
+    message: Option<&str>,
+    object_format: HashAlgorithm,
+    session: &mut PushSession,
+) -> BoxResult<()> {
+    remote_repo.adopt_hash_algorithm(object_format)?;
+
+    let mut base_refs = remote_repo.refs.clone();
+
+    if queue_mode {
+        let multi_object = remote_repo
+            .prepare_push_from_str(
+                src,
+                dst,
+                force,
+                repo,
+                ipfs,
+                api,
+                ips_id,
+                config,
+                enforce_quota,
+                session,
+            )
+            .await?;
+
+        // The flush may come long after the node collected unpinned garbage
+        for cid in chunking::chunk_cids(&multi_object)? {
+            ipfs_pin(ipfs, &cid).await;
+        }
+
+        let path = queue::stage(
+            state,
+            &QueuedPush {
+                ips_id,
+                subasset_id,
+                ref_dst: dst.to_owned(),
+                base_refs,
+                message: message.map(str::to_owned),
+                multi_object,
+                repo_data: remote_repo.clone(),
+            },
+        )?;
+
+        eprintln!(
+            "Queued push of {} at {}. Run `git-remote-inv4 flush` to submit it.",
+            dst,
+            path.display()
+        );
+
+        return Ok(());
+    }
+
+    let signer = &get_signer(config).await?;
+
+    let mut watcher = RepoDataWatcher::start(api, ips_id).await?;
+
     // Upload the object tree
-    match remote_repo
-        .push_ref_from_str(src, dst, force, &mut repo, &mut ipfs, api, signer, ips_id)
+    let result = match remote_repo
+        .push_ref_from_str(
+            src,
+            dst,
+            force,
+            repo,
+            ipfs,
+            api,
+            signer,
+            ips_id,
+            subasset_id,
+            config,
+            enforce_quota,
+            session,
+        )
         .await
     {
-        Ok(pack_ipf_id) => {
-            let (new_repo_data, old_repo_data) = remote_repo
-                .mint_return_new_old_id(&mut ipfs, api, signer, ips_id)
-                .await?;
+        Ok((packs, manifest_entries)) => {
+            let pack_ipf_ids: Vec<IpfId> =
+                packs.iter().map(|(pack_ipf_id, _)| *pack_ipf_id).collect();
+            let replaced = replace_repo_data(
+                api,
+                remote_repo,
+                &mut base_refs,
+                &mut watcher,
+                &pack_ipf_ids,
+                manifest_entries,
+                ips_id,
+                subasset_id,
+                ipfs,
+                signer,
+                config,
+                message,
+            )
+            .await;
+
+            if replaced.is_err() {
+                for (_, pack_cid) in &packs {
+                    ipfs_unpin(ipfs, pack_cid).await;
+                }
+            }
 
-            if let Some(old_id) = old_repo_data {
-                eprintln!("Removing old Repo Data with IPF ID: {}", old_id);
+            replaced
+        }
+        Err(e) => Err(e),
+    };
+    session.settle(result.is_ok());
 
-                let remove_call = Call::INV4(IpsCall::remove {
-                    ips_id,
-                    assets: vec![(AnyId::IpfId(old_id), Alice.to_account_id())],
-                    new_metadata: None,
-                });
-
-                api.tx()
-                    .inv4()
-                    .operate_multisig(false, (ips_id, subasset_id), remove_call)?
-                    .sign_and_submit_default(signer)
-                    .await?;
-            }
+    ledger::commit(state, dst, config)?;
+    receipts::commit(state)?;
 
-            eprintln!(
-                "Appending new objects and repo data to repository under IPS ID: {}",
-                ips_id
-            );
+    result
+}
 
-            let append_call = Call::INV4(IpsCall::append {
-                ips_id,
-                assets: vec![AnyId::IpfId(pack_ipf_id), AnyId::IpfId(new_repo_data)], //ipf_id_list.into_iter().map(AnyId::IpfId).collect(),
-                new_metadata: None,
-            });
+/// Merges concurrent pushes into `remote_repo` and swaps it in for the IPS's current RepoData.
+async fn replace_repo_data(
+    api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    remote_repo: &mut RepoData,
+    base_refs: &mut BTreeMap<String, GitHash>,
+    watcher: &mut RepoDataWatcher,
+    pack_ipf_ids: &[IpfId],
+    manifest_entries: Vec<ManifestEntry>,
+    ips_id: IpsId,
+    subasset_id: Option<u32>,
+    ipfs: &mut Ipfs,
+    signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+    config: &Config,
+    message: Option<&str>,
+) -> BoxResult<()> {
+    watcher
+        .merge_concurrent_pushes(api, signer.account_id(), remote_repo, base_refs)
+        .await?;
+    remote_repo
+        .record_ref_updates(base_refs, signer.account_id(), api, config.reflog_len)
+        .await?;
 
-            api.tx()
-                .inv4()
-                .operate_multisig(true, (ips_id, subasset_id), append_call)?
-                .sign_and_submit_then_watch_default(signer)
-                .await?
-                .wait_for_in_block()
-                .await?;
+    let manifest = if config.manifest {
+        Some(Manifest::updated(api, ipfs, ips_id, remote_repo, manifest_entries).await?)
+    } else {
+        None
+    };
+
+    let receipt = if config.push_receipts {
+        Some(PushReceipt::new(
+            signer.account_id().clone(),
+            RefUpdate::between(base_refs, &remote_repo.refs),
+            message.map(str::to_owned),
+        )?)
+    } else {
+        None
+    };
+
+    remote_repo
+        .replace_on_chain(
+            pack_ipf_ids,
+            ipfs,
+            api,
+            signer,
+            ips_id,
+            subasset_id,
+            receipt.as_ref(),
+            manifest.as_ref(),
+            config,
+        )
+        .await
+}
+
+/// Opens the repository git is running us in.
+///
+/// Only the object database and refs are ever touched, so bare repositories work the same
+/// as ones with a worktree.
+fn open_repository() -> BoxResult<Repository> {
+    Repository::open_from_env().map_err(|e| {
+        format!(
+            "Could not open the local git repository (set GIT_DIR for bare repositories): {}",
+            e.message()
+        )
+        .into()
+    })
+}
 
-            eprintln!("New objects successfully appended to on-chain repository!");
+/// Submits pushes staged with `git push -o queue` to remotes on the configured chain.
+async fn flush(config: &Config) -> BoxResult<()> {
+    let repo = open_repository()?;
+    let api = connect(config).await?;
+    let network = network_id(&api);
 
-            println!("ok {}", dst);
+    let mut states = Vec::new();
+    for state in RemoteState::all(&repo)? {
+        if queue::pending(&state)?.is_empty() {
+            continue;
         }
-        Err(e) => {
-            println!("error {} \"{}\"", dst, e);
+
+        if state.network == network {
+            states.push(state);
+        } else {
+            eprintln!(
+                "Skipping pushes queued for {} on another chain. Point `chain.endpoint` at it to flush them.",
+                state.remote
+            );
         }
     }
 
-    println!();
+    if states.is_empty() {
+        eprintln!("Nothing to flush");
+        return Ok(());
+    }
+
+    let signer = get_signer(config).await?;
+    let mut ipfs = Ipfs::default();
+
+    for state in states {
+        queue::flush(&state, &mut ipfs, &api, &signer, config).await?;
+    }
+
     Ok(())
 }
 
+/// Answers git's `fetch <sha> <name>` with the MultiObjects of `source`.
 async fn fetch(
     remote_repo: &RepoData,
-    api: &invarch::RuntimeApi<DefaultConfig, PolkadotExtrinsicParams<DefaultConfig>>,
-    ips_id: u32,
-    mut repo: Repository,
-    mut ipfs: IpfsClient,
+    source: &mut impl MultiObjectSource,
+    repo: &Repository,
+    ipfs: &Ipfs,
     sha: &str,
     name: &str,
+    remote: &str,
+    accept_rewrite: bool,
+    fetched: &mut Fetched,
+    out: &mut impl Write,
 ) -> BoxResult<()> {
-    remote_repo
-        .fetch_to_ref_from_str(sha, name, &mut repo, &mut ipfs, api, ips_id)
+    let git_hash = sha.parse::<GitHash>()?;
+
+    let objects = remote_repo
+        .fetch_objects_for(&git_hash, name, repo, ipfs, source)
         .await?;
+    rewrite::check(repo, remote, name, git_hash.to_oid()?, accept_rewrite)?;
 
-    println!();
+    fetched.objects.extend(objects);
+    fetched.tips.push(git_hash.to_oid()?);
+
+    writeln!(out)?;
 
     Ok(())
 }
 
-fn capabilities() -> BoxResult<()> {
-    println!("push");
-    println!("fetch\n");
+fn capabilities(out: &mut impl Write) -> BoxResult<()> {
+    writeln!(out, "option")?;
+    writeln!(out, "push")?;
+    writeln!(out, "fetch\n")?;
     Ok(())
 }
 
-fn list(remote_repo: &RepoData) -> BoxResult<()> {
-    for (name, git_hash) in &remote_repo.refs {
-        let output = format!("{} {}", git_hash, name);
-        println!("{}", output);
+fn list(
+    remote_repo: &RepoData,
+    ref_filter: &RefFilter,
+    namespace: &Namespace,
+    out: &mut impl Write,
+) -> BoxResult<()> {
+    for (name, git_hash) in &namespace.view(&remote_repo.refs) {
+        if !ref_filter.allows(name) {
+            continue;
+        }
+
+        writeln!(out, "{} {}", git_hash, name)?;
     }
-    println!();
+    // Lets clones check out the branch HEAD stands for instead of guessing
+    if let Some(head) = namespace
+        .head(&remote_repo.symrefs, &remote_repo.refs)
+        .filter(|head| ref_filter.allows(head))
+    {
+        writeln!(out, "@{} HEAD", head)?;
+    }
+    writeln!(out)?;
 
     Ok(())
 }
@@ -0,0 +1,148 @@
+use crate::{error, primitives::BoxResult};
+use git2::{ObjectType, Oid, Repository};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, process::Stdio};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// Housekeeping after large fetches, so a repository cloned from chain performs like one cloned
+/// from a git host.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Maintenance {
+    /// Fetches of fewer objects are left alone
+    #[serde(default = "default_min_objects")]
+    pub min_objects: usize,
+    /// Add the fetched commits to the commit-graph
+    #[serde(default)]
+    pub commit_graph: bool,
+    /// Write a multi-pack-index with a reachability bitmap
+    #[serde(default)]
+    pub bitmap: bool,
+}
+
+fn default_min_objects() -> usize {
+    1000
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            min_objects: default_min_objects(),
+            commit_graph: false,
+            bitmap: false,
+        }
+    }
+}
+
+/// What the fetches of one helper session wrote to the local repository.
+#[derive(Default)]
+pub struct Fetched {
+    pub objects: HashSet<Oid>,
+    /// The tips git asked for
+    pub tips: Vec<Oid>,
+}
+
+impl Maintenance {
+    /// Runs the enabled steps once `fetched` holds at least `min_objects` objects.
+    ///
+    /// git only updates its refs after the helper is done, so every step works from the fetched
+    /// objects and tips instead. Failures are only warned about, as the fetch itself succeeded.
+    pub async fn run(&self, repo: &Repository, fetched: &Fetched) {
+        if !(self.commit_graph || self.bitmap) || fetched.objects.len() < self.min_objects {
+            return;
+        }
+
+        eprintln!(
+            "Optimizing the repository after fetching {} objects...",
+            fetched.objects.len()
+        );
+
+        if self.commit_graph {
+            if let Err(e) = commit_graph(repo, fetched).await {
+                eprintln!("Warning: could not write the commit-graph: {}", e);
+            }
+        }
+
+        if self.bitmap {
+            if let Err(e) = bitmap(repo, fetched).await {
+                eprintln!("Warning: could not write a reachability bitmap: {}", e);
+            }
+        }
+    }
+}
+
+async fn commit_graph(repo: &Repository, fetched: &Fetched) -> BoxResult<()> {
+    let odb = repo.odb()?;
+    let commits: String = fetched
+        .objects
+        .iter()
+        .filter(|&&oid| matches!(odb.read_header(oid), Ok((_, ObjectType::Commit))))
+        .map(|oid| format!("{}\n", oid))
+        .collect();
+
+    git(
+        repo,
+        &["commit-graph", "write", "--stdin-commits", "--append"],
+        Some(commits),
+    )
+    .await
+}
+
+async fn bitmap(repo: &Repository, fetched: &Fetched) -> BoxResult<()> {
+    // Bitmapped commits are picked from the refs, which don't point at the fetched tips yet
+    let snapshot = repo.path().join("inv4-refs-snapshot");
+    std::fs::write(
+        &snapshot,
+        fetched
+            .tips
+            .iter()
+            .map(|oid| format!("{}\n", oid))
+            .collect::<String>(),
+    )?;
+
+    let written = git(
+        repo,
+        &[
+            "multi-pack-index",
+            "write",
+            "--bitmap",
+            &format!("--refs-snapshot={}", snapshot.display()),
+        ],
+        None,
+    )
+    .await;
+
+    let _ = std::fs::remove_file(&snapshot);
+
+    written
+}
+
+/// Runs a git command on `repo`, feeding it `input`.
+pub async fn git(repo: &Repository, args: &[&str], input: Option<String>) -> BoxResult<()> {
+    let mut cmd = Command::new("git");
+    cmd.arg("--git-dir").arg(repo.path());
+    cmd.args(args);
+    cmd.stdin(if input.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    });
+    cmd.stdout(Stdio::null());
+
+    let mut child = cmd.spawn()?;
+
+    if let Some(input) = input {
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or("child did not have a handle to stdin")?;
+        stdin.write_all(input.as_bytes()).await?;
+    }
+
+    let status = child.wait().await?;
+    if !status.success() {
+        error!(format!("git {} exited with {}", args.join(" "), status))
+    }
+
+    Ok(())
+}
@@ -0,0 +1,374 @@
+use crate::{
+    chunking,
+    config::Config,
+    connect, error,
+    exit::ExitCode,
+    fees::InvArchExtrinsicParams,
+    invarch,
+    ipfs::Ipfs,
+    primitives::{
+        BoxResult, GitObjectMetadata, MultiObject, RepoData, RepoDataOps, SUBMODULE_TIP_MARKER,
+    },
+    timeout::{within, Operation},
+    types::{GitHash, HashAlgorithm, IpfId, IpsId},
+    util::{
+        decode_ipf_metadata, find_ipf, flag, ipf_infos, ipfs_cat, ips_ipf_ids, is_git_ipf,
+        IPF_BATCH_SIZE,
+    },
+};
+use cid::Cid;
+use codec::Decode;
+use git2::Oid;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use subxt::{sp_core::hashing::blake2_256, DefaultConfig};
+use twox_hash::xxh3;
+
+static USAGE: &str = "Usage: git-remote-inv4 verify-manifest --ips <id> [--sample <objects>]";
+
+pub use inv4_git_core::metadata::MANIFEST_NAME;
+
+/// First word of an encoded manifest, followed by the format version.
+const MAGIC: &str = "inv4-manifest";
+
+const VERSION: u32 = 1;
+
+/// How many objects `verify-manifest` downloads unless told otherwise.
+const DEFAULT_SAMPLE: usize = 8;
+
+/// One object as the manifest lists it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub git_hash: GitHash,
+    /// CID of the MultiObject IPF holding the object
+    pub cid: String,
+    /// Length of the object's content
+    pub len: u64,
+    /// blake2-256 of the object's content
+    pub checksum: [u8; 32],
+}
+
+/// Every object of a RepoData with where it's stored and a checksum of its content, so an
+/// auditor can check the RepoData against the chain and spot-check objects without cloning.
+///
+/// Encoded as text, one object per line sorted by git hash, below a header naming the
+/// blake2-256 of the RepoData it was made for.
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    pub entries: BTreeMap<GitHash, ManifestEntry>,
+}
+
+impl Manifest {
+    /// The entries for the objects of `layers`, minted as `packs`.
+    ///
+    /// Empty layers mint nothing, so the packs line up with the layers that have objects.
+    /// Streamed blobs are checksummed as they are read; those a queued push lost the checksum
+    /// of stay out, like objects pushed before the IPS had a manifest.
+    pub fn entries_for(layers: &[MultiObject], packs: &[(IpfId, Cid)]) -> Vec<ManifestEntry> {
+        layers
+            .iter()
+            .filter(|layer| !layer.objects.is_empty())
+            .zip(packs)
+            .flat_map(|(layer, (_, cid))| {
+                layer.objects.values().filter_map(move |object| {
+                    let checksum = match (&object.metadata, object.streamed) {
+                        (_, Some(streamed)) => streamed.checksum?,
+                        (GitObjectMetadata::ChunkedBlob { .. }, None) => return None,
+                        _ => blake2_256(&object.data),
+                    };
+
+                    Some(ManifestEntry {
+                        git_hash: object.git_hash.clone(),
+                        cid: cid.to_string(),
+                        len: object.header().size,
+                        checksum,
+                    })
+                })
+            })
+            .collect()
+    }
+
+    /// The manifest of the IPS with `added`, limited to the objects `repo_data` lists.
+    ///
+    /// Objects pushed before the IPS had a manifest stay out of it, as their content would have
+    /// to be downloaded first.
+    pub async fn updated(
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ipfs: &mut Ipfs,
+        ips_id: IpsId,
+        repo_data: &RepoData,
+        added: Vec<ManifestEntry>,
+    ) -> BoxResult<Self> {
+        let mut manifest = match find_ipf(chain_api, ips_id, MANIFEST_NAME).await? {
+            Some((_, cid)) => match Self::decode(&ipfs_cat(ipfs, &cid).await?) {
+                Ok((_, manifest)) => manifest,
+                Err(e) => {
+                    eprintln!(
+                        "Warning: ignoring the unreadable manifest of IPS {}: {}",
+                        ips_id, e
+                    );
+                    Self::default()
+                }
+            },
+            None => Self::default(),
+        };
+
+        for entry in added {
+            manifest.entries.insert(entry.git_hash.clone(), entry);
+        }
+        manifest
+            .entries
+            .retain(|git_hash, _| repo_data.objects.contains_key(git_hash));
+
+        Ok(manifest)
+    }
+
+    /// The manifest as text, for the RepoData whose encoding hashes to `repo_data_digest`.
+    pub fn encode(&self, repo_data_digest: [u8; 32]) -> Vec<u8> {
+        let mut text = format!("{} {} {}\n", MAGIC, VERSION, hex::encode(repo_data_digest));
+
+        for entry in self.entries.values() {
+            text.push_str(&format!(
+                "{}\t{}\t{}\t{}\n",
+                entry.git_hash,
+                entry.cid,
+                entry.len,
+                hex::encode(entry.checksum)
+            ));
+        }
+
+        text.into_bytes()
+    }
+
+    /// A manifest and the digest of the RepoData it was made for.
+    pub fn decode(encoded: &[u8]) -> BoxResult<([u8; 32], Self)> {
+        let text = std::str::from_utf8(encoded)?;
+        let mut lines = text.lines();
+
+        let header: Vec<&str> = lines
+            .next()
+            .ok_or("The manifest is empty")?
+            .split(' ')
+            .collect();
+        let digest = match header.as_slice() {
+            [MAGIC, version, digest] if version.parse::<u32>().ok() == Some(VERSION) => {
+                decode_digest(digest)?
+            }
+            [MAGIC, version, ..] => error!(format!(
+                "Unsupported manifest version {}, try updating git-remote-inv4",
+                version
+            )),
+            _ => error!("Not a manifest"),
+        };
+
+        let mut manifest = Self::default();
+        for line in lines {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let entry = match fields.as_slice() {
+                [git_hash, cid, len, checksum] => ManifestEntry {
+                    git_hash: git_hash.parse()?,
+                    cid: cid.to_string(),
+                    len: len.parse()?,
+                    checksum: decode_digest(checksum)?,
+                },
+                _ => error!(format!("Malformed manifest line: {}", line)),
+            };
+            manifest.entries.insert(entry.git_hash.clone(), entry);
+        }
+
+        Ok((digest, manifest))
+    }
+}
+
+fn decode_digest(hex_digest: &str) -> BoxResult<[u8; 32]> {
+    hex::decode(hex_digest)?
+        .try_into()
+        .map_err(|_| format!("{} is not a blake2-256 digest", hex_digest).into())
+}
+
+/// `verify-manifest`: checks the manifest of an IPS against its RepoData and the MultiObject
+/// IPFs on chain, then downloads a few random objects to check them against their checksums.
+pub async fn verify(config: &Config, args: &[String]) -> BoxResult<()> {
+    let ips_id: IpsId = config
+        .ips_arg(args)?
+        .ok_or(format!("Missing IPS id. {}", USAGE))?;
+    let sample = match flag(args, "--sample") {
+        Some(sample) => sample.parse()?,
+        None => DEFAULT_SAMPLE,
+    };
+
+    let chain_api = connect(config).await?;
+    let mut ipfs = Ipfs::default();
+
+    let (_, repo_data_cid) = find_ipf(&chain_api, ips_id, "RepoData")
+        .await?
+        .ok_or(format!("IPS {} holds no repository", ips_id))?;
+    let encoded = ipfs_cat(&ipfs, &repo_data_cid).await?;
+    let repo_data = RepoData::decode(&mut encoded.as_slice())?;
+    repo_data.verify_signature(&encoded)?;
+
+    let (_, manifest_cid) = find_ipf(&chain_api, ips_id, MANIFEST_NAME)
+        .await?
+        .ok_or(format!(
+            "IPS {} has no manifest. Pushes mint one with `chain.manifest` set.",
+            ips_id
+        ))?;
+    let (digest, manifest) = Manifest::decode(&ipfs_cat(&ipfs, &manifest_cid).await?)?;
+
+    if digest != blake2_256(&encoded) {
+        error!(ExitCode::Verification.wrap(format!(
+            "Manifest {} was made for another RepoData than {}",
+            manifest_cid, repo_data_cid
+        )))
+    }
+
+    let pack_cids = pack_cids(&chain_api, ips_id).await?;
+    let mut problems = Vec::new();
+
+    for entry in manifest.entries.values() {
+        match repo_data.objects.get(&entry.git_hash) {
+            Some(multi_object_hash) => match pack_cids.get(multi_object_hash) {
+                Some(cid) if *cid == entry.cid => {}
+                Some(cid) => problems.push(format!(
+                    "{} is in {} on chain, but the manifest says {}",
+                    entry.git_hash, cid, entry.cid
+                )),
+                None => problems.push(format!(
+                    "{} is in MultiObject {}, which is not in the IPS",
+                    entry.git_hash, multi_object_hash
+                )),
+            },
+            None => problems.push(format!(
+                "{} is in the manifest but not in RepoData",
+                entry.git_hash
+            )),
+        }
+    }
+
+    let uncovered = repo_data
+        .objects
+        .iter()
+        .filter(|(git_hash, multi_object_hash)| {
+            *multi_object_hash != SUBMODULE_TIP_MARKER && !manifest.entries.contains_key(git_hash)
+        })
+        .count();
+
+    let entries: Vec<&ManifestEntry> = manifest.entries.values().collect();
+    let sampled = sample_entries(&entries, sample);
+    for entry in &sampled {
+        if let Err(e) = spot_check(entry, &mut ipfs, repo_data.hash_algorithm).await {
+            problems.push(e.to_string());
+        }
+    }
+
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    if !problems.is_empty() {
+        error!(ExitCode::Verification.wrap(format!(
+            "The manifest of IPS {} doesn't match the chain: {} problems",
+            ips_id,
+            problems.len()
+        )))
+    }
+
+    println!(
+        "Manifest matches RepoData: {} objects listed, {} spot-checked",
+        entries.len(),
+        sampled.len()
+    );
+    if uncovered > 0 {
+        println!(
+            "{} objects pushed before the manifest existed aren't covered",
+            uncovered
+        );
+    }
+
+    Ok(())
+}
+
+/// The CIDs of the IPFs of an IPS, by name.
+async fn pack_cids(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ips_id: IpsId,
+) -> BoxResult<HashMap<String, String>> {
+    let ipf_ids = ips_ipf_ids(chain_api, ips_id).await?;
+    let mut cids = HashMap::new();
+
+    for batch in ipf_ids.chunks(IPF_BATCH_SIZE) {
+        let ipf_infos = within(
+            Operation::ChainQuery,
+            &format!("reading IPFs of IPS {} from chain", ips_id),
+            ipf_infos(chain_api, batch),
+        )
+        .await?;
+
+        for ipf_info in ipf_infos.into_iter().flatten() {
+            // Receipts and other assets of the IPS have no name and CID
+            if !is_git_ipf(&ipf_info.metadata.0) {
+                continue;
+            }
+
+            let (name, cid) = decode_ipf_metadata(&ipf_info.metadata.0, ipf_info.data.0)?;
+            cids.insert(name, cid.to_string());
+        }
+    }
+
+    Ok(cids)
+}
+
+/// Up to `sample` distinct entries, picked anew on every run so repeated audits cover more.
+fn sample_entries<'a>(entries: &[&'a ManifestEntry], sample: usize) -> Vec<&'a ManifestEntry> {
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default();
+
+    let mut picked = BTreeMap::new();
+    let mut round = 0u64;
+    while picked.len() < sample.min(entries.len()) {
+        let i = xxh3::hash64_with_seed(&round.to_le_bytes(), seed) as usize % entries.len();
+        picked.insert(i, entries[i]);
+        round += 1;
+    }
+
+    picked.into_values().collect()
+}
+
+/// Downloads the MultiObject holding `entry` and checks the object's content against it.
+async fn spot_check(
+    entry: &ManifestEntry,
+    ipfs: &mut Ipfs,
+    hash_algorithm: HashAlgorithm,
+) -> BoxResult<()> {
+    let cid: Cid = entry.cid.parse()?;
+    let mut multi_object = MultiObject::decode(&mut ipfs_cat(ipfs, &cid).await?.as_slice())?;
+    chunking::reassemble(&mut multi_object, ipfs).await?;
+
+    let object = multi_object
+        .objects
+        .get(&entry.git_hash)
+        .ok_or(format!("{} is not in {}", entry.git_hash, entry.cid))?;
+
+    if object.data.len() as u64 != entry.len || blake2_256(&object.data) != entry.checksum {
+        error!(format!(
+            "{} in {} doesn't match its length and checksum in the manifest",
+            entry.git_hash, entry.cid
+        ))
+    }
+
+    // libgit2 only hashes SHA-1 objects
+    if hash_algorithm == HashAlgorithm::Sha1 {
+        let hashed = Oid::hash_object(object.header().kind.object_type(), &object.data)?;
+        if GitHash::from(hashed) != entry.git_hash {
+            error!(format!(
+                "{} in {} hashes to {}",
+                entry.git_hash, entry.cid, hashed
+            ))
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use crate::{error, primitives::BoxResult, types::GitHash};
+use git2::Repository;
+use std::collections::BTreeMap;
+
+/// Where the refs of a namespace live in RepoData, as in `refs/namespaces/team-a/heads/main`.
+static NAMESPACES: &str = "refs/namespaces/";
+
+/// The namespace of an IPS's refs a remote works in, so several teams can push the same
+/// repository to one IPS without their branches colliding.
+///
+/// Read from `remote.<name>.inv4Namespace`, or `inv4.namespace` for all remotes. In namespace
+/// `team-a` the local `refs/heads/main` is `refs/namespaces/team-a/heads/main` on chain, and
+/// refs outside the namespace aren't listed. Without either setting refs are used as they are.
+#[derive(Debug, Clone, Default)]
+pub struct Namespace {
+    /// `refs/namespaces/<name>/`
+    prefix: Option<String>,
+}
+
+impl Namespace {
+    pub fn load(repo: &Repository, remote: &str) -> BoxResult<Self> {
+        let config = repo.config()?;
+
+        let name = match config.get_string(&format!("remote.{}.inv4namespace", remote)) {
+            Ok(name) => name,
+            Err(_) => match config.get_string("inv4.namespace") {
+                Ok(name) => name,
+                Err(_) => return Ok(Self::default()),
+            },
+        };
+
+        let valid = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+            && !name.starts_with('.');
+        if !valid {
+            error!(format!(
+                "Invalid ref namespace {:?}: use letters, digits, '-', '_' and '.' only",
+                name
+            ))
+        }
+
+        Ok(Self {
+            prefix: Some(format!("{}{}/", NAMESPACES, name)),
+        })
+    }
+
+    /// The name on chain of the local ref `name`.
+    pub fn to_chain(&self, name: &str) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{}{}", prefix, name.strip_prefix("refs/").unwrap_or(name)),
+            None => name.to_owned(),
+        }
+    }
+
+    /// The name git sees for the ref `name` on chain, `None` outside the namespace.
+    pub fn to_local(&self, name: &str) -> Option<String> {
+        match &self.prefix {
+            Some(prefix) => {
+                let rest = name.strip_prefix(prefix.as_str())?;
+                match rest {
+                    "HEAD" => Some(rest.to_owned()),
+                    _ => Some(format!("refs/{}", rest)),
+                }
+            }
+            None => Some(name.to_owned()),
+        }
+    }
+
+    /// The refs of `refs` in the namespace, named as git sees them.
+    pub fn view(&self, refs: &BTreeMap<String, GitHash>) -> BTreeMap<String, GitHash> {
+        refs.iter()
+            .filter_map(|(name, git_hash)| Some((self.to_local(name)?, git_hash.clone())))
+            .collect()
+    }
+
+    /// The ref HEAD of the namespace stands for as git sees it, if it stands for one in `refs`.
+    pub fn head(
+        &self,
+        symrefs: &BTreeMap<String, String>,
+        refs: &BTreeMap<String, GitHash>,
+    ) -> Option<String> {
+        symrefs
+            .get(&self.to_chain("HEAD"))
+            .filter(|target| refs.contains_key(*target))
+            .and_then(|target| self.to_local(target))
+    }
+}
+
+/// The HEAD a push to the ref `name` on chain may point, and the prefix of the branches next
+/// to it: `refs/namespaces/<name>/HEAD` for refs of a namespace, the IPS's own HEAD otherwise.
+pub fn head_for(name: &str) -> (String, String) {
+    let namespace = name
+        .strip_prefix(NAMESPACES)
+        .and_then(|rest| rest.split_once('/'))
+        .map(|(namespace, _)| namespace);
+
+    match namespace {
+        Some(namespace) => (
+            format!("{}{}/HEAD", NAMESPACES, namespace),
+            format!("{}{}/heads/", NAMESPACES, namespace),
+        ),
+        None => ("HEAD".to_owned(), "refs/heads/".to_owned()),
+    }
+}
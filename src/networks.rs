@@ -0,0 +1,107 @@
+use crate::{config::Config, error, primitives::BoxResult};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+static USAGE: &str = "Usage: git-remote-inv4 networks list";
+
+/// A network `chain.endpoint` can name instead of giving a URL, as in `endpoint = "tinkernet"`.
+///
+/// Custom ones are added as `[networks.<name>]` in the config file, and replace a built-in
+/// network of the same name.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Network {
+    /// WebSocket URLs of public nodes, the first one is connected to
+    pub endpoints: Vec<String>,
+    pub ss58_prefix: u16,
+    pub token_symbol: String,
+    pub token_decimals: u8,
+}
+
+/// The networks known without any configuration.
+fn builtin() -> BTreeMap<String, Network> {
+    let network = |endpoints: &[&str], ss58_prefix, token_symbol: &str, token_decimals| Network {
+        endpoints: endpoints.iter().map(|e| e.to_string()).collect(),
+        ss58_prefix,
+        token_symbol: token_symbol.to_owned(),
+        token_decimals,
+    };
+
+    BTreeMap::from([
+        (
+            "local".to_owned(),
+            network(&["ws://127.0.0.1:9944"], 42, "UNIT", 12),
+        ),
+        (
+            "tinkernet".to_owned(),
+            network(&["wss://tinker.invarch.network"], 117, "TNKR", 12),
+        ),
+        (
+            "invarch".to_owned(),
+            network(&["wss://invarch-rpc.dwellir.com"], 117, "VARCH", 12),
+        ),
+    ])
+}
+
+/// Every known network, the config file's over the built-in ones.
+pub fn all(custom: &BTreeMap<String, Network>) -> BTreeMap<String, Network> {
+    let mut networks = builtin();
+    networks.extend(custom.clone());
+    networks
+}
+
+/// The network called `name`, with suggestions of the known names it could be a typo or the
+/// start of if there is none.
+pub fn find(name: &str, custom: &BTreeMap<String, Network>) -> Result<Network, String> {
+    let networks = all(custom);
+
+    if let Some(network) = networks.get(name) {
+        return Ok(network.clone());
+    }
+
+    let lowercase = name.to_lowercase();
+    let suggestions: Vec<&str> = networks
+        .keys()
+        .filter(|known| known.starts_with(&lowercase) || lowercase.starts_with(known.as_str()))
+        .map(String::as_str)
+        .collect();
+
+    match suggestions.as_slice() {
+        [] => Err(format!(
+            "expected a ws:// or wss:// URL or one of the networks {}, got {:?}",
+            networks.keys().cloned().collect::<Vec<_>>().join(", "),
+            name
+        )),
+        _ => Err(format!(
+            "unknown network {:?}, did you mean {}?",
+            name,
+            suggestions.join(" or ")
+        )),
+    }
+}
+
+/// `networks list`: prints every known network with its endpoints, SS58 prefix and token.
+pub fn run(config: &Config, args: &[String]) -> BoxResult<()> {
+    if args.first().map(String::as_str) != Some("list") {
+        error!(USAGE)
+    }
+
+    for (name, network) in all(&config.networks) {
+        let origin = if config.networks.contains_key(&name) {
+            "config"
+        } else {
+            "built-in"
+        };
+        println!(
+            "{}\t{}\tss58 {}\t{} ({} decimals)\t{}",
+            name,
+            network.endpoints.join(","),
+            network.ss58_prefix,
+            network.token_symbol,
+            network.token_decimals,
+            origin
+        );
+    }
+
+    Ok(())
+}
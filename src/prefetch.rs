@@ -0,0 +1,301 @@
+use crate::{
+    fees::InvArchExtrinsicParams,
+    invarch,
+    ipfs::Ipfs,
+    primitives::{
+        BoxResult, GitObjectMetadata, MultiObject, MultiObjectOps, RepoData, SUBMODULE_TIP_MARKER,
+    },
+    types::{GitHash, IpsId},
+};
+use codec::Decode;
+use futures::{future::LocalBoxFuture, stream::FuturesUnordered, StreamExt};
+use git2::Repository;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    rc::Rc,
+    sync::Mutex,
+};
+use subxt::DefaultConfig;
+
+/// How far ahead fetches download MultiObjects they are likely to need, so walking the history
+/// of a branch doesn't wait for one download per generation.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Prefetch {
+    /// Generations of MultiObjects downloaded ahead of the one being read, each the ones the
+    /// objects of the generation before point into; 0 to download only what is needed
+    #[serde(default = "default_generations")]
+    pub generations: usize,
+    /// Downloads running at once
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Bytes of downloaded MultiObjects kept around before no more are downloaded ahead
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+}
+
+fn default_generations() -> usize {
+    DEFAULT.generations
+}
+
+fn default_concurrency() -> usize {
+    DEFAULT.concurrency
+}
+
+fn default_max_bytes() -> u64 {
+    DEFAULT.max_bytes
+}
+
+const DEFAULT: Prefetch = Prefetch {
+    generations: 2,
+    concurrency: 4,
+    max_bytes: 64 * 1024 * 1024,
+};
+
+impl Default for Prefetch {
+    fn default() -> Self {
+        DEFAULT
+    }
+}
+
+/// The limits prefetchers keep to, those of the last loaded config.
+static CURRENT: Mutex<Prefetch> = Mutex::new(DEFAULT);
+
+impl Prefetch {
+    /// Makes these the limits of every following fetch.
+    pub fn install(self) {
+        if let Ok(mut current) = CURRENT.lock() {
+            *current = self;
+        }
+    }
+}
+
+type Download<'a> = LocalBoxFuture<'a, (String, usize, BoxResult<Vec<u8>>)>;
+
+/// The MultiObjects of a RepoData as one walk of its history reads them.
+///
+/// Every MultiObject asked for is kept, so its other objects don't download it again, and the
+/// MultiObjects its objects point into start downloading right away, up to `generations` ahead.
+/// Downloads only make progress while `get` waits for one, which is when the walk needs them.
+pub struct Prefetcher<'a> {
+    repo_data: &'a RepoData,
+    repo: &'a Repository,
+    ipfs: &'a Ipfs,
+    chain_api: &'a invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ips_id: IpsId,
+    limits: Prefetch,
+    running: FuturesUnordered<Download<'a>>,
+    /// MultiObjects running, downloaded, or queued to be
+    requested: HashSet<String>,
+    /// MultiObjects to download once there is room, with their generation
+    queued: VecDeque<(String, usize)>,
+    /// {MultiObject hash -> (MultiObject, generation)}
+    ready: HashMap<String, (Rc<MultiObject>, usize)>,
+    /// The order `ready` was filled in, to drop the oldest first
+    ready_order: VecDeque<String>,
+    ready_bytes: u64,
+    /// Encoded sizes of the MultiObjects in `ready`
+    sizes: HashMap<String, u64>,
+}
+
+/// Where the fetch walk gets the MultiObjects it follows the objects of from.
+pub trait MultiObjectSource {
+    /// The MultiObject `hash`, with its chunked blobs still in chunks.
+    fn get<'b>(&'b mut self, hash: &'b str) -> LocalBoxFuture<'b, BoxResult<Rc<MultiObject>>>;
+
+    /// The MultiObject `hash` as stored on IPFS, for writing its objects out.
+    fn get_encoded<'b>(&'b mut self, hash: &'b str) -> LocalBoxFuture<'b, BoxResult<Vec<u8>>>;
+}
+
+impl MultiObjectSource for Prefetcher<'_> {
+    fn get<'b>(&'b mut self, hash: &'b str) -> LocalBoxFuture<'b, BoxResult<Rc<MultiObject>>> {
+        Box::pin(Prefetcher::get(self, hash))
+    }
+
+    fn get_encoded<'b>(&'b mut self, hash: &'b str) -> LocalBoxFuture<'b, BoxResult<Vec<u8>>> {
+        Box::pin(MultiObject::chain_get_encoded(
+            hash,
+            self.ipfs,
+            self.chain_api,
+            self.ips_id,
+        ))
+    }
+}
+
+impl<'a> Prefetcher<'a> {
+    pub fn new(
+        repo_data: &'a RepoData,
+        repo: &'a Repository,
+        ipfs: &'a Ipfs,
+        chain_api: &'a invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> Self {
+        Self {
+            repo_data,
+            repo,
+            ipfs,
+            chain_api,
+            ips_id,
+            limits: CURRENT.lock().map_or(DEFAULT, |current| *current),
+            running: FuturesUnordered::new(),
+            requested: HashSet::new(),
+            queued: VecDeque::new(),
+            ready: HashMap::new(),
+            ready_order: VecDeque::new(),
+            ready_bytes: 0,
+            sizes: HashMap::new(),
+        }
+    }
+
+    /// The MultiObject `hash`, with its chunked blobs still in chunks.
+    pub async fn get(&mut self, hash: &str) -> BoxResult<Rc<MultiObject>> {
+        loop {
+            if let Some((multi_object, generation)) = self.ready.get_mut(hash) {
+                let multi_object = multi_object.clone();
+                // Reached by the walk, so what it points into is the next generation now
+                if *generation > 0 {
+                    *generation = 0;
+                    self.look_ahead(&multi_object, 0)?;
+                }
+                return Ok(multi_object);
+            }
+
+            if !self.requested.contains(hash)
+                || self.queued.iter().any(|(queued, _)| queued == hash)
+            {
+                self.queued.retain(|(queued, _)| queued != hash);
+                self.requested.insert(hash.to_owned());
+                self.start(hash.to_owned(), 0);
+            }
+
+            let (done, generation, encoded) = self
+                .running
+                .next()
+                .await
+                .ok_or("Internal error: a MultiObject to fetch was never downloaded")?;
+
+            match encoded.and_then(|encoded| self.finish(&done, generation, encoded)) {
+                Ok(()) => {}
+                Err(e) if done == hash => return Err(e),
+                // Whoever needs it downloads it again and gets to see the error
+                Err(e) => {
+                    debug!("Downloading MultiObject {} ahead failed: {}", done, e);
+                    self.requested.remove(&done);
+                }
+            }
+            self.fill();
+        }
+    }
+
+    fn start(&mut self, hash: String, generation: usize) {
+        let (ipfs, chain_api, ips_id) = (self.ipfs, self.chain_api, self.ips_id);
+
+        self.running.push(Box::pin(async move {
+            let encoded = MultiObject::chain_get_encoded(&hash, ipfs, chain_api, ips_id).await;
+            (hash, generation, encoded)
+        }));
+    }
+
+    /// Starts queued downloads while there is room for them.
+    fn fill(&mut self) {
+        while self.running.len() < self.limits.concurrency.max(1)
+            && self.ready_bytes < self.limits.max_bytes
+        {
+            match self.queued.pop_front() {
+                Some((hash, generation)) => {
+                    debug!("Downloading MultiObject {} ahead", hash);
+                    self.start(hash, generation);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn finish(&mut self, hash: &str, generation: usize, encoded: Vec<u8>) -> BoxResult<()> {
+        let multi_object = MultiObject::decode(&mut encoded.as_slice())?;
+        if multi_object.hash != hash {
+            return Err(format!(
+                "The IPF of MultiObject {} holds MultiObject {}",
+                hash, multi_object.hash
+            )
+            .into());
+        }
+        let multi_object = Rc::new(multi_object);
+
+        self.look_ahead(&multi_object, generation)?;
+
+        self.ready_bytes += encoded.len() as u64;
+        self.sizes.insert(hash.to_owned(), encoded.len() as u64);
+        self.ready_order.push_back(hash.to_owned());
+        self.ready
+            .insert(hash.to_owned(), (multi_object, generation));
+
+        // The newest one stays, as `get` may be waiting for it
+        while self.ready_bytes > self.limits.max_bytes && self.ready_order.len() > 1 {
+            if let Some(oldest) = self.ready_order.pop_front() {
+                self.ready.remove(&oldest);
+                self.requested.remove(&oldest);
+                self.ready_bytes -= self.sizes.remove(&oldest).unwrap_or(0);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Queues the MultiObjects the objects of `multi_object` point into, unless the local
+    /// repository has the objects they'd be needed for.
+    fn look_ahead(&mut self, multi_object: &MultiObject, generation: usize) -> BoxResult<()> {
+        if generation >= self.limits.generations {
+            return Ok(());
+        }
+
+        let odb = self.repo.odb()?;
+        let mut next = BTreeSet::new();
+
+        for (git_hash, object) in &multi_object.objects {
+            let pointed_at: Vec<&GitHash> = match &object.metadata {
+                GitObjectMetadata::Commit {
+                    parent_git_hashes,
+                    tree_git_hash,
+                } => {
+                    let mut pointed_at = vec![tree_git_hash];
+                    // The walk doesn't go past shallow cuts either
+                    if !self.repo_data.shallow.contains(git_hash) {
+                        pointed_at.extend(parent_git_hashes);
+                    }
+                    pointed_at
+                }
+                GitObjectMetadata::Tag { target_git_hash } => vec![target_git_hash],
+                GitObjectMetadata::Tree { entry_git_hashes } => entry_git_hashes.iter().collect(),
+                GitObjectMetadata::Blob | GitObjectMetadata::ChunkedBlob { .. } => continue,
+            };
+
+            for pointed_at in pointed_at {
+                if multi_object.objects.contains_key(pointed_at) {
+                    continue;
+                }
+                let hash = match self.repo_data.objects.get(pointed_at) {
+                    Some(hash) if hash != SUBMODULE_TIP_MARKER => hash,
+                    _ => continue,
+                };
+                if self.requested.contains(hash) || next.contains(hash) {
+                    continue;
+                }
+                if odb.read_header(pointed_at.to_oid()?).is_ok() {
+                    continue;
+                }
+                next.insert(hash.clone());
+            }
+        }
+
+        for hash in next {
+            self.requested.insert(hash.clone());
+            self.queued.push_back((hash, generation + 1));
+        }
+        self.fill();
+
+        Ok(())
+    }
+}
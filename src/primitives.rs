@@ -1,208 +1,1139 @@
+#[cfg(feature = "gix-backend")]
+use crate::backend::GixStore;
+#[cfg(not(feature = "gix-backend"))]
+use crate::backend::{pack_files, sync_path};
 use crate::{
+    address, adopt,
+    backend::{Fsync, ObjectStore},
+    chunking,
+    config::Config,
     error,
-    invarch::{self, runtime_types::pallet_inv4::pallet::AnyId},
-    util::generate_cid,
+    exit::ExitCode,
+    fallback,
+    fees::InvArchExtrinsicParams,
+    index::{self, ObjectIndex, INDEX_NAME},
+    integrity,
+    invarch::{
+        self,
+        runtime_types::{
+            invarch_runtime::Call,
+            pallet_inv4::pallet::{AnyId, Call as IpsCall},
+            pallet_ipf::pallet::Call as IpfCall,
+            pallet_utility::pallet::Call as UtilityCall,
+        },
+    },
+    ipfs::Ipfs,
+    ledger,
+    manifest::{Manifest, ManifestEntry, MANIFEST_NAME},
+    namespace,
+    prefetch::{MultiObjectSource, Prefetcher},
+    progress::{self, Phase},
+    receipts, reclaim, resubmit, shallow,
+    timeout::{within, Operation},
+    treasury,
+    types::{GitHash, HashAlgorithm, IpfId, IpsId},
+    util::{
+        encode_ipf_metadata, find_ipf, ipf_infos, ipf_metadata_name, ipfs_add, ipfs_cat, ipfs_pin,
+        ipfs_unpin, ips_ipf_ids, is_git_ipf, mfs_mirror, object_metadata_name, IPF_BATCH_SIZE,
+    },
 };
 use cid::Cid;
 use codec::{Decode, Encode};
-use futures::TryStreamExt;
-use git2::{Blob, Commit, Object, ObjectType, Odb, Oid, Repository, Tag, Tree};
-use ipfs_api::{IpfsApi, IpfsClient};
+#[cfg(not(feature = "gix-backend"))]
+use git2::Buf;
+use git2::{Object, ObjectType, Oid, Repository};
+pub use inv4_git_core::{
+    objects::{
+        object_signing_payload, BlobChunk, GitObject, GitObjectMetadata, MultiObject, ObjectHeader,
+        ObjectKind, ObjectSignature, StreamedBlob,
+    },
+    repo_data::{
+        signing_payload, RefLogEntry, RefUpdate, RepoData, RepoDataSignature, REPO_DATA_MARKER,
+        REPO_DATA_VERSION,
+    },
+    BoxResult,
+};
 use log::debug;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::{
-    collections::{BTreeMap, BTreeSet, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     error::Error,
-    io::Cursor,
+    ffi::{OsStr, OsString},
+    os::unix::{ffi::OsStrExt, fs::OpenOptionsExt},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use subxt::{
+    sp_core::{crypto::AccountId32, hashing::blake2_256, sr25519, Pair},
+    DefaultConfig, PairSigner,
 };
-use subxt::{sp_core::H256, DefaultConfig, PairSigner, PolkadotExtrinsicParams};
+use tokio::sync::{mpsc, oneshot};
 use twox_hash::xxh3;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Config {
-    pub chain_endpoint: String,
-}
-
 /// A magic value used to signal that a hash is a submodule tip (to be obtained by git on its own).
 pub static SUBMODULE_TIP_MARKER: &str = "submodule-tip";
 
-pub type BoxResult<T> = Result<T, Box<dyn Error>>;
+/// Blobs over this many bytes are streamed from the object database to IPFS as chunks, so a push
+/// never holds one in memory whole.
+const STREAMED_BLOB_BYTES: u64 = 16 * 1024 * 1024;
+
+/// How many downloaded MultiObjects a fetch lets wait to be written before it stops downloading.
+const FETCH_PREFETCH_DEPTH: usize = 4;
+
+/// Puts the in-memory ODB backend fetches write to ahead of the loose and pack backends.
+#[cfg(not(feature = "gix-backend"))]
+const MEMPACK_PRIORITY: i32 = 999;
+
+/// Bytes an IPF id takes up in `inv4.append`, as `AnyId::IpfId`.
+const APPENDED_IPF_BYTES: u32 = 9;
+
+/// Most IPFs appended by one `inv4.append`, as its weight grows with every one and the chain
+/// doesn't say by how much.
+const MAX_APPEND_BATCH: usize = 2048;
+
+/// How many IPFs one `inv4.append` may add to an IPS: what fits in a quarter of the space a
+/// block has for normal extrinsics, so there is room left for others, up to `MAX_APPEND_BATCH`.
+fn append_batch_size(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+) -> BoxResult<usize> {
+    let block_length = chain_api.constants().system().block_length()?.max.normal;
+    let fits = (block_length / 4 / APPENDED_IPF_BYTES) as usize;
+
+    Ok(fits.clamp(1, MAX_APPEND_BATCH))
+}
+
+/// How the objects of a push are split into MultiObjects and minted, `push.ordering`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PushOrdering {
+    /// All objects in one MultiObject
+    #[default]
+    #[serde(rename = "atomic")]
+    Atomic,
+    /// Blobs, then trees, then commits and tags, each in a MultiObject minted once the one
+    /// before is in a block, so a commit is never on chain without its tree
+    #[serde(rename = "leaves-first")]
+    LeavesFirst,
+}
+
+/// Minting and fetching MultiObjects.
+pub trait MultiObjectOps {
+    /// Uploads this MultiObject to IPFS and mints it as an IPF, returning the IPF id and
+    /// the CID of the content, which is pinned once the mint succeeded.
+    ///
+    /// Nothing is minted for an empty MultiObject, as pushing a ref to a commit that is already
+    /// on chain produces.
+    async fn mint(
+        &self,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+        ips_id: IpsId,
+        subasset_id: Option<u32>,
+        config: &Config,
+    ) -> Result<Option<(IpfId, Cid)>, Box<dyn Error>>;
 
-#[derive(Clone, Debug, Encode, Decode)]
-pub struct MultiObject {
-    pub hash: String,
-    pub git_hashes: Vec<String>,
-    pub objects: BTreeMap<String, GitObject>,
+    async fn chain_get(
+        hash: String,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> Result<Self, Box<dyn Error>>;
+
+    /// The MultiObject `hash` of `ips_id` as stored on IPFS, with its chunked blobs still in
+    /// chunks.
+    async fn chain_get_encoded(
+        hash: &str,
+        ipfs: &Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> BoxResult<Vec<u8>>;
+
+    /// Decodes `encoded` as the MultiObject `hash` and checks the signatures of its objects,
+    /// which is CPU work fetches do on the rayon pool while downloading the next MultiObject.
+    fn decode_verified(hash: &str, encoded: &[u8]) -> Result<Self, String>;
 }
 
-impl MultiObject {
-    pub fn add(&mut self, object: GitObject) {
-        let hash = object.git_hash.clone();
-        self.objects.insert(hash.clone(), object);
-        self.git_hashes.push(hash);
+impl MultiObjectOps for MultiObject {
+    async fn mint(
+        &self,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+        ips_id: IpsId,
+        subasset_id: Option<u32>,
+        config: &Config,
+    ) -> Result<Option<(IpfId, Cid)>, Box<dyn Error>> {
+        if self.objects.is_empty() {
+            eprintln!("No new objects to mint");
+            return Ok(None);
+        }
+
+        eprintln!("Minting 2 IPFs");
+
+        let threshold = config
+            .chunk_blobs_over
+            .map_or(STREAMED_BLOB_BYTES, |threshold| {
+                threshold.min(STREAMED_BLOB_BYTES)
+            });
+        let (chunks, mut chunk_cids) = chunking::chunk_blobs(self, threshold, ipfs, config).await?;
+        // Blobs streamed while the push was prepared are chunks already
+        for cid in chunking::chunk_cids(self)? {
+            if !chunk_cids.contains(&cid) {
+                chunk_cids.push(cid);
+            }
+        }
+
+        // An earlier push may have timed out waiting for a mint that landed after all
+        if let Some((ipf_id, cid)) = adopt::find(chain_api, ips_id, &self.hash).await? {
+            eprintln!(
+                "MultiObject {} was minted by an earlier push as IPF {}, using that",
+                self.hash, ipf_id
+            );
+            ipfs_pin(ipfs, &cid).await;
+            for chunk_cid in &chunk_cids {
+                ipfs_pin(ipfs, chunk_cid).await;
+            }
+            adopt::landed(ips_id, &self.hash);
+            progress::sink().on_mint(ipf_id);
+
+            return Ok(Some((ipf_id, cid)));
+        }
+
+        let sign = |object: &GitObject| object.signature_by(signer.signer());
+        let encoded = self.encode_upload(
+            config.compression,
+            &chunks,
+            config
+                .sign_objects
+                .then_some(&sign as &dyn Fn(&GitObject) -> ObjectSignature),
+        );
+
+        debug!("Pushing MultiObject to IPFS");
+        progress::sink().on_phase_change(Phase::Uploading);
+        let (cid, ipfs_hash) = ipfs_add(ipfs, encoded, config.cid_format).await?;
+
+        if config.mfs_mirror {
+            mfs_mirror(ipfs, ips_id, &self.hash, &cid).await;
+        }
+
+        debug!("Sending MultiObject to the chain");
+        progress::sink().on_phase_change(Phase::Minting);
+        let name = object_metadata_name(
+            config.metadata_template.as_deref(),
+            ips_id,
+            self.object_type(),
+            &self.hash,
+        );
+        let metadata =
+            encode_ipf_metadata(&name, &cid, config.cid_format, config.license.as_deref());
+        adopt::submitting(chain_api, ips_id, &self.hash, &cid).await?;
+
+        let tx_events = if config.treasury_pays {
+            let call = Call::Ipf(IpfCall::mint {
+                metadata,
+                data: ipfs_hash,
+            });
+            treasury::submit(chain_api, signer, ips_id, subasset_id, call).await?
+        } else {
+            let tx = chain_api.tx().ipf().mint(metadata, ipfs_hash)?;
+            let events = resubmit::sign_and_submit("ipf.mint", || {
+                tx.sign_and_submit_then_watch_default(signer)
+            })
+            .await?;
+            let events = within(
+                Operation::Extrinsic,
+                "waiting for ipf.mint to be included in a block",
+                events.wait_for_in_block(),
+            )
+            .await?;
+
+            let tx_events = events.fetch_events().await?;
+            ledger::record("ipf.mint", &tx_events, signer.account_id())?;
+
+            events
+                .wait_for_success()
+                .await
+                .map_err(|e| ExitCode::PermissionDenied.wrap(e))?;
+
+            tx_events
+        };
+
+        let ipf_id = IpfId(
+            tx_events
+                .find_first::<invarch::ipf::events::Minted>()?
+                .ok_or("MultiObject mint emitted no Minted event")?
+                .1,
+        );
+        adopt::landed(ips_id, &self.hash);
+
+        ipfs_pin(ipfs, &cid).await;
+        for chunk_cid in &chunk_cids {
+            ipfs_pin(ipfs, chunk_cid).await;
+        }
+        receipts::record(chain_api, &tx_events, ips_id, ipf_id, self).await;
+        progress::sink().on_mint(ipf_id);
+
+        eprintln!("Minted Git Objects on-chain with IPF ID: {}", ipf_id);
+
+        Ok(Some((ipf_id, cid)))
     }
 
-    pub async fn chain_get(
+    async fn chain_get(
         hash: String,
-        ipfs: &mut IpfsClient,
-        chain_api: &invarch::RuntimeApi<DefaultConfig, PolkadotExtrinsicParams<DefaultConfig>>,
-        ips_id: u32,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
     ) -> Result<Self, Box<dyn Error>> {
-        let ips_info = chain_api
-            .storage()
-            .inv4()
-            .ip_storage(&ips_id, None)
-            .await?
-            .ok_or(format!("IPS {ips_id} does not exist"))?;
-
-        for file in ips_info.data.0 {
-            if let AnyId::IpfId(id) = file {
-                let ipf_info = chain_api
-                    .storage()
-                    .ipf()
-                    .ipf_storage(&id, None)
-                    .await?
-                    .ok_or("Internal error: IPF listed from IPS does not exist")?;
-                if String::from_utf8(ipf_info.metadata.0.clone())? == *hash {
-                    return Ok(Self::decode(
-                        &mut ipfs
-                            .cat(&generate_cid(ipf_info.data.0.into())?.to_string())
-                            .map_ok(|c| c.to_vec())
-                            .try_concat()
-                            .await?
-                            .as_slice(),
-                    )?);
+        let encoded = Self::chain_get_encoded(&hash, ipfs, chain_api, ips_id).await?;
+
+        let mut multi_object = Self::decode(&mut encoded.as_slice())?;
+        chunking::reassemble(&mut multi_object, ipfs).await?;
+
+        Ok(multi_object)
+    }
+
+    async fn chain_get_encoded(
+        hash: &str,
+        ipfs: &Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> BoxResult<Vec<u8>> {
+        let mut found = index::lookup(chain_api, ipfs, ips_id, hash).await?;
+        // MultiObjects borrowed from a fallback IPS are minted there, not in this one
+        if let (None, Some(source)) = (&found, fallback::source(hash)) {
+            found = match index::lookup(chain_api, ipfs, source, hash).await? {
+                Some(found) => Some(found),
+                None => find_ipf(chain_api, source, hash).await?,
+            };
+        }
+        if found.is_none() {
+            found = find_ipf(chain_api, ips_id, hash).await?;
+        }
+        let (_, cid) = found.ok_or_else(|| {
+            ExitCode::Verification.wrap(format!(
+                "MultiObject {} listed in RepoData is not in IPS {}, the push that wrote this RepoData may not have completed",
+                hash, ips_id
+            ))
+        })?;
+
+        ipfs_cat(ipfs, &cid).await
+    }
+
+    fn decode_verified(hash: &str, encoded: &[u8]) -> Result<Self, String> {
+        let multi_object = Self::decode(&mut &encoded[..])
+            .map_err(|e| format!("Could not decode MultiObject {}: {}", hash, e))?;
+
+        if multi_object.hash != hash {
+            return Err(format!(
+                "The IPF of MultiObject {} holds MultiObject {}",
+                hash, multi_object.hash
+            ));
+        }
+
+        multi_object
+            .objects
+            .par_iter()
+            .try_for_each(|(_, object)| object.verify_signature().map_err(|e| e.to_string()))?;
+
+        Ok(multi_object)
+    }
+}
+
+/// Signing objects with the sr25519 keys of the chain.
+pub trait GitObjectOps {
+    /// The signature of `pair` over this object.
+    fn signature_by(&self, pair: &sr25519::Pair) -> ObjectSignature;
+
+    /// Fails if this object carries a signature that wasn't made over it.
+    fn verify_signature(&self) -> BoxResult<()>;
+}
+
+impl GitObjectOps for GitObject {
+    fn signature_by(&self, pair: &sr25519::Pair) -> ObjectSignature {
+        ObjectSignature {
+            signer: pair.public().0,
+            signature: pair.sign(&object_signing_payload(&self.git_hash)).0,
+        }
+    }
+
+    fn verify_signature(&self) -> BoxResult<()> {
+        if let Some(signature) = &self.signature {
+            let valid = sr25519::Pair::verify(
+                &sr25519::Signature::from_raw(signature.signature),
+                object_signing_payload(&self.git_hash),
+                &sr25519::Public::from_raw(signature.signer),
+            );
+
+            if !valid {
+                error!(ExitCode::Verification.wrap(format!(
+                    "Object {} claims to be signed by {}, but the signature doesn't match. It may have been tampered with.",
+                    self.git_hash,
+                    address::address(&AccountId32::from(signature.signer))
+                )))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Mints `multi_objects` one after the other, each once the one before is in a block.
+///
+/// Returns the IPF and CID of every MultiObject minted. If one fails, the ones minted before it
+/// are unpinned again, as they won't be appended to the IPS.
+pub async fn mint_in_order(
+    multi_objects: &[MultiObject],
+    ipfs: &mut Ipfs,
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+    ips_id: IpsId,
+    subasset_id: Option<u32>,
+    config: &Config,
+) -> BoxResult<Vec<(IpfId, Cid)>> {
+    let mut minted = Vec::new();
+
+    for multi_object in multi_objects {
+        match multi_object
+            .mint(ipfs, chain_api, signer, ips_id, subasset_id, config)
+            .await
+        {
+            Ok(pack) => minted.extend(pack),
+            Err(e) => {
+                for (_, cid) in &minted {
+                    ipfs_unpin(ipfs, cid).await;
                 }
+                return Err(e);
             }
         }
-        error!("git_hash ipf not found")
     }
+
+    Ok(minted)
 }
 
-#[derive(Clone, Debug, Encode, Decode)]
-pub struct GitObject {
-    /// The git hash of the underlying git object
-    pub git_hash: String,
-    /// A link to the raw form of the object
-    pub data: Vec<u8>,
-    /// Object-type-specific metadata
-    pub metadata: GitObjectMetadata,
+/// What the pushes of one `git push` minted, so refs sharing history upload each object once.
+///
+/// The RepoData a push builds on may be reloaded or merged with a concurrent push in between,
+/// so the objects minted earlier are added back to it before enumerating the next push.
+#[derive(Default)]
+pub struct PushSession {
+    /// Push the history of a shallow or grafted clone down to where it's cut off, recording
+    /// the commits missing parents in RepoData instead of refusing the push
+    pub allow_shallow: bool,
+    /// Objects of earlier pushes whose RepoData made it on chain, with their MultiObject
+    minted: BTreeMap<GitHash, (String, ObjectHeader)>,
+    /// Objects minted by the running push, which count once its RepoData is swapped in
+    in_flight: BTreeMap<GitHash, (String, ObjectHeader)>,
 }
 
-#[derive(Clone, Debug, Encode, Decode)]
-pub enum GitObjectMetadata {
-    #[allow(missing_docs)]
-    Commit {
-        parent_git_hashes: BTreeSet<String>,
-        tree_git_hash: String,
-    },
-    #[allow(missing_docs)]
-    Tag { target_git_hash: String },
-    #[allow(missing_docs)]
-    Tree { entry_git_hashes: BTreeSet<String> },
-    #[allow(missing_docs)]
-    Blob,
+impl PushSession {
+    /// Adds the objects earlier pushes minted to `repo_data` unless it lists them already.
+    fn restore(&self, repo_data: &mut RepoData) {
+        for (git_hash, (multi_object_hash, header)) in &self.minted {
+            if !repo_data.objects.contains_key(git_hash) {
+                repo_data
+                    .objects
+                    .insert(git_hash.clone(), multi_object_hash.clone());
+                repo_data.object_headers.insert(git_hash.clone(), *header);
+            }
+        }
+    }
+
+    fn minting(&mut self, multi_object: &MultiObject) {
+        self.in_flight
+            .extend(multi_object.objects.iter().map(|(git_hash, object)| {
+                (
+                    git_hash.clone(),
+                    (multi_object.hash.clone(), object.header()),
+                )
+            }));
+    }
+
+    /// Ends the running push. Its objects are kept only if its RepoData is on chain.
+    pub fn settle(&mut self, succeeded: bool) {
+        let in_flight = std::mem::take(&mut self.in_flight);
+        if succeeded {
+            self.minted.extend(in_flight);
+        }
+    }
 }
 
-impl GitObject {
-    pub fn from_git_blob(blob: &Blob, odb: &Odb) -> Result<Self, Box<dyn Error>> {
-        let odb_obj = odb.read(blob.id())?;
+/// The IPFs a RepoData was minted with, and the ones of the RepoData it replaces.
+pub struct MintedRepoData {
+    pub repo_data: (IpfId, Cid),
+    pub receipt: Option<IpfId>,
+    pub manifest: Option<(IpfId, Cid)>,
+    pub index: Option<(IpfId, Cid)>,
+    pub old_repo_data: Option<(IpfId, Cid)>,
+    pub old_manifest: Option<(IpfId, Cid)>,
+    pub old_index: Option<(IpfId, Cid)>,
+}
 
-        Ok(Self {
-            git_hash: blob.id().to_string(),
-            data: odb_obj.data().to_vec(),
-            metadata: GitObjectMetadata::Blob,
-        })
+/// Fails if `signature` wasn't made over `payload`.
+pub fn check_signature(signature: &RepoDataSignature, payload: &[u8]) -> BoxResult<()> {
+    let valid = sr25519::Pair::verify(
+        &sr25519::Signature::from_raw(signature.signature),
+        payload,
+        &sr25519::Public::from_raw(signature.signer),
+    );
+
+    if !valid {
+        error!(ExitCode::Verification.wrap(format!(
+            "RepoData claims to be signed by {}, but the signature doesn't match. It may have been tampered with.",
+            address::address(&AccountId32::from(signature.signer))
+        )))
     }
 
-    pub fn from_git_commit(commit: &Commit, odb: &Odb) -> Result<Self, Box<dyn Error>> {
-        let odb_obj = odb.read(commit.id())?;
+    Ok(())
+}
+
+/// The push option (`git push -o message=...`) setting the message of the push receipt.
+pub static RECEIPT_MESSAGE_PUSH_OPTION: &str = "message=";
 
-        let parent_git_hashes: BTreeSet<String> = commit
-            .parent_ids()
-            .map(|parent_id| format!("{}", parent_id))
-            .collect();
+/// Longest IPF metadata the chain accepts.
+const MAX_IPF_METADATA: usize = 10000;
 
-        let tree_git_hash = format!("{}", commit.tree()?.id());
+/// A human readable summary of a push, minted as IPF metadata in the same batch as its RepoData.
+#[derive(Debug, Clone)]
+pub struct PushReceipt {
+    pub pusher: AccountId32,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub ref_updates: Vec<RefUpdate>,
+    pub message: Option<String>,
+}
 
+impl PushReceipt {
+    pub fn new(
+        pusher: AccountId32,
+        ref_updates: Vec<RefUpdate>,
+        message: Option<String>,
+    ) -> BoxResult<Self> {
         Ok(Self {
-            git_hash: commit.id().to_string(),
-            data: odb_obj.data().to_vec(),
-            metadata: GitObjectMetadata::Commit {
-                parent_git_hashes,
-                tree_git_hash,
-            },
+            pusher,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            ref_updates,
+            message,
         })
     }
 
-    pub fn from_git_tag(tag: &Tag, odb: &Odb) -> Result<Self, Box<dyn Error>> {
-        let odb_obj = odb.read(tag.id())?;
+    /// The receipt as JSON IPF metadata.
+    ///
+    /// JSON never contains a raw `METADATA_CID_SEPARATOR`, so receipts are never mistaken for
+    /// named IPFs. Ref updates that don't fit are left out and only counted.
+    pub fn to_metadata(&self) -> Vec<u8> {
+        let mut refs: Vec<Value> = self.ref_updates.iter().map(RefUpdate::to_json).collect();
+
+        loop {
+            let metadata = json!({
+                "type": "PushReceipt",
+                "pusher": address::address(&self.pusher),
+                "timestamp": self.timestamp,
+                "message": self.message,
+                "refs": refs,
+                "omitted_refs": self.ref_updates.len() - refs.len(),
+            })
+            .to_string()
+            .into_bytes();
+
+            if metadata.len() <= MAX_IPF_METADATA || refs.pop().is_none() {
+                return metadata;
+            }
+        }
+    }
+}
+
+/// What the helper does with RepoData beyond its encoding, on top of git2, IPFS and the chain.
+pub trait RepoDataOps {
+    /// A copy of this RepoData signed by `pair`, chained to the RepoData encoded as `previous`.
+    fn signed(&self, pair: &sr25519::Pair, previous: Option<&[u8]>) -> BoxResult<Self>;
+
+    /// Fails if this RepoData, decoded from `encoded`, carries a signature that doesn't match it.
+    ///
+    /// The signature is checked against the bytes it was made over, not against this RepoData
+    /// encoded again.
+    fn verify_signature(&self, encoded: &[u8]) -> BoxResult<()>;
+
+    /// Appends the ref updates this RepoData has over `base` to the reflogs, keeping the last
+    /// `reflog_len` updates of each ref. Nothing is recorded with a `reflog_len` of 0.
+    async fn record_ref_updates(
+        &mut self,
+        base: &BTreeMap<String, GitHash>,
+        pusher: &AccountId32,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        reflog_len: usize,
+    ) -> BoxResult<()>;
+
+    /// Points HEAD at `ref_dst` when that's where the local HEAD points `ref_src`, or when
+    /// `ref_dst` is the first branch of this repository, as a bare repository would.
+    ///
+    /// Branches of a ref namespace point the namespace's own HEAD.
+    fn point_head(&mut self, ref_src: &str, ref_dst: &str, repo: &Repository);
+
+    async fn from_ipfs(cid: Cid, ipfs: &mut Ipfs) -> Result<Self, Box<dyn Error>>;
+
+    async fn push_ref_from_str(
+        &mut self,
+        ref_src: &str,
+        ref_dst: &str,
+        force: bool,
+        repo: &mut Repository,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+        ips_id: IpsId,
+        subasset_id: Option<u32>,
+        config: &Config,
+        enforce_quota: bool,
+        session: &mut PushSession,
+    ) -> Result<(Vec<(IpfId, Cid)>, Vec<ManifestEntry>), Box<dyn Error>>;
+
+    /// Splits `multi_object` into the MultiObjects `ordering` mints, in minting order, and
+    /// points the objects of this RepoData at the ones they end up in.
+    ///
+    /// With `per_object` every object gets a MultiObject of its own, minted leaves first
+    /// whatever `ordering` says.
+    fn order_for_mint(
+        &mut self,
+        multi_object: MultiObject,
+        ordering: PushOrdering,
+        per_object: bool,
+    ) -> Vec<MultiObject>;
+
+    /// Updates this RepoData for the push and returns the MultiObject holding the new
+    /// objects. Only blobs too large to hold in memory are written to IPFS, unpinned, and
+    /// nothing to the chain.
+    ///
+    /// With `enforce_quota` the new objects have to fit in the configured quota. Objects minted
+    /// earlier in `session` are never included again.
+    async fn prepare_push_from_str(
+        &mut self,
+        ref_src: &str,
+        ref_dst: &str,
+        force: bool,
+        repo: &mut Repository,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+        config: &Config,
+        enforce_quota: bool,
+        session: &PushSession,
+    ) -> Result<MultiObject, Box<dyn Error>>;
+
+    /// Tells the user which remote refs moved past what `repo` has and how to catch up with
+    /// them, after a push to `ref_dst` found `missing` objects there.
+    fn print_rejection_hints(
+        &self,
+        ref_dst: &str,
+        missing: &HashSet<Oid>,
+        repo: &Repository,
+        ips_id: IpsId,
+    );
+
+    /// Drops objects whose MultiObject IPF is missing from the IPS, so they get pushed again.
+    async fn verify_objects(
+        &mut self,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> Result<usize, Box<dyn Error>>;
+
+    /// Collects the objects reachable from `obj` that this RepoData doesn't have yet.
+    ///
+    /// Commits and tags are walked on this thread, the trees they point to are then expanded
+    /// level by level on the rayon pool, each worker reading them through its own `Repository`.
+    fn enumerate_for_push(
+        &self,
+        obj: &Object,
+        push_todo: &mut HashSet<Oid>,
+        submodules: &mut HashSet<Oid>,
+        repo: &Repository,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Marks the objects only `deleted` reached as tombstones, returning how many there are.
+    ///
+    /// They stay in `objects`, as their MultiObjects are still on chain. Without the history of
+    /// every ref in `repo` nothing is marked, as what is still reachable can't be told.
+    fn tombstone_unreachable(&mut self, deleted: &GitHash, repo: &Repository) -> BoxResult<usize>;
+
+    /// Adds `oid` to `push_todo` unless it's there already or in this RepoData.
+    fn claim_for_push(&self, oid: Oid, push_todo: &mut HashSet<Oid>) -> bool;
+
+    /// Writes the files of `name`, a ref or the git hash of a commit, into the directory `path`
+    /// without creating a git repository.
+    ///
+    /// `path` has to be empty or missing. Only the MultiObjects holding that one tree are
+    /// downloaded; submodules become empty directories. Returns the number of files written.
+    async fn checkout_to_dir(
+        &self,
+        name: &str,
+        path: &Path,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> BoxResult<usize>;
+
+    /// The git object `git_hash`, downloading its MultiObject into `multi_objects` unless an
+    /// earlier call already did.
+    async fn get_git_object(
+        &self,
+        git_hash: &GitHash,
+        multi_objects: &mut HashMap<String, MultiObject>,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> BoxResult<GitObject>;
+
+    /// Like `fetch_objects_for`, then points `ref_name` at `git_hash`, for repositories
+    /// this crate keeps itself, like mirrors and bundles.
+    async fn fetch_to_ref_from_str(
+        &self,
+        git_hash: &GitHash,
+        ref_name: &str,
+        repo: &mut Repository,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> Result<HashSet<Oid>, Box<dyn Error>>;
+
+    /// Fetches `git_hash` and everything it needs into `repo` from the MultiObjects of
+    /// `source`, returning the objects that were missing, without touching any ref.
+    ///
+    /// This is what git's `fetch` command asks of a remote helper: git itself then writes
+    /// FETCH_HEAD, updates `refs/remotes/<remote>/*` along the remote's refspecs and follows
+    /// tags, as it does for any other transport.
+    async fn fetch_objects_for(
+        &self,
+        git_hash: &GitHash,
+        ref_name: &str,
+        repo: &Repository,
+        ipfs: &Ipfs,
+        source: &mut impl MultiObjectSource,
+    ) -> Result<HashSet<Oid>, Box<dyn Error>>;
+
+    async fn enumerate_for_fetch(
+        &self,
+        oid: Oid,
+        fetch_todo: &mut HashSet<Oid>,
+        repo: &Repository,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Adds the objects reachable from `oid` that `repo` lacks to `fetch_todo`, reading the
+    /// MultiObjects they are in from `source`.
+    async fn enumerate_from(
+        &self,
+        oid: Oid,
+        fetch_todo: &mut HashSet<Oid>,
+        repo: &Repository,
+        source: &mut impl MultiObjectSource,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Packs the git objects in `oids` into a MultiObject and records them in this RepoData.
+    fn collect_git_objects(
+        &mut self,
+        oids: &HashSet<Oid>,
+        repo: &Repository,
+    ) -> Result<MultiObject, Box<dyn Error>>;
+
+    /// Records what the tags in `refs` point at if they were pushed before RepoData had tag
+    /// targets, reading the tags from `repo`. Tags `repo` doesn't have are left out.
+    fn backfill_tag_targets(&mut self, repo: &Repository);
+
+    /// The commits of `multi_object` that `repo` has without a parent, as a shallow or grafted
+    /// clone does, whose parent isn't on chain either.
+    fn cut_off_commits(
+        &self,
+        multi_object: &MultiObject,
+        repo: &Repository,
+    ) -> BoxResult<BTreeSet<GitHash>>;
+
+    /// Makes `repo` shallow at the fetched commits that were pushed without parents, so git
+    /// accepts the history ending there.
+    fn record_shallow(&self, fetched: &HashSet<Oid>, repo: &Repository) -> BoxResult<()>;
+
+    /// Fails unless every object `multi_object` points to is in it or already on chain.
+    ///
+    /// A commit minted without its tree or parents can't be fetched by anyone, so an incomplete
+    /// push due to an enumeration bug is stopped before anything is minted.
+    fn check_closure(&self, multi_object: &MultiObject) -> BoxResult<()>;
+
+    /// Download git objects in `oids` from IPFS and instantiate them in `repo`.
+    ///
+    /// Objects with a header in this RepoData are checked against it before being written.
+    ///
+    /// The MultiObjects holding `oids` are downloaded while the ones already downloaded are
+    /// written, with at most `FETCH_PREFETCH_DEPTH` of them waiting. Commits and tags are held
+    /// back until everything else is written and then written parents first.
+    ///
+    /// Objects are written to an in-memory ODB backend and land in `repo` as a single pack once
+    /// all of them are in, instead of as one loose file each. A failed fetch writes nothing, and
+    /// the pack only gets its name once it is complete, so an interrupted fetch can't leave a
+    /// partial one behind; it's flushed to disk as `core.fsync` says. With the `gix-backend`
+    /// feature they are written loose through gitoxide instead.
+    async fn fetch_git_objects(
+        &self,
+        oids: &HashSet<Oid>,
+        repo: &Repository,
+        ipfs: &Ipfs,
+        source: &mut impl MultiObjectSource,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Downloads the MultiObjects in `wanted` from `source` and writes the objects wanted from
+    /// each to `store`, returning how many of them it didn't have yet.
+    async fn write_fetched<S: ObjectStore>(
+        &self,
+        wanted: &BTreeMap<String, Vec<Oid>>,
+        total: usize,
+        store: &S,
+        ipfs: &Ipfs,
+        source: &mut impl MultiObjectSource,
+    ) -> Result<usize, Box<dyn Error>>;
+
+    /// Writes `git_object` to `store` unless it is there already, returning whether it was new.
+    fn write_git_object<S: ObjectStore>(
+        &self,
+        store: &S,
+        git_object: &GitObject,
+    ) -> Result<bool, Box<dyn Error>>;
+
+    /// Mints this RepoData, and `receipt` and `manifest` in the same batch if given.
+    ///
+    /// With `sign_repo_data` the minted copy is signed by `signer` and chained to the RepoData it
+    /// replaces, otherwise any signature left over from the RepoData it was built from is dropped.
+    ///
+    /// Returns the new RepoData IPF and its CID, the receipt IPF, the manifest IPF and its CID,
+    /// and the RepoData IPF currently in the IPS.
+    async fn mint_return_new_old_id(
+        &self,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+        ips_id: IpsId,
+        subasset_id: Option<u32>,
+        receipt: Option<&PushReceipt>,
+        manifest: Option<&Manifest>,
+        pack_ipf_ids: &[IpfId],
+        config: &Config,
+    ) -> Result<MintedRepoData, Box<dyn Error>>;
+
+    /// Mints this RepoData, swaps it for the previous one in the IPS and appends the pushed objects,
+    /// if there were any.
+    ///
+    /// The new RepoData is unpinned again if it couldn't be appended, the old one once it was removed.
+    async fn replace_on_chain(
+        &self,
+        pack_ipf_ids: &[IpfId],
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+        ips_id: IpsId,
+        subasset_id: Option<u32>,
+        receipt: Option<&PushReceipt>,
+        manifest: Option<&Manifest>,
+        config: &Config,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Appends the new RepoData with the pushed objects to the IPS, then removes the `replaced`
+    /// RepoData IPF and the manifest that went with it, giving them to the signer.
+    ///
+    /// The pushed objects are in the IPS by the time a reader can find the new RepoData, and
+    /// the old one stays until then, so no reader ever sees an IPS without a RepoData or one
+    /// listing objects that aren't there. Lookups take the newest RepoData if both are in, so
+    /// failing to remove the old one is only warned about.
+    ///
+    /// More IPFs than one `inv4.append` may add are appended in several, in order, so the new
+    /// RepoData still comes after the objects it lists. IPFs already in the IPS, as appended by
+    /// an earlier push that failed halfway, are left out, and the IPS is read back afterwards
+    /// to check every one of `appended` is in it.
+    ///
+    /// Returns whether the replaced IPFs were removed.
+    async fn swap_on_chain(
+        appended: &[IpfId],
+        replaced: &[IpfId],
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+        ips_id: IpsId,
+        subasset_id: Option<u32>,
+    ) -> Result<bool, Box<dyn Error>>;
+}
+
+impl RepoDataOps for RepoData {
+    fn signed(&self, pair: &sr25519::Pair, previous: Option<&[u8]>) -> BoxResult<Self> {
+        let previous = previous.map(blake2_256);
 
         Ok(Self {
-            git_hash: tag.id().to_string(),
-            data: odb_obj.data().to_vec(),
-            metadata: GitObjectMetadata::Tag {
-                target_git_hash: format!("{}", tag.target_id()),
-            },
+            signature: Some(RepoDataSignature {
+                previous,
+                signer: pair.public().0,
+                signature: pair
+                    .sign(&signing_payload(
+                        blake2_256(&self.unsigned_encoding()?),
+                        &previous,
+                    ))
+                    .0,
+            }),
+            ..self.clone()
         })
     }
 
-    pub fn from_git_tree(tree: &Tree, odb: &Odb) -> Result<Self, Box<dyn Error>> {
-        let odb_obj = odb.read(tree.id())?;
+    fn verify_signature(&self, encoded: &[u8]) -> BoxResult<()> {
+        if let Some(signature) = &self.signature {
+            let unsigned = encoded
+                .len()
+                .checked_sub(self.signature.encoded_size())
+                .map(|len| &encoded[..len])
+                .ok_or("RepoData is shorter than its signature")?;
+
+            check_signature(
+                signature,
+                &signing_payload(blake2_256(unsigned), &signature.previous),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    async fn record_ref_updates(
+        &mut self,
+        base: &BTreeMap<String, GitHash>,
+        pusher: &AccountId32,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        reflog_len: usize,
+    ) -> BoxResult<()> {
+        let updates = RefUpdate::between(base, &self.refs);
+        if reflog_len == 0 || updates.is_empty() {
+            return Ok(());
+        }
 
-        let entry_git_hashes: BTreeSet<String> =
-            tree.iter().map(|entry| format!("{}", entry.id())).collect();
+        let block = within(
+            Operation::ChainQuery,
+            "reading the best block from chain",
+            chain_api.client.rpc().header(None),
+        )
+        .await?
+        .ok_or("No best block")?
+        .number;
+
+        for update in updates {
+            let entries = self.reflogs.entry(update.name).or_default();
+            entries.push(RefLogEntry {
+                old: update.old,
+                new: update.new,
+                pusher: *pusher.as_ref(),
+                block,
+            });
+
+            let excess = entries.len().saturating_sub(reflog_len);
+            entries.drain(..excess);
+        }
 
-        Ok(Self {
-            git_hash: tree.id().to_string(),
-            data: odb_obj.data().to_vec(),
-            metadata: GitObjectMetadata::Tree { entry_git_hashes },
-        })
+        Ok(())
     }
-}
 
-#[derive(Encode, Decode, Debug, Clone)]
-pub struct RepoData {
-    /// All refs this repository knows; a {name -> sha1} map
-    pub refs: BTreeMap<String, String>,
-    /// All objects this repository contains; a {sha1 -> MultiObject hash} map
-    pub objects: BTreeMap<String, String>,
-}
+    fn point_head(&mut self, ref_src: &str, ref_dst: &str, repo: &Repository) {
+        let (head, branches) = namespace::head_for(ref_dst);
+        if !ref_dst.starts_with(&branches) {
+            return;
+        }
 
-impl RepoData {
-    pub async fn from_ipfs(ipfs_hash: H256, ipfs: &mut IpfsClient) -> Result<Self, Box<dyn Error>> {
-        let refs_cid = generate_cid(ipfs_hash)?.to_string();
-        let refs_content = ipfs
-            .cat(&refs_cid)
-            .map_ok(|c| c.to_vec())
-            .try_concat()
-            .await?;
+        let local_head = repo
+            .find_reference("HEAD")
+            .ok()
+            .and_then(|head| head.symbolic_target().map(str::to_owned));
+
+        let first_branch = !self
+            .refs
+            .keys()
+            .any(|name| name != ref_dst && name.starts_with(&branches));
+
+        let head_missing = self
+            .symrefs
+            .get(&head)
+            .is_none_or(|target| !self.refs.contains_key(target));
+
+        if head_missing && (first_branch || local_head.as_deref() == Some(ref_src)) {
+            debug!("Pointing {} at {}", head, ref_dst);
+            self.symrefs.insert(head, ref_dst.to_owned());
+        }
+    }
+
+    async fn from_ipfs(cid: Cid, ipfs: &mut Ipfs) -> Result<Self, Box<dyn Error>> {
+        let refs_content = ipfs_cat(ipfs, &cid).await?;
+
+        let repo_data = Self::decode(&mut refs_content.as_slice())?;
+        repo_data.verify_signature(&refs_content)?;
 
-        Ok(Self::decode(&mut refs_content.as_slice())?)
+        Ok(repo_data)
     }
 
-    pub async fn push_ref_from_str(
+    async fn push_ref_from_str(
         &mut self,
         ref_src: &str,
         ref_dst: &str,
         force: bool,
         repo: &mut Repository,
-        ipfs: &mut IpfsClient,
-        chain_api: &invarch::RuntimeApi<DefaultConfig, PolkadotExtrinsicParams<DefaultConfig>>,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
         signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
-        ips_id: u32,
-    ) -> Result<u64, Box<dyn Error>> {
+        ips_id: IpsId,
+        subasset_id: Option<u32>,
+        config: &Config,
+        enforce_quota: bool,
+        session: &mut PushSession,
+    ) -> Result<(Vec<(IpfId, Cid)>, Vec<ManifestEntry>), Box<dyn Error>> {
+        progress::sink().on_phase_change(Phase::Enumerating);
+        let multi_object = self
+            .prepare_push_from_str(
+                ref_src,
+                ref_dst,
+                force,
+                repo,
+                ipfs,
+                chain_api,
+                ips_id,
+                config,
+                enforce_quota,
+                session,
+            )
+            .await?;
+        progress::sink().on_enumerate(multi_object.objects.len());
+
+        let per_object = config
+            .bundling
+            .per_object(&multi_object, chain_api, signer, ips_id, config)
+            .await?;
+        let layers = self.order_for_mint(multi_object, config.push_ordering, per_object);
+        let packs = mint_in_order(
+            &layers,
+            ipfs,
+            chain_api,
+            signer,
+            ips_id,
+            subasset_id,
+            config,
+        )
+        .await?;
+        for layer in &layers {
+            session.minting(layer);
+        }
+
+        let manifest_entries = if config.manifest {
+            Manifest::entries_for(&layers, &packs)
+        } else {
+            Vec::new()
+        };
+
+        Ok((packs, manifest_entries))
+    }
+
+    fn order_for_mint(
+        &mut self,
+        multi_object: MultiObject,
+        ordering: PushOrdering,
+        per_object: bool,
+    ) -> Vec<MultiObject> {
+        if ordering == PushOrdering::Atomic && !per_object {
+            return vec![multi_object];
+        }
+
+        let mut layers: Vec<MultiObject> = (0..3)
+            .map(|_| MultiObject {
+                hash: String::new(),
+                git_hashes: Vec::new(),
+                objects: BTreeMap::new(),
+            })
+            .collect();
+
+        let mut objects = multi_object.objects;
+        for git_hash in multi_object.git_hashes {
+            if let Some(object) = objects.remove(&git_hash) {
+                let layer = match object.metadata.object_type() {
+                    ObjectType::Blob => 0,
+                    ObjectType::Tree => 1,
+                    _ => 2,
+                };
+                layers[layer].add(object);
+            }
+        }
+
+        if per_object {
+            layers = layers
+                .into_iter()
+                .flat_map(|layer| {
+                    let MultiObject {
+                        git_hashes,
+                        mut objects,
+                        ..
+                    } = layer;
+                    git_hashes
+                        .into_iter()
+                        .filter_map(move |git_hash| objects.remove(&git_hash))
+                })
+                .map(|object| {
+                    let mut single = MultiObject {
+                        hash: String::new(),
+                        git_hashes: Vec::new(),
+                        objects: BTreeMap::new(),
+                    };
+                    single.add(object);
+                    single
+                })
+                .collect();
+        }
+
+        layers.retain(|layer| !layer.objects.is_empty());
+
+        for layer in &mut layers {
+            layer.hash = xxh3::hash64(layer.git_hashes.encode().as_slice()).to_string();
+            for git_hash in &layer.git_hashes {
+                self.objects.insert(git_hash.clone(), layer.hash.clone());
+            }
+        }
+
+        layers
+    }
+
+    async fn prepare_push_from_str(
+        &mut self,
+        ref_src: &str,
+        ref_dst: &str,
+        force: bool,
+        repo: &mut Repository,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+        config: &Config,
+        enforce_quota: bool,
+        session: &PushSession,
+    ) -> Result<MultiObject, Box<dyn Error>> {
         // Deleting `ref_dst` was requested
         if ref_src.is_empty() {
             debug!("Removing ref {} from index", ref_dst);
-            if self.refs.remove(ref_dst).is_none() {
-                debug!(
-                    "Nothing to delete, ref {} not part of the index ref set",
-                    ref_dst
+            let deleted = match self.refs.remove(ref_dst) {
+                Some(deleted) => deleted,
+                None => {
+                    debug!("Available refs:\n{:#?}", self.refs);
+                    error!(format!("Nothing to delete, {} doesn't exist", ref_dst))
+                }
+            };
+
+            let tombstoned = self.tombstone_unreachable(&deleted, repo)?;
+            if tombstoned > 0 {
+                eprintln!(
+                    "{} objects are no longer reachable from any ref",
+                    tombstoned
                 );
-                debug!("Available refs:\n{:#?}", self.refs);
             }
-            error!("ref_srd empty")
+            self.symrefs.retain(|_, target| target != ref_dst);
+
+            return Ok(MultiObject {
+                hash: String::new(),
+                git_hashes: Vec::new(),
+                objects: BTreeMap::new(),
+            });
         }
         let reference = repo.find_reference(ref_src)?.resolve()?;
 
@@ -218,15 +1149,29 @@ impl RepoData {
             obj.id()
         );
 
+        // Objects of a deleted ref that get pushed again are reachable again
+        if !self.tombstones.is_empty() {
+            if let Some(reached) = reachable_locally(repo, [obj.id()])? {
+                self.tombstones.retain(|git_hash| {
+                    git_hash
+                        .to_oid()
+                        .map_or(true, |oid| !reached.contains(&oid))
+                });
+            }
+        }
+
         if force {
             eprintln!("This push will be forced");
         } else {
             eprintln!("Checking for work ahead of us...");
 
+            // Only the remote ref's own history has to be present locally. A new ref, like an
+            // orphan gh-pages branch, may share no history with any other ref, and git itself
+            // rejects a remote tip it knows that isn't an ancestor of what's pushed.
             if let Some(dst_git_hash) = self.refs.get(ref_dst) {
                 let mut missing_objects = HashSet::new();
                 self.enumerate_for_fetch(
-                    dst_git_hash.parse()?,
+                    dst_git_hash.to_oid()?,
                     &mut missing_objects,
                     repo,
                     ipfs,
@@ -236,18 +1181,22 @@ impl RepoData {
                 .await?;
 
                 if !missing_objects.is_empty() {
-                    eprintln!(
-                        "There's {} objects in {} not present locally. Please fetch first or force-push.",
-                        missing_objects.len(),
-                        ref_dst
-                        );
+                    self.print_rejection_hints(ref_dst, &missing_objects, repo, ips_id);
 
                     debug!("Missing objects:\n{:#?}", missing_objects);
-                    return Err("There's objects in the index not present in the local repo - a pull is needed".into());
+                    error!(ExitCode::NonFastForward.wrap(
+                        "There's objects in the index not present in the local repo - a pull is needed"
+                    ))
                 }
             }
         }
 
+        session.restore(self);
+
+        if config.verify_objects {
+            self.verify_objects(chain_api, ips_id).await?;
+        }
+
         let mut objs_for_push = HashSet::new();
         let mut submodules_for_push = HashSet::new();
 
@@ -258,21 +1207,203 @@ impl RepoData {
             repo,
         )?;
 
-        let ipf_id = self
-            .push_git_objects(&objs_for_push, repo, ipfs, chain_api, signer)
-            .await?;
+        let mut multi_object = self.collect_git_objects(&objs_for_push, repo)?;
 
-        for submod_oid in submodules_for_push {
-            self.objects
-                .insert(submod_oid.to_string(), SUBMODULE_TIP_MARKER.to_owned());
+        if let Err(e) = integrity::check(&multi_object) {
+            for git_hash in &multi_object.git_hashes {
+                self.objects.remove(git_hash);
+                self.object_headers.remove(git_hash);
+            }
+            return Err(e);
         }
+        self.backfill_tag_targets(repo);
 
-        self.refs
-            .insert(ref_dst.to_owned(), format!("{}", obj.id()));
-        Ok(ipf_id)
-    }
+        if enforce_quota {
+            if let Err(e) = config.quota.check(&multi_object, repo) {
+                for git_hash in &multi_object.git_hashes {
+                    self.objects.remove(git_hash);
+                    self.object_headers.remove(git_hash);
+                }
+                return Err(e);
+            }
+        }
+
+        for submod_oid in submodules_for_push {
+            self.objects
+                .insert(submod_oid.into(), SUBMODULE_TIP_MARKER.to_owned());
+        }
+
+        let cut_off = self.cut_off_commits(&multi_object, repo)?;
+        if !cut_off.is_empty() {
+            if !session.allow_shallow {
+                for git_hash in &multi_object.git_hashes {
+                    self.objects.remove(git_hash);
+                    self.object_headers.remove(git_hash);
+                }
+                error!(format!(
+                    "Refusing to push from a shallow or grafted clone: {} pushed commits have parents that are neither local nor on chain, so nobody could fetch their history. Fetch the full history with `git fetch --unshallow` first, or push with `git push -o {}` to record where the history is cut off; fetches then stop there.",
+                    cut_off.len(),
+                    shallow::ALLOW_SHALLOW_PUSH_OPTION
+                ))
+            }
+
+            eprintln!(
+                "Warning: pushing {} commits without their parents, fetches of {} will be shallow",
+                cut_off.len(),
+                ref_dst
+            );
+            self.shallow.extend(cut_off);
+        }
+
+        if let Err(e) = self.check_closure(&multi_object) {
+            for git_hash in &multi_object.git_hashes {
+                self.objects.remove(git_hash);
+                self.object_headers.remove(git_hash);
+            }
+            return Err(e);
+        }
+
+        if let Err(e) = chunking::stream_blobs(&mut multi_object, repo, ipfs, config).await {
+            for git_hash in &multi_object.git_hashes {
+                self.objects.remove(git_hash);
+                self.object_headers.remove(git_hash);
+            }
+            return Err(e);
+        }
+
+        self.refs.insert(ref_dst.to_owned(), obj.id().into());
+        self.point_head(ref_src, ref_dst, repo);
+        Ok(multi_object)
+    }
+
+    fn print_rejection_hints(
+        &self,
+        ref_dst: &str,
+        missing: &HashSet<Oid>,
+        repo: &Repository,
+        ips_id: IpsId,
+    ) {
+        let remote = remote_name(repo, ips_id);
+
+        eprintln!("hint: Updates were rejected because the remote contains work that you do not");
+        eprintln!("hint: have locally.");
+
+        let commits = missing
+            .iter()
+            .filter(|&&oid| {
+                matches!(
+                    self.object_headers.get(&GitHash::from(oid)),
+                    Some(ObjectHeader {
+                        kind: ObjectKind::Commit,
+                        ..
+                    })
+                )
+            })
+            .count();
+        if commits > 0 {
+            eprintln!(
+                "hint: The remote {} is {} commit(s) ahead of yours.",
+                ref_dst, commits
+            );
+        } else {
+            eprintln!(
+                "hint: The remote {} has {} objects you don't have.",
+                ref_dst,
+                missing.len()
+            );
+        }
+
+        if let Ok(odb) = repo.odb() {
+            let moved: Vec<(&String, &GitHash)> = self
+                .refs
+                .iter()
+                .filter(|(_, git_hash)| git_hash.to_oid().is_ok_and(|oid| !odb.exists(oid)))
+                .collect();
+
+            if !moved.is_empty() {
+                eprintln!("hint: Remote refs that moved since you last fetched:");
+            }
+            for (name, git_hash) in moved {
+                let tracking = name.strip_prefix("refs/heads/").and_then(|branch| {
+                    repo.refname_to_id(&format!("refs/remotes/{}/{}", remote, branch))
+                        .ok()
+                });
+                match tracking {
+                    Some(oid) => eprintln!(
+                        "hint:   {} {}..{}",
+                        name,
+                        &oid.to_string()[..7],
+                        &git_hash.to_string()[..7]
+                    ),
+                    None => eprintln!("hint:   {} now at {}", name, &git_hash.to_string()[..7]),
+                }
+            }
+        }
+
+        eprintln!("hint: Integrate the remote changes before pushing again:");
+        match ref_dst.strip_prefix("refs/heads/") {
+            Some(branch) => eprintln!(
+                "hint:   git fetch {} && git rebase {}/{}",
+                remote, remote, branch
+            ),
+            None => eprintln!("hint:   git fetch {}", remote),
+        }
+        eprintln!("hint: or overwrite them with `git push --force`.");
+    }
+
+    async fn verify_objects(
+        &mut self,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> Result<usize, Box<dyn Error>> {
+        eprintln!("Verifying objects against IPS {}...", ips_id);
+
+        let ipf_ids = ips_ipf_ids(chain_api, ips_id).await?;
+        let mut on_chain = HashSet::new();
+        for batch in ipf_ids.chunks(IPF_BATCH_SIZE) {
+            let ipf_infos = within(
+                Operation::ChainQuery,
+                &format!("reading IPFs of IPS {} from chain", ips_id),
+                ipf_infos(chain_api, batch),
+            )
+            .await?;
+
+            on_chain.extend(
+                ipf_infos
+                    .into_iter()
+                    .flatten()
+                    .filter(|ipf_info| is_git_ipf(&ipf_info.metadata.0))
+                    .filter_map(|ipf_info| ipf_metadata_name(&ipf_info.metadata.0).ok()),
+            );
+        }
+
+        let missing: Vec<GitHash> = self
+            .objects
+            .iter()
+            .filter(|(_, multi_object_hash)| {
+                multi_object_hash.as_str() != SUBMODULE_TIP_MARKER
+                    && !on_chain.contains(multi_object_hash.as_str())
+            })
+            .map(|(git_hash, _)| git_hash.clone())
+            .collect();
+
+        for git_hash in &missing {
+            debug!("Object {} is not backed by an IPF in the IPS", git_hash);
+            self.objects.remove(git_hash);
+            self.object_headers.remove(git_hash);
+        }
+
+        if !missing.is_empty() {
+            eprintln!(
+                "{} objects listed in RepoData are missing on chain and will be pushed again",
+                missing.len()
+            );
+        }
+
+        Ok(missing.len())
+    }
 
-    pub fn enumerate_for_push(
+    fn enumerate_for_push(
         &self,
         obj: &Object,
         push_todo: &mut HashSet<Oid>,
@@ -281,26 +1412,24 @@ impl RepoData {
     ) -> Result<(), Box<dyn Error>> {
         // Object tree traversal state
         let mut stack = vec![obj.clone()];
+        let mut trees = Vec::new();
 
         let mut obj_cnt = 1;
         while let Some(obj) = stack.pop() {
-            if self.objects.contains_key(&obj.id().to_string()) {
-                debug!("Object {} already in RepoData", obj.id());
-                continue;
-            }
-
-            if push_todo.contains(&obj.id()) {
-                debug!("Object {} already in state", obj.id());
-                continue;
-            }
-
             let obj_type = obj.kind().ok_or_else(|| {
                 let msg = format!("Cannot determine type of object {}", obj.id());
                 debug!("{}", msg);
                 msg
             })?;
 
-            push_todo.insert(obj.id());
+            if obj_type == ObjectType::Tree {
+                trees.push(obj.id());
+                continue;
+            }
+
+            if !self.claim_for_push(obj.id(), push_todo) {
+                continue;
+            }
 
             match obj_type {
                 ObjectType::Commit => {
@@ -309,11 +1438,9 @@ impl RepoData {
                         .ok_or_else(|| eprintln!("Could not view {:?} as a commit", obj))
                         .unwrap();
                     debug!("[{}] Counting commit {:?}", obj_cnt, commit);
+                    debug!("Commit {}: Handling tree {}", commit.id(), commit.tree_id());
 
-                    let tree_obj = obj.peel(ObjectType::Tree)?;
-                    debug!("Commit {}: Handling tree {}", commit.id(), tree_obj.id());
-
-                    stack.push(tree_obj);
+                    trees.push(commit.tree_id());
 
                     for parent in commit.parents() {
                         debug!(
@@ -324,39 +1451,8 @@ impl RepoData {
                         stack.push(parent.into_object());
                     }
                 }
-                ObjectType::Tree => {
-                    let tree = obj
-                        .as_tree()
-                        .ok_or_else(|| eprintln!("Could not view {:?} as a tree", obj))
-                        .unwrap();
-                    debug!("[{}] Counting tree {:?}", obj_cnt, tree);
-
-                    for entry in tree.into_iter() {
-                        // Weed out submodules (Implicitly known as commit children of tree objects)
-                        if let Some(ObjectType::Commit) = entry.kind() {
-                            debug!("Skipping submodule at {}", entry.id());
-
-                            submodules.insert(entry.id());
-
-                            continue;
-                        }
-
-                        debug!(
-                            "Tree {}: Pushing tree entry {} ({:?})",
-                            tree.id(),
-                            entry.id(),
-                            entry.kind()
-                        );
-
-                        stack.push(entry.to_object(repo)?);
-                    }
-                }
                 ObjectType::Blob => {
-                    let blob = obj
-                        .as_blob()
-                        .ok_or_else(|| eprintln!("Could not view {:?} as a blob", obj))
-                        .unwrap();
-                    debug!("[{}] Counting blob {:?}", obj_cnt, blob);
+                    debug!("[{}] Counting blob {}", obj_cnt, obj.id());
                 }
                 ObjectType::Tag => {
                     let tag = obj
@@ -374,35 +1470,244 @@ impl RepoData {
 
             obj_cnt += 1;
         }
+
+        let path = repo.path().to_owned();
+
+        while !trees.is_empty() {
+            let level: Vec<Oid> = trees
+                .drain(..)
+                .filter(|&oid| self.claim_for_push(oid, push_todo))
+                .collect();
+
+            let entries = level
+                .par_iter()
+                .map_init(
+                    || Repository::open(&path),
+                    |thread_repo, &oid| -> Result<Vec<(Oid, Option<ObjectType>)>, String> {
+                        let thread_repo = thread_repo.as_ref().map_err(|e| e.to_string())?;
+                        let tree = thread_repo.find_tree(oid).map_err(|e| e.to_string())?;
+                        debug!("Counting tree {:?}", tree);
+
+                        Ok(tree
+                            .iter()
+                            .map(|entry| (entry.id(), entry.kind()))
+                            .collect())
+                    },
+                )
+                .collect::<Result<Vec<_>, String>>()?;
+
+            for (oid, kind) in entries.into_iter().flatten() {
+                match kind {
+                    // Weed out submodules (Implicitly known as commit children of tree objects)
+                    Some(ObjectType::Commit) => {
+                        debug!("Skipping submodule at {}", oid);
+                        submodules.insert(oid);
+                    }
+                    Some(ObjectType::Tree) => trees.push(oid),
+                    _ => {
+                        if self.claim_for_push(oid, push_todo) {
+                            debug!("Counting blob {}", oid);
+                        }
+                    }
+                }
+            }
+        }
+
         Ok(())
     }
 
-    pub async fn fetch_to_ref_from_str(
+    fn tombstone_unreachable(&mut self, deleted: &GitHash, repo: &Repository) -> BoxResult<usize> {
+        let tips = self
+            .refs
+            .values()
+            .map(GitHash::to_oid)
+            .collect::<BoxResult<Vec<Oid>>>()?;
+
+        let (orphaned, kept) = match (
+            reachable_locally(repo, [deleted.to_oid()?])?,
+            reachable_locally(repo, tips)?,
+        ) {
+            (Some(orphaned), Some(kept)) => (orphaned, kept),
+            _ => {
+                eprintln!("Warning: the local repository lacks history of the IPS's refs, not marking the objects {} reached as unreachable", deleted);
+                return Ok(0);
+            }
+        };
+
+        let before = self.tombstones.len();
+        self.tombstones.extend(
+            orphaned
+                .difference(&kept)
+                .map(|&oid| GitHash::from(oid))
+                .filter(|git_hash| self.objects.contains_key(git_hash)),
+        );
+
+        Ok(self.tombstones.len() - before)
+    }
+
+    fn claim_for_push(&self, oid: Oid, push_todo: &mut HashSet<Oid>) -> bool {
+        if self.objects.contains_key(&GitHash::from(oid)) {
+            debug!("Object {} already in RepoData", oid);
+            return false;
+        }
+
+        if !push_todo.insert(oid) {
+            debug!("Object {} already in state", oid);
+            return false;
+        }
+
+        true
+    }
+
+    async fn checkout_to_dir(
         &self,
-        git_hash: &str,
-        ref_name: &str,
-        repo: &mut Repository,
-        ipfs: &mut IpfsClient,
-        chain_api: &invarch::RuntimeApi<DefaultConfig, PolkadotExtrinsicParams<DefaultConfig>>,
-        ips_id: u32,
-    ) -> Result<(), Box<dyn Error>> {
-        debug!("Fetching {} for {}", git_hash, ref_name);
+        name: &str,
+        path: &Path,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> BoxResult<usize> {
+        let mut git_hash = match name.parse::<GitHash>() {
+            Ok(git_hash) if self.objects.contains_key(&git_hash) => git_hash,
+            _ => self.refs[&self.resolve_ref(name)?].clone(),
+        };
 
-        let git_hash_oid = Oid::from_str(git_hash)?;
-        let mut oids_for_fetch = HashSet::new();
+        std::fs::create_dir_all(path)?;
+        if std::fs::read_dir(path)?.next().is_some() {
+            error!(format!("{} is not empty", path.display()))
+        }
 
-        self.enumerate_for_fetch(
-            git_hash_oid,
-            &mut oids_for_fetch,
-            repo,
-            ipfs,
-            chain_api,
-            ips_id,
-        )
-        .await?;
+        let mut multi_objects = HashMap::new();
+
+        let root = loop {
+            let object = self
+                .get_git_object(&git_hash, &mut multi_objects, ipfs, chain_api, ips_id)
+                .await?;
+
+            match object.metadata {
+                GitObjectMetadata::Tag { target_git_hash } => git_hash = target_git_hash,
+                GitObjectMetadata::Commit { tree_git_hash, .. } => git_hash = tree_git_hash,
+                GitObjectMetadata::Tree { .. } => break object,
+                GitObjectMetadata::Blob | GitObjectMetadata::ChunkedBlob { .. } => {
+                    error!(format!("{} points to a blob, not a commit", name))
+                }
+            }
+        };
+
+        let mut files = 0;
+        let mut stack = vec![(root, path.to_owned())];
+
+        while let Some((tree, dir)) = stack.pop() {
+            for entry in parse_tree(&tree.data, self.hash_algorithm)? {
+                let entry_path = dir.join(&entry.name);
+                debug!(
+                    "Checking out {} to {}",
+                    entry.git_hash,
+                    entry_path.display()
+                );
+
+                match entry.mode {
+                    TREE_MODE => {
+                        std::fs::create_dir(&entry_path)?;
+                        let subtree = self
+                            .get_git_object(
+                                &entry.git_hash,
+                                &mut multi_objects,
+                                ipfs,
+                                chain_api,
+                                ips_id,
+                            )
+                            .await?;
+                        stack.push((subtree, entry_path));
+                    }
+                    SUBMODULE_MODE => std::fs::create_dir(&entry_path)?,
+                    mode => {
+                        let blob = self
+                            .get_git_object(
+                                &entry.git_hash,
+                                &mut multi_objects,
+                                ipfs,
+                                chain_api,
+                                ips_id,
+                            )
+                            .await?;
+
+                        if mode == SYMLINK_MODE {
+                            std::os::unix::fs::symlink(OsStr::from_bytes(&blob.data), &entry_path)?;
+                        } else {
+                            // Never write through whatever may already be at that path
+                            let mut file = std::fs::OpenOptions::new()
+                                .write(true)
+                                .create_new(true)
+                                .mode(if mode == EXECUTABLE_MODE {
+                                    0o755
+                                } else {
+                                    0o644
+                                })
+                                .open(&entry_path)?;
+                            file.write_all(&blob.data)?;
+                        }
+
+                        files += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    async fn get_git_object(
+        &self,
+        git_hash: &GitHash,
+        multi_objects: &mut HashMap<String, MultiObject>,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> BoxResult<GitObject> {
+        let multi_object_hash = match self.objects.get(git_hash) {
+            Some(hash) if hash != SUBMODULE_TIP_MARKER => hash.clone(),
+            _ => error!(format!("Object {} is not in IPS {}", git_hash, ips_id)),
+        };
+
+        if !multi_objects.contains_key(&multi_object_hash) {
+            let multi_object =
+                MultiObject::chain_get(multi_object_hash.clone(), ipfs, chain_api, ips_id).await?;
+            multi_objects.insert(multi_object_hash.clone(), multi_object);
+        }
+
+        let object = multi_objects[&multi_object_hash]
+            .objects
+            .get(git_hash)
+            .ok_or(format!(
+                "Object {} is missing from its MultiObject",
+                git_hash
+            ))?
+            .clone();
+
+        if let Some(header) = self.object_headers.get(git_hash) {
+            object.check_header(header)?;
+        }
+        object.verify_signature()?;
 
-        self.fetch_git_objects(&oids_for_fetch, repo, ipfs, chain_api, ips_id)
+        Ok(object)
+    }
+
+    async fn fetch_to_ref_from_str(
+        &self,
+        git_hash: &GitHash,
+        ref_name: &str,
+        repo: &mut Repository,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> Result<HashSet<Oid>, Box<dyn Error>> {
+        let ipfs: &Ipfs = ipfs;
+        let mut prefetcher = Prefetcher::new(self, repo, ipfs, chain_api, ips_id);
+        let oids_for_fetch = self
+            .fetch_objects_for(git_hash, ref_name, repo, ipfs, &mut prefetcher)
             .await?;
+        let git_hash_oid = git_hash.to_oid()?;
 
         match repo.odb()?.read_header(git_hash_oid)?.1 {
             ObjectType::Commit if ref_name.starts_with("refs/tags") => {
@@ -422,22 +1727,64 @@ impl RepoData {
             }
         }
 
+        Ok(oids_for_fetch)
+    }
+
+    async fn fetch_objects_for(
+        &self,
+        git_hash: &GitHash,
+        ref_name: &str,
+        repo: &Repository,
+        ipfs: &Ipfs,
+        source: &mut impl MultiObjectSource,
+    ) -> Result<HashSet<Oid>, Box<dyn Error>> {
+        debug!("Fetching {} for {}", git_hash, ref_name);
+        let sink = progress::sink();
+        sink.on_phase_change(Phase::Enumerating);
+
+        let git_hash_oid = git_hash.to_oid()?;
+        let mut oids_for_fetch = HashSet::new();
+
+        self.enumerate_from(git_hash_oid, &mut oids_for_fetch, repo, source)
+            .await?;
+        sink.on_enumerate(oids_for_fetch.len());
+
+        sink.on_phase_change(Phase::Fetching);
+        self.fetch_git_objects(&oids_for_fetch, repo, ipfs, source)
+            .await?;
+        self.record_shallow(&oids_for_fetch, repo)?;
+        sink.on_phase_change(Phase::Done);
+
         debug!("Fetched {} for {} OK.", git_hash, ref_name);
-        Ok(())
+        Ok(oids_for_fetch)
+    }
+
+    async fn enumerate_for_fetch(
+        &self,
+        oid: Oid,
+        fetch_todo: &mut HashSet<Oid>,
+        repo: &Repository,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut prefetcher = Prefetcher::new(self, repo, ipfs, chain_api, ips_id);
+        self.enumerate_from(oid, fetch_todo, repo, &mut prefetcher)
+            .await
     }
 
-    pub async fn enumerate_for_fetch(
+    async fn enumerate_from(
         &self,
         oid: Oid,
         fetch_todo: &mut HashSet<Oid>,
         repo: &Repository,
-        ipfs: &mut IpfsClient,
-        chain_api: &invarch::RuntimeApi<DefaultConfig, PolkadotExtrinsicParams<DefaultConfig>>,
-        ips_id: u32,
+        source: &mut impl MultiObjectSource,
     ) -> Result<(), Box<dyn Error>> {
         let mut stack = vec![oid];
 
         while let Some(oid) = stack.pop() {
+            // This includes the empty tree, which libgit2 and git know without storing it, so
+            // commits of empty trees fetch even if the pusher's RepoData doesn't list it
             if repo.odb()?.read_header(oid).is_ok() {
                 debug!("Object {} already present locally!", oid);
                 continue;
@@ -450,7 +1797,7 @@ impl RepoData {
 
             let multi_object_hash = self
                 .objects
-                .get(&format!("{}", oid))
+                .get(&GitHash::from(oid))
                 .ok_or_else(|| {
                     let msg = format!("Could not find object {} in the index", oid);
                     debug!("{}", msg);
@@ -458,73 +1805,88 @@ impl RepoData {
                 })?
                 .clone();
 
+            // The rest of the stack may hold other parents or unrelated roots still to visit
             if multi_object_hash == SUBMODULE_TIP_MARKER {
                 debug!("Ommitting submodule {}", oid.to_string());
-                return Ok(());
+                continue;
             }
 
             fetch_todo.insert(oid);
 
-            let multi_object =
-                MultiObject::chain_get(multi_object_hash, ipfs, chain_api, ips_id).await?;
+            // Tags RepoData knows the target of are written with the rest of the fetch
+            if let Some(target) = self.tag_targets.get(&GitHash::from(oid)) {
+                stack.push(target.to_oid()?);
+                continue;
+            }
 
-            match multi_object
+            let multi_object = source.get(&multi_object_hash).await?;
+
+            match &multi_object
                 .objects
-                .get(&oid.to_string())
+                .get(&GitHash::from(oid))
                 .expect("Oid not found in MultiObject")
-                .clone()
                 .metadata
             {
                 GitObjectMetadata::Commit {
                     parent_git_hashes,
                     tree_git_hash,
                 } => {
-                    stack.push(Oid::from_str(&tree_git_hash)?);
+                    stack.push(tree_git_hash.to_oid()?);
 
+                    // History pushed from a shallow clone ends here unless someone pushed more
+                    let cut_off = self.shallow.contains(&GitHash::from(oid));
                     for parent_git_hash in parent_git_hashes {
-                        stack.push(Oid::from_str(&parent_git_hash)?);
+                        if cut_off && !self.objects.contains_key(parent_git_hash) {
+                            continue;
+                        }
+                        stack.push(parent_git_hash.to_oid()?);
                     }
                 }
                 GitObjectMetadata::Tag { target_git_hash } => {
-                    stack.push(Oid::from_str(&target_git_hash)?);
+                    stack.push(target_git_hash.to_oid()?);
                 }
                 GitObjectMetadata::Tree { entry_git_hashes } => {
                     for entry_git_hash in entry_git_hashes {
-                        stack.push(Oid::from_str(&entry_git_hash)?);
+                        stack.push(entry_git_hash.to_oid()?);
                     }
                 }
-                GitObjectMetadata::Blob => {}
+                GitObjectMetadata::Blob | GitObjectMetadata::ChunkedBlob { .. } => {}
             }
         }
 
         Ok(())
     }
 
-    pub async fn push_git_objects(
+    fn collect_git_objects(
         &mut self,
         oids: &HashSet<Oid>,
         repo: &Repository,
-        ipfs: &mut IpfsClient,
-        chain_api: &invarch::RuntimeApi<DefaultConfig, PolkadotExtrinsicParams<DefaultConfig>>,
-        signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
-    ) -> Result<u64, Box<dyn Error>> {
-        eprintln!("Minting 2 IPFs");
-
+    ) -> Result<MultiObject, Box<dyn Error>> {
         let mut multi_object = MultiObject {
             hash: String::new(),
             git_hashes: vec![],
             objects: BTreeMap::new(),
         };
 
+        let odb = repo.odb()?;
+
         for oid in oids {
-            let obj = repo.find_object(*oid, None)?;
-            debug!("Current object: {:?} at {}", obj.kind(), obj.id());
+            if self.objects.contains_key(&GitHash::from(*oid)) {
+                debug!("push_objects: Object {} already in RepoData", oid);
+                continue;
+            }
 
-            if self.objects.contains_key(&obj.id().to_string()) {
-                debug!("push_objects: Object {} already in RepoData", obj.id());
+            // Only the header is read here, large blobs are streamed once the push is checked
+            let (size, kind) = odb.read_header(*oid)?;
+            if kind == ObjectType::Blob && size as u64 > STREAMED_BLOB_BYTES {
+                debug!("Streaming blob {} ({} bytes)", oid, size);
+                multi_object.add(GitObject::streamed_blob(*oid, size as u64));
                 continue;
             }
 
+            let obj = repo.find_object(*oid, Some(kind))?;
+            debug!("Current object: {:?} at {}", obj.kind(), obj.id());
+
             let obj_type = obj.kind().ok_or_else(|| {
                 let msg = format!("Cannot determine type of object {}", obj.id());
                 debug!("{}", msg);
@@ -539,7 +1901,7 @@ impl RepoData {
                         .unwrap();
                     debug!("Pushing commit {:?}", commit);
 
-                    multi_object.add(GitObject::from_git_commit(commit, &repo.odb()?)?);
+                    multi_object.add(GitObject::from_git_commit(commit, &odb)?);
                 }
                 ObjectType::Tree => {
                     let tree = obj
@@ -548,7 +1910,7 @@ impl RepoData {
                         .unwrap();
                     debug!("Pushing tree {:?}", tree);
 
-                    multi_object.add(GitObject::from_git_tree(tree, &repo.odb()?)?);
+                    multi_object.add(GitObject::from_git_tree(tree, &odb)?);
                 }
                 ObjectType::Blob => {
                     let blob = obj
@@ -557,7 +1919,7 @@ impl RepoData {
                         .unwrap();
                     debug!("Pushing blob {:?}", blob);
 
-                    multi_object.add(GitObject::from_git_blob(blob, &repo.odb()?)?);
+                    multi_object.add(GitObject::from_git_blob(blob, &odb)?);
                 }
                 ObjectType::Tag => {
                     let tag = obj
@@ -566,7 +1928,7 @@ impl RepoData {
                         .unwrap();
                     debug!("Pushing tag {:?}", tag);
 
-                    multi_object.add(GitObject::from_git_tag(tag, &repo.odb()?)?);
+                    multi_object.add(GitObject::from_git_tag(tag, &odb)?);
                 }
                 other => {
                     return Err(format!("Don't know how to traverse a {}", other).into());
@@ -576,159 +1938,1513 @@ impl RepoData {
 
         multi_object.hash = xxh3::hash64(multi_object.git_hashes.encode().as_slice()).to_string();
 
-        for oid in multi_object.git_hashes.clone() {
-            self.objects.insert(oid, multi_object.hash.clone());
+        for (git_hash, object) in &multi_object.objects {
+            self.objects
+                .insert(git_hash.clone(), multi_object.hash.clone());
+            self.object_headers
+                .insert(git_hash.clone(), object.header());
+            if let GitObjectMetadata::Tag { target_git_hash } = &object.metadata {
+                self.tag_targets
+                    .insert(git_hash.clone(), target_git_hash.clone());
+            }
         }
 
-        debug!("Pushing MultiObject to IPFS");
-        let ipfs_hash = &Cid::try_from(ipfs.add(Cursor::new(multi_object.encode())).await?.hash)?
-            .to_bytes()[2..];
-
-        debug!("Sending MultiObject to the chain");
-        let events = chain_api
-            .tx()
-            .ipf()
-            .mint(
-                multi_object.hash.as_bytes().to_vec(),
-                H256::from_slice(ipfs_hash),
-            )?
-            .sign_and_submit_then_watch_default(signer)
-            .await?
-            .wait_for_in_block()
-            .await?;
-
-        let ipf_id = events
-            .fetch_events()
-            .await?
-            .find_first::<invarch::ipf::events::Minted>()?
-            .unwrap()
-            .1;
+        Ok(multi_object)
+    }
 
-        events.wait_for_success().await?;
+    fn backfill_tag_targets(&mut self, repo: &Repository) {
+        let mut stack: Vec<GitHash> = self
+            .refs
+            .iter()
+            .filter(|(name, _)| name.starts_with("refs/tags/"))
+            .map(|(_, git_hash)| git_hash.clone())
+            .collect();
 
-        eprintln!("Minted Git Objects on-chain with IPF ID: {}", ipf_id);
+        // Tags of tags are followed down to what isn't a tag
+        while let Some(git_hash) = stack.pop() {
+            if self.tag_targets.contains_key(&git_hash) || !self.objects.contains_key(&git_hash) {
+                continue;
+            }
+            let tag = match git_hash
+                .to_oid()
+                .ok()
+                .and_then(|oid| repo.find_tag(oid).ok())
+            {
+                Some(tag) => tag,
+                None => continue,
+            };
 
-        Ok(ipf_id)
+            let target = GitHash::from(tag.target_id());
+            stack.push(target.clone());
+            self.tag_targets.insert(git_hash, target);
+        }
     }
 
-    /// Download git objects in `oids` from IPFS and instantiate them in `repo`.
-    pub async fn fetch_git_objects(
+    fn cut_off_commits(
         &self,
-        oids: &HashSet<Oid>,
-        repo: &mut Repository,
-        ipfs: &mut IpfsClient,
-        chain_api: &invarch::RuntimeApi<DefaultConfig, PolkadotExtrinsicParams<DefaultConfig>>,
-        ips_id: u32,
-    ) -> Result<(), Box<dyn Error>> {
-        let mut fetched_objects = BTreeMap::new();
-
-        let objects_deduped = {
-            let mut o = self.objects.values().collect::<Vec<&String>>();
-            o.sort();
-            o.dedup();
-            o
-        };
-
-        for object_hash in objects_deduped {
-            let mut multi_object =
-                MultiObject::chain_get(object_hash.clone(), ipfs, chain_api, ips_id).await?;
-
-            fetched_objects.append(&mut multi_object.objects)
+        multi_object: &MultiObject,
+        repo: &Repository,
+    ) -> BoxResult<BTreeSet<GitHash>> {
+        let boundary = shallow::boundary(repo)?;
+        if boundary.is_empty() {
+            return Ok(BTreeSet::new());
         }
 
-        for (i, &oid) in oids.iter().enumerate() {
-            debug!("[{}/{}] Fetching object {}", i + 1, oids.len(), oid);
+        Ok(multi_object
+            .objects
+            .iter()
+            .filter(|(git_hash, object)| {
+                boundary.contains(git_hash)
+                    && match &object.metadata {
+                        GitObjectMetadata::Commit {
+                            parent_git_hashes, ..
+                        } => parent_git_hashes.iter().any(|parent| {
+                            !multi_object.objects.contains_key(parent)
+                                && !self.objects.contains_key(parent)
+                        }),
+                        _ => false,
+                    }
+            })
+            .map(|(git_hash, _)| git_hash.clone())
+            .collect())
+    }
 
-            let git_object = fetched_objects
-                .get(&format!("{}", oid))
-                .ok_or_else(|| {
-                    let msg = format!("Could not find object {} in the index", oid);
-                    debug!("{}", msg);
-                    msg
-                })?
-                .clone();
+    fn record_shallow(&self, fetched: &HashSet<Oid>, repo: &Repository) -> BoxResult<()> {
+        if self.shallow.is_empty() {
+            return Ok(());
+        }
 
-            if repo.odb()?.read_header(oid).is_ok() {
-                debug!("fetch objects: Object {} already present locally!", oid);
+        let odb = repo.odb()?;
+        let mut cut_off = BTreeSet::new();
+        for git_hash in &self.shallow {
+            let oid = git_hash.to_oid()?;
+            if !fetched.contains(&oid) {
                 continue;
             }
-
-            let written_oid = repo.odb()?.write(
-                match git_object.metadata {
-                    GitObjectMetadata::Blob => ObjectType::Blob,
-                    GitObjectMetadata::Commit { .. } => ObjectType::Commit,
-                    GitObjectMetadata::Tag { .. } => ObjectType::Tag,
-                    GitObjectMetadata::Tree { .. } => ObjectType::Tree,
-                },
-                &git_object.data,
-            )?;
-            if written_oid != oid {
-                let msg = format!(
-                    "Object tree inconsistency detected: fetched {}, but write result hashes to {}",
-                    oid, written_oid
-                );
-                debug!("{}", msg);
-                return Err(msg.into());
+            if repo
+                .find_commit(oid)?
+                .parent_ids()
+                .any(|parent| odb.read_header(parent).is_err())
+            {
+                cut_off.insert(git_hash.clone());
             }
-            debug!("Fetched object {}", written_oid);
         }
-        Ok(())
-    }
-
-    pub async fn mint_return_new_old_id(
-        &self,
-        ipfs: &mut IpfsClient,
-        chain_api: &invarch::RuntimeApi<DefaultConfig, PolkadotExtrinsicParams<DefaultConfig>>,
-        signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
-        ips_id: u32,
-    ) -> Result<(u64, Option<u64>), Box<dyn Error>> {
-        let events = chain_api
-            .tx()
-            .ipf()
-            .mint(
-                b"RepoData".to_vec(),
-                H256::from_slice(
-                    &Cid::try_from(ipfs.add(Cursor::new(self.encode())).await?.hash)?.to_bytes()
-                        [2..],
-                ),
-            )?
-            .sign_and_submit_then_watch_default(signer)
-            .await?
-            .wait_for_in_block()
-            .await?;
 
-        let new_ipf_id = events
-            .fetch_events()
-            .await?
-            .find_first::<invarch::ipf::events::Minted>()?
-            .unwrap()
-            .1;
+        if !cut_off.is_empty() {
+            eprintln!(
+                "{} fetched commits were pushed without their parents, the history is shallow there",
+                cut_off.len()
+            );
+        }
+        shallow::record(repo, &cut_off)
+    }
 
-        events.wait_for_success().await?;
+    fn check_closure(&self, multi_object: &MultiObject) -> BoxResult<()> {
+        let present = |git_hash: &GitHash| {
+            multi_object.objects.contains_key(git_hash) || self.objects.contains_key(git_hash)
+        };
 
-        eprintln!("Minted Repo Data on-chain with IPF ID: {}", new_ipf_id);
+        let mut missing = Vec::new();
 
-        let ips_info = chain_api
-            .storage()
-            .inv4()
-            .ip_storage(&ips_id, None)
-            .await?
-            .ok_or(format!("IPS {ips_id} does not exist"))?;
-
-        for file in ips_info.data.0 {
-            if let AnyId::IpfId(id) = file {
-                let ipf_info = chain_api
-                    .storage()
-                    .ipf()
-                    .ipf_storage(&id, None)
-                    .await?
-                    .ok_or("Internal error: IPF listed from IPS does not exist")?;
-                if String::from_utf8(ipf_info.metadata.0.clone())? == *"RepoData" {
-                    return Ok((new_ipf_id, Some(id)));
+        for (git_hash, object) in &multi_object.objects {
+            let dependencies: Vec<(&str, &GitHash)> = match &object.metadata {
+                GitObjectMetadata::Commit {
+                    parent_git_hashes,
+                    tree_git_hash,
+                } => std::iter::once(("tree", tree_git_hash))
+                    .chain(parent_git_hashes.iter().map(|parent| ("parent", parent)))
+                    .collect(),
+                GitObjectMetadata::Tag { target_git_hash } => vec![("target", target_git_hash)],
+                GitObjectMetadata::Tree { entry_git_hashes } => entry_git_hashes
+                    .iter()
+                    .map(|entry| ("entry", entry))
+                    .collect(),
+                GitObjectMetadata::Blob | GitObjectMetadata::ChunkedBlob { .. } => Vec::new(),
+            };
+
+            for (role, dependency) in dependencies {
+                if role == "parent" && self.shallow.contains(git_hash) {
+                    continue;
                 }
+                if !present(dependency) {
+                    missing.push(format!(
+                        "{} {}: {} {}",
+                        object.metadata.object_type(),
+                        git_hash,
+                        role,
+                        dependency
+                    ));
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            for line in &missing {
+                eprintln!("  {} is neither on chain nor in this push", line);
             }
+            error!(ExitCode::Verification.wrap(format!(
+                "Refusing to mint an incomplete push, {} objects it points to are missing. This is a bug, please report it",
+                missing.len()
+            )))
         }
 
-        Ok((new_ipf_id, None))
+        Ok(())
+    }
+
+    async fn fetch_git_objects(
+        &self,
+        oids: &HashSet<Oid>,
+        repo: &Repository,
+        ipfs: &Ipfs,
+        source: &mut impl MultiObjectSource,
+    ) -> Result<(), Box<dyn Error>> {
+        let known: Vec<&ObjectHeader> = oids
+            .iter()
+            .filter_map(|&oid| self.object_headers.get(&GitHash::from(oid)))
+            .collect();
+        if !known.is_empty() {
+            eprintln!(
+                "Fetching {} objects, {} of them totalling {} bytes",
+                oids.len(),
+                known.len(),
+                known.iter().map(|header| header.size).sum::<u64>()
+            );
+        }
+
+        let mut wanted: BTreeMap<String, Vec<Oid>> = BTreeMap::new();
+        for &oid in oids {
+            match self.objects.get(&GitHash::from(oid)) {
+                Some(hash) if hash != SUBMODULE_TIP_MARKER => {
+                    wanted.entry(hash.clone()).or_default().push(oid)
+                }
+                _ => {
+                    let msg = format!("Could not find object {} in the index", oid);
+                    debug!("{}", msg);
+                    return Err(msg.into());
+                }
+            }
+        }
+
+        // gitoxide writes loose objects for `git gc` to pack later
+        #[cfg(feature = "gix-backend")]
+        {
+            let store = GixStore::open(repo.path(), Fsync::load(repo))?;
+            self.write_fetched(&wanted, oids.len(), &store, ipfs, source)
+                .await?;
+        }
+
+        #[cfg(not(feature = "gix-backend"))]
+        {
+            // A handle of its own, so the in-memory backend doesn't outlive the fetch
+            let pack_repo = Repository::open(repo.path())?;
+            let pack_odb = pack_repo.odb()?;
+            let mempack = pack_odb.add_new_mempack_backend(MEMPACK_PRIORITY)?;
+
+            let new_objects = self
+                .write_fetched(&wanted, oids.len(), &pack_odb, ipfs, source)
+                .await?;
+
+            if new_objects > 0 {
+                let mut pack = Buf::new();
+                mempack.dump(&pack_repo, &mut pack)?;
+
+                // The in-memory backend can't take packs, so this one goes to the repository's
+                let pack_dir = repo.path().join("objects").join("pack");
+                let before = pack_files(&pack_dir)?;
+                let mut writer = pack_odb.packwriter()?;
+                writer.write_all(&pack)?;
+                writer.commit()?;
+                debug!("Wrote a pack of {} bytes", pack.len());
+
+                // libgit2 renames the pack into place without flushing it
+                if Fsync::load(repo).pack {
+                    for name in pack_files(&pack_dir)?.difference(&before) {
+                        sync_path(&pack_dir.join(name))?;
+                    }
+                    sync_path(&pack_dir)?;
+                }
+            }
+            mempack.reset()?;
+        }
+
+        Ok(())
+    }
+
+    async fn write_fetched<S: ObjectStore>(
+        &self,
+        wanted: &BTreeMap<String, Vec<Oid>>,
+        total: usize,
+        store: &S,
+        ipfs: &Ipfs,
+        source: &mut impl MultiObjectSource,
+    ) -> Result<usize, Box<dyn Error>> {
+        let (sender, mut receiver) = mpsc::channel(FETCH_PREFETCH_DEPTH);
+        let hashes: Vec<String> = wanted.keys().cloned().collect();
+
+        // Downloads on this task and decodes on the rayon pool, so the two overlap
+        let download = async move {
+            for hash in hashes {
+                let decoded = source.get_encoded(&hash).await.map(|encoded| {
+                    let (done, decoded) = oneshot::channel();
+                    rayon::spawn(move || {
+                        let _ = done.send(MultiObject::decode_verified(&hash, &encoded));
+                    });
+                    decoded
+                });
+                let failed = decoded.is_err();
+
+                // A closed channel means writing failed, so there is no point in going on
+                if sender.send(decoded).await.is_err() || failed {
+                    break;
+                }
+            }
+        };
+
+        // Owns the receiver, so a failed write closes the channel and stops the download
+        let sink = progress::sink();
+        let write = async move {
+            let mut written = 0;
+            let mut new_objects = 0;
+            let mut held_back = Vec::new();
+
+            while let Some(decoded) = receiver.recv().await {
+                let mut multi_object = decoded?
+                    .await
+                    .map_err(|_| "Decoding a MultiObject failed unexpectedly")?
+                    .map_err(|e| ExitCode::Verification.wrap(e))?;
+                chunking::reassemble(&mut multi_object, ipfs).await?;
+
+                for oid in &wanted[&multi_object.hash] {
+                    let git_object =
+                        multi_object
+                            .objects
+                            .get(&GitHash::from(*oid))
+                            .ok_or(format!(
+                                "Object {} is missing from its MultiObject {}",
+                                oid, multi_object.hash
+                            ))?;
+
+                    match git_object.metadata {
+                        GitObjectMetadata::Commit { .. } | GitObjectMetadata::Tag { .. } => {
+                            held_back.push(git_object.clone())
+                        }
+                        _ => {
+                            written += 1;
+                            debug!("[{}/{}] Fetching object {}", written, total, oid);
+                            sink.on_fetch_object(written, total);
+                            if self.write_git_object(store, git_object)? {
+                                new_objects += 1;
+                            }
+                        }
+                    }
+                }
+            }
+
+            for git_object in dependency_order(held_back) {
+                written += 1;
+                debug!(
+                    "[{}/{}] Fetching object {}",
+                    written, total, git_object.git_hash
+                );
+                sink.on_fetch_object(written, total);
+                if self.write_git_object(store, &git_object)? {
+                    new_objects += 1;
+                }
+            }
+
+            Ok::<usize, Box<dyn Error>>(new_objects)
+        };
+
+        let ((), new_objects) = futures::join!(download, write);
+
+        new_objects
+    }
+
+    fn write_git_object<S: ObjectStore>(
+        &self,
+        store: &S,
+        git_object: &GitObject,
+    ) -> Result<bool, Box<dyn Error>> {
+        let git_hash = &git_object.git_hash;
+
+        if store.contains(git_hash) {
+            debug!(
+                "fetch objects: Object {} already present locally!",
+                git_hash
+            );
+            return Ok(false);
+        }
+
+        if let Some(header) = self.object_headers.get(git_hash) {
+            git_object.check_header(header)?;
+        }
+
+        let written = store.write(git_object.header().kind, &git_object.data)?;
+        if &written != git_hash {
+            let msg = format!(
+                "Object tree inconsistency detected: fetched {}, but write result hashes to {}",
+                git_hash, written
+            );
+            debug!("{}", msg);
+            error!(ExitCode::Verification.wrap(msg))
+        }
+        debug!("Fetched object {}", written);
+
+        Ok(true)
+    }
+
+    async fn mint_return_new_old_id(
+        &self,
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+        ips_id: IpsId,
+        subasset_id: Option<u32>,
+        receipt: Option<&PushReceipt>,
+        manifest: Option<&Manifest>,
+        pack_ipf_ids: &[IpfId],
+        config: &Config,
+    ) -> Result<MintedRepoData, Box<dyn Error>> {
+        // The new RepoData isn't in the IPS yet, so this is the one it replaces
+        let old_repo_data = find_ipf(chain_api, ips_id, "RepoData").await?;
+        let old_manifest = match manifest {
+            Some(_) => find_ipf(chain_api, ips_id, MANIFEST_NAME).await?,
+            None => None,
+        };
+        let (old_index, index) = if config.object_index {
+            let old_index = find_ipf(chain_api, ips_id, INDEX_NAME).await?;
+            let index = ObjectIndex::updated(
+                chain_api,
+                ipfs,
+                old_index.as_ref().map(|(_, cid)| cid),
+                self,
+                pack_ipf_ids,
+            )
+            .await?;
+            (old_index, Some(index))
+        } else {
+            (None, None)
+        };
+
+        let repo_data = if config.sign_repo_data {
+            let previous = match &old_repo_data {
+                Some((_, old_cid)) => Some(ipfs_cat(ipfs, old_cid).await?),
+                None => None,
+            };
+            self.signed(signer.signer(), previous.as_deref())?
+        } else {
+            Self {
+                signature: None,
+                ..self.clone()
+            }
+        };
+
+        let encoded = repo_data.encode()?;
+        let (cid, ipfs_hash) = ipfs_add(ipfs, encoded.clone(), config.cid_format).await?;
+
+        if config.mfs_mirror {
+            mfs_mirror(ipfs, ips_id, "RepoData", &cid).await;
+        }
+
+        let metadata = encode_ipf_metadata(
+            "RepoData",
+            &cid,
+            config.cid_format,
+            config.license.as_deref(),
+        );
+
+        let mut calls = vec![Call::Ipf(IpfCall::mint {
+            metadata: metadata.clone(),
+            data: ipfs_hash,
+        })];
+
+        // The receipt points at the same content, so it can be checked against the RepoData
+        if let Some(receipt) = receipt {
+            calls.push(Call::Ipf(IpfCall::mint {
+                metadata: receipt.to_metadata(),
+                data: ipfs_hash,
+            }));
+        }
+
+        let manifest_cid = match manifest {
+            Some(manifest) => {
+                let (manifest_cid, manifest_hash) = ipfs_add(
+                    ipfs,
+                    manifest.encode(blake2_256(&encoded)),
+                    config.cid_format,
+                )
+                .await?;
+                calls.push(Call::Ipf(IpfCall::mint {
+                    metadata: encode_ipf_metadata(
+                        MANIFEST_NAME,
+                        &manifest_cid,
+                        config.cid_format,
+                        config.license.as_deref(),
+                    ),
+                    data: manifest_hash,
+                }));
+                Some(manifest_cid)
+            }
+            None => None,
+        };
+
+        let index_cid = match &index {
+            Some(index) => {
+                let (index_cid, index_hash) =
+                    ipfs_add(ipfs, index.encode(), config.cid_format).await?;
+                calls.push(Call::Ipf(IpfCall::mint {
+                    metadata: encode_ipf_metadata(
+                        INDEX_NAME,
+                        &index_cid,
+                        config.cid_format,
+                        config.license.as_deref(),
+                    ),
+                    data: index_hash,
+                }));
+                Some(index_cid)
+            }
+            None => None,
+        };
+
+        let tx_events = if config.treasury_pays {
+            let call = match calls.len() {
+                1 => calls.remove(0),
+                _ => Call::Utility(UtilityCall::batch_all { calls }),
+            };
+            treasury::submit(chain_api, signer, ips_id, subasset_id, call).await?
+        } else {
+            // The batch pays for the receipt, manifest and index mints as well, so it gets its
+            // own ledger label instead of inflating "ipf.mint"
+            let extrinsic = match calls.len() {
+                1 => "ipf.mint",
+                _ => "repo_data.batch",
+            };
+            let progress = match calls.len() {
+                1 => {
+                    let tx = chain_api.tx().ipf().mint(metadata, ipfs_hash)?;
+                    resubmit::sign_and_submit("ipf.mint", || {
+                        tx.sign_and_submit_then_watch_default(signer)
+                    })
+                    .await?
+                }
+                _ => {
+                    let tx = chain_api.tx().utility().batch_all(calls)?;
+                    resubmit::sign_and_submit("utility.batch_all", || {
+                        tx.sign_and_submit_then_watch_default(signer)
+                    })
+                    .await?
+                }
+            };
+            let events = within(
+                Operation::Extrinsic,
+                "waiting for the RepoData mint to be included in a block",
+                progress.wait_for_in_block(),
+            )
+            .await?;
+
+            let tx_events = events.fetch_events().await?;
+            ledger::record(extrinsic, &tx_events, signer.account_id())?;
+
+            events
+                .wait_for_success()
+                .await
+                .map_err(|e| ExitCode::PermissionDenied.wrap(e))?;
+
+            tx_events
+        };
+
+        let minted = tx_events
+            .find::<invarch::ipf::events::Minted>()
+            .map(|minted| minted.map(|minted| IpfId(minted.1)))
+            .collect::<Result<Vec<IpfId>, _>>()?;
+
+        let new_ipf_id = *minted
+            .first()
+            .ok_or("RepoData mint emitted no Minted event")?;
+        let receipt_ipf_id = receipt.and_then(|_| minted.get(1).copied());
+        let manifest = match manifest_cid {
+            Some(manifest_cid) => {
+                let manifest_ipf_id = *minted
+                    .get(1 + receipt.is_some() as usize)
+                    .ok_or("Manifest mint emitted no Minted event")?;
+                ipfs_pin(ipfs, &manifest_cid).await;
+                Some((manifest_ipf_id, manifest_cid))
+            }
+            None => None,
+        };
+        let index = match index_cid {
+            Some(index_cid) => {
+                let index_ipf_id = *minted
+                    .get(1 + receipt.is_some() as usize + manifest.is_some() as usize)
+                    .ok_or("Object index mint emitted no Minted event")?;
+                ipfs_pin(ipfs, &index_cid).await;
+                Some((index_ipf_id, index_cid))
+            }
+            None => None,
+        };
+
+        ipfs_pin(ipfs, &cid).await;
+
+        if let Some(backup) = &config.backup {
+            backup
+                .store(chain_api, ips_id, tx_events.block_hash(), &encoded, config)
+                .await;
+        }
+
+        let sink = progress::sink();
+        sink.on_mint(new_ipf_id);
+        if let Some((manifest_ipf_id, _)) = &manifest {
+            sink.on_mint(*manifest_ipf_id);
+        }
+        if let Some((index_ipf_id, _)) = &index {
+            sink.on_mint(*index_ipf_id);
+        }
+        sink.on_phase_change(Phase::Done);
+
+        eprintln!("Minted Repo Data on-chain with IPF ID: {}", new_ipf_id);
+        if let Some(receipt_ipf_id) = receipt_ipf_id {
+            eprintln!(
+                "Minted Push Receipt on-chain with IPF ID: {}",
+                receipt_ipf_id
+            );
+        }
+        if let Some((manifest_ipf_id, _)) = &manifest {
+            eprintln!("Minted Manifest on-chain with IPF ID: {}", manifest_ipf_id);
+        }
+        if let Some((index_ipf_id, _)) = &index {
+            eprintln!("Minted Object Index on-chain with IPF ID: {}", index_ipf_id);
+        }
+
+        Ok(MintedRepoData {
+            repo_data: (new_ipf_id, cid),
+            receipt: receipt_ipf_id,
+            manifest,
+            index,
+            old_repo_data,
+            old_manifest,
+            old_index,
+        })
+    }
+
+    async fn replace_on_chain(
+        &self,
+        pack_ipf_ids: &[IpfId],
+        ipfs: &mut Ipfs,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+        ips_id: IpsId,
+        subasset_id: Option<u32>,
+        receipt: Option<&PushReceipt>,
+        manifest: Option<&Manifest>,
+        config: &Config,
+    ) -> Result<(), Box<dyn Error>> {
+        let minted = self
+            .mint_return_new_old_id(
+                ipfs,
+                chain_api,
+                signer,
+                ips_id,
+                subasset_id,
+                receipt,
+                manifest,
+                pack_ipf_ids,
+                config,
+            )
+            .await?;
+
+        let mut appended = pack_ipf_ids.to_vec();
+        appended.push(minted.repo_data.0);
+        appended.extend(minted.receipt);
+        appended.extend(minted.manifest.as_ref().map(|(id, _)| *id));
+        appended.extend(minted.index.as_ref().map(|(id, _)| *id));
+
+        let mut replaced: Vec<&(IpfId, Cid)> = minted.old_repo_data.iter().collect();
+        replaced.extend(&minted.old_manifest);
+        replaced.extend(&minted.old_index);
+
+        let result = Self::swap_on_chain(
+            &appended,
+            &replaced.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            chain_api,
+            signer,
+            ips_id,
+            subasset_id,
+        )
+        .await;
+
+        match result {
+            Ok(removed) => {
+                if removed {
+                    for (_, old_cid) in &replaced {
+                        ipfs_unpin(ipfs, old_cid).await;
+                    }
+                    let replaced_ids: Vec<IpfId> = replaced.iter().map(|(id, _)| *id).collect();
+                    reclaim::burn(chain_api, signer, &replaced_ids, config.reclaim_deposits).await;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                ipfs_unpin(ipfs, &minted.repo_data.1).await;
+                if let Some((_, manifest_cid)) = &minted.manifest {
+                    ipfs_unpin(ipfs, manifest_cid).await;
+                }
+                if let Some((_, index_cid)) = &minted.index {
+                    ipfs_unpin(ipfs, index_cid).await;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn swap_on_chain(
+        appended: &[IpfId],
+        replaced: &[IpfId],
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+        ips_id: IpsId,
+        subasset_id: Option<u32>,
+    ) -> Result<bool, Box<dyn Error>> {
+        eprintln!(
+            "Appending new objects and repo data to repository under IPS ID: {}",
+            ips_id
+        );
+
+        let present: BTreeSet<u64> = ips_ipf_ids(chain_api, ips_id).await?.into_iter().collect();
+        let missing: Vec<IpfId> = appended
+            .iter()
+            .filter(|id| !present.contains(&id.0))
+            .copied()
+            .collect();
+        let batch_size = append_batch_size(chain_api)?;
+        let batches = missing.chunks(batch_size).len();
+
+        for (i, batch) in missing.chunks(batch_size).enumerate() {
+            if batches > 1 {
+                eprintln!(
+                    "Appending {} IPFs, batch {} of {}",
+                    batch.len(),
+                    i + 1,
+                    batches
+                );
+            }
+
+            let append_call = Call::INV4(IpsCall::append {
+                ips_id: ips_id.0,
+                assets: batch.iter().map(|id| AnyId::IpfId(id.0)).collect(),
+                new_metadata: None,
+            });
+
+            let tx = chain_api.tx().inv4().operate_multisig(
+                true,
+                (ips_id.0, subasset_id),
+                append_call,
+            )?;
+            let progress = resubmit::sign_and_submit("inv4.append", || {
+                tx.sign_and_submit_then_watch_default(signer)
+            })
+            .await?;
+            let tx_events = within(
+                Operation::Extrinsic,
+                "waiting for inv4.append to be included in a block",
+                progress.wait_for_in_block(),
+            )
+            .await?
+            .fetch_events()
+            .await?;
+            ledger::record("inv4.append", &tx_events, signer.account_id())?;
+
+            // The rest would go to the vote too, and readers would see a RepoData without objects
+            if tx_events
+                .find_first::<invarch::inv4::events::MultisigVoteStarted>()?
+                .is_some()
+            {
+                error!(format!(
+                    "Appending to IPS {} started a multisig vote instead, push again once it passed",
+                    ips_id
+                ))
+            }
+        }
+
+        let present: BTreeSet<u64> = ips_ipf_ids(chain_api, ips_id).await?.into_iter().collect();
+        let lacking: Vec<String> = appended
+            .iter()
+            .filter(|id| !present.contains(&id.0))
+            .map(ToString::to_string)
+            .collect();
+        if !lacking.is_empty() {
+            error!(format!(
+                "IPS {} lacks IPFs {} after appending them",
+                ips_id,
+                lacking.join(", ")
+            ))
+        }
+
+        eprintln!("New objects successfully appended to on-chain repository!");
+
+        if replaced.is_empty() {
+            return Ok(false);
+        }
+
+        let old_ids = replaced
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        eprintln!("Removing old Repo Data with IPF ID: {}", old_ids);
+
+        let removed = async {
+            let remove_call = Call::INV4(IpsCall::remove {
+                ips_id: ips_id.0,
+                assets: replaced
+                    .iter()
+                    .map(|old_id| (AnyId::IpfId(old_id.0), signer.account_id().clone()))
+                    .collect(),
+                new_metadata: None,
+            });
+
+            let tx = chain_api.tx().inv4().operate_multisig(
+                false,
+                (ips_id.0, subasset_id),
+                remove_call,
+            )?;
+            let progress = resubmit::sign_and_submit("inv4.remove", || {
+                tx.sign_and_submit_then_watch_default(signer)
+            })
+            .await?;
+            let tx_events = within(
+                Operation::Extrinsic,
+                "waiting for inv4.remove to be included in a block",
+                progress.wait_for_in_block(),
+            )
+            .await?
+            .fetch_events()
+            .await?;
+            ledger::record("inv4.remove", &tx_events, signer.account_id())
+        }
+        .await;
+
+        match removed {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                eprintln!(
+                    "Warning: could not remove the old Repo Data with IPF ID {}, the new one is used anyway: {}",
+                    old_ids, e
+                );
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// The name of the remote in `repo` whose URL points at `ips_id`, for hints that name it.
+///
+/// A nested IPS can be anywhere along the URL's path, as in `inv4://<parent>/<ips_id>`.
+fn remote_name(repo: &Repository, ips_id: IpsId) -> String {
+    let points_at_ips = |url: &str| {
+        url.strip_prefix("inv4://")
+            .and_then(|rest| rest.split('?').next())
+            .is_some_and(|path| path.split('/').any(|id| id == ips_id.to_string()))
+    };
+
+    repo.remotes()
+        .ok()
+        .and_then(|names| {
+            names
+                .iter()
+                .flatten()
+                .find(|name| {
+                    repo.find_remote(name)
+                        .ok()
+                        .and_then(|remote| remote.url().map(points_at_ips))
+                        .unwrap_or(false)
+                })
+                .map(str::to_owned)
+        })
+        .unwrap_or_else(|| "<remote>".to_owned())
+}
+
+/// Every object reachable from `tips` in `repo`, or `None` if some of them aren't there.
+///
+/// Submodule commits in trees count as reached without being walked.
+fn reachable_locally(
+    repo: &Repository,
+    tips: impl IntoIterator<Item = Oid>,
+) -> BoxResult<Option<HashSet<Oid>>> {
+    let mut reached = HashSet::new();
+    let mut stack: Vec<Oid> = tips.into_iter().collect();
+
+    while let Some(oid) = stack.pop() {
+        if !reached.insert(oid) {
+            continue;
+        }
+
+        let obj = match repo.find_object(oid, None) {
+            Ok(obj) => obj,
+            Err(_) => return Ok(None),
+        };
+
+        if let Some(commit) = obj.as_commit() {
+            stack.push(commit.tree_id());
+            stack.extend(commit.parent_ids());
+        } else if let Some(tag) = obj.as_tag() {
+            stack.push(tag.target_id());
+        } else if let Some(tree) = obj.as_tree() {
+            for entry in tree.iter() {
+                match entry.kind() {
+                    Some(ObjectType::Commit) => {
+                        reached.insert(entry.id());
+                    }
+                    _ => stack.push(entry.id()),
+                }
+            }
+        }
+    }
+
+    Ok(Some(reached))
+}
+
+/// Orders commits and tags so that whatever they point at among `objects` comes before them.
+fn dependency_order(objects: Vec<GitObject>) -> Vec<GitObject> {
+    let mut pending: BTreeMap<GitHash, GitObject> = objects
+        .into_iter()
+        .map(|object| (object.git_hash.clone(), object))
+        .collect();
+    let mut ordered = Vec::with_capacity(pending.len());
+
+    while let Some(first) = pending.keys().next().cloned() {
+        let mut stack = vec![first];
+
+        while let Some(git_hash) = stack.last().cloned() {
+            let object = match pending.get(&git_hash) {
+                Some(object) => object,
+                None => {
+                    stack.pop();
+                    continue;
+                }
+            };
+
+            let dependency = match &object.metadata {
+                GitObjectMetadata::Commit {
+                    parent_git_hashes, ..
+                } => parent_git_hashes
+                    .iter()
+                    .find(|parent| pending.contains_key(*parent)),
+                GitObjectMetadata::Tag { target_git_hash } => {
+                    Some(target_git_hash).filter(|target| pending.contains_key(*target))
+                }
+                _ => None,
+            }
+            .cloned();
+
+            match dependency {
+                Some(dependency) => stack.push(dependency),
+                None => {
+                    stack.pop();
+                    ordered.extend(pending.remove(&git_hash));
+                }
+            }
+        }
+    }
+
+    ordered
+}
+
+const TREE_MODE: u32 = 0o040000;
+const EXECUTABLE_MODE: u32 = 0o100755;
+const SYMLINK_MODE: u32 = 0o120000;
+pub const SUBMODULE_MODE: u32 = 0o160000;
+
+/// One entry of a raw git tree object.
+pub struct RawTreeEntry {
+    pub mode: u32,
+    pub name: OsString,
+    pub git_hash: GitHash,
+}
+
+/// Splits the raw data of a tree object into its entries.
+///
+/// Fails on names that would leave the directory the tree is checked out to.
+pub fn parse_tree(data: &[u8], hash_algorithm: HashAlgorithm) -> BoxResult<Vec<RawTreeEntry>> {
+    let mut entries = Vec::new();
+    let mut rest = data;
+
+    while !rest.is_empty() {
+        let (space, nul) = match (
+            rest.iter().position(|&b| b == b' '),
+            rest.iter().position(|&b| b == 0),
+        ) {
+            (Some(space), Some(nul))
+                if space < nul && rest.len() >= nul + 1 + hash_algorithm.raw_len() =>
+            {
+                (space, nul)
+            }
+            _ => error!("Malformed tree object"),
+        };
+
+        let mode = u32::from_str_radix(std::str::from_utf8(&rest[..space])?, 8)?;
+        let name = &rest[space + 1..nul];
+        if matches!(name, b"" | b"." | b".." | b".git") || name.contains(&b'/') {
+            error!(format!(
+                "Refusing to check out the tree entry {:?}",
+                String::from_utf8_lossy(name)
+            ))
+        }
+
+        let hash_end = nul + 1 + hash_algorithm.raw_len();
+        entries.push(RawTreeEntry {
+            mode,
+            name: OsStr::from_bytes(name).to_owned(),
+            git_hash: GitHash::from_raw(&rest[nul + 1..hash_end]),
+        });
+        rest = &rest[hash_end..];
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        advertise::RefFilter, compression::Compression, maintenance::Fetched, namespace::Namespace,
+    };
+    use futures::future::LocalBoxFuture;
+    use git2::Commit;
+    use std::{
+        fs::{File, OpenOptions},
+        io::{BufRead, BufReader, Write},
+        os::unix::fs::PermissionsExt,
+        process::{Command, Stdio},
+        rc::Rc,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+    use temp_dir::TempDir;
+
+    /// The MultiObjects of a push, by hash.
+    struct Pushed(HashMap<String, Rc<MultiObject>>);
+
+    impl MultiObjectSource for Pushed {
+        fn get<'b>(&'b mut self, hash: &'b str) -> LocalBoxFuture<'b, BoxResult<Rc<MultiObject>>> {
+            let multi_object = self.0.get(hash).cloned().ok_or("Not pushed");
+            Box::pin(async move { Ok(multi_object?) })
+        }
+
+        fn get_encoded<'b>(&'b mut self, hash: &'b str) -> LocalBoxFuture<'b, BoxResult<Vec<u8>>> {
+            let encoded = self.0.get(hash).ok_or("Not pushed").map(|multi_object| {
+                multi_object.encode_upload(Compression::None, &BTreeMap::new(), None)
+            });
+            Box::pin(async move { Ok(encoded?) })
+        }
+    }
+
+    /// A RepoData `tip` of `repo` was pushed to, as `push_from_str` records it.
+    fn push(repo: &Repository, tip: Oid) -> (RepoData, Pushed) {
+        let mut repo_data = RepoData::new(HashAlgorithm::Sha1);
+        let mut objects = HashSet::new();
+        let mut submodules = HashSet::new();
+        repo_data
+            .enumerate_for_push(
+                &repo.find_object(tip, None).unwrap(),
+                &mut objects,
+                &mut submodules,
+                repo,
+            )
+            .unwrap();
+
+        let multi_object = repo_data.collect_git_objects(&objects, repo).unwrap();
+        for submodule in submodules {
+            repo_data
+                .objects
+                .insert(submodule.into(), SUBMODULE_TIP_MARKER.to_owned());
+        }
+
+        let pushed = HashMap::from([(multi_object.hash.clone(), Rc::new(multi_object))]);
+        (repo_data, Pushed(pushed))
+    }
+
+    /// Commits `tree` with `parents`, without moving any ref.
+    fn commit(repo: &Repository, tree: Oid, parents: &[Oid]) -> Oid {
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+        let parents: Vec<Commit> = parents
+            .iter()
+            .map(|&parent| repo.find_commit(parent).unwrap())
+            .collect();
+
+        repo.commit(
+            None,
+            &signature,
+            &signature,
+            "Commit",
+            &repo.find_tree(tree).unwrap(),
+            &parents.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    /// A tree holding `contents` as the file `name`.
+    fn tree_of(repo: &Repository, name: &str, contents: &[u8]) -> Oid {
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder
+            .insert(name, repo.blob(contents).unwrap(), 0o100644)
+            .unwrap();
+        builder.write().unwrap()
+    }
+
+    /// What `fetch_objects_for` does with `tip` of `repo_data`, with the MultiObjects read from
+    /// `pushed`: writes the objects `repo` lacks, and nothing else.
+    fn fetch_objects(repo_data: &RepoData, pushed: &mut Pushed, repo: &Repository, tip: Oid) {
+        let mut fetch_todo = HashSet::new();
+        futures::executor::block_on(repo_data.enumerate_from(tip, &mut fetch_todo, repo, pushed))
+            .unwrap();
+
+        let odb = repo.odb().unwrap();
+        for multi_object in pushed.0.values() {
+            for (git_hash, object) in &multi_object.objects {
+                if fetch_todo.contains(&git_hash.to_oid().unwrap()) {
+                    repo_data.write_git_object(&odb, object).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Every ref of `repo` and what it points at, with a `FETCH_HEAD <what>` for every ref
+    /// FETCH_HEAD records, less the URL it was fetched from.
+    fn refs(repo: &Repository) -> Vec<(String, Option<Oid>)> {
+        let mut refs: Vec<(String, Option<Oid>)> = repo
+            .references()
+            .unwrap()
+            .map(|reference| {
+                let reference = reference.unwrap();
+                (reference.name().unwrap().to_owned(), reference.target())
+            })
+            .collect();
+        if let Ok(fetch_head) = std::fs::read_to_string(repo.path().join("FETCH_HEAD")) {
+            // `<oid>\t[not-for-merge]\t<what> of <url>`
+            for line in fetch_head.lines() {
+                let (oid, what) = line.split_once('\t').unwrap();
+                let what = what.rsplit_once(" of ").map_or(what, |(what, _)| what);
+                refs.push((format!("FETCH_HEAD {}", what), Oid::from_str(oid).ok()));
+            }
+        }
+        refs.sort();
+        refs
+    }
+
+    /// git in `dir`, ignoring the user's configuration.
+    fn git_command(dir: &Path, args: &[&str]) -> Command {
+        let mut command = Command::new("git");
+        command
+            .current_dir(dir)
+            .args(args)
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("HOME", dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        command
+    }
+
+    /// Runs git in `dir`, ignoring the user's configuration.
+    fn git(dir: &Path, args: &[&str]) {
+        let status = git_command(dir, args).status().unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    /// Answers the helper commands of one session of git's on `input` with the handlers of the
+    /// command loop in main.rs, reading MultiObjects from `pushed`.
+    async fn serve_fetch(
+        repo_data: &RepoData,
+        pushed: &mut Pushed,
+        repo: &Repository,
+        input: impl BufRead,
+        mut output: impl Write,
+    ) -> BoxResult<()> {
+        let ipfs = Ipfs::default();
+        let mut fetched = Fetched::default();
+
+        for line in input.lines() {
+            let line = line?;
+            let mut args = line.split_ascii_whitespace();
+
+            match (args.next(), args.next(), args.next()) {
+                (Some("capabilities"), None, None) => crate::capabilities(&mut output)?,
+                (Some("option"), Some(_), _) => writeln!(output, "unsupported")?,
+                (Some("list"), _, None) => crate::list(
+                    repo_data,
+                    &RefFilter::default(),
+                    &Namespace::default(),
+                    &mut output,
+                )?,
+                (Some("fetch"), Some(sha), Some(name)) => {
+                    crate::fetch(
+                        repo_data,
+                        pushed,
+                        repo,
+                        &ipfs,
+                        sha,
+                        name,
+                        "origin",
+                        false,
+                        &mut fetched,
+                        &mut output,
+                    )
+                    .await?
+                }
+                (None, None, None) => {}
+                _ => return Err(format!("Unexpected helper command {:?}", line).into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// What fetching `tip` of `repo_data` into an empty repository enumerates.
+    fn enumerated(repo_data: &RepoData, pushed: &mut Pushed, tip: Oid) -> HashSet<Oid> {
+        let dir = TempDir::new().unwrap();
+        let fetching = Repository::init_bare(dir.path()).unwrap();
+        let mut fetch_todo = HashSet::new();
+
+        futures::executor::block_on(repo_data.enumerate_from(
+            tip,
+            &mut fetch_todo,
+            &fetching,
+            pushed,
+        ))
+        .unwrap();
+        fetch_todo
+    }
+
+    /// A repository with one commit of an empty tree, and that commit's hash.
+    fn repo_with_commit(dir: &TempDir) -> (Repository, Oid) {
+        let repo = Repository::init(dir.path()).unwrap();
+        let tree = repo.treebuilder(None).unwrap().write().unwrap();
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+
+        let commit = repo
+            .commit(
+                None,
+                &signature,
+                &signature,
+                "Initial commit",
+                &repo.find_tree(tree).unwrap(),
+                &[],
+            )
+            .unwrap();
+        (repo, commit)
+    }
+
+    #[test]
+    fn multi_objects_are_named_after_the_types_they_hold() {
+        let dir = TempDir::new().unwrap();
+        let (repo, commit) = repo_with_commit(&dir);
+        let tree = repo.find_commit(commit).unwrap().tree_id();
+
+        let pushed = MultiObject::rebuild(&[tree.into(), commit.into()], &repo).unwrap();
+        assert_eq!(pushed.object_type(), "commit");
+
+        let trees = MultiObject::rebuild(&[tree.into()], &repo).unwrap();
+        assert_eq!(trees.object_type(), "tree");
+    }
+
+    #[test]
+    fn large_blobs_are_left_in_the_object_database_until_streamed() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let large = repo
+            .blob(&vec![7u8; STREAMED_BLOB_BYTES as usize + 1])
+            .unwrap();
+        let small = repo.blob(b"small").unwrap();
+
+        let mut repo_data = RepoData::new(HashAlgorithm::Sha1);
+        let multi_object = repo_data
+            .collect_git_objects(&HashSet::from([large, small]), &repo)
+            .unwrap();
+
+        let streamed = &multi_object.objects[&GitHash::from(large)];
+        assert!(streamed.data.is_empty());
+        assert_eq!(
+            streamed.streamed,
+            Some(StreamedBlob {
+                size: STREAMED_BLOB_BYTES + 1,
+                checksum: None,
+            })
+        );
+        assert_eq!(
+            repo_data.object_headers[&GitHash::from(large)].size,
+            STREAMED_BLOB_BYTES + 1
+        );
+        assert!(integrity::check(&multi_object).is_ok());
+
+        let loaded = &multi_object.objects[&GitHash::from(small)];
+        assert_eq!(loaded.data, b"small");
+        assert_eq!(loaded.streamed, None);
+    }
+
+    #[test]
+    fn empty_trees_and_blobs_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let (repo, commit) = repo_with_commit(&dir);
+        let empty_tree = repo.find_commit(commit).unwrap().tree_id();
+        let empty_blob = repo.blob(b"").unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("empty", empty_blob, 0o100644).unwrap();
+        let tree = builder.write().unwrap();
+
+        let git_hashes: Vec<GitHash> = [empty_blob, tree, empty_tree, commit]
+            .into_iter()
+            .map(GitHash::from)
+            .collect();
+        let multi_object = MultiObject::rebuild(&git_hashes, &repo).unwrap();
+        assert!(multi_object.objects[&GitHash::from(empty_blob)]
+            .data
+            .is_empty());
+
+        for compression in [Compression::None, Compression::Zstd, Compression::Brotli] {
+            let encoded = multi_object.encode_upload(compression, &BTreeMap::new(), None);
+            let decoded = MultiObject::decode_verified(&multi_object.hash, &encoded).unwrap();
+            assert_eq!(decoded.git_hashes, git_hashes);
+
+            let fetched_dir = TempDir::new().unwrap();
+            let fetched = Repository::init_bare(fetched_dir.path()).unwrap();
+            let odb = fetched.odb().unwrap();
+            let repo_data = RepoData::new(HashAlgorithm::Sha1);
+            for git_hash in &git_hashes {
+                let object = &decoded.objects[git_hash];
+                assert_eq!(object.data, multi_object.objects[git_hash].data);
+                repo_data.write_git_object(&odb, object).unwrap();
+                assert!(odb.exists(git_hash.to_oid().unwrap()));
+            }
+        }
+    }
+
+    #[test]
+    fn bare_repositories_push_and_fetch() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let tree = tree_of(&repo, "file", b"contents");
+        let tip = commit(&repo, tree, &[]);
+        let (repo_data, mut pushed) = push(&repo, tip);
+
+        let fetched_dir = TempDir::new().unwrap();
+        let fetched = Repository::init_bare(fetched_dir.path()).unwrap();
+        fetch_objects(&repo_data, &mut pushed, &fetched, tip);
+        fetched
+            .reference("refs/heads/main", tip, false, "fetch")
+            .unwrap();
+        let tree = fetched.find_commit(tip).unwrap().tree().unwrap();
+        assert!(tree.get_name("file").is_some());
+    }
+
+    #[tokio::test]
+    async fn fetches_stop_downloading_when_a_multi_object_fails_to_write() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+
+        // More MultiObjects after the broken one than the download may queue
+        let mut pushed = Pushed(HashMap::new());
+        let mut wanted = BTreeMap::new();
+        for i in 0..FETCH_PREFETCH_DEPTH * 3 {
+            let blob = repo.blob(format!("blob {}", i).as_bytes()).unwrap();
+            let multi_object = MultiObject::rebuild(&[blob.into()], &repo).unwrap();
+            wanted.insert(multi_object.hash.clone(), vec![blob]);
+            pushed
+                .0
+                .insert(multi_object.hash.clone(), Rc::new(multi_object));
+        }
+
+        // The second MultiObject's IPF holds the first one instead
+        let hashes: Vec<String> = wanted.keys().cloned().collect();
+        let first = pushed.0[&hashes[0]].clone();
+        pushed.0.insert(hashes[1].clone(), first);
+
+        let fetched_dir = TempDir::new().unwrap();
+        let fetched = Repository::init_bare(fetched_dir.path()).unwrap();
+        let odb = fetched.odb().unwrap();
+        let repo_data = RepoData::new(HashAlgorithm::Sha1);
+
+        let written = tokio::time::timeout(
+            std::time::Duration::from_secs(60),
+            repo_data.write_fetched(&wanted, wanted.len(), &odb, &Ipfs::default(), &mut pushed),
+        )
+        .await
+        .expect("the fetch hung after a failed write");
+
+        let e = written.unwrap_err().to_string();
+        assert!(e.contains(&hashes[1]), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn helper_fetches_leave_refs_to_git_like_a_standard_remote() {
+        let remote_dir = TempDir::new().unwrap();
+        let remote = Repository::init(remote_dir.path()).unwrap();
+        let remote_tree = tree_of(&remote, "file", b"remote");
+        let tip = commit(&remote, remote_tree, &[]);
+        remote
+            .reference("refs/heads/main", tip, false, "test")
+            .unwrap();
+        remote
+            .reference("refs/tags/v1", tip, false, "test")
+            .unwrap();
+        remote.set_head("refs/heads/main").unwrap();
+
+        let (mut repo_data, mut pushed) = push(&remote, tip);
+        repo_data
+            .refs
+            .insert("refs/heads/main".to_owned(), tip.into());
+        repo_data.refs.insert("refs/tags/v1".to_owned(), tip.into());
+        repo_data
+            .symrefs
+            .insert("HEAD".to_owned(), "refs/heads/main".to_owned());
+
+        // A local main that fetching must not move, in a repository with either as its origin
+        let with_origin = |url: &str| {
+            let dir = TempDir::new().unwrap();
+            let repo = Repository::init(dir.path()).unwrap();
+            let tree = tree_of(&repo, "file", b"local");
+            let main = commit(&repo, tree, &[]);
+            repo.reference("refs/heads/main", main, false, "test")
+                .unwrap();
+            git(dir.path(), &["remote", "add", "origin", url]);
+            (dir, repo, main)
+        };
+        let (standard_dir, standard, standard_main) =
+            with_origin(remote_dir.path().to_str().unwrap());
+        let (inv4_dir, inv4, inv4_main) = with_origin("stub::remote");
+
+        git(standard_dir.path(), &["fetch", "origin"]);
+
+        // git runs `git-remote-stub` for `stub::` URLs, which hands its stdin and stdout on to
+        // this test through FIFOs
+        let helper_dir = TempDir::new().unwrap();
+        let commands = helper_dir.path().join("commands");
+        let answers = helper_dir.path().join("answers");
+        for fifo in [&commands, &answers] {
+            assert!(Command::new("mkfifo").arg(fifo).status().unwrap().success());
+        }
+        let helper = helper_dir.path().join("git-remote-stub");
+        std::fs::write(
+            &helper,
+            format!(
+                "#!/bin/sh\nexec 3<&0\ncat <&3 >'{}' &\nexec cat '{}'\n",
+                commands.display(),
+                answers.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let path = format!(
+            "{}:{}",
+            helper_dir.path().display(),
+            std::env::var("PATH").unwrap_or_default()
+        );
+        let mut fetch = git_command(inv4_dir.path(), &["fetch", "origin"])
+            .env("PATH", path)
+            .spawn()
+            .unwrap();
+
+        // Once git is done, the wait for its next session is ended by connecting to it
+        let done = Arc::new(AtomicBool::new(false));
+        let waiting = std::thread::spawn({
+            let (done, commands, answers) = (done.clone(), commands.clone(), answers.clone());
+            move || {
+                let status = fetch.wait().unwrap();
+                done.store(true, Ordering::SeqCst);
+                let _commands = OpenOptions::new().write(true).open(commands).unwrap();
+                let _answers = File::open(answers).unwrap();
+                status
+            }
+        });
+
+        loop {
+            let input = File::open(&commands).unwrap();
+            let output = OpenOptions::new().write(true).open(&answers).unwrap();
+            if done.load(Ordering::SeqCst) {
+                break;
+            }
+            serve_fetch(
+                &repo_data,
+                &mut pushed,
+                &inv4,
+                BufReader::new(input),
+                output,
+            )
+            .await
+            .unwrap();
+        }
+        assert!(waiting.join().unwrap().success(), "git fetch failed");
+
+        // git writes FETCH_HEAD and the remote-tracking ref, follows the tag and leaves local
+        // branches be, whichever remote it fetched from
+        let fetched = |repo: &Repository, main: Oid| {
+            let mut fetched = refs(repo);
+            let local = ("refs/heads/main".to_owned(), Some(main));
+            assert!(fetched.contains(&local));
+            fetched.retain(|reference| *reference != local);
+            fetched
+        };
+        let standard_refs = fetched(&standard, standard_main);
+        assert!(standard_refs.contains(&("refs/remotes/origin/main".to_owned(), Some(tip))));
+        assert!(standard_refs.contains(&("refs/tags/v1".to_owned(), Some(tip))));
+        assert!(standard_refs.contains(&(
+            "FETCH_HEAD not-for-merge\tbranch 'main'".to_owned(),
+            Some(tip)
+        )));
+        assert_eq!(fetched(&inv4, inv4_main), standard_refs);
+        assert!(inv4.find_commit(tip).is_ok());
+    }
+
+    #[test]
+    fn fetches_enumerate_every_root_of_a_merge_of_orphan_branches() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let main_tree = tree_of(&repo, "main", b"main");
+        let main = commit(&repo, main_tree, &[]);
+        let orphan_tree = tree_of(&repo, "orphan", b"orphan");
+        let orphan = commit(&repo, orphan_tree, &[]);
+        let merge = commit(&repo, main_tree, &[main, orphan]);
+
+        let (repo_data, mut pushed) = push(&repo, merge);
+        let fetch_todo = enumerated(&repo_data, &mut pushed, merge);
+
+        let objects: HashSet<Oid> = repo_data
+            .objects
+            .keys()
+            .map(|git_hash| git_hash.to_oid().unwrap())
+            .collect();
+        assert!(fetch_todo.contains(&orphan));
+        assert!(fetch_todo.contains(&orphan_tree));
+        assert_eq!(fetch_todo, objects);
+    }
+
+    #[test]
+    fn fetches_enumerate_past_submodules() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let submodule = Oid::from_str("ffffffffffffffffffffffffffffffffffffffff").unwrap();
+        let file = repo.blob(b"file").unwrap();
+
+        // Entries are walked highest hash first, so the submodule comes before the file
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("a-file", file, 0o100644).unwrap();
+        builder.insert("submodule", submodule, 0o160000).unwrap();
+        let tree = builder.write().unwrap();
+        let parent_tree = tree_of(&repo, "parent", b"parent");
+        let parent = commit(&repo, parent_tree, &[]);
+        let tip = commit(&repo, tree, &[parent]);
+
+        let (repo_data, mut pushed) = push(&repo, tip);
+        assert_eq!(
+            repo_data.objects[&GitHash::from(submodule)],
+            SUBMODULE_TIP_MARKER
+        );
+        let fetch_todo = enumerated(&repo_data, &mut pushed, tip);
+
+        assert!(!fetch_todo.contains(&submodule));
+        for wanted in [tip, tree, file, parent, parent_tree] {
+            assert!(fetch_todo.contains(&wanted), "{} wasn't enumerated", wanted);
+        }
     }
 }
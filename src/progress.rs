@@ -0,0 +1,94 @@
+use crate::types::IpfId;
+use log::debug;
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+/// The stages of a push or fetch, in the order they happen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Finding the objects to push or fetch
+    Enumerating,
+    /// Adding objects to IPFS
+    Uploading,
+    /// Submitting extrinsics and waiting for them to be in a block
+    Minting,
+    /// Downloading and writing objects
+    Fetching,
+    Done,
+}
+
+/// Receives the progress of pushes and fetches, for frontends that show it their own way
+/// instead of reading stderr.
+///
+/// Every method does nothing by default, so sinks implement only what they show. They are
+/// called from the task doing the work and should return quickly.
+pub trait ProgressSink: Send + Sync {
+    /// `objects` were found to be pushed or fetched.
+    fn on_enumerate(&self, _objects: usize) {}
+
+    /// `bytes` more were added to IPFS.
+    fn on_upload(&self, _bytes: u64) {}
+
+    /// An IPF was minted.
+    fn on_mint(&self, _ipf_id: IpfId) {}
+
+    /// `done` of the `total` objects of a fetch are written.
+    fn on_fetch_object(&self, _done: usize, _total: usize) {}
+
+    fn on_phase_change(&self, _phase: Phase) {}
+}
+
+/// What the command line shows: a counter of fetched objects on stderr, the rest in the
+/// debug log next to the messages pushes print anyway.
+pub struct Terminal;
+
+impl ProgressSink for Terminal {
+    fn on_enumerate(&self, objects: usize) {
+        debug!("{} objects to transfer", objects);
+    }
+
+    fn on_upload(&self, bytes: u64) {
+        debug!("Added {} bytes to IPFS", bytes);
+    }
+
+    fn on_fetch_object(&self, done: usize, total: usize) {
+        eprint!(
+            "\rWriting objects: {}% ({}/{})",
+            done * 100 / total.max(1),
+            done,
+            total
+        );
+        if done == total {
+            eprintln!(", done.");
+        }
+        let _ = std::io::stderr().flush();
+    }
+
+    fn on_phase_change(&self, phase: Phase) {
+        debug!("Now {:?}", phase);
+    }
+}
+
+/// The sink `sink` returns; the terminal one until another is installed.
+static CURRENT: Mutex<Option<Arc<dyn ProgressSink>>> = Mutex::new(None);
+
+/// Makes `sink` receive the progress of every following push and fetch.
+///
+/// For embedders, the CLI itself keeps the terminal sink.
+#[allow(dead_code)]
+pub fn install(sink: Arc<dyn ProgressSink>) {
+    if let Ok(mut current) = CURRENT.lock() {
+        *current = Some(sink);
+    }
+}
+
+/// The sink to report progress to.
+pub fn sink() -> Arc<dyn ProgressSink> {
+    CURRENT
+        .lock()
+        .ok()
+        .and_then(|current| current.clone())
+        .unwrap_or_else(|| Arc::new(Terminal))
+}
@@ -0,0 +1,92 @@
+use crate::{config::Config, primitives::BoxResult, types::GitHash};
+use git2::{Direction, ReferenceType, Repository};
+use log::debug;
+use std::collections::BTreeMap;
+
+/// Whether fetches from `remote` delete the remote-tracking refs of refs gone from chain.
+///
+/// Like `git fetch`, `remote.<name>.prune` wins over `fetch.prune`, with the config's
+/// `fetch.prune` as the default.
+fn enabled(repo: &Repository, remote: &str, config: &Config) -> bool {
+    let git_config = match repo.config() {
+        Ok(git_config) => git_config,
+        Err(_) => return config.prune,
+    };
+
+    git_config
+        .get_bool(&format!("remote.{}.prune", remote))
+        .or_else(|_| git_config.get_bool("fetch.prune"))
+        .unwrap_or(config.prune)
+}
+
+/// Deletes the refs `remote`'s fetch refspecs map from refs missing in `remote_refs`.
+///
+/// Only refs deleted on chain count as missing, so refs hidden from `list` by an advertise
+/// filter keep their tracking refs. Fetches from a bare URL have no tracking refs to prune.
+/// Runs once git has fetched objects; `git fetch --prune` prunes by itself against the listed
+/// refs when there is nothing to download.
+pub fn run(
+    repo: &Repository,
+    remote: &str,
+    remote_refs: &BTreeMap<String, GitHash>,
+    config: &Config,
+) -> BoxResult<()> {
+    let git_remote = match repo.find_remote(remote) {
+        Ok(git_remote) => git_remote,
+        Err(_) => return Ok(()),
+    };
+
+    if !enabled(repo, remote, config) {
+        return Ok(());
+    }
+
+    let refspecs: Vec<_> = git_remote
+        .refspecs()
+        .filter(|refspec| refspec.direction() == Direction::Fetch)
+        .collect();
+
+    // The checked out branch is never deleted from under the worktree
+    let head = repo
+        .find_reference("HEAD")
+        .ok()
+        .and_then(|head| head.symbolic_target().map(str::to_owned));
+
+    let mut stale = Vec::new();
+
+    for reference in repo.references()? {
+        let reference = reference?;
+        let name = match reference.name() {
+            Some(name) => name,
+            None => continue,
+        };
+
+        if reference.kind() == Some(ReferenceType::Symbolic) || head.as_deref() == Some(name) {
+            continue;
+        }
+
+        let source = refspecs
+            .iter()
+            .filter(|refspec| refspec.dst_matches(name))
+            .find_map(|refspec| {
+                refspec
+                    .rtransform(name)
+                    .ok()
+                    .and_then(|source| source.as_str().map(str::to_owned))
+            });
+
+        match source {
+            Some(source) if !remote_refs.contains_key(&source) => {
+                debug!("{} tracks {}, which is gone from chain", name, source);
+                stale.push(name.to_owned());
+            }
+            _ => {}
+        }
+    }
+
+    for name in stale {
+        repo.find_reference(&name)?.delete()?;
+        eprintln!(" - [deleted]         (none)     -> {}", name);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,227 @@
+use crate::{
+    config::Config,
+    fees::InvArchExtrinsicParams,
+    invarch,
+    ipfs::Ipfs,
+    ledger,
+    manifest::Manifest,
+    primitives::{
+        mint_in_order, BoxResult, MultiObject, PushReceipt, RefUpdate, RepoData, RepoDataOps,
+    },
+    receipts, set_repo,
+    state::RemoteState,
+    types::{GitHash, IpfId, IpsId},
+    util::ipfs_unpin,
+};
+use codec::{Decode, Encode};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use subxt::{DefaultConfig, PairSigner};
+
+/// The push option (`git push -o queue`) that stages pushes instead of submitting them.
+pub static QUEUE_PUSH_OPTION: &str = "queue";
+
+/// A push that was planned locally and is waiting to be submitted to the chain.
+#[derive(Decode, Debug, Clone)]
+pub struct QueuedPush {
+    pub ips_id: IpsId,
+    pub subasset_id: Option<u32>,
+    pub ref_dst: String,
+    /// Refs of the RepoData this push was planned on top of
+    pub base_refs: BTreeMap<String, GitHash>,
+    /// Message for the push receipt
+    pub message: Option<String>,
+    /// The new objects to mint
+    pub multi_object: MultiObject,
+    /// The RepoData to mint once the objects are on chain
+    pub repo_data: RepoData,
+}
+
+impl QueuedPush {
+    /// The fields in order, as `Decode` reads them back.
+    fn encode(&self) -> BoxResult<Vec<u8>> {
+        let mut encoded = (
+            self.ips_id,
+            self.subasset_id,
+            &self.ref_dst,
+            &self.base_refs,
+            &self.message,
+            &self.multi_object,
+        )
+            .encode();
+        encoded.extend(self.repo_data.encode()?);
+        Ok(encoded)
+    }
+}
+
+pub fn queue_dir(state: &RemoteState) -> PathBuf {
+    state.dir().join("queue")
+}
+
+/// Stores `queued` in the remote's pending directory.
+pub fn stage(state: &RemoteState, queued: &QueuedPush) -> BoxResult<PathBuf> {
+    let dir = queue_dir(state);
+    std::fs::create_dir_all(&dir)?;
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    let path = dir.join(format!("{:039}-{}.scale", nanos, queued.ips_id));
+
+    std::fs::write(&path, queued.encode()?)?;
+
+    Ok(path)
+}
+
+/// All queued pushes to the remote, oldest first.
+pub fn pending(state: &RemoteState) -> BoxResult<Vec<(PathBuf, QueuedPush)>> {
+    let dir = queue_dir(state);
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut paths = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()?;
+    paths.retain(|path| path.extension().map(|e| e == "scale").unwrap_or(false));
+    paths.sort();
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let queued = read(&path)?;
+            Ok((path, queued))
+        })
+        .collect()
+}
+
+fn read(path: &Path) -> BoxResult<QueuedPush> {
+    let bytes = std::fs::read(path)?;
+    QueuedPush::decode(&mut bytes.as_slice())
+        .map_err(|e| format!("Queued push {} is corrupt: {}", path.display(), e).into())
+}
+
+/// The RepoData of the newest queued push to the remote, so new pushes stack on top of it.
+pub fn latest_repo_data(state: &RemoteState) -> BoxResult<Option<RepoData>> {
+    Ok(pending(state)?.pop().map(|(_, queued)| queued.repo_data))
+}
+
+/// Submits every queued push to the remote in order, stopping at the first one that can't be
+/// applied.
+pub async fn flush(
+    state: &RemoteState,
+    ipfs: &mut Ipfs,
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+    config: &Config,
+) -> BoxResult<()> {
+    let queued_pushes = pending(state)?;
+
+    for (i, (path, queued)) in queued_pushes.iter().enumerate() {
+        eprintln!(
+            "[{}/{}] Submitting queued push of {} to {} (IPS ID: {})",
+            i + 1,
+            queued_pushes.len(),
+            queued.ref_dst,
+            state.remote,
+            queued.ips_id
+        );
+
+        let mut repo_data = queued.repo_data.clone();
+        let mut base_refs = queued.base_refs.clone();
+
+        let current = set_repo(queued.ips_id, chain_api.clone()).await?;
+        if current.refs != base_refs {
+            repo_data.merge(&base_refs, &current).map_err(|e| {
+                format!(
+                    "IPS {} changed since {} was queued and {} Delete {} afterwards.",
+                    queued.ips_id,
+                    queued.ref_dst,
+                    e,
+                    path.display()
+                )
+            })?;
+            base_refs = current.refs;
+        }
+        repo_data
+            .record_ref_updates(
+                &base_refs,
+                signer.account_id(),
+                chain_api,
+                config.reflog_len,
+            )
+            .await?;
+
+        let per_object = config
+            .bundling
+            .per_object(
+                &queued.multi_object,
+                chain_api,
+                signer,
+                queued.ips_id,
+                config,
+            )
+            .await?;
+        let layers = repo_data.order_for_mint(
+            queued.multi_object.clone(),
+            config.push_ordering,
+            per_object,
+        );
+        let packs = mint_in_order(
+            &layers,
+            ipfs,
+            chain_api,
+            signer,
+            queued.ips_id,
+            queued.subasset_id,
+            config,
+        )
+        .await?;
+        let pack_ipf_ids: Vec<IpfId> = packs.iter().map(|(pack_ipf_id, _)| *pack_ipf_id).collect();
+
+        let manifest = if config.manifest {
+            let entries = Manifest::entries_for(&layers, &packs);
+            Some(Manifest::updated(chain_api, ipfs, queued.ips_id, &repo_data, entries).await?)
+        } else {
+            None
+        };
+
+        let receipt = if config.push_receipts {
+            Some(PushReceipt::new(
+                signer.account_id().clone(),
+                RefUpdate::between(&base_refs, &repo_data.refs),
+                queued.message.clone(),
+            )?)
+        } else {
+            None
+        };
+
+        if let Err(e) = repo_data
+            .replace_on_chain(
+                &pack_ipf_ids,
+                ipfs,
+                chain_api,
+                signer,
+                queued.ips_id,
+                queued.subasset_id,
+                receipt.as_ref(),
+                manifest.as_ref(),
+                config,
+            )
+            .await
+        {
+            for (_, pack_cid) in &packs {
+                ipfs_unpin(ipfs, pack_cid).await;
+            }
+            return Err(e);
+        }
+
+        ledger::commit(state, &queued.ref_dst, config)?;
+        receipts::commit(state)?;
+
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
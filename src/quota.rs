@@ -0,0 +1,126 @@
+use crate::{
+    error,
+    primitives::{BoxResult, GitObjectMetadata, MultiObject},
+    types::GitHash,
+};
+use git2::{Repository, TreeWalkMode, TreeWalkResult};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The push option (`git push -o ignore-quota`) that skips the quota checks for one push.
+pub static IGNORE_QUOTA_PUSH_OPTION: &str = "ignore-quota";
+
+/// Limits on what a single push may add on chain and to IPFS; unset limits don't apply.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Quota {
+    /// Most git objects one push may add
+    pub max_objects: Option<usize>,
+    /// Most bytes of git objects one push may add
+    pub max_bytes: Option<u64>,
+    /// Largest blob one push may add
+    pub max_blob_bytes: Option<u64>,
+}
+
+impl Quota {
+    /// Fails if the objects in `multi_object` don't fit in this quota.
+    ///
+    /// Every exceeded limit is printed, oversized blobs with the paths they have in the pushed
+    /// commits, since the error itself has to fit on one line of the helper protocol.
+    pub fn check(&self, multi_object: &MultiObject, repo: &Repository) -> BoxResult<()> {
+        let mut violations = Vec::new();
+
+        let objects = multi_object.objects.len();
+        if let Some(max_objects) = self.max_objects {
+            if objects > max_objects {
+                violations.push(format!(
+                    "{} new objects exceed max_objects = {}",
+                    objects, max_objects
+                ));
+            }
+        }
+
+        let bytes: u64 = multi_object
+            .objects
+            .values()
+            .map(|object| object.header().size)
+            .sum();
+        if let Some(max_bytes) = self.max_bytes {
+            if bytes > max_bytes {
+                violations.push(format!(
+                    "{} bytes of new objects exceed max_bytes = {}",
+                    bytes, max_bytes
+                ));
+            }
+        }
+
+        if let Some(max_blob_bytes) = self.max_blob_bytes {
+            let oversized: BTreeMap<GitHash, u64> = multi_object
+                .objects
+                .values()
+                .filter(|object| matches!(object.metadata, GitObjectMetadata::Blob))
+                .filter(|object| object.header().size > max_blob_bytes)
+                .map(|object| (object.git_hash.clone(), object.header().size))
+                .collect();
+
+            if !oversized.is_empty() {
+                let paths = blob_paths(multi_object, repo, &oversized)?;
+
+                for (git_hash, size) in &oversized {
+                    let at = match paths.get(git_hash) {
+                        Some(paths) => paths.iter().cloned().collect::<Vec<_>>().join(", "),
+                        None => git_hash.to_string(),
+                    };
+
+                    violations.push(format!(
+                        "{} ({} bytes) exceeds max_blob_bytes = {}",
+                        at, size, max_blob_bytes
+                    ));
+                }
+            }
+        }
+
+        if !violations.is_empty() {
+            eprintln!("This push exceeds the quota:");
+            for violation in &violations {
+                eprintln!("  {}", violation);
+            }
+
+            error!(format!(
+                "push exceeds the quota in {} ways, push with `-o {}` to override",
+                violations.len(),
+                IGNORE_QUOTA_PUSH_OPTION
+            ))
+        }
+
+        Ok(())
+    }
+}
+
+/// The paths the blobs in `wanted` have in the trees of the commits in `multi_object`.
+fn blob_paths(
+    multi_object: &MultiObject,
+    repo: &Repository,
+    wanted: &BTreeMap<GitHash, u64>,
+) -> BoxResult<BTreeMap<GitHash, BTreeSet<String>>> {
+    let mut paths: BTreeMap<GitHash, BTreeSet<String>> = BTreeMap::new();
+
+    for object in multi_object.objects.values() {
+        if let GitObjectMetadata::Commit { tree_git_hash, .. } = &object.metadata {
+            let tree = repo.find_tree(tree_git_hash.to_oid()?)?;
+
+            tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+                let git_hash = GitHash::from(entry.id());
+                if wanted.contains_key(&git_hash) {
+                    paths.entry(git_hash).or_default().insert(format!(
+                        "{}{}",
+                        root,
+                        entry.name().unwrap_or("<non UTF-8 name>")
+                    ));
+                }
+                TreeWalkResult::Ok
+            })?;
+        }
+    }
+
+    Ok(paths)
+}
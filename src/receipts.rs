@@ -0,0 +1,311 @@
+use crate::{
+    config::Config,
+    error,
+    fees::InvArchExtrinsicParams,
+    get_signer, invarch,
+    ipfs::Ipfs,
+    primitives::{BoxResult, MultiObject, MultiObjectOps, RepoData, RepoDataOps},
+    state::{inv4_dir, RemoteState},
+    timeout::{within, Operation},
+    types::{GitHash, IpfId, IpsId},
+    util::{block_number, ipf_metadata_name},
+};
+use git2::Repository;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use subxt::{DefaultConfig, TransactionEvents};
+
+static USAGE: &str = "Usage: git-remote-inv4 receipt <git-hash>";
+
+/// How long after a mint its receipt is checked against the canonical chain, as a fork it was
+/// included on may still be reorganized away until its block is finalized.
+const REORG_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+/// Where the objects of one minted MultiObject ended up on chain, a line of `receipts.jsonl`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TxReceipt {
+    pub ips_id: u32,
+    pub ipf_id: u64,
+    pub multi_object: String,
+    pub git_hashes: Vec<String>,
+    pub extrinsic_hash: String,
+    pub block_hash: String,
+    /// Missing if the node couldn't be asked for it right after the mint
+    pub block_number: Option<u32>,
+    pub timestamp: u64,
+}
+
+/// Receipts of mints since the last `commit`.
+static PENDING: Mutex<Vec<TxReceipt>> = Mutex::new(Vec::new());
+
+fn receipts_path(inv4_dir: &Path) -> PathBuf {
+    inv4_dir.join("receipts.jsonl")
+}
+
+/// Notes the extrinsic that minted `multi_object` as IPF `ipf_id`, as `events` tell.
+pub async fn record(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    events: &TransactionEvents<DefaultConfig, invarch::Event>,
+    ips_id: IpsId,
+    ipf_id: IpfId,
+    multi_object: &MultiObject,
+) {
+    let block_number = match block_number(chain_api, events.block_hash()).await {
+        Ok(number) => Some(number),
+        Err(e) => {
+            debug!("Could not look up the block of IPF {}: {}", ipf_id, e);
+            None
+        }
+    };
+
+    let receipt = TxReceipt {
+        ips_id: ips_id.0,
+        ipf_id: ipf_id.0,
+        multi_object: multi_object.hash.clone(),
+        git_hashes: multi_object
+            .git_hashes
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        extrinsic_hash: format!("{:?}", events.extrinsic_hash()),
+        block_hash: format!("{:?}", events.block_hash()),
+        block_number,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs()),
+    };
+
+    match PENDING.lock() {
+        Ok(mut pending) => pending.push(receipt),
+        Err(_) => debug!("Receipts are poisoned, not recording IPF {}", ipf_id),
+    }
+}
+
+/// Appends the receipts recorded since the last call to the repository's `receipts.jsonl`.
+pub fn commit(state: &RemoteState) -> BoxResult<()> {
+    let receipts = std::mem::take(&mut *PENDING.lock().map_err(|_| "Receipts are poisoned")?);
+    if receipts.is_empty() {
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(state.inv4_dir())?;
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(receipts_path(state.inv4_dir()))?;
+
+    for receipt in receipts {
+        writeln!(f, "{}", serde_json::to_string(&receipt)?)?;
+    }
+
+    Ok(())
+}
+
+/// Checks that the recent mints of `ips_id` are still in the blocks their receipts name, once
+/// those blocks are final, so a push included on a fork that was reorganized away is noticed.
+///
+/// A mint the canonical chain included elsewhere only has its receipt updated. One it dropped
+/// is minted again from `repo` and appended to the IPS if `repo_data` still lists it; otherwise
+/// the whole push went with the fork and has to be pushed again.
+pub async fn check_reorgs(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    state: &RemoteState,
+    repo: &Repository,
+    repo_data: &RepoData,
+    ips_id: IpsId,
+    subasset_id: Option<u32>,
+    config: &Config,
+) -> BoxResult<()> {
+    let path = receipts_path(state.inv4_dir());
+    let lines: Vec<String> = match std::fs::File::open(&path) {
+        Ok(f) => BufReader::new(f).lines().collect::<Result<_, _>>()?,
+        Err(_) => return Ok(()),
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let recent: Vec<(usize, TxReceipt)> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| Some((i, serde_json::from_str::<TxReceipt>(line).ok()?)))
+        .filter(|(_, receipt)| {
+            receipt.ips_id == ips_id.0
+                && receipt.block_number.is_some()
+                && now.saturating_sub(receipt.timestamp) < REORG_WINDOW_SECS
+        })
+        .collect();
+    if recent.is_empty() {
+        return Ok(());
+    }
+
+    let rpc = chain_api.client.rpc();
+    let finalized = block_number(chain_api, rpc.finalized_head().await?).await?;
+
+    let mut updated = lines.clone();
+    let mut remint = Vec::new();
+
+    for (i, receipt) in recent {
+        let number = receipt.block_number.unwrap_or_default();
+        if number > finalized {
+            debug!(
+                "Block #{} of IPF {} isn't final yet",
+                number, receipt.ipf_id
+            );
+            continue;
+        }
+        let canonical = rpc.block_hash(Some(number.into())).await?;
+        if canonical.map(|hash| format!("{:?}", hash)).as_deref()
+            == Some(receipt.block_hash.as_str())
+        {
+            continue;
+        }
+
+        eprintln!(
+            "Block #{} that minted IPF {} (MultiObject {}) is no longer on the canonical chain",
+            number, receipt.ipf_id, receipt.multi_object
+        );
+
+        let ipf_info = within(
+            Operation::ChainQuery,
+            &format!("reading IPF {} from chain", receipt.ipf_id),
+            chain_api.storage().ipf().ipf_storage(&receipt.ipf_id, None),
+        )
+        .await?;
+        let still_minted = match ipf_info {
+            Some(ipf_info) => ipf_metadata_name(&ipf_info.metadata.0)? == receipt.multi_object,
+            None => false,
+        };
+
+        if still_minted {
+            eprintln!("  The canonical chain minted it in another block");
+            updated[i] = serde_json::to_string(&TxReceipt {
+                block_hash: "unknown".to_owned(),
+                block_number: None,
+                ..receipt
+            })?;
+        } else if repo_data
+            .objects
+            .values()
+            .any(|hash| hash == &receipt.multi_object)
+        {
+            eprintln!("  RepoData still lists it, minting it again");
+            remint.push(i);
+        } else {
+            eprintln!(
+                "  The push that minted it was dropped as well, push {} again",
+                receipt.git_hashes.first().map_or("it", String::as_str)
+            );
+            updated[i].clear();
+        }
+    }
+
+    if !remint.is_empty() {
+        let signer = get_signer(config).await?;
+        let mut ipfs = Ipfs::default();
+        let mut minted = Vec::new();
+
+        for &i in &remint {
+            let receipt: TxReceipt = serde_json::from_str(&lines[i])?;
+            let git_hashes = receipt
+                .git_hashes
+                .iter()
+                .map(|git_hash| git_hash.parse::<GitHash>())
+                .collect::<Result<Vec<_>, _>>()?;
+            let multi_object = MultiObject::rebuild(&git_hashes, repo)?;
+            if multi_object.hash != receipt.multi_object {
+                error!(format!(
+                    "Rebuilding MultiObject {} from the local repository gave MultiObject {}",
+                    receipt.multi_object, multi_object.hash
+                ))
+            }
+
+            minted.extend(
+                multi_object
+                    .mint(&mut ipfs, chain_api, &signer, ips_id, subasset_id, config)
+                    .await?
+                    .map(|(ipf_id, _)| ipf_id),
+            );
+            // The new mint records a receipt of its own
+            updated[i].clear();
+        }
+
+        RepoData::swap_on_chain(&minted, &[], chain_api, &signer, ips_id, subasset_id).await?;
+    }
+
+    if updated != lines {
+        let temp_path = path.with_extension("jsonl.tmp");
+        let mut f = std::fs::File::create(&temp_path)?;
+        for line in updated.iter().filter(|line| !line.is_empty()) {
+            writeln!(f, "{}", line)?;
+        }
+        f.sync_all()?;
+        std::fs::rename(&temp_path, &path)?;
+    }
+
+    commit(state)
+}
+
+/// `receipt <git-hash>`: prints the extrinsics that minted an object, named by its hash, a
+/// prefix of it or any revision `repo` can resolve.
+pub fn run(repo: &Repository, args: &[String]) -> BoxResult<()> {
+    let wanted = args.first().ok_or(USAGE)?;
+    let wanted = match repo.revparse_single(wanted) {
+        Ok(object) => object.id().to_string(),
+        Err(_) => wanted.to_lowercase(),
+    };
+
+    let path = receipts_path(&inv4_dir(repo));
+    let f = match std::fs::File::open(&path) {
+        Ok(f) => f,
+        Err(_) => error!(format!("No receipts recorded yet at {}", path.display())),
+    };
+
+    let mut found = 0;
+
+    for line in BufReader::new(f).lines() {
+        let receipt: TxReceipt = match serde_json::from_str(&line?) {
+            Ok(receipt) => receipt,
+            Err(e) => {
+                debug!("Skipping unreadable receipt: {}", e);
+                continue;
+            }
+        };
+
+        for git_hash in receipt
+            .git_hashes
+            .iter()
+            .filter(|git_hash| git_hash.starts_with(&wanted))
+        {
+            found += 1;
+            println!("{}", git_hash);
+            println!(
+                "  IPS {}, IPF {} (MultiObject {})",
+                receipt.ips_id, receipt.ipf_id, receipt.multi_object
+            );
+            match receipt.block_number {
+                Some(number) => println!(
+                    "  extrinsic {} in block #{} ({})",
+                    receipt.extrinsic_hash, number, receipt.block_hash
+                ),
+                None => println!(
+                    "  extrinsic {} in block {}",
+                    receipt.extrinsic_hash, receipt.block_hash
+                ),
+            }
+        }
+    }
+
+    if found == 0 {
+        error!(format!("No receipt for {} in {}", wanted, path.display()))
+    }
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use crate::{
+    askpass,
+    fees::InvArchExtrinsicParams,
+    invarch::{self, balances::events::Unreserved},
+    ledger,
+    primitives::BoxResult,
+    resubmit,
+    timeout::{within, Operation},
+    token,
+    types::IpfId,
+};
+use serde::{Deserialize, Serialize};
+use subxt::{DefaultConfig, PairSigner};
+
+/// What happens to the IPFs a push removes from its IPS, `push.reclaim_deposits`.
+///
+/// Removed IPFs go to the signer, whose deposit for them stays reserved until they are burned.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Reclaim {
+    /// Keep them, so they can be appended again
+    #[default]
+    #[serde(rename = "never")]
+    Never,
+    /// Ask on the terminal or through askpass before burning them
+    #[serde(rename = "ask")]
+    Ask,
+    /// Burn them without asking, for pushes nobody watches
+    #[serde(rename = "always")]
+    Always,
+}
+
+/// Burns `ipf_ids`, owned by the signer, as `reclaim` says, and reports the deposits that
+/// came back.
+///
+/// Failing to burn is only warned about: the IPFs stay with the signer and can be burned
+/// later. Returns the amount unreserved.
+pub async fn burn(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+    ipf_ids: &[IpfId],
+    reclaim: Reclaim,
+) -> u128 {
+    if ipf_ids.is_empty() || reclaim == Reclaim::Never {
+        return 0;
+    }
+
+    let ids = ipf_ids
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if reclaim == Reclaim::Ask {
+        let prompt = format!(
+            "Burn the removed IPFs {} to get their deposits back? They can't be appended again afterwards. [y/N] ",
+            ids
+        );
+        match askpass::confirm(&prompt) {
+            Ok(true) => {}
+            Ok(false) => {
+                eprintln!("Keeping the removed IPFs {}", ids);
+                return 0;
+            }
+            Err(e) => {
+                eprintln!(
+                    "Warning: could not ask whether to burn the removed IPFs {}, keeping them: {}",
+                    ids, e
+                );
+                return 0;
+            }
+        }
+    }
+
+    let mut reclaimed = 0;
+    for ipf_id in ipf_ids {
+        match burn_one(chain_api, signer, *ipf_id).await {
+            Ok(unreserved) => reclaimed += unreserved,
+            Err(e) => eprintln!(
+                "Warning: could not burn IPF {}, it stays with the signer: {}",
+                ipf_id, e
+            ),
+        }
+    }
+
+    eprintln!(
+        "Reclaimed {} in deposits by burning IPFs {}",
+        token::format(reclaimed),
+        ids
+    );
+
+    reclaimed
+}
+
+/// Burns `ipf_id`, returning what was unreserved for the signer.
+async fn burn_one(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+    ipf_id: IpfId,
+) -> BoxResult<u128> {
+    let tx = chain_api.tx().ipf().burn(ipf_id.0)?;
+    let progress =
+        resubmit::sign_and_submit("ipf.burn", || tx.sign_and_submit_then_watch_default(signer))
+            .await?;
+    let progress = within(
+        Operation::Extrinsic,
+        "waiting for ipf.burn to be included in a block",
+        progress.wait_for_in_block(),
+    )
+    .await?;
+
+    let tx_events = progress.fetch_events().await?;
+    ledger::record("ipf.burn", &tx_events, signer.account_id())?;
+    progress.wait_for_success().await?;
+
+    let mut unreserved = 0;
+    for event in tx_events.find::<Unreserved>() {
+        let event = event?;
+        if &event.who == signer.account_id() {
+            unreserved += event.amount;
+        }
+    }
+
+    Ok(unreserved)
+}
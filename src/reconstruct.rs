@@ -0,0 +1,338 @@
+use crate::{
+    config::Config,
+    connect, error,
+    fees::InvArchExtrinsicParams,
+    get_signer,
+    index::INDEX_NAME,
+    invarch,
+    ipfs::Ipfs,
+    manifest::MANIFEST_NAME,
+    primitives::{
+        parse_tree, BoxResult, GitObjectMetadata, MultiObject, RepoData, RepoDataOps,
+        SUBMODULE_MODE, SUBMODULE_TIP_MARKER,
+    },
+    timeout::{within, Operation},
+    types::{GitHash, IpsId},
+    util::{
+        decode_ipf_metadata, find_ipf, ipf_infos, ipfs_cat, ips_ipf_ids, is_git_ipf, IPF_BATCH_SIZE,
+    },
+};
+use codec::Decode;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use subxt::DefaultConfig;
+
+static USAGE: &str = "Usage: git-remote-inv4 reconstruct --ips <id> [--dry-run] [--force]";
+
+/// Rebuilds the RepoData of an IPS from the MultiObject IPFs it still holds and mints it.
+///
+/// The refs are inferred: every annotated tag no other tag points at gets its own name under
+/// `refs/tags/`, and every commit that is neither a parent nor tagged becomes a branch
+/// `refs/heads/recovered/<hash>`. `--dry-run` only prints them; `--force` replaces a RepoData
+/// that is still readable.
+pub async fn run(config: &Config, args: &[String]) -> BoxResult<()> {
+    let ips_id: IpsId = config
+        .ips_arg(args)?
+        .ok_or(format!("Missing IPS id. {}", USAGE))?;
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let force = args.iter().any(|arg| arg == "--force");
+
+    let chain_api = connect(config).await?;
+    let mut ipfs = Ipfs::default();
+
+    if let Some((ipf_id, cid)) = find_ipf(&chain_api, ips_id, "RepoData").await? {
+        match RepoData::from_ipfs(cid, &mut ipfs).await {
+            Ok(_) if !force => error!(format!(
+                "IPS {} still has a readable RepoData (IPF {}). Pass --force to replace it anyway.",
+                ips_id, ipf_id
+            )),
+            Ok(_) => eprintln!("Replacing the readable RepoData IPF {}", ipf_id),
+            Err(e) => eprintln!("RepoData IPF {} is unreadable: {}", ipf_id, e),
+        }
+    }
+
+    let multi_objects = scan(&chain_api, &mut ipfs, ips_id).await?;
+    let repo_data = rebuild(&multi_objects)?;
+
+    eprintln!(
+        "Recovered {} objects from {} MultiObjects",
+        repo_data.objects.len(),
+        multi_objects.len()
+    );
+    for (name, git_hash) in &repo_data.refs {
+        println!("{} {}", git_hash, name);
+    }
+
+    if dry_run {
+        return Ok(());
+    }
+
+    let signer = get_signer(config).await?;
+    repo_data
+        .replace_on_chain(
+            &[],
+            &mut ipfs,
+            &chain_api,
+            &signer,
+            ips_id,
+            None,
+            None,
+            None,
+            config,
+        )
+        .await?;
+
+    eprintln!("Minted the reconstructed RepoData to IPS {}", ips_id);
+
+    Ok(())
+}
+
+/// What a remote does with an IPS that holds git objects but no RepoData,
+/// `fetch.missing_repo_data`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum MissingRepoData {
+    /// List no refs, after explaining how to reconstruct the RepoData
+    #[default]
+    #[serde(rename = "warn")]
+    Warn,
+    /// Fail, so nobody mistakes the IPS for an empty repository
+    #[serde(rename = "fail")]
+    Fail,
+    /// Reconstruct the RepoData for this session only and list its refs, as
+    /// `inv4://<id>?recover` does
+    #[serde(rename = "recover")]
+    Recover,
+}
+
+/// Checks whether an IPS without RepoData holds git IPFs anyway, as when the push that
+/// created it failed between minting its objects and its RepoData, and diagnoses that as
+/// `missing` says.
+///
+/// Returns the reconstructed RepoData to use instead of an empty one with `Recover`.
+pub async fn check_missing(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ips_id: IpsId,
+    missing: MissingRepoData,
+) -> BoxResult<Option<RepoData>> {
+    if find_ipf(chain_api, ips_id, "RepoData").await?.is_some() {
+        return Ok(None);
+    }
+
+    let ipf_ids = ips_ipf_ids(chain_api, ips_id).await?;
+    let mut git_ipfs = 0;
+    for batch in ipf_ids.chunks(IPF_BATCH_SIZE) {
+        let ipf_infos = within(
+            Operation::ChainQuery,
+            &format!("reading IPFs of IPS {} from chain", ips_id),
+            ipf_infos(chain_api, batch),
+        )
+        .await?;
+
+        git_ipfs += ipf_infos
+            .iter()
+            .flatten()
+            .filter(|ipf_info| is_git_ipf(&ipf_info.metadata.0))
+            .count();
+    }
+
+    if git_ipfs == 0 {
+        return Ok(None);
+    }
+
+    let diagnosis = format!(
+        "IPS {} holds {} git IPFs but no RepoData, as if a push failed before minting it.",
+        ips_id, git_ipfs
+    );
+
+    match missing {
+        MissingRepoData::Warn => {
+            eprintln!(
+                "Warning: {} Listing it as empty. Run `git-remote-inv4 reconstruct --ips {}` to rebuild and mint its RepoData, or fetch from inv4://{}?recover to rebuild it for one fetch.",
+                diagnosis, ips_id, ips_id
+            );
+            Ok(None)
+        }
+        MissingRepoData::Fail => error!(format!(
+            "{} Run `git-remote-inv4 reconstruct --ips {}` to rebuild and mint its RepoData.",
+            diagnosis, ips_id
+        )),
+        MissingRepoData::Recover => {
+            eprintln!("{} Reconstructing it for this session.", diagnosis);
+
+            let multi_objects = scan(chain_api, &mut Ipfs::default(), ips_id).await?;
+            let repo_data = rebuild(&multi_objects)?;
+            eprintln!(
+                "Recovered {} objects and {} refs from {} MultiObjects, run `git-remote-inv4 reconstruct --ips {}` to mint them",
+                repo_data.objects.len(),
+                repo_data.refs.len(),
+                multi_objects.len(),
+                ips_id
+            );
+
+            Ok(Some(repo_data))
+        }
+    }
+}
+
+/// Downloads every IPF of the IPS that decodes as a MultiObject.
+async fn scan(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ipfs: &mut Ipfs,
+    ips_id: IpsId,
+) -> BoxResult<Vec<MultiObject>> {
+    let ipf_ids = ips_ipf_ids(chain_api, ips_id).await?;
+
+    eprintln!("Scanning {} IPFs of IPS {}...", ipf_ids.len(), ips_id);
+
+    let mut multi_objects = Vec::new();
+
+    for batch in ipf_ids.chunks(IPF_BATCH_SIZE) {
+        let ipf_infos = within(
+            Operation::ChainQuery,
+            &format!("reading IPFs of IPS {} from chain", ips_id),
+            ipf_infos(chain_api, batch),
+        )
+        .await?;
+
+        for (id, ipf_info) in batch.iter().zip(ipf_infos) {
+            let ipf_info = ipf_info.ok_or("Internal error: IPF listed from IPS does not exist")?;
+
+            // Receipts and anything else that isn't a MultiObject is skipped
+            if !is_git_ipf(&ipf_info.metadata.0) {
+                continue;
+            }
+            let (name, cid) = match decode_ipf_metadata(&ipf_info.metadata.0, ipf_info.data.0) {
+                Ok((name, _))
+                    if name == "RepoData" || name == MANIFEST_NAME || name == INDEX_NAME =>
+                {
+                    continue
+                }
+                Ok(named) => named,
+                Err(e) => {
+                    debug!("Skipping IPF {}: {}", id, e);
+                    continue;
+                }
+            };
+
+            let content = match ipfs_cat(ipfs, &cid).await {
+                Ok(content) => content,
+                Err(e) => {
+                    eprintln!("Warning: IPF {} ({}) is not retrievable: {}", id, cid, e);
+                    continue;
+                }
+            };
+
+            match MultiObject::decode(&mut content.as_slice()) {
+                Ok(multi_object) if multi_object.hash == name => multi_objects.push(multi_object),
+                Ok(_) => eprintln!(
+                    "Warning: IPF {} claims to be MultiObject {} but holds another one",
+                    id, name
+                ),
+                Err(_) => debug!("Skipping IPF {} ({}), it isn't a MultiObject", id, name),
+            }
+        }
+    }
+
+    Ok(multi_objects)
+}
+
+/// Builds a RepoData holding every object in `multi_objects`, with refs at the tips of history.
+fn rebuild(multi_objects: &[MultiObject]) -> BoxResult<RepoData> {
+    let hash_algorithm = multi_objects
+        .iter()
+        .filter_map(|multi_object| multi_object.git_hashes.first())
+        .map(GitHash::algorithm)
+        .next()
+        .ok_or("No MultiObjects left in the IPS, there is nothing to reconstruct")?;
+
+    let mut repo_data = RepoData::new(hash_algorithm);
+
+    for multi_object in multi_objects {
+        for (git_hash, object) in &multi_object.objects {
+            repo_data
+                .objects
+                .entry(git_hash.clone())
+                .or_insert_with(|| multi_object.hash.clone());
+            repo_data
+                .object_headers
+                .entry(git_hash.clone())
+                .or_insert_with(|| object.header());
+        }
+    }
+
+    let objects: BTreeMap<&GitHash, _> = multi_objects
+        .iter()
+        .flat_map(|multi_object| multi_object.objects.iter())
+        .collect();
+
+    let mut referenced = BTreeSet::new();
+    let mut submodules = BTreeSet::new();
+
+    for (git_hash, object) in &objects {
+        match &object.metadata {
+            GitObjectMetadata::Commit {
+                parent_git_hashes, ..
+            } => referenced.extend(parent_git_hashes.iter().cloned()),
+            GitObjectMetadata::Tag { target_git_hash } => {
+                referenced.insert(target_git_hash.clone());
+            }
+            GitObjectMetadata::Tree { .. } => match parse_tree(&object.data, hash_algorithm) {
+                Ok(entries) => submodules.extend(
+                    entries
+                        .into_iter()
+                        .filter(|entry| entry.mode == SUBMODULE_MODE)
+                        .map(|entry| entry.git_hash),
+                ),
+                Err(e) => eprintln!("Warning: could not read tree {}: {}", git_hash, e),
+            },
+            GitObjectMetadata::Blob | GitObjectMetadata::ChunkedBlob { .. } => {}
+        }
+    }
+
+    for git_hash in submodules {
+        repo_data
+            .objects
+            .entry(git_hash)
+            .or_insert_with(|| SUBMODULE_TIP_MARKER.to_owned());
+    }
+
+    for (git_hash, object) in &objects {
+        if referenced.contains(*git_hash) {
+            continue;
+        }
+
+        let name = match &object.metadata {
+            GitObjectMetadata::Commit { .. } => {
+                format!("refs/heads/recovered/{}", short(git_hash))
+            }
+            GitObjectMetadata::Tag { .. } => match tag_name(&object.data) {
+                Some(name) => format!("refs/tags/{}", name),
+                None => format!("refs/tags/recovered/{}", short(git_hash)),
+            },
+            _ => continue,
+        };
+
+        repo_data.refs.insert(name, (*git_hash).clone());
+    }
+
+    if repo_data.refs.is_empty() {
+        error!("The recovered objects contain no commits to point refs at")
+    }
+
+    Ok(repo_data)
+}
+
+fn short(git_hash: &GitHash) -> String {
+    git_hash.to_string()[..12].to_owned()
+}
+
+/// The name in the `tag` header of an annotated tag's raw data.
+fn tag_name(data: &[u8]) -> Option<String> {
+    String::from_utf8_lossy(data)
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| line.strip_prefix("tag "))
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+}
@@ -0,0 +1,119 @@
+use crate::{maintenance::git, primitives::BoxResult};
+use git2::Repository;
+use log::debug;
+use std::path::{Path, PathBuf};
+
+/// A local repository whose objects fetches take instead of downloading them, like
+/// `git clone --reference`, named in the remote URL as in `inv4://5?reference=/src/upstream`.
+pub struct Reference {
+    /// The reference's object directory
+    objects: PathBuf,
+    /// Copy the borrowed objects in once fetched instead of keeping the reference as an alternate
+    dissociate: bool,
+}
+
+impl Reference {
+    /// The reference the query of a remote URL names, `&dissociate` asking for a copy.
+    pub fn from_query(query: &str) -> BoxResult<Option<Self>> {
+        let params: Vec<&str> = query.split('&').collect();
+
+        let path = match params
+            .iter()
+            .find_map(|param| param.strip_prefix("reference="))
+        {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let reference = Repository::open(path)
+            .map_err(|e| format!("Could not open reference repository {}: {}", path, e))?;
+
+        Ok(Some(Self {
+            objects: reference.path().join("objects").canonicalize()?,
+            dissociate: params.contains(&"dissociate"),
+        }))
+    }
+
+    /// Makes the objects of the reference visible to `repo`.
+    ///
+    /// Unless dissociating, the reference is also added to `repo`'s alternates, as fetched
+    /// history keeps depending on the objects it lends.
+    pub fn attach(&self, repo: &Repository) -> BoxResult<()> {
+        if !self.dissociate {
+            let mut alternates = read_alternates(repo)?;
+            if !alternates.contains(&self.objects) {
+                alternates.push(self.objects.clone());
+                write_alternates(repo, &alternates)?;
+            }
+        }
+
+        repo.odb()?
+            .add_disk_alternate(&self.objects.to_string_lossy())?;
+        debug!("Borrowing objects from {}", self.objects.display());
+
+        Ok(())
+    }
+
+    /// When dissociating, copies the objects fetches borrowed from the reference into `repo` by
+    /// repacking it with the reference as a temporary alternate, as `git clone --dissociate` does.
+    pub async fn dissociate(&self, repo: &Repository) -> BoxResult<()> {
+        if !self.dissociate {
+            return Ok(());
+        }
+
+        let alternates = read_alternates(repo)?;
+        let mut borrowing = alternates.clone();
+        if !borrowing.contains(&self.objects) {
+            borrowing.push(self.objects.clone());
+        }
+        write_alternates(repo, &borrowing)?;
+
+        eprintln!(
+            "Copying objects borrowed from {}...",
+            self.objects.display()
+        );
+        let repacked = git(repo, &["repack", "-a", "-d", "-q"], None).await;
+
+        write_alternates(repo, &alternates)?;
+
+        repacked
+    }
+}
+
+fn alternates_path(repo: &Repository) -> PathBuf {
+    repo.path().join("objects/info/alternates")
+}
+
+fn read_alternates(repo: &Repository) -> BoxResult<Vec<PathBuf>> {
+    match std::fs::read_to_string(alternates_path(repo)) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(PathBuf::from)
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_alternates(repo: &Repository, alternates: &[PathBuf]) -> BoxResult<()> {
+    let path = alternates_path(repo);
+
+    if alternates.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(path.parent().unwrap_or_else(|| Path::new(".")))?;
+    std::fs::write(
+        &path,
+        alternates
+            .iter()
+            .map(|alternate| format!("{}\n", alternate.display()))
+            .collect::<String>(),
+    )?;
+
+    Ok(())
+}
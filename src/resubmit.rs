@@ -0,0 +1,71 @@
+use crate::{exit::Class, primitives::BoxResult};
+use std::{future::Future, time::Duration};
+use subxt::BasicError;
+
+/// How often an extrinsic the transaction pool rejected is signed and submitted again.
+const MAX_RESUBMITS: u32 = 3;
+
+/// How long to wait before the first resubmission, doubled for every further one, so the
+/// extrinsic holding the nonce has time to make it into a block.
+const FIRST_DELAY: Duration = Duration::from_secs(2);
+
+/// What nodes answer when the nonce or era of an extrinsic went stale, for instance because
+/// an extrinsic signed just before took the same nonce. Signing again fixes all of them.
+static POOL_REJECTIONS: &[&str] = &[
+    "Priority is too low",
+    "Transaction is outdated",
+    "Transaction has an ancient birth block",
+    "Transaction is temporarily banned",
+];
+
+/// Transaction pool rejections that signing again fixes are transient, everything else is
+/// permanent as far as resubmitting goes.
+///
+/// "Transaction Already Imported" isn't transient: the same extrinsic is already on its way,
+/// and signing it again with the next nonce would run it twice.
+fn classify(e: &BasicError) -> Class {
+    let message = e.to_string();
+    if POOL_REJECTIONS
+        .iter()
+        .any(|rejection| message.contains(rejection))
+    {
+        Class::Transient
+    } else {
+        Class::Permanent
+    }
+}
+
+/// Runs `sign_and_submit`, running it again if the pool rejected the extrinsic for its nonce
+/// or era.
+///
+/// `sign_and_submit` is expected to sign anew every time, as subxt's `sign_and_submit_*` do:
+/// they ask the node for the account's next nonce, which counts what's in the pool, and
+/// build fresh extrinsic parameters.
+pub async fn sign_and_submit<T, F, Fut>(what: &str, mut sign_and_submit: F) -> BoxResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, BasicError>>,
+{
+    let mut delay = FIRST_DELAY;
+    let mut attempt = 0;
+
+    loop {
+        match sign_and_submit().await {
+            Ok(progress) => return Ok(progress),
+            Err(e) if attempt < MAX_RESUBMITS && classify(&e) == Class::Transient => {
+                attempt += 1;
+                eprintln!(
+                    "The transaction pool rejected {} ({}), signing it again in {}s ({}/{})",
+                    what,
+                    e,
+                    delay.as_secs(),
+                    attempt,
+                    MAX_RESUBMITS
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{error, exit::ExitCode, primitives::BoxResult};
+use git2::{Direction, Oid, Repository};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the tips a rewrite on chain dropped are kept, as in
+/// `refs/inv4/backup/origin/heads/main/<unix time>`.
+static BACKUP_PREFIX: &str = "refs/inv4/backup";
+
+/// Fails the fetch of `name` from `remote` at `new` if that drops commits its remote-tracking
+/// ref has, unless `accept` says the rewrite is expected.
+///
+/// Every tip a rewrite drops is kept under `refs/inv4/backup/` first, accepted or not, so no
+/// commit is lost either way. Needs `new` in `repo` already. Fetches from a bare URL have no
+/// tracking refs to compare with.
+pub fn check(repo: &Repository, remote: &str, name: &str, new: Oid, accept: bool) -> BoxResult<()> {
+    let git_remote = match repo.find_remote(remote) {
+        Ok(git_remote) => git_remote,
+        Err(_) => return Ok(()),
+    };
+
+    let tracking = git_remote
+        .refspecs()
+        .filter(|refspec| refspec.direction() == Direction::Fetch && refspec.src_matches(name))
+        .find_map(|refspec| {
+            refspec
+                .transform(name)
+                .ok()
+                .and_then(|tracking| tracking.as_str().map(str::to_owned))
+        });
+    let tracking = match tracking {
+        Some(tracking) => tracking,
+        None => return Ok(()),
+    };
+
+    let old = match repo
+        .find_reference(&tracking)
+        .and_then(|reference| reference.peel_to_commit())
+    {
+        Ok(old) => old.id(),
+        Err(_) => return Ok(()),
+    };
+
+    let new = match repo.find_object(new, None)?.peel_to_commit() {
+        Ok(new) => new.id(),
+        Err(_) => return Ok(()),
+    };
+
+    if old == new || repo.graph_descendant_of(new, old)? {
+        return Ok(());
+    }
+
+    let dropped = dropped_commits(repo, old, new)?;
+    let backup = format!(
+        "{}/{}/{}/{}",
+        BACKUP_PREFIX,
+        remote,
+        name.trim_start_matches("refs/"),
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+    );
+    repo.reference(
+        &backup,
+        old,
+        false,
+        &format!("inv4: {} was rewritten on chain", name),
+    )?;
+
+    eprintln!(
+        "WARNING: {} was rewritten on chain. {} commits of {} ({}) are not in the new history; they are kept as {}.",
+        name, dropped, tracking, old, backup
+    );
+
+    if !accept {
+        error!(ExitCode::NonFastForward.wrap(format!(
+            "Refusing to fetch the rewritten {}. Fetch again with `fetch.accept_rewrite = true` in the config, INV4_GIT_FETCH_ACCEPT_REWRITE=true or from inv4://<ips>?accept-rewrite to take it anyway.",
+            name
+        )))
+    }
+
+    Ok(())
+}
+
+/// How many commits `old` has that `new` doesn't.
+fn dropped_commits(repo: &Repository, old: Oid, new: Oid) -> BoxResult<usize> {
+    let mut walk = repo.revwalk()?;
+    walk.push(old)?;
+    walk.hide(new)?;
+
+    Ok(walk.count())
+}
@@ -0,0 +1,245 @@
+use crate::{
+    config::Config,
+    connect, error,
+    fees::InvArchExtrinsicParams,
+    invarch,
+    ipfs::Ipfs,
+    primitives::{
+        BoxResult, MultiObject, MultiObjectOps, PushSession, RepoData, SUBMODULE_TIP_MARKER,
+    },
+    push_ref, set_repo,
+    spool::SpooledRepoData,
+    state::{network_id, RemoteState},
+    types::{GitHash, HashAlgorithm, IpsId},
+    util::flag,
+};
+use git2::Repository;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use subxt::DefaultConfig;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::UnixListener,
+};
+
+/// Answers line-delimited JSON commands about an IPS on stdio, or on `--socket`.
+///
+/// Every line is a request like `{"id": 1, "method": "list_refs", "params": {}}` and gets a
+/// response line with the same id and either a `result` or an `error`. The methods are
+/// `list_refs`, `get_repo_data`, `fetch_object` (`git_hash`) and `push_objects` (`repo`,
+/// `src`, `dst` and optionally `force` and `message`).
+pub async fn run(config: &Config, args: &[String]) -> BoxResult<()> {
+    let ips_id: IpsId = config
+        .ips_arg(args)?
+        .ok_or("Missing IPS id. Usage: git-remote-inv4 rpc --ips <id> [--socket <path>]")?;
+
+    let chain_api = connect(config).await?;
+
+    let mut rpc = Rpc {
+        ips_id,
+        config: config.clone(),
+        network: network_id(&chain_api),
+        chain_api,
+        ipfs: Ipfs::default(),
+        multi_objects: HashMap::new(),
+    };
+
+    match flag(args, "--socket") {
+        Some(path) => {
+            // A socket left over from an earlier run would make binding fail
+            let _ = std::fs::remove_file(path);
+            let listener = UnixListener::bind(path)?;
+            eprintln!("Answering commands for IPS {} on {}", ips_id, path);
+
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let (reader, mut writer) = stream.into_split();
+
+                if let Err(e) = rpc.serve(BufReader::new(reader), &mut writer).await {
+                    eprintln!("Connection failed: {}", e);
+                }
+            }
+        }
+        None => {
+            rpc.serve(BufReader::new(tokio::io::stdin()), &mut tokio::io::stdout())
+                .await
+        }
+    }
+}
+
+struct Rpc {
+    ips_id: IpsId,
+    config: Config,
+    network: String,
+    chain_api: invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ipfs: Ipfs,
+    /// MultiObjects already fetched, by their hash
+    multi_objects: HashMap<String, MultiObject>,
+}
+
+impl Rpc {
+    /// Answers requests read from `reader` until it is closed.
+    async fn serve<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+        &mut self,
+        reader: R,
+        writer: &mut W,
+    ) -> BoxResult<()> {
+        let mut lines = reader.lines();
+
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let response = match serde_json::from_str::<Value>(&line) {
+                Ok(request) => {
+                    let id = request.get("id").cloned().unwrap_or(Value::Null);
+
+                    match self.call(&request).await {
+                        Ok(result) => json!({ "id": id, "result": result }),
+                        Err(e) => json!({ "id": id, "error": e.to_string() }),
+                    }
+                }
+                Err(e) => json!({ "id": null, "error": format!("Invalid request: {}", e) }),
+            };
+
+            writer
+                .write_all(format!("{}\n", response).as_bytes())
+                .await?;
+            writer.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn call(&mut self, request: &Value) -> BoxResult<Value> {
+        let method = request
+            .get("method")
+            .and_then(Value::as_str)
+            .ok_or("Missing method")?;
+        let params = request.get("params").cloned().unwrap_or_else(|| json!({}));
+
+        match method {
+            "list_refs" => Ok(refs_to_json(
+                &SpooledRepoData::current(&self.chain_api, self.ips_id)
+                    .await?
+                    .refs,
+            )),
+            "get_repo_data" => {
+                let repo_data = self.repo_data().await?;
+
+                Ok(json!({
+                    "hash_algorithm": repo_data.hash_algorithm.name(),
+                    "refs": refs_to_json(&repo_data.refs),
+                    "objects": repo_data
+                        .objects
+                        .iter()
+                        .map(|(git_hash, multi_object_hash)| {
+                            (git_hash.to_string(), Value::from(multi_object_hash.as_str()))
+                        })
+                        .collect::<Map<String, Value>>(),
+                }))
+            }
+            "fetch_object" => {
+                let git_hash: GitHash = string_param(&params, "git_hash")?.parse()?;
+                self.fetch_object(&git_hash).await
+            }
+            "push_objects" => self.push_objects(&params).await,
+            other => error!(format!("Unknown method {:?}", other)),
+        }
+    }
+
+    /// The current RepoData; always read from the chain so other pushers are seen.
+    async fn repo_data(&self) -> BoxResult<RepoData> {
+        set_repo(self.ips_id, self.chain_api.clone()).await
+    }
+
+    async fn fetch_object(&mut self, git_hash: &GitHash) -> BoxResult<Value> {
+        // Looked up on disk, fetching one object shouldn't need the whole object list
+        let repo_data = SpooledRepoData::current(&self.chain_api, self.ips_id).await?;
+
+        let multi_object_hash = match repo_data.object(git_hash)? {
+            Some(hash) if hash != SUBMODULE_TIP_MARKER => hash,
+            _ => error!(format!("Object {} is not in IPS {}", git_hash, self.ips_id)),
+        };
+
+        if !self.multi_objects.contains_key(&multi_object_hash) {
+            let multi_object = MultiObject::chain_get(
+                multi_object_hash.clone(),
+                &mut self.ipfs,
+                &self.chain_api,
+                self.ips_id,
+            )
+            .await?;
+            self.multi_objects
+                .insert(multi_object_hash.clone(), multi_object);
+        }
+
+        let git_object = self.multi_objects[&multi_object_hash]
+            .objects
+            .get(git_hash)
+            .ok_or(format!(
+                "Object {} is missing from its MultiObject",
+                git_hash
+            ))?;
+
+        Ok(json!({
+            "git_hash": git_hash.to_string(),
+            "type": git_object.metadata.object_type().str(),
+            "size": git_object.data.len(),
+            "data": hex::encode(&git_object.data),
+        }))
+    }
+
+    /// Pushes `src` of the repository at `repo` to `dst`, like `git push <repo> src:dst` would.
+    async fn push_objects(&mut self, params: &Value) -> BoxResult<Value> {
+        let mut repo = Repository::open(string_param(params, "repo")?)?;
+        let src = string_param(params, "src")?;
+        let dst = string_param(params, "dst")?;
+        let force = params
+            .get("force")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let message = params.get("message").and_then(Value::as_str);
+
+        let state = RemoteState::new(&repo, "rpc", self.network.clone(), self.ips_id);
+        let mut remote_repo = self.repo_data().await?;
+        let mut session = PushSession::default();
+
+        push_ref(
+            &self.chain_api,
+            &mut remote_repo,
+            self.ips_id,
+            None,
+            &mut repo,
+            &state,
+            &mut self.ipfs,
+            src,
+            dst,
+            force,
+            &self.config,
+            false,
+            true,
+            message,
+            HashAlgorithm::Sha1,
+            &mut session,
+        )
+        .await?;
+
+        Ok(refs_to_json(&remote_repo.refs))
+    }
+}
+
+fn refs_to_json(refs: &BTreeMap<String, GitHash>) -> Value {
+    refs.iter()
+        .map(|(name, git_hash)| (name.clone(), Value::from(git_hash.to_string())))
+        .collect::<Map<String, Value>>()
+        .into()
+}
+
+fn string_param<'a>(params: &'a Value, name: &str) -> BoxResult<&'a str> {
+    Ok(params
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or(format!("Missing string parameter {:?}", name))?)
+}
@@ -0,0 +1,193 @@
+use crate::{
+    address::address,
+    config::Config,
+    connect, error,
+    ipfs::Ipfs,
+    primitives::{BoxResult, RefLogEntry, RepoData, RepoDataOps, RepoDataSignature},
+    types::{GitHash, HashAlgorithm, IpsId},
+    util::find_ipf,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use subxt::sp_core::crypto::AccountId32;
+
+static USAGE: &str = "Usage: git-remote-inv4 repodata dump --ips <id> --json";
+
+/// Version of the JSON `repodata dump --json` prints.
+///
+/// Fields may be added without changing it; it goes up when one is removed, renamed or
+/// changes meaning, so frontends can refuse dumps they don't understand.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A RepoData as JSON, for readers without SCALE.
+///
+/// Git hashes are lowercase hex, accounts SS58 addresses with the network's prefix and
+/// signatures hex. Maps are sorted by key.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RepoDataJson {
+    /// `SCHEMA_VERSION` when this was written
+    pub schema: u32,
+    pub ips_id: u32,
+    /// The IPF and CID the RepoData was read from, none for an IPS without one
+    pub ipf_id: Option<u64>,
+    pub cid: Option<String>,
+    /// `sha1` or `sha256`
+    pub hash_algorithm: String,
+    /// {ref name -> git hash}
+    pub refs: BTreeMap<String, String>,
+    /// {ref name -> name of the ref it stands for}, like `HEAD`
+    pub symrefs: BTreeMap<String, String>,
+    /// {ref name -> updates}, oldest first
+    pub reflogs: BTreeMap<String, Vec<RefLogEntryJson>>,
+    /// {git hash -> where the object is}
+    pub objects: BTreeMap<String, ObjectJson>,
+    /// Objects no ref reached anymore after a ref was deleted, only a hint
+    pub tombstones: Vec<String>,
+    /// Commits pushed without their parents from shallow clones
+    #[serde(default)]
+    pub shallow: Vec<String>,
+    /// What annotated tags point at; a {tag -> target} map
+    #[serde(default)]
+    pub tag_targets: BTreeMap<String, String>,
+    pub signature: Option<SignatureJson>,
+}
+
+/// Where an object of a RepoData is, and what it is if the pusher recorded it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ObjectJson {
+    /// Name of the IPF whose MultiObject holds the object
+    pub multi_object: String,
+    /// `commit`, `tag`, `tree` or `blob`, none for objects pushed with unversioned RepoData
+    pub kind: Option<String>,
+    /// Length of the object's raw data, present with `kind`
+    pub size: Option<u64>,
+}
+
+/// One update of a ref.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RefLogEntryJson {
+    /// None when the update created the ref
+    pub old: Option<String>,
+    /// None when the update deleted the ref
+    pub new: Option<String>,
+    pub pusher: String,
+    /// The best block when the update was pushed
+    pub block: u32,
+}
+
+/// The signature chaining a RepoData to the one it replaced.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SignatureJson {
+    /// blake2-256 of the encoded RepoData this one replaced, none for the first one
+    pub previous: Option<String>,
+    /// sr25519 public key of the signer
+    pub signer: String,
+    pub signature: String,
+}
+
+impl RepoDataJson {
+    pub fn new(ips_id: IpsId, found: Option<(u64, String)>, repo_data: &RepoData) -> Self {
+        let hex_hash = |git_hash: &GitHash| git_hash.to_string();
+        let (ipf_id, cid) = match found {
+            Some((ipf_id, cid)) => (Some(ipf_id), Some(cid)),
+            None => (None, None),
+        };
+
+        Self {
+            schema: SCHEMA_VERSION,
+            ips_id: ips_id.0,
+            ipf_id,
+            cid,
+            hash_algorithm: repo_data.hash_algorithm.name().to_owned(),
+            refs: repo_data
+                .refs
+                .iter()
+                .map(|(name, git_hash)| (name.clone(), hex_hash(git_hash)))
+                .collect(),
+            symrefs: repo_data.symrefs.clone(),
+            reflogs: repo_data
+                .reflogs
+                .iter()
+                .map(|(name, entries)| {
+                    (
+                        name.clone(),
+                        entries.iter().map(RefLogEntryJson::from).collect(),
+                    )
+                })
+                .collect(),
+            objects: repo_data
+                .objects
+                .iter()
+                .map(|(git_hash, multi_object)| {
+                    let header = repo_data.object_headers.get(git_hash);
+                    let object = ObjectJson {
+                        multi_object: multi_object.clone(),
+                        kind: header.map(|header| header.kind.object_type().str().to_owned()),
+                        size: header.map(|header| header.size),
+                    };
+                    (hex_hash(git_hash), object)
+                })
+                .collect(),
+            tombstones: repo_data.tombstones.iter().map(hex_hash).collect(),
+            shallow: repo_data.shallow.iter().map(hex_hash).collect(),
+            tag_targets: repo_data
+                .tag_targets
+                .iter()
+                .map(|(tag, target)| (hex_hash(tag), hex_hash(target)))
+                .collect(),
+            signature: repo_data.signature.as_ref().map(SignatureJson::from),
+        }
+    }
+}
+
+impl From<&RefLogEntry> for RefLogEntryJson {
+    fn from(entry: &RefLogEntry) -> Self {
+        Self {
+            old: entry.old.as_ref().map(GitHash::to_string),
+            new: entry.new.as_ref().map(GitHash::to_string),
+            pusher: address(&AccountId32::from(entry.pusher)),
+            block: entry.block,
+        }
+    }
+}
+
+impl From<&RepoDataSignature> for SignatureJson {
+    fn from(signature: &RepoDataSignature) -> Self {
+        Self {
+            previous: signature.previous.map(hex::encode),
+            signer: hex::encode(signature.signer),
+            signature: hex::encode(signature.signature),
+        }
+    }
+}
+
+/// `repodata dump --json`: prints the current RepoData of an IPS as a `RepoDataJson`.
+pub async fn run(config: &Config, args: &[String]) -> BoxResult<()> {
+    if args.first().map(String::as_str) != Some("dump") {
+        error!(USAGE)
+    }
+    if !args.iter().any(|arg| arg == "--json") {
+        error!(format!("JSON is the only format dumps come in. {}", USAGE))
+    }
+
+    let ips_id: IpsId = config
+        .ips_arg(args)?
+        .ok_or(format!("Missing IPS id. {}", USAGE))?;
+
+    let chain_api = connect(config).await?;
+
+    let (found, repo_data) = match find_ipf(&chain_api, ips_id, "RepoData").await? {
+        Some((ipf_id, cid)) => (
+            Some((ipf_id.0, cid.to_string())),
+            RepoData::from_ipfs(cid, &mut Ipfs::default()).await?,
+        ),
+        None => (None, RepoData::new(HashAlgorithm::Sha1)),
+    };
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&RepoDataJson::new(ips_id, found, &repo_data))?
+    );
+
+    Ok(())
+}
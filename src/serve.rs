@@ -0,0 +1,712 @@
+use crate::{
+    config::Config,
+    connect, error,
+    exit::{self, Class},
+    fees::InvArchExtrinsicParams,
+    invarch,
+    ipfs::Ipfs,
+    primitives::{BoxResult, MultiObject, MultiObjectOps, RepoDataOps, SUBMODULE_TIP_MARKER},
+    spool::SpooledRepoData,
+    types::{GitHash, IpsId},
+    util::flag,
+    watch::AppendedIpfs,
+};
+use dirs::cache_dir;
+use flate2::{read::GzDecoder, write::ZlibEncoder, Compression};
+use git2::Repository;
+use log::debug;
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::Stdio,
+    rc::Rc,
+    time::Duration,
+};
+use subxt::DefaultConfig;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    process::Command,
+    sync::Mutex,
+    task::LocalSet,
+    time::timeout,
+};
+
+/// Where `serve` listens without `--addr`. `--addr :<port>` listens on every interface.
+static DEFAULT_ADDR: &str = "127.0.0.1:8080";
+
+/// How long a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a client may take to read its response.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Bytes of MultiObjects kept around for the loose object requests that follow, the oldest
+/// dropped first. The newest one is kept whatever its size.
+const MAX_CACHED_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Requests with a longer head than this are rejected.
+const MAX_REQUEST_HEAD: usize = 8192;
+
+/// Requests with a longer body than this are rejected before any of it is read. Even the
+/// negotiation of a large clone stays far below it.
+const MAX_REQUEST_BODY: usize = 64 * 1024 * 1024;
+
+/// A request that isn't read at all, answered with `.0` as the status.
+#[derive(Debug)]
+struct Refused(&'static str);
+
+impl std::fmt::Display for Refused {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Refused request: {}", self.0)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// Serves an IPS over HTTP, so it can be cloned without the helper.
+///
+/// Git's dumb protocol is always available: `info/refs` reloads RepoData from the chain and
+/// loose objects are built on demand from the MultiObjects they were pushed in. With `--smart`,
+/// a bare mirror is kept in sync with the chain and fronted by `git upload-pack`.
+pub async fn run(config: &Config, args: &[String]) -> BoxResult<()> {
+    let ips_id: IpsId = config
+        .ips_arg(args)?
+        .ok_or("Missing IPS id. Usage: git-remote-inv4 serve --ips <id> [--addr 127.0.0.1:8080] [--smart [--mirror <path>]]")?;
+
+    let addr = match flag(args, "--addr").unwrap_or(DEFAULT_ADDR) {
+        port if port.starts_with(':') => format!("0.0.0.0{}", port),
+        addr => addr.to_owned(),
+    };
+
+    let mirror = if args.iter().any(|arg| arg == "--smart") {
+        Some(match flag(args, "--mirror") {
+            Some(path) => PathBuf::from(path),
+            None => cache_dir()
+                .ok_or("Operating system's cache directory not found")?
+                .join("INV4-Git")
+                .join("mirrors")
+                .join(format!("{}.git", ips_id)),
+        })
+    } else {
+        None
+    };
+
+    let chain_api = connect(config).await?;
+
+    let mut server = Server {
+        ips_id,
+        repo_data: SpooledRepoData::current(&chain_api, ips_id).await?,
+        chain_api,
+        ipfs: Ipfs::default(),
+        multi_objects: HashMap::new(),
+        cached_order: VecDeque::new(),
+        cached_bytes: 0,
+        mirror,
+    };
+
+    let mut appended = AppendedIpfs::subscribe(&server.chain_api, ips_id).await?;
+
+    if server.mirror.is_some() {
+        server.sync_mirror().await?;
+    }
+
+    let listener = TcpListener::bind(&addr).await?;
+    eprintln!("Serving IPS {} at http://{}/", ips_id, addr);
+
+    // Every connection gets a task, so a slow client only holds up itself
+    let server = Rc::new(Mutex::new(server));
+    LocalSet::new()
+        .run_until(async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => {
+                        let (stream, peer) = accepted?;
+                        let server = server.clone();
+
+                        tokio::task::spawn_local(async move {
+                            if let Err(e) = handle(&server, stream).await {
+                                eprintln!("Request from {} failed: {}", peer, e);
+                            }
+                        });
+                    }
+                    Some(_) = appended.next() => {
+                        debug!("IPS {} changed on chain", ips_id);
+                        let mut server = server.lock().await;
+                        // Keeps serving the previous RepoData until the next change loads
+                        match SpooledRepoData::current(&server.chain_api, ips_id).await {
+                            Ok(repo_data) => server.repo_data = repo_data,
+                            Err(e) => {
+                                eprintln!("Could not load the new RepoData: {}", e);
+                                continue;
+                            }
+                        }
+
+                        if server.mirror.is_some() {
+                            if let Err(e) = server.sync_mirror().await {
+                                eprintln!("Could not update the mirror: {}", e);
+                            }
+                        }
+                    }
+                }
+            }
+        })
+        .await
+}
+
+/// Reads the request on `stream` and answers it. The server is only locked in between, so
+/// clients slow to send or receive don't keep the others waiting.
+async fn handle(server: &Mutex<Server>, mut stream: TcpStream) -> BoxResult<()> {
+    let (reader, mut writer) = stream.split();
+
+    let request = match timeout(REQUEST_TIMEOUT, read_request(&mut BufReader::new(reader))).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(e)) => {
+            if let Some(Refused(status)) = e.downcast_ref::<Refused>() {
+                let response = Response::error(status);
+                timeout(
+                    RESPONSE_TIMEOUT,
+                    write_response(&mut writer, &response, false),
+                )
+                .await
+                .map_err(|_| "Timed out sending the response")??;
+            }
+            return Err(e);
+        }
+        Err(_) => error!("Timed out waiting for the request"),
+    };
+
+    debug!("{} {}?{}", request.method, request.path, request.query);
+
+    let response = server.lock().await.respond(&request).await;
+
+    timeout(
+        RESPONSE_TIMEOUT,
+        write_response(&mut writer, &response, request.method == "HEAD"),
+    )
+    .await
+    .map_err(|_| "Timed out sending the response")?
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: Vec<u8>) -> Self {
+        Self {
+            status: "200 OK",
+            content_type,
+            body,
+        }
+    }
+
+    fn error(status: &'static str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: format!("{}\n", status).into_bytes(),
+        }
+    }
+}
+
+struct Server {
+    ips_id: IpsId,
+    chain_api: invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ipfs: Ipfs,
+    /// Only refs are kept in memory, objects are looked up on disk
+    repo_data: SpooledRepoData,
+    /// MultiObjects fetched recently, by hash
+    multi_objects: HashMap<String, Rc<MultiObject>>,
+    /// The order `multi_objects` was filled in, to drop the oldest first
+    cached_order: VecDeque<(String, u64)>,
+    cached_bytes: u64,
+    /// The bare repository smart HTTP requests are answered from
+    mirror: Option<PathBuf>,
+}
+
+impl Server {
+    async fn respond(&mut self, request: &Request) -> Response {
+        match self.route(request).await {
+            Ok(Some(response)) => response,
+            Ok(None) => Response::error("404 Not Found"),
+            Err(e) => {
+                eprintln!("Could not serve {}: {}", request.path, e);
+                // Tells HTTP clients whether asking again later may work
+                match exit::classify(&*e) {
+                    Class::Transient => Response::error("503 Service Unavailable"),
+                    Class::UserActionable | Class::Permanent => {
+                        Response::error("500 Internal Server Error")
+                    }
+                }
+            }
+        }
+    }
+
+    /// Answers the files a git HTTP client asks for; any prefix before them is ignored.
+    async fn route(&mut self, request: &Request) -> BoxResult<Option<Response>> {
+        let path = request.path.as_str();
+
+        match request.method.as_str() {
+            "GET" | "HEAD" => {}
+            "POST" if path.ends_with("/git-upload-pack") => return self.upload_pack(request).await,
+            _ => return Ok(Some(Response::error("405 Method Not Allowed"))),
+        }
+
+        if path.ends_with("/info/refs") {
+            if request
+                .query
+                .split('&')
+                .any(|q| q == "service=git-upload-pack")
+            {
+                return self.advertise_refs().await;
+            }
+
+            self.repo_data = SpooledRepoData::current(&self.chain_api, self.ips_id).await?;
+            return Ok(Some(Response::ok("text/plain", self.info_refs())));
+        }
+
+        if path.ends_with("/HEAD") {
+            return Ok(self
+                .head()
+                .map(|head| Response::ok("text/plain", format!("ref: {}\n", head).into_bytes())));
+        }
+
+        let mut components = path.rsplit('/');
+        if let (Some(rest), Some(dir), Some("objects")) =
+            (components.next(), components.next(), components.next())
+        {
+            if let Ok(git_hash) = format!("{}{}", dir, rest).parse::<GitHash>() {
+                return Ok(self
+                    .loose_object(&git_hash)
+                    .await?
+                    .map(|object| Response::ok("application/x-git-loose-object", object)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn info_refs(&self) -> Vec<u8> {
+        self.repo_data
+            .refs
+            .iter()
+            .map(|(name, git_hash)| format!("{}\t{}\n", git_hash, name))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    fn head(&self) -> Option<&str> {
+        default_head(&self.repo_data)
+    }
+
+    /// The zlib compressed loose object file for `git_hash`.
+    async fn loose_object(&mut self, git_hash: &GitHash) -> BoxResult<Option<Vec<u8>>> {
+        let multi_object_hash = match self.repo_data.object(git_hash)? {
+            Some(hash) if hash != SUBMODULE_TIP_MARKER => hash,
+            _ => return Ok(None),
+        };
+
+        let multi_object = self.multi_object(multi_object_hash).await?;
+        let git_object = match multi_object.objects.get(git_hash) {
+            Some(git_object) => git_object,
+            None => return Ok(None),
+        };
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        write!(
+            encoder,
+            "{} {}\0",
+            git_object.metadata.object_type().str(),
+            git_object.data.len()
+        )?;
+        encoder.write_all(&git_object.data)?;
+
+        Ok(Some(encoder.finish()?))
+    }
+
+    /// The MultiObject `hash`, from the cache if it was fetched recently.
+    async fn multi_object(&mut self, hash: String) -> BoxResult<Rc<MultiObject>> {
+        if let Some(multi_object) = self.multi_objects.get(&hash) {
+            return Ok(multi_object.clone());
+        }
+
+        let multi_object = Rc::new(
+            MultiObject::chain_get(hash.clone(), &mut self.ipfs, &self.chain_api, self.ips_id)
+                .await?,
+        );
+        let size: u64 = multi_object
+            .objects
+            .values()
+            .map(|object| object.data.len() as u64)
+            .sum();
+
+        self.multi_objects
+            .insert(hash.clone(), multi_object.clone());
+        self.cached_order.push_back((hash, size));
+        self.cached_bytes += size;
+
+        while self.cached_bytes > MAX_CACHED_BYTES && self.cached_order.len() > 1 {
+            if let Some((oldest, size)) = self.cached_order.pop_front() {
+                self.multi_objects.remove(&oldest);
+                self.cached_bytes -= size;
+            }
+        }
+
+        Ok(multi_object)
+    }
+
+    /// Brings the mirror's refs and objects in line with the current RepoData.
+    async fn sync_mirror(&mut self) -> BoxResult<()> {
+        let path = self.mirror.clone().ok_or("No mirror configured")?;
+
+        sync_mirror(
+            &path,
+            &self.repo_data,
+            &mut self.ipfs,
+            &self.chain_api,
+            self.ips_id,
+        )
+        .await
+    }
+
+    /// The smart protocol ref advertisement for `git-upload-pack`.
+    async fn advertise_refs(&self) -> BoxResult<Option<Response>> {
+        let mirror = match &self.mirror {
+            Some(mirror) => mirror,
+            None => return Ok(None),
+        };
+
+        let mut body = pkt_line("# service=git-upload-pack\n");
+        body.extend(b"0000");
+        body.extend(
+            run_upload_pack(mirror, &["--stateless-rpc", "--advertise-refs"], vec![]).await?,
+        );
+
+        Ok(Some(Response::ok(
+            "application/x-git-upload-pack-advertisement",
+            body,
+        )))
+    }
+
+    async fn upload_pack(&self, request: &Request) -> BoxResult<Option<Response>> {
+        let mirror = match &self.mirror {
+            Some(mirror) => mirror,
+            None => return Ok(None),
+        };
+
+        let input = if request.header("Content-Encoding") == Some("gzip") {
+            match gunzip(&request.body)? {
+                Some(decoded) => decoded,
+                None => return Ok(Some(Response::error("413 Payload Too Large"))),
+            }
+        } else {
+            request.body.clone()
+        };
+
+        Ok(Some(Response::ok(
+            "application/x-git-upload-pack-result",
+            run_upload_pack(mirror, &["--stateless-rpc"], input).await?,
+        )))
+    }
+}
+
+/// Decompresses a gzip request body, or `None` if it inflates past `MAX_REQUEST_BODY`.
+fn gunzip(body: &[u8]) -> BoxResult<Option<Vec<u8>>> {
+    let mut decoded = Vec::new();
+    GzDecoder::new(body)
+        .take(MAX_REQUEST_BODY as u64 + 1)
+        .read_to_end(&mut decoded)?;
+
+    Ok((decoded.len() <= MAX_REQUEST_BODY).then_some(decoded))
+}
+
+/// Brings the bare repository at `path` in line with `repo_data`, creating it if needed.
+pub async fn sync_mirror(
+    path: &Path,
+    repo_data: &SpooledRepoData,
+    ipfs: &mut Ipfs,
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ips_id: IpsId,
+) -> BoxResult<()> {
+    let mut mirror = match Repository::open_bare(path) {
+        Ok(mirror) => mirror,
+        Err(_) => {
+            std::fs::create_dir_all(path)?;
+            Repository::init_bare(path)?
+        }
+    };
+
+    // Only a mirror that is behind needs every object at once
+    let mut loaded = None;
+
+    for (name, git_hash) in &repo_data.refs {
+        let oid = git_hash.to_oid()?;
+        if mirror.refname_to_id(name).ok() == Some(oid) {
+            continue;
+        }
+
+        eprintln!("Mirroring {} at {}", name, git_hash);
+        if loaded.is_none() {
+            loaded = Some(repo_data.load()?);
+        }
+        loaded
+            .as_ref()
+            .ok_or("Internal error: RepoData was not loaded")?
+            .fetch_to_ref_from_str(git_hash, name, &mut mirror, ipfs, chain_api, ips_id)
+            .await?;
+
+        // Unlike a fetch into a working repository, the mirror needs every ref, tags included
+        mirror.reference(name, oid, true, "inv4-git mirror")?;
+    }
+
+    let stale: Vec<String> = mirror
+        .references()?
+        .filter_map(|reference| reference.ok()?.name().map(str::to_owned))
+        .filter(|name| !repo_data.refs.contains_key(name))
+        .collect();
+
+    for name in stale {
+        debug!("Removing {} from the mirror", name);
+        mirror.find_reference(&name)?.delete()?;
+    }
+
+    if let Some(head) = default_head(repo_data) {
+        mirror.set_head(head)?;
+    }
+
+    Ok(())
+}
+
+/// The branch HEAD stands for in the RepoData, or for RepoData that doesn't record HEAD the
+/// branch a clone most likely wants.
+fn default_head(repo_data: &SpooledRepoData) -> Option<&str> {
+    let refs = &repo_data.refs;
+    if let Some(target) = repo_data
+        .symrefs
+        .get("HEAD")
+        .filter(|target| refs.contains_key(target.as_str()))
+    {
+        return Some(target);
+    }
+
+    if let Some(name) = ["refs/heads/main", "refs/heads/master"]
+        .into_iter()
+        .find(|name| refs.contains_key(*name))
+    {
+        return Some(name);
+    }
+
+    refs.keys()
+        .map(String::as_str)
+        .find(|name| name.starts_with("refs/heads/"))
+}
+
+fn pkt_line(data: &str) -> Vec<u8> {
+    format!("{:04x}{}", data.len() + 4, data).into_bytes()
+}
+
+async fn run_upload_pack(mirror: &Path, args: &[&str], input: Vec<u8>) -> BoxResult<Vec<u8>> {
+    let mut child = Command::new("git")
+        .arg("upload-pack")
+        .args(args)
+        .arg(mirror)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().ok_or("upload-pack has no stdin")?;
+    let writer = tokio::spawn(async move { stdin.write_all(&input).await });
+
+    let output = child.wait_with_output().await?;
+    writer.await??;
+
+    if !output.status.success() {
+        error!(format!("git upload-pack exited with {}", output.status))
+    }
+
+    Ok(output.stdout)
+}
+
+async fn write_response<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    response: &Response,
+    head_only: bool,
+) -> BoxResult<()> {
+    let mut out = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )
+    .into_bytes();
+
+    if !head_only {
+        out.extend(&response.body);
+    }
+
+    writer.write_all(&out).await?;
+    writer.shutdown().await?;
+
+    Ok(())
+}
+
+/// Reads a request with its body, which may be sent chunked.
+///
+/// Fails with `Refused` for requests whose head or body is larger than the server reads.
+async fn read_request<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> BoxResult<Request> {
+    let mut lines = Vec::new();
+    let mut head_len = 0;
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            break;
+        }
+
+        head_len += line.len();
+        if head_len > MAX_REQUEST_HEAD {
+            error!(Refused("431 Request Header Fields Too Large"))
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']).to_owned();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut lines = lines.into_iter();
+    let request_line = lines.next().ok_or("Empty request")?;
+    let mut parts = request_line.split(' ');
+    let method = parts.next().unwrap_or_default().to_owned();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim().to_owned(), value.trim().to_owned()))
+        })
+        .collect();
+
+    let mut request = Request {
+        method,
+        path: path.to_owned(),
+        query: query.to_owned(),
+        headers,
+        body: vec![],
+    };
+
+    if request.header("Transfer-Encoding") == Some("chunked") {
+        loop {
+            let mut size_line = String::new();
+            reader.read_line(&mut size_line).await?;
+            let size =
+                usize::from_str_radix(size_line.trim().split(';').next().unwrap_or_default(), 16)?;
+            if size > MAX_REQUEST_BODY - request.body.len() {
+                error!(Refused("413 Payload Too Large"))
+            }
+
+            let mut chunk = vec![0u8; size + 2];
+            reader.read_exact(&mut chunk).await?;
+
+            if size == 0 {
+                break;
+            }
+            request.body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(length) = request.header("Content-Length") {
+        let length: usize = length.parse()?;
+        if length > MAX_REQUEST_BODY {
+            error!(Refused("413 Payload Too Large"))
+        }
+
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body).await?;
+        request.body = body;
+    }
+
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+
+    async fn refusal(request: &str) -> Option<&'static str> {
+        match read_request(&mut BufReader::new(request.as_bytes())).await {
+            Ok(_) => None,
+            Err(e) => e.downcast_ref::<Refused>().map(|refused| refused.0),
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_refused_before_reading() {
+        let long = format!(
+            "POST /git-upload-pack HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_REQUEST_BODY + 1
+        );
+        assert_eq!(refusal(&long).await, Some("413 Payload Too Large"));
+
+        let chunked = format!(
+            "POST /git-upload-pack HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+            usize::MAX
+        );
+        assert_eq!(refusal(&chunked).await, Some("413 Payload Too Large"));
+    }
+
+    #[tokio::test]
+    async fn bodies_within_the_limit_are_read() {
+        let request = read_request(&mut BufReader::new(
+            &b"POST /git-upload-pack HTTP/1.1\r\nContent-Length: 4\r\n\r\n0000"[..],
+        ))
+        .await
+        .unwrap();
+        assert_eq!(request.body, b"0000");
+
+        let request = read_request(&mut BufReader::new(
+            &b"POST /x HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n0\r\n\r\n"[..],
+        ))
+        .await
+        .unwrap();
+        assert_eq!(request.body, b"ab");
+    }
+
+    #[test]
+    fn gzip_bodies_inflating_past_the_limit_are_refused() {
+        let gzip = |data: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        assert_eq!(gunzip(&gzip(b"0000")).unwrap(), Some(b"0000".to_vec()));
+
+        let bomb = gzip(&vec![0u8; MAX_REQUEST_BODY + 1]);
+        assert!(bomb.len() < MAX_REQUEST_BODY / 100);
+        assert_eq!(gunzip(&bomb).unwrap(), None);
+    }
+}
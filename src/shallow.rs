@@ -0,0 +1,70 @@
+use crate::{primitives::BoxResult, types::GitHash};
+use git2::{Oid, Repository};
+use std::collections::BTreeSet;
+
+/// Push option, as in `git push -o allow-shallow`, that pushes history of a shallow or grafted
+/// clone down to where it is cut off, recording the cut in RepoData.
+pub static ALLOW_SHALLOW_PUSH_OPTION: &str = "allow-shallow";
+
+/// The commits of `repo` that miss a parent: the boundary of a shallow clone, as listed in
+/// `.git/shallow`, and grafted commits whose real parents aren't there.
+pub fn boundary(repo: &Repository) -> BoxResult<BTreeSet<GitHash>> {
+    let mut candidates = read_hashes(&repo.path().join("shallow"))?;
+    candidates.extend(read_hashes(&repo.path().join("info").join("grafts"))?);
+
+    let odb = repo.odb()?;
+    let mut boundary = BTreeSet::new();
+    for oid in candidates {
+        let commit = match repo.find_commit(oid) {
+            Ok(commit) => commit,
+            Err(_) => continue,
+        };
+        if commit
+            .parent_ids()
+            .any(|parent| odb.read_header(parent).is_err())
+        {
+            boundary.insert(GitHash::from(oid));
+        }
+    }
+
+    Ok(boundary)
+}
+
+/// Adds `commits` to `.git/shallow` of `repo`, so git accepts that their parents are missing.
+pub fn record(repo: &Repository, commits: &BTreeSet<GitHash>) -> BoxResult<()> {
+    if commits.is_empty() {
+        return Ok(());
+    }
+
+    let path = repo.path().join("shallow");
+    let mut shallow: BTreeSet<String> = read_hashes(&path)?
+        .into_iter()
+        .map(|oid| oid.to_string())
+        .collect();
+    shallow.extend(commits.iter().map(GitHash::to_string));
+
+    let contents: String = shallow.iter().map(|hash| format!("{}\n", hash)).collect();
+    std::fs::write(path, contents)?;
+
+    Ok(())
+}
+
+/// The first hash of every line of `path`, which is all of `shallow` and the grafted commit of
+/// every line of `info/grafts`.
+fn read_hashes(path: &std::path::Path) -> BoxResult<BTreeSet<Oid>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut hashes = BTreeSet::new();
+    for hash in contents
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+    {
+        hashes.insert(Oid::from_str(hash)?);
+    }
+
+    Ok(hashes)
+}
@@ -0,0 +1,269 @@
+use crate::{
+    bloom::ObjectFilter,
+    fees::InvArchExtrinsicParams,
+    invarch,
+    ipfs::{Ipfs, IpfsOps},
+    primitives::{
+        check_signature, signing_payload, BoxResult, RepoData, RepoDataOps, RepoDataSignature,
+        REPO_DATA_MARKER, REPO_DATA_VERSION,
+    },
+    timeout::{within, Operation},
+    types::{GitHash, HashAlgorithm, IpsId},
+    util::find_ipf,
+};
+use blake2::{
+    digest::{consts::U32, Digest},
+    Blake2b,
+};
+use cid::Cid;
+use codec::{Compact, Decode, Input, IoReader};
+use log::debug;
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::PathBuf,
+};
+use subxt::DefaultConfig;
+use temp_dir::TempDir;
+
+/// How much of a skipped section is read at a time.
+const SKIP_CHUNK: usize = 64 * 1024;
+
+/// A RepoData kept on disk, with only its refs in memory.
+///
+/// Decoding a RepoData materializes every object it lists, which a relay on a small device may
+/// not have the memory for. The sections after the refs are length-prefixed, so they are only
+/// streamed through to check the signature and read again when an object is looked up, and a
+/// bloom filter in front of them answers most lookups of objects the RepoData doesn't have.
+pub struct SpooledRepoData {
+    /// The object format of the repository
+    pub hash_algorithm: HashAlgorithm,
+    /// All refs this repository knows; a {name -> git hash} map
+    pub refs: BTreeMap<String, GitHash>,
+    /// Refs standing for another ref, like HEAD; a {name -> target} map
+    pub symrefs: BTreeMap<String, String>,
+    objects: Objects,
+    /// Holds the encoded RepoData; `None` for an IPS without one
+    dir: Option<TempDir>,
+}
+
+enum Objects {
+    /// Where the objects section starts in the spooled RepoData, its length and the filter of
+    /// the objects in it
+    Section {
+        offset: u64,
+        len: u64,
+        filter: ObjectFilter,
+    },
+    /// Unversioned RepoData can't be skipped through, so its objects are decoded like before
+    Decoded(BTreeMap<GitHash, String>),
+}
+
+impl SpooledRepoData {
+    /// The current RepoData of `ips_id`, like `set_repo`.
+    pub async fn current(
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> BoxResult<Self> {
+        match find_ipf(chain_api, ips_id, "RepoData").await? {
+            Some((_, cid)) => Self::from_ipfs(cid, &Ipfs::default()).await,
+            None => Ok(Self {
+                hash_algorithm: HashAlgorithm::Sha1,
+                refs: BTreeMap::new(),
+                symrefs: BTreeMap::new(),
+                objects: Objects::Decoded(BTreeMap::new()),
+                dir: None,
+            }),
+        }
+    }
+
+    /// Streams the RepoData at `cid` to disk and reads its refs, checking its signature.
+    pub async fn from_ipfs(cid: Cid, ipfs: &Ipfs) -> BoxResult<Self> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("repo-data.scale");
+        let mut file = File::create(&path)?;
+
+        within(
+            Operation::IpfsCat,
+            &format!("reading {} from IPFS", cid),
+            ipfs.cat_to(&cid.to_string(), &mut file),
+        )
+        .await?;
+
+        let mut input = Hashing::new(BufReader::new(File::open(&path)?));
+
+        let version = match input.read_byte()? {
+            REPO_DATA_MARKER => input.read_byte()?,
+            _ => 0,
+        };
+
+        if version != REPO_DATA_VERSION {
+            debug!("RepoData {} is unversioned, decoding it whole", cid);
+
+            let encoded = std::fs::read(&path)?;
+            let repo_data = RepoData::decode(&mut encoded.as_slice())?;
+            repo_data.verify_signature(&encoded)?;
+
+            return Ok(Self {
+                hash_algorithm: repo_data.hash_algorithm,
+                refs: repo_data.refs,
+                symrefs: repo_data.symrefs,
+                objects: Objects::Decoded(repo_data.objects),
+                dir: Some(dir),
+            });
+        }
+
+        let hash_algorithm = HashAlgorithm::decode(&mut input)?;
+
+        let mut refs = BTreeMap::new();
+        for _ in 0..Compact::<u32>::decode(&mut input)?.0 {
+            let name = String::decode(&mut input)?;
+            refs.insert(name, read_git_hash(&mut input, hash_algorithm)?);
+        }
+
+        // Reflogs are only needed to audit, so they stay on disk
+        let symrefs = BTreeMap::decode(&mut input)?;
+        let len = Compact::<u64>::decode(&mut input)?.0;
+        input.skip(len)?;
+
+        Compact::<u64>::decode(&mut input)?;
+        let filter = ObjectFilter::decode(&mut input)?;
+
+        let len = Compact::<u64>::decode(&mut input)?.0;
+        let objects = Objects::Section {
+            offset: input.position,
+            len,
+            filter,
+        };
+        input.skip(len)?;
+
+        // Object headers, tombstones, shallow commits and tag targets
+        for _ in 0..4 {
+            let len = Compact::<u64>::decode(&mut input)?.0;
+            input.skip(len)?;
+        }
+
+        let digest = input.digest();
+        if let Some(signature) = Option::<RepoDataSignature>::decode(&mut input)? {
+            check_signature(&signature, &signing_payload(digest, &signature.previous))?;
+        }
+
+        Ok(Self {
+            hash_algorithm,
+            refs,
+            symrefs,
+            objects,
+            dir: Some(dir),
+        })
+    }
+
+    /// The hash of the MultiObject `git_hash` was pushed in, read from disk.
+    pub fn object(&self, git_hash: &GitHash) -> BoxResult<Option<String>> {
+        let (offset, len) = match &self.objects {
+            Objects::Decoded(objects) => return Ok(objects.get(git_hash).cloned()),
+            Objects::Section { filter, .. } if !filter.may_contain(git_hash) => return Ok(None),
+            Objects::Section { offset, len, .. } => (*offset, *len),
+        };
+
+        let mut file = File::open(self.path()?)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut input = IoReader(BufReader::new(file.take(len)));
+
+        for _ in 0..Compact::<u32>::decode(&mut input)?.0 {
+            let found = read_git_hash(&mut input, self.hash_algorithm)?;
+            let multi_object_hash = String::decode(&mut input)?;
+
+            // Objects are encoded in order
+            match found.cmp(git_hash) {
+                Ordering::Equal => return Ok(Some(multi_object_hash)),
+                Ordering::Greater => break,
+                Ordering::Less => {}
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The whole RepoData, for the few uses that need every object at once.
+    pub fn load(&self) -> BoxResult<RepoData> {
+        if self.dir.is_none() {
+            return Ok(RepoData::new(self.hash_algorithm));
+        }
+
+        let encoded = std::fs::read(self.path()?)?;
+        Ok(RepoData::decode(&mut encoded.as_slice())?)
+    }
+
+    fn path(&self) -> BoxResult<PathBuf> {
+        Ok(self
+            .dir
+            .as_ref()
+            .ok_or("Internal error: empty RepoData has nothing spooled")?
+            .path()
+            .join("repo-data.scale"))
+    }
+}
+
+fn read_git_hash<I: Input>(
+    input: &mut I,
+    hash_algorithm: HashAlgorithm,
+) -> Result<GitHash, codec::Error> {
+    let mut raw = vec![0u8; hash_algorithm.raw_len()];
+    input.read(&mut raw)?;
+    Ok(GitHash::from_raw(&raw))
+}
+
+/// Decodes from `reader` while hashing what was read, as signatures cover the blake2-256 of the
+/// unsigned encoding.
+struct Hashing<R: Read> {
+    reader: R,
+    hasher: Blake2b<U32>,
+    /// How many bytes were read so far
+    position: u64,
+}
+
+impl<R: Read> Hashing<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            hasher: Blake2b::new(),
+            position: 0,
+        }
+    }
+
+    /// Reads past `len` bytes without keeping them.
+    fn skip(&mut self, len: u64) -> Result<(), codec::Error> {
+        let mut remaining = len;
+        let mut chunk = vec![0u8; SKIP_CHUNK];
+
+        while remaining > 0 {
+            let n = remaining.min(SKIP_CHUNK as u64) as usize;
+            self.read(&mut chunk[..n])?;
+            remaining -= n as u64;
+        }
+
+        Ok(())
+    }
+
+    /// The blake2-256 of everything read so far.
+    fn digest(&self) -> [u8; 32] {
+        self.hasher.clone().finalize().into()
+    }
+}
+
+impl<R: Read> Input for Hashing<R> {
+    fn remaining_len(&mut self) -> Result<Option<usize>, codec::Error> {
+        Ok(None)
+    }
+
+    fn read(&mut self, into: &mut [u8]) -> Result<(), codec::Error> {
+        self.reader
+            .read_exact(into)
+            .map_err(|_| codec::Error::from("RepoData ends early"))?;
+        self.hasher.update(&*into);
+        self.position += into.len() as u64;
+        Ok(())
+    }
+}
@@ -0,0 +1,313 @@
+use crate::{
+    error, exit::ExitCode, fees::InvArchExtrinsicParams, invarch, primitives::BoxResult,
+    types::IpsId,
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression, GzBuilder};
+use git2::Repository;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+use subxt::DefaultConfig;
+
+static USAGE: &str =
+    "Usage: git-remote-inv4 state export <state.tar.gz> | state import <state.tar.gz> [--force]";
+
+/// The local state (queued pushes, fee ledger) of one remote.
+///
+/// Lives in `.git/inv4/<remote>/<network>-<ips_id>/` so two inv4 remotes of the same repository,
+/// or a remote whose URL was pointed at another IPS or chain, never see each other's state.
+#[derive(Debug, Clone)]
+pub struct RemoteState {
+    pub remote: String,
+    pub network: String,
+    pub ips_id: IpsId,
+    dir: PathBuf,
+    inv4_dir: PathBuf,
+}
+
+/// `.git/inv4/`, which holds the state of every remote and what they share.
+pub fn inv4_dir(repo: &Repository) -> PathBuf {
+    repo.path().join("inv4")
+}
+
+/// Identifies the chain `chain_api` is connected to by its genesis hash, so switching between
+/// nodes of the same network keeps the state.
+pub fn network_id(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+) -> String {
+    hex::encode(&chain_api.client.genesis().as_bytes()[..8])
+}
+
+/// Turns a remote name (or the URL git passes for anonymous remotes) into a directory name.
+pub fn sanitize(remote: &str) -> String {
+    remote
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>()
+        .trim_start_matches('.')
+        .to_owned()
+}
+
+/// `state export|import`: moves the local state of every remote, the cached RepoData, queued
+/// pushes, ledgers and receipts, between clones as a tarball, so a CI job can pick up where an
+/// earlier one left off.
+pub fn run(repo: &Repository, args: &[String]) -> BoxResult<()> {
+    match (args.first().map(String::as_str), args.get(1)) {
+        (Some("export"), Some(path)) => export(repo, Path::new(path)),
+        (Some("import"), Some(path)) => import(
+            repo,
+            Path::new(path),
+            args[2..].iter().any(|arg| arg == "--force"),
+        ),
+        _ => error!(USAGE),
+    }
+}
+
+/// Writes `.git/inv4/` to `path` as a gzipped tarball.
+///
+/// Entries are sorted and carry no timestamps or owners, so the same state always gives the
+/// same bytes.
+fn export(repo: &Repository, path: &Path) -> BoxResult<()> {
+    let root = inv4_dir(repo);
+    if !root.exists() {
+        error!(format!("No local state at {} to export", root.display()))
+    }
+
+    let gz = GzBuilder::new().write(File::create(path)?, Compression::default());
+    let mut tar = tar::Builder::new(gz);
+    tar.mode(tar::HeaderMode::Deterministic);
+    tar.follow_symlinks(false);
+
+    let mut files = 0;
+    append_sorted(&mut tar, &root, Path::new(""), &mut files)?;
+    tar.into_inner()?.finish()?;
+
+    eprintln!(
+        "Exported {} files of {} to {}",
+        files,
+        root.display(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+fn append_sorted(
+    tar: &mut tar::Builder<GzEncoder<File>>,
+    dir: &Path,
+    prefix: &Path,
+    files: &mut usize,
+) -> BoxResult<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = prefix.join(entry.file_name());
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            tar.append_dir(&name, entry.path())?;
+            append_sorted(tar, &entry.path(), &name, files)?;
+        } else if file_type.is_file() {
+            tar.append_path_with_name(entry.path(), &name)?;
+            *files += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Unpacks a tarball written by `export` into `.git/inv4/`.
+///
+/// Existing state is only replaced with `force`, as queued pushes in it would be lost.
+fn import(repo: &Repository, path: &Path, force: bool) -> BoxResult<()> {
+    let root = inv4_dir(repo);
+    let has_state = std::fs::read_dir(&root)
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false);
+
+    if has_state {
+        if !force {
+            error!(ExitCode::Config.wrap(format!(
+                "{} already holds local state, pass --force to replace it",
+                root.display()
+            )))
+        }
+        std::fs::remove_dir_all(&root)?;
+    }
+
+    std::fs::create_dir_all(&root)?;
+
+    let f = File::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+    let mut tar = tar::Archive::new(GzDecoder::new(f));
+    let mut files = 0;
+
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        // `unpack_in` refuses entries that would land outside of `root`
+        if entry.unpack_in(&root)? && entry.header().entry_type().is_file() {
+            files += 1;
+        }
+    }
+
+    eprintln!(
+        "Imported {} files from {} to {}",
+        files,
+        path.display(),
+        root.display()
+    );
+
+    Ok(())
+}
+
+impl RemoteState {
+    pub fn new(repo: &Repository, remote: &str, network: String, ips_id: IpsId) -> Self {
+        let remote = sanitize(remote);
+        let inv4_dir = inv4_dir(repo);
+        let dir = inv4_dir
+            .join(&remote)
+            .join(format!("{}-{}", network, ips_id));
+
+        Self {
+            remote,
+            network,
+            ips_id,
+            dir,
+            inv4_dir,
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The `.git/inv4/` this remote's directory is in.
+    pub fn inv4_dir(&self) -> &Path {
+        &self.inv4_dir
+    }
+
+    /// The state of every remote that was ever used from `repo`.
+    pub fn all(repo: &Repository) -> BoxResult<Vec<Self>> {
+        let root = inv4_dir(repo);
+        if !root.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut states = Vec::new();
+
+        for remote_dir in std::fs::read_dir(&root)? {
+            let remote_dir = remote_dir?;
+            if !remote_dir.file_type()?.is_dir() {
+                continue;
+            }
+
+            for state_dir in std::fs::read_dir(remote_dir.path())? {
+                let state_dir = state_dir?;
+                if !state_dir.file_type()?.is_dir() {
+                    continue;
+                }
+
+                let name = state_dir.file_name();
+                let parsed = name
+                    .to_str()
+                    .and_then(|name| name.split_once('-'))
+                    .and_then(|(network, ips_id)| Some((network, ips_id.parse::<IpsId>().ok()?)));
+
+                if let (Some((network, ips_id)), Some(remote)) =
+                    (parsed, remote_dir.file_name().to_str())
+                {
+                    states.push(Self {
+                        remote: remote.to_owned(),
+                        network: network.to_owned(),
+                        ips_id,
+                        dir: state_dir.path(),
+                        inv4_dir: root.clone(),
+                    });
+                }
+            }
+        }
+
+        states.sort_by(|a, b| a.dir.cmp(&b.dir));
+
+        Ok(states)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        primitives::{MultiObject, RepoData},
+        queue::{self, QueuedPush},
+        types::HashAlgorithm,
+    };
+    use temp_dir::TempDir;
+
+    #[test]
+    fn bare_repositories_export_and_import_state() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let state = RemoteState::new(&repo, "origin", "network".to_owned(), IpsId(1));
+        std::fs::create_dir_all(state.dir()).unwrap();
+        std::fs::write(state.dir().join("ledger.tsv"), "fees\n").unwrap();
+        assert!(state.dir().starts_with(dir.path()));
+
+        let tarball = dir.path().join("state.tar.gz");
+        export(&repo, &tarball).unwrap();
+
+        let imported_dir = TempDir::new().unwrap();
+        let imported = Repository::init_bare(imported_dir.path()).unwrap();
+        import(&imported, &tarball, false).unwrap();
+
+        let imported_state = RemoteState::new(&imported, "origin", "network".to_owned(), IpsId(1));
+        assert_eq!(
+            std::fs::read_to_string(imported_state.dir().join("ledger.tsv")).unwrap(),
+            "fees\n"
+        );
+    }
+
+    #[test]
+    fn remotes_of_one_repository_keep_separate_state() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let origin = RemoteState::new(&repo, "origin", "network".to_owned(), IpsId(1));
+        let backup = RemoteState::new(&repo, "backup", "network".to_owned(), IpsId(1));
+        let anonymous = RemoteState::new(&repo, "inv4://1", "network".to_owned(), IpsId(1));
+        assert_ne!(origin.dir(), backup.dir());
+        assert_ne!(origin.dir(), anonymous.dir());
+        assert_eq!(origin.inv4_dir(), backup.inv4_dir());
+
+        let queued = QueuedPush {
+            ips_id: IpsId(1),
+            subasset_id: None,
+            ref_dst: "refs/heads/main".to_owned(),
+            base_refs: Default::default(),
+            message: None,
+            multi_object: MultiObject {
+                hash: String::new(),
+                git_hashes: vec![],
+                objects: Default::default(),
+            },
+            repo_data: RepoData::new(HashAlgorithm::Sha1),
+        };
+        queue::stage(&origin, &queued).unwrap();
+
+        assert_eq!(queue::pending(&origin).unwrap().len(), 1);
+        assert!(queue::pending(&backup).unwrap().is_empty());
+        assert!(queue::pending(&anonymous).unwrap().is_empty());
+
+        let remotes: Vec<String> = RemoteState::all(&repo)
+            .unwrap()
+            .into_iter()
+            .map(|state| state.remote)
+            .collect();
+        assert_eq!(remotes, ["origin"]);
+    }
+}
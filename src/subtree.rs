@@ -0,0 +1,219 @@
+use crate::{
+    config::Config,
+    error, maintenance, open_repository,
+    primitives::BoxResult,
+    state::{inv4_dir, sanitize},
+    types::IpsId,
+    util::flag,
+};
+use git2::{Oid, Repository, Sort};
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+};
+
+static USAGE: &str =
+    "Usage: git-remote-inv4 subtree push --prefix <dir> --ips <id> [--branch <name>] [--rev <rev>]";
+
+/// `subtree push`: pushes only the history of `--prefix` to its own IPS, like `git subtree
+/// split` followed by a push of the split.
+///
+/// Split commits keep the author, committer and message of the commits they come from, so
+/// everyone splitting the same history gets the same commits. Which commit was split to which
+/// is kept in `.git/inv4/subtree/`, so later splits only look at new commits.
+pub async fn run(config: &Config, args: &[String]) -> BoxResult<()> {
+    if args.first().map(String::as_str) != Some("push") {
+        error!(USAGE)
+    }
+
+    let prefix =
+        normalize_prefix(flag(args, "--prefix").ok_or(format!("Missing --prefix. {}", USAGE))?)?;
+    let ips_id: IpsId = config
+        .ips_arg(args)?
+        .ok_or(format!("Missing IPS id. {}", USAGE))?;
+    let branch = flag(args, "--branch").unwrap_or("main");
+    let rev = flag(args, "--rev").unwrap_or("HEAD");
+
+    let repo = open_repository()?;
+    let head = repo.revparse_single(rev)?.peel_to_commit()?.id();
+
+    let map_path = map_path(&repo, &prefix);
+    let mut map = load_map(&map_path)?;
+    let known = map.len();
+
+    let split = split(&repo, &prefix, head, &mut map)?.ok_or(format!(
+        "{} has no commit touching {}",
+        rev,
+        prefix.display()
+    ))?;
+    save_map(&map_path, &map)?;
+
+    eprintln!(
+        "Split {} into {} ({} new commits looked at)",
+        prefix.display(),
+        split,
+        map.len() - known
+    );
+
+    // Keeps the split commits from being collected before they were pushed
+    repo.reference(
+        &format!("refs/inv4/subtree/{}", sanitize(&prefix.to_string_lossy())),
+        split,
+        true,
+        "subtree split",
+    )?;
+
+    maintenance::git(
+        &repo,
+        &[
+            "push",
+            &format!("inv4://{}", ips_id),
+            &format!("{}:refs/heads/{}", split, branch),
+        ],
+        None,
+    )
+    .await
+}
+
+/// `prefix` as a relative path within the worktree, without a trailing slash.
+fn normalize_prefix(prefix: &str) -> BoxResult<PathBuf> {
+    let path = Path::new(prefix.trim_end_matches('/'));
+
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        error!(format!(
+            "--prefix has to be a directory inside the repository, got {:?}",
+            prefix
+        ))
+    }
+
+    Ok(path.to_owned())
+}
+
+/// Where the commits already split for `prefix` are listed.
+fn map_path(repo: &Repository, prefix: &Path) -> PathBuf {
+    inv4_dir(repo)
+        .join("subtree")
+        .join(sanitize(&prefix.to_string_lossy()))
+}
+
+/// Reads `<commit> <split commit>` lines, with `-` for commits that leave the prefix out and
+/// have no split parent.
+fn load_map(path: &Path) -> BoxResult<BTreeMap<Oid, Option<Oid>>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut map = BTreeMap::new();
+    for line in contents.lines() {
+        let (commit, split) =
+            line.split_once(' ')
+                .ok_or(format!("Invalid line {:?} in {}", line, path.display()))?;
+        let split = match split {
+            "-" => None,
+            split => Some(Oid::from_str(split)?),
+        };
+        map.insert(Oid::from_str(commit)?, split);
+    }
+
+    Ok(map)
+}
+
+fn save_map(path: &Path, map: &BTreeMap<Oid, Option<Oid>>) -> BoxResult<()> {
+    let contents: String = map
+        .iter()
+        .map(|(commit, split)| match split {
+            Some(split) => format!("{} {}\n", commit, split),
+            None => format!("{} -\n", commit),
+        })
+        .collect();
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, contents)?;
+
+    Ok(())
+}
+
+/// Rewrites the history of `head` to the history of `prefix` in it, returning the split of
+/// `head`; `None` if no commit has `prefix`.
+///
+/// Commits without `prefix`, and commits leaving it as their only split parent had it, get
+/// no split commit of their own and stand for their split parent instead.
+fn split(
+    repo: &Repository,
+    prefix: &Path,
+    head: Oid,
+    map: &mut BTreeMap<Oid, Option<Oid>>,
+) -> BoxResult<Option<Oid>> {
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    walk.push(head)?;
+    for commit in map.keys() {
+        // Commits of other branches that were split before aren't in this history
+        if repo.find_commit(*commit).is_ok() {
+            walk.hide(*commit)?;
+        }
+    }
+
+    for oid in walk {
+        let oid = oid?;
+        let commit = repo.find_commit(oid)?;
+
+        let mut parents: Vec<Oid> = Vec::new();
+        for parent in commit.parent_ids() {
+            let mapped = match map.get(&parent) {
+                Some(mapped) => *mapped,
+                None => error!(format!(
+                    "Parent {} of {} was not split, remove {} to split everything again",
+                    parent,
+                    oid,
+                    inv4_dir(repo).join("subtree").display()
+                )),
+            };
+            if let Some(mapped) = mapped {
+                if !parents.contains(&mapped) {
+                    parents.push(mapped);
+                }
+            }
+        }
+
+        let tree = match commit.tree()?.get_path(prefix) {
+            Ok(entry) if entry.kind() == Some(git2::ObjectType::Tree) => entry.id(),
+            _ => {
+                map.insert(oid, parents.first().copied());
+                continue;
+            }
+        };
+
+        if let [parent] = parents.as_slice() {
+            if repo.find_commit(*parent)?.tree_id() == tree {
+                map.insert(oid, Some(*parent));
+                continue;
+            }
+        }
+
+        let parent_commits = parents
+            .iter()
+            .map(|parent| repo.find_commit(*parent))
+            .collect::<Result<Vec<_>, _>>()?;
+        let split = repo.commit(
+            None,
+            &commit.author(),
+            &commit.committer(),
+            &String::from_utf8_lossy(commit.message_raw_bytes()),
+            &repo.find_tree(tree)?,
+            &parent_commits.iter().collect::<Vec<_>>(),
+        )?;
+
+        map.insert(oid, Some(split));
+    }
+
+    Ok(map.get(&head).copied().flatten())
+}
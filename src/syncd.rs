@@ -0,0 +1,288 @@
+use crate::{
+    config::Config, connect, error, exit::ExitCode, fees::InvArchExtrinsicParams, invarch,
+    ipfs::Ipfs, primitives::BoxResult, serve, spool::SpooledRepoData, types::IpsId, util::flag,
+    watch::AppendedIpfs,
+};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    path::PathBuf,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use subxt::DefaultConfig;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    task::{self, LocalSet},
+};
+
+static USAGE: &str = "Usage: git-remote-inv4 syncd --config <repos.toml>";
+
+/// The repositories `syncd` mirrors, as in:
+///
+/// ```toml
+/// dir = "/srv/inv4"
+/// status = "127.0.0.1:8090"
+///
+/// [[repo]]
+/// ips = 5
+///
+/// [[repo]]
+/// ips = 12
+/// path = "/srv/git/website.git"
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct SyncConfig {
+    /// Where mirrors without a `path` are kept, as `<ips>.git`
+    dir: PathBuf,
+    /// The address the status endpoint listens on, none without one
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(rename = "repo", default)]
+    repos: Vec<RepoEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RepoEntry {
+    ips: u32,
+    #[serde(default)]
+    path: Option<PathBuf>,
+}
+
+/// One mirror as the status endpoint reports it.
+#[derive(Serialize, Clone, Debug)]
+struct RepoStatus {
+    ips: u32,
+    path: PathBuf,
+    syncing: bool,
+    /// How many refs the mirror had after its last successful sync
+    refs: usize,
+    /// Unix time of the last successful sync
+    last_synced: Option<u64>,
+    /// Why the last sync failed, none if it succeeded
+    last_error: Option<String>,
+}
+
+type Statuses = Rc<RefCell<BTreeMap<IpsId, RepoStatus>>>;
+
+struct Mirror {
+    ips_id: IpsId,
+    path: PathBuf,
+    /// Held while the mirror is synced, so syncs of the same IPS never overlap
+    lock: Mutex<()>,
+    /// Whether a sync is waiting for the lock
+    queued: Cell<bool>,
+}
+
+/// Keeps bare mirrors of several IP Sets in sync with the chain.
+///
+/// All IP Sets are watched through one event subscription. Different IP Sets sync
+/// concurrently, while changes to an IPS that is already syncing are coalesced into one more
+/// sync once it's done.
+pub async fn run(config: &Config, args: &[String]) -> BoxResult<()> {
+    let path = flag(args, "--config").ok_or(format!("Missing config file. {}", USAGE))?;
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| ExitCode::Config.wrap(format!("Could not read {}: {}", path, e)))?;
+    let sync_config: SyncConfig = toml::from_str(&contents)
+        .map_err(|e| ExitCode::Config.wrap(format!("Invalid {}: {}", path, e)))?;
+
+    if sync_config.repos.is_empty() {
+        return Err(ExitCode::Config.wrap(format!("{} lists no [[repo]]", path)));
+    }
+
+    let status_addr = sync_config.status;
+    let mut mirrors = BTreeMap::new();
+    let statuses: Statuses = Rc::default();
+
+    for entry in sync_config.repos {
+        let ips_id = IpsId(entry.ips);
+        let path = entry
+            .path
+            .unwrap_or_else(|| sync_config.dir.join(format!("{}.git", ips_id)));
+
+        statuses.borrow_mut().insert(
+            ips_id,
+            RepoStatus {
+                ips: ips_id.0,
+                path: path.clone(),
+                syncing: false,
+                refs: 0,
+                last_synced: None,
+                last_error: None,
+            },
+        );
+
+        let mirror = Mirror {
+            ips_id,
+            path,
+            lock: Mutex::new(()),
+            queued: Cell::new(false),
+        };
+
+        if mirrors.insert(ips_id, Rc::new(mirror)).is_some() {
+            return Err(ExitCode::Config.wrap(format!("IPS {} is listed twice", ips_id)));
+        }
+    }
+
+    let chain_api = connect(config).await?;
+    let appended =
+        AppendedIpfs::subscribe_to(&chain_api, mirrors.keys().copied().collect()).await?;
+
+    // Mirror state is shared between tasks without being sent across threads
+    LocalSet::new()
+        .run_until(mirror_all(
+            mirrors,
+            chain_api,
+            appended,
+            status_addr,
+            statuses,
+        ))
+        .await
+}
+
+async fn mirror_all(
+    mirrors: BTreeMap<IpsId, Rc<Mirror>>,
+    chain_api: invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    mut appended: AppendedIpfs,
+    status_addr: Option<String>,
+    statuses: Statuses,
+) -> BoxResult<()> {
+    if let Some(addr) = status_addr {
+        let listener = TcpListener::bind(&addr).await?;
+        eprintln!("Serving sync status at http://{}/", addr);
+        task::spawn_local(serve_status(listener, statuses.clone()));
+    }
+
+    for mirror in mirrors.values() {
+        task::spawn_local(sync(mirror.clone(), chain_api.clone(), statuses.clone()));
+    }
+
+    eprintln!("Mirroring {} IP Sets", mirrors.len());
+
+    while let Some(event) = appended.next().await {
+        if let Some(mirror) = mirrors.get(&event.ips_id) {
+            debug!("IPS {} changed on chain", event.ips_id);
+            task::spawn_local(sync(mirror.clone(), chain_api.clone(), statuses.clone()));
+        }
+    }
+
+    error!("Lost the chain subscription")
+}
+
+/// Syncs `mirror` once it isn't syncing anymore, unless another sync is already waiting.
+async fn sync(
+    mirror: Rc<Mirror>,
+    chain_api: invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    statuses: Statuses,
+) {
+    // The waiting sync reads the RepoData after this change too
+    if mirror.queued.replace(true) {
+        return;
+    }
+
+    let _guard = mirror.lock.lock().await;
+    mirror.queued.set(false);
+    set_status(&statuses, mirror.ips_id, |status| status.syncing = true);
+
+    let result = async {
+        let repo_data = SpooledRepoData::current(&chain_api, mirror.ips_id).await?;
+        serve::sync_mirror(
+            &mirror.path,
+            &repo_data,
+            &mut Ipfs::default(),
+            &chain_api,
+            mirror.ips_id,
+        )
+        .await?;
+
+        Ok::<usize, Box<dyn std::error::Error>>(repo_data.refs.len())
+    }
+    .await;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .ok();
+
+    set_status(&statuses, mirror.ips_id, |status| {
+        status.syncing = false;
+
+        match result {
+            Ok(refs) => {
+                status.refs = refs;
+                status.last_synced = now;
+                status.last_error = None;
+            }
+            Err(e) => {
+                eprintln!("Could not sync IPS {}: {}", mirror.ips_id, e);
+                status.last_error = Some(e.to_string());
+            }
+        }
+    });
+}
+
+fn set_status(statuses: &Statuses, ips_id: IpsId, update: impl FnOnce(&mut RepoStatus)) {
+    if let Some(status) = statuses.borrow_mut().get_mut(&ips_id) {
+        update(status);
+    }
+}
+
+/// Answers every request with the status of all mirrors as JSON.
+async fn serve_status(listener: TcpListener, statuses: Statuses) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("Status endpoint stopped: {}", e);
+                return;
+            }
+        };
+
+        let body = match serde_json::to_vec(&statuses.borrow().values().collect::<Vec<_>>()) {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("Could not encode the sync status: {}", e);
+                continue;
+            }
+        };
+
+        task::spawn_local(async move {
+            if let Err(e) = respond(stream, body).await {
+                debug!("Status request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn respond(stream: TcpStream, body: Vec<u8>) -> BoxResult<()> {
+    let mut reader = BufReader::new(stream);
+
+    // The request itself doesn't matter, only that it was sent before answering
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut stream = reader.into_inner();
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.write_all(&body).await?;
+
+    Ok(())
+}
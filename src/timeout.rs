@@ -0,0 +1,126 @@
+use crate::{error, exit::ExitCode, primitives::BoxResult};
+use serde::{Deserialize, Serialize};
+use std::{error::Error, future::Future, sync::Mutex, time::Duration};
+
+/// How many seconds network operations may take before they fail, 0 to wait forever.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Timeouts {
+    /// Reading chain storage
+    #[serde(default = "default_chain_query")]
+    pub chain_query: u64,
+    /// Waiting for a submitted extrinsic to make it into a block
+    #[serde(default = "default_extrinsic")]
+    pub extrinsic: u64,
+    /// Adding content to IPFS
+    #[serde(default = "default_ipfs_add")]
+    pub ipfs_add: u64,
+    /// Reading content from IPFS
+    #[serde(default = "default_ipfs_cat")]
+    pub ipfs_cat: u64,
+}
+
+fn default_chain_query() -> u64 {
+    DEFAULT.chain_query
+}
+
+fn default_extrinsic() -> u64 {
+    DEFAULT.extrinsic
+}
+
+fn default_ipfs_add() -> u64 {
+    DEFAULT.ipfs_add
+}
+
+fn default_ipfs_cat() -> u64 {
+    DEFAULT.ipfs_cat
+}
+
+const DEFAULT: Timeouts = Timeouts {
+    chain_query: 30,
+    extrinsic: 300,
+    ipfs_add: 300,
+    ipfs_cat: 120,
+};
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        DEFAULT
+    }
+}
+
+/// The timeouts `within` applies, those of the last loaded config.
+static CURRENT: Mutex<Timeouts> = Mutex::new(DEFAULT);
+
+impl Timeouts {
+    /// Makes these the timeouts of every following network operation.
+    pub fn install(self) {
+        if let Ok(mut current) = CURRENT.lock() {
+            *current = self;
+        }
+    }
+}
+
+/// The kinds of network operations that have a timeout of their own.
+#[derive(Clone, Copy, Debug)]
+pub enum Operation {
+    ChainQuery,
+    Extrinsic,
+    IpfsAdd,
+    IpfsCat,
+}
+
+impl Operation {
+    /// The config key setting this operation's timeout.
+    fn key(self) -> &'static str {
+        match self {
+            Self::ChainQuery => "timeouts.chain_query",
+            Self::Extrinsic => "timeouts.extrinsic",
+            Self::IpfsAdd => "timeouts.ipfs_add",
+            Self::IpfsCat => "timeouts.ipfs_cat",
+        }
+    }
+
+    /// What failing this operation exits with.
+    fn exit_code(self) -> ExitCode {
+        match self {
+            Self::ChainQuery | Self::Extrinsic => ExitCode::ChainUnreachable,
+            Self::IpfsAdd | Self::IpfsCat => ExitCode::Ipfs,
+        }
+    }
+
+    fn seconds(self) -> u64 {
+        let timeouts = CURRENT.lock().map_or(DEFAULT, |current| *current);
+
+        match self {
+            Self::ChainQuery => timeouts.chain_query,
+            Self::Extrinsic => timeouts.extrinsic,
+            Self::IpfsAdd => timeouts.ipfs_add,
+            Self::IpfsCat => timeouts.ipfs_cat,
+        }
+    }
+}
+
+/// Awaits `future`, failing once it takes longer than `operation` may.
+///
+/// `what` completes the timeout error, as in "Timed out after 120s reading <cid> from IPFS".
+pub async fn within<T, E, F>(operation: Operation, what: &str, future: F) -> BoxResult<T>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Into<Box<dyn Error>>,
+{
+    let seconds = operation.seconds();
+    if seconds == 0 {
+        return future.await.map_err(Into::into);
+    }
+
+    match tokio::time::timeout(Duration::from_secs(seconds), future).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => error!(operation.exit_code().wrap(format!(
+            "Timed out after {}s {}, raise {} in the config if it is just slow",
+            seconds,
+            what,
+            operation.key()
+        ))),
+    }
+}
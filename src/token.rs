@@ -0,0 +1,119 @@
+use crate::{
+    config::Config,
+    fees::InvArchExtrinsicParams,
+    invarch,
+    primitives::BoxResult,
+    timeout::{within, Operation},
+};
+use log::debug;
+use serde_json::Value;
+use std::sync::Mutex;
+use subxt::{
+    rpc::{rpc_params, ClientT},
+    DefaultConfig,
+};
+
+/// The native token of a chain, which fees and deposits are paid in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+impl Token {
+    /// `amount` of the smallest unit in whole tokens, as in `0.42 TNKR`.
+    pub fn format(&self, amount: u128) -> String {
+        let unit = 10u128.pow(u32::from(self.decimals));
+        let fraction = format!("{:0width$}", amount % unit, width = self.decimals as usize);
+        let fraction = fraction.trim_end_matches('0');
+
+        if fraction.is_empty() {
+            format!("{} {}", amount / unit, self.symbol)
+        } else {
+            format!("{}.{} {}", amount / unit, fraction, self.symbol)
+        }
+    }
+}
+
+/// The token of the chain connected to, with the endpoint it was read from.
+static CURRENT: Mutex<Option<(String, Token)>> = Mutex::new(None);
+
+/// Reads the token of `chain_api` from the node's `system_properties`, once per endpoint and
+/// process, so `format` can show amounts in it.
+///
+/// Nodes that don't declare one get the token of the network `chain.endpoint` names, if any.
+pub async fn install(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    config: &Config,
+) {
+    let installed = CURRENT
+        .lock()
+        .map(|current| {
+            current
+                .as_ref()
+                .is_some_and(|(endpoint, _)| *endpoint == config.chain_endpoint)
+        })
+        .unwrap_or(true);
+    if installed {
+        return;
+    }
+
+    let token = match properties(chain_api).await {
+        Ok(Some(token)) => Some(token),
+        Ok(None) => offline(config),
+        Err(e) => {
+            debug!("Could not read the chain's token: {}", e);
+            offline(config)
+        }
+    };
+
+    if let (Some(token), Ok(mut current)) = (token, CURRENT.lock()) {
+        *current = Some((config.chain_endpoint.clone(), token));
+    }
+}
+
+/// The token `system_properties` declares; the first one on chains with several.
+async fn properties(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+) -> BoxResult<Option<Token>> {
+    let properties: Value = within(
+        Operation::ChainQuery,
+        "reading system_properties",
+        chain_api
+            .client
+            .rpc()
+            .client
+            .request("system_properties", rpc_params![]),
+    )
+    .await?;
+
+    let first = |value: &Value| match value {
+        Value::Array(values) => values.first().cloned(),
+        value => Some(value.clone()),
+    };
+
+    let symbol = first(&properties["tokenSymbol"]).and_then(|v| v.as_str().map(str::to_owned));
+    let decimals = first(&properties["tokenDecimals"])
+        .and_then(|v| v.as_u64())
+        .and_then(|decimals| u8::try_from(decimals).ok());
+
+    Ok(symbol
+        .zip(decimals)
+        .map(|(symbol, decimals)| Token { symbol, decimals }))
+}
+
+/// The token known without asking a node, from the network `chain.endpoint` names.
+pub fn offline(config: &Config) -> Option<Token> {
+    config.network.as_ref().map(|(_, network)| Token {
+        symbol: network.token_symbol.clone(),
+        decimals: network.token_decimals,
+    })
+}
+
+/// `amount` in the installed token, or as a bare number of the smallest unit if none is.
+pub fn format(amount: u128) -> String {
+    match CURRENT.lock().ok().and_then(|current| current.clone()) {
+        Some((_, token)) => token.format(amount),
+        None => amount.to_string(),
+    }
+}
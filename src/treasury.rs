@@ -0,0 +1,135 @@
+use crate::{
+    error,
+    exit::ExitCode,
+    fees::{self, InvArchExtrinsicParams},
+    invarch::{
+        self,
+        inv4::events::{MultisigExecuted, MultisigVoteStarted},
+        runtime_types::{invarch_primitives::Parentage, invarch_runtime::Call},
+    },
+    ledger,
+    primitives::BoxResult,
+    resubmit,
+    timeout::{within, Operation},
+    token,
+    types::IpsId,
+};
+use subxt::{sp_core::crypto::AccountId32, DefaultConfig, PairSigner, TransactionEvents};
+
+/// The account behind an IPS, which owns and pays for everything minted through its multisig.
+pub async fn ips_account(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ips_id: IpsId,
+) -> BoxResult<AccountId32> {
+    let ip_storage = chain_api.storage().inv4();
+    let ips_info = within(
+        Operation::ChainQuery,
+        &format!("reading IPS {} from chain", ips_id),
+        ip_storage.ip_storage(&ips_id.0, None),
+    )
+    .await?
+    .ok_or(format!("IPS {ips_id} does not exist"))?;
+
+    Ok(match ips_info.parentage {
+        Parentage::Parent(account) => account,
+        Parentage::Child(_, account) => account,
+    })
+}
+
+/// Dispatches `call` from the IPS account through `operate_multisig`.
+///
+/// The fee is estimated first and compared against the IPS account's free balance, so a
+/// treasury that can't pay fails before anything is submitted. `call` only runs right away
+/// if the signer's IPT meet the execution threshold, otherwise a vote is started and this fails.
+pub async fn submit(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    signer: &PairSigner<DefaultConfig, sp_keyring::sr25519::sr25519::Pair>,
+    ips_id: IpsId,
+    subasset_id: Option<u32>,
+    call: Call,
+) -> BoxResult<TransactionEvents<DefaultConfig, invarch::Event>> {
+    let tx = chain_api
+        .tx()
+        .inv4()
+        .operate_multisig(false, (ips_id.0, subasset_id), call)?;
+
+    let estimate = fees::estimate(
+        chain_api,
+        tx.create_signed(signer, Default::default())
+            .await?
+            .encoded()
+            .to_vec(),
+    )
+    .await?;
+    check_funds(chain_api, ips_id, estimate).await?;
+
+    let events = within(
+        Operation::Extrinsic,
+        "waiting for inv4.operate_multisig to be included in a block",
+        resubmit::sign_and_submit("inv4.operate_multisig", || {
+            tx.sign_and_submit_then_watch_default(signer)
+        })
+        .await?
+        .wait_for_in_block(),
+    )
+    .await?;
+
+    let tx_events = events.fetch_events().await?;
+    ledger::record("inv4.operate_multisig", &tx_events, signer.account_id())?;
+
+    events
+        .wait_for_success()
+        .await
+        .map_err(|e| ExitCode::PermissionDenied.wrap(e))?;
+
+    if let Some(vote) = tx_events.find_first::<MultisigVoteStarted>()? {
+        error!(format!(
+            "Your IPT don't meet the execution threshold of IPS {}, so a multisig vote was started instead (call hash 0x{}). Push again once it passed, or set `treasury_pays = false` in the [chain] section of the config.",
+            ips_id,
+            hex::encode(vote.3)
+        ))
+    }
+
+    if let Some(executed) = tx_events.find_first::<MultisigExecuted>()? {
+        if !executed.2 {
+            error!(format!(
+                "The treasury of IPS {} could not execute the mint",
+                ips_id
+            ))
+        }
+    }
+
+    Ok(tx_events)
+}
+
+/// Fails if the IPS account can't pay `estimate` and stay above the existential deposit.
+async fn check_funds(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ips_id: IpsId,
+    estimate: u128,
+) -> BoxResult<()> {
+    let account = ips_account(chain_api, ips_id).await?;
+    let system = chain_api.storage().system();
+    let free = within(
+        Operation::ChainQuery,
+        &format!("reading the balance of {} from chain", account),
+        system.account(&account, None),
+    )
+    .await?
+    .data
+    .free;
+    let existential_deposit = chain_api.constants().balances().existential_deposit()?;
+
+    if free < estimate.saturating_add(existential_deposit) {
+        error!(format!(
+            "The treasury of IPS {} (account {}) has a free balance of {}, but minting is estimated to cost {} and it has to keep {}. Transfer funds to it or set `treasury_pays = false` in the [chain] section of the config.",
+            ips_id,
+            account,
+            token::format(free),
+            token::format(estimate),
+            token::format(existential_deposit)
+        ))
+    }
+
+    Ok(())
+}
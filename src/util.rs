@@ -1,7 +1,31 @@
-use cid::{multihash::MultihashGeneric, CidGeneric};
-use subxt::sp_core::H256;
+use cid::Cid;
+use codec::{Decode, Encode};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use subxt::{
+    rpc::{rpc_params, ClientT},
+    sp_core::{
+        hashing::{blake2_128, twox_128},
+        H256,
+    },
+    DefaultConfig, StorageEntry,
+};
 
-use crate::primitives::BoxResult;
+use crate::{
+    fees::InvArchExtrinsicParams,
+    invarch::{self, runtime_types::pallet_inv4::pallet::AnyId},
+    ipfs::{AddOptions, Ipfs, IpfsOps},
+    primitives::BoxResult,
+    progress,
+    timeout::{within, Operation},
+    types::{IpfId, IpfsCidBytes, IpsId},
+};
+pub use inv4_git_core::metadata::{
+    decode_ipf_metadata, generate_cid, ipf_metadata_license, ipf_metadata_name, is_git_ipf,
+    BLAKE2B_256, GIT_IPF_TAG, METADATA_CID_SEPARATOR, METADATA_LICENSE_SEPARATOR, SHA2_256,
+};
 
 #[macro_export]
 macro_rules! error {
@@ -10,8 +34,486 @@ macro_rules! error {
     }};
 }
 
-pub fn generate_cid(hash: H256) -> BoxResult<CidGeneric<32>> {
-    Ok(CidGeneric::new_v0(MultihashGeneric::<32>::from_bytes(
-        hex::decode(format!("{:?}", hash).replace("0x", "1220"))?.as_slice(),
-    )?)?)
+/// Longest license tag accepted, well within what IPF metadata holds.
+pub const MAX_LICENSE_BYTES: usize = 256;
+
+/// Longest `chain.metadata_template` accepted, leaving room for the CID and a license.
+pub const MAX_METADATA_TEMPLATE_BYTES: usize = 128;
+
+/// What `chain.metadata_template` can refer to.
+const METADATA_TEMPLATE_PLACEHOLDERS: &[&str] = &["{repo}", "{type}", "{hash}"];
+
+/// How many IPF storage entries are requested in one RPC, and so held in memory at once,
+/// while walking an IPS.
+pub const IPF_BATCH_SIZE: usize = 256;
+
+/// An IPF as the chain stores it.
+pub type IpfInfo = <invarch::ipf::storage::IpfStorage<'static> as StorageEntry>::Value;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CidVersion {
+    #[serde(rename = "v0")]
+    V0,
+    #[serde(rename = "v1")]
+    V1,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashFunction {
+    #[serde(rename = "sha2-256")]
+    Sha2_256,
+    #[serde(rename = "blake2b-256")]
+    Blake2b256,
+}
+
+impl HashFunction {
+    pub fn code(&self) -> u64 {
+        match self {
+            HashFunction::Sha2_256 => SHA2_256,
+            HashFunction::Blake2b256 => BLAKE2B_256,
+        }
+    }
+
+    /// The name the IPFS API expects for this hash function.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashFunction::Sha2_256 => "sha2-256",
+            HashFunction::Blake2b256 => "blake2b-256",
+        }
+    }
+}
+
+/// How content is addressed when it is added to IPFS.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CidFormat {
+    pub version: CidVersion,
+    pub hash: HashFunction,
+}
+
+impl Default for CidFormat {
+    fn default() -> Self {
+        Self {
+            version: CidVersion::V0,
+            hash: HashFunction::Sha2_256,
+        }
+    }
+}
+
+impl CidFormat {
+    pub fn validate(&self) -> BoxResult<()> {
+        if self.version == CidVersion::V0 && self.hash != HashFunction::Sha2_256 {
+            error!(format!(
+                "CIDv0 only supports sha2-256, but {} was configured. Use CIDv1 instead.",
+                self.hash.name()
+            ))
+        }
+        Ok(())
+    }
+
+    /// Whether CIDs in this format can be rebuilt from the 32 byte digest alone.
+    pub fn is_legacy(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Checks that a CID returned by the IPFS node matches this format and fits on chain.
+    pub fn check(&self, cid: &Cid) -> BoxResult<H256> {
+        let expected_version = match self.version {
+            CidVersion::V0 => cid::Version::V0,
+            CidVersion::V1 => cid::Version::V1,
+        };
+
+        if cid.version() != expected_version {
+            error!(format!(
+                "IPFS node returned {} as a {:?} CID, but {:?} was requested",
+                cid,
+                cid.version(),
+                expected_version
+            ))
+        }
+
+        if cid.hash().code() != self.hash.code() {
+            error!(format!(
+                "IPFS node hashed {} with multihash code {:#x}, but {} was requested",
+                cid,
+                cid.hash().code(),
+                self.hash.name()
+            ))
+        }
+
+        let digest = cid.hash().digest();
+        if digest.len() != 32 {
+            error!(format!(
+                "IPFS node returned {} with a {} byte digest, but only 32 byte digests fit on chain",
+                cid,
+                digest.len()
+            ))
+        }
+
+        Ok(H256::from_slice(digest))
+    }
+}
+
+/// Adds `data` to IPFS using `format`, returning the resulting CID and the digest to store on chain.
+///
+/// The content is not pinned, so payloads of pushes that never make it on chain get garbage
+/// collected. Call `ipfs_pin` once the IPF is minted.
+pub async fn ipfs_add(ipfs: &mut Ipfs, data: Vec<u8>, format: CidFormat) -> BoxResult<(Cid, H256)> {
+    let options = AddOptions {
+        cid_version: Some(match format.version {
+            CidVersion::V0 => 0,
+            CidVersion::V1 => 1,
+        }),
+        hash: Some(format.hash.name()),
+        pin: false,
+    };
+
+    let size = data.len();
+    let cid = Cid::try_from(
+        within(
+            Operation::IpfsAdd,
+            &format!("adding {} bytes to IPFS", size),
+            ipfs.add(data, options),
+        )
+        .await?,
+    )?;
+    let digest = format.check(&cid)?;
+    progress::sink().on_upload(size as u64);
+
+    Ok((cid, digest))
+}
+
+/// Reads `cid` from IPFS.
+pub async fn ipfs_cat(ipfs: &Ipfs, cid: &Cid) -> BoxResult<Vec<u8>> {
+    within(
+        Operation::IpfsCat,
+        &format!("reading {} from IPFS", cid),
+        ipfs.cat(&cid.to_string()),
+    )
+    .await
+}
+
+/// The value following `name` in a subcommand's arguments, as in `--ips 3`.
+pub fn flag<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+}
+
+/// Pins `cid` on the local node, for content whose IPF has been minted.
+///
+/// Failures only produce a warning, the content stays available until the node's next GC.
+pub async fn ipfs_pin(ipfs: &mut Ipfs, cid: &Cid) {
+    if let Err(e) = ipfs.pin(&cid.to_string()).await {
+        eprintln!(
+            "Warning: could not pin {}, it may be garbage collected: {}",
+            cid, e
+        );
+    }
+}
+
+/// Unpins content that is no longer part of a repository.
+///
+/// Content pinned by another node or never pinned here is left alone.
+pub async fn ipfs_unpin(ipfs: &mut Ipfs, cid: &Cid) {
+    if let Err(e) = ipfs.unpin(&cid.to_string()).await {
+        debug!("Not unpinning {}: {}", cid, e);
+    }
+}
+
+/// Encodes IPF metadata for `name`, licensed under `license`.
+///
+/// The name comes after `GIT_IPF_TAG`. CIDv0 sha2-256 content keeps just the name, anything
+/// else is followed by a separator and the full CID so readers don't have to guess the codec
+/// and hash function. A license tag always comes after the full CID, behind another separator.
+pub fn encode_ipf_metadata(
+    name: &str,
+    cid: &Cid,
+    format: CidFormat,
+    license: Option<&str>,
+) -> Vec<u8> {
+    let mut metadata = GIT_IPF_TAG.to_vec();
+    metadata.extend(name.as_bytes());
+
+    if !format.is_legacy() || license.is_some() {
+        metadata.push(METADATA_CID_SEPARATOR);
+        metadata.extend(IpfsCidBytes::from(cid).as_bytes());
+    }
+
+    if let Some(license) = license {
+        metadata.push(METADATA_LICENSE_SEPARATOR);
+        metadata.extend(license.as_bytes());
+    }
+
+    metadata
+}
+
+/// Checks that `license` can be stored as a license tag.
+pub fn validate_license(license: &str) -> BoxResult<()> {
+    if license.trim().is_empty() {
+        error!("license is empty, leave it out to mint without one")
+    }
+    if license.len() > MAX_LICENSE_BYTES {
+        error!(format!(
+            "license is {} bytes long, at most {} fit in IPF metadata next to the name; put longer terms on IPFS and give their CID",
+            license.len(),
+            MAX_LICENSE_BYTES
+        ))
+    }
+    if license.bytes().any(|b| b == METADATA_LICENSE_SEPARATOR) {
+        error!("license can't contain NUL bytes")
+    }
+
+    Ok(())
+}
+
+/// Checks that a `chain.metadata_template` can still be looked up by hash: it has to end in
+/// `{hash}`, behind a `/` unless that's all there is.
+pub fn validate_metadata_template(template: &str) -> BoxResult<()> {
+    if template.len() > MAX_METADATA_TEMPLATE_BYTES {
+        error!(format!(
+            "metadata_template is {} bytes long, at most {} fit in IPF metadata",
+            template.len(),
+            MAX_METADATA_TEMPLATE_BYTES
+        ))
+    }
+    if template.bytes().any(|b| b == METADATA_CID_SEPARATOR) {
+        error!("metadata_template can't contain NUL bytes")
+    }
+    if template != "{hash}" && !template.ends_with("/{hash}") {
+        error!(format!(
+            "metadata_template has to end in \"/{{hash}}\", got {:?}",
+            template
+        ))
+    }
+
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let placeholder = match rest[start..].find('}') {
+            Some(end) => &rest[start..start + end + 1],
+            None => error!(format!(
+                "metadata_template has an unclosed {{ in {:?}",
+                template
+            )),
+        };
+        if !METADATA_TEMPLATE_PLACEHOLDERS.contains(&placeholder) {
+            error!(format!(
+                "metadata_template refers to unknown {}, expected one of {}",
+                placeholder,
+                METADATA_TEMPLATE_PLACEHOLDERS.join(", ")
+            ))
+        }
+        rest = &rest[start + placeholder.len()..];
+    }
+
+    Ok(())
+}
+
+/// The name the MultiObject `hash` is minted under: `template` filled in, or the bare hash.
+pub fn object_metadata_name(
+    template: Option<&str>,
+    ips_id: IpsId,
+    object_type: &str,
+    hash: &str,
+) -> String {
+    match template {
+        Some(template) => template
+            .replace("{repo}", &ips_id.to_string())
+            .replace("{type}", object_type)
+            .replace("{hash}", hash),
+        None => hash.to_owned(),
+    }
+}
+
+/// The ids of all IPFs in an IPS, oldest first.
+pub async fn ips_ipf_ids(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ips_id: IpsId,
+) -> BoxResult<Vec<u64>> {
+    let ip_storage = chain_api.storage().inv4();
+
+    Ok(within(
+        Operation::ChainQuery,
+        &format!("reading IPS {} from chain", ips_id),
+        ip_storage.ip_storage(&ips_id.0, None),
+    )
+    .await?
+    .ok_or(format!("IPS {ips_id} does not exist"))?
+    .data
+    .0
+    .into_iter()
+    .filter_map(|file| match file {
+        AnyId::IpfId(id) => Some(id),
+        _ => None,
+    })
+    .collect())
+}
+
+/// The ids of the IP Sets nested in an IPS.
+pub async fn ips_child_ids(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ips_id: IpsId,
+) -> BoxResult<Vec<u32>> {
+    let ip_storage = chain_api.storage().inv4();
+
+    Ok(within(
+        Operation::ChainQuery,
+        &format!("reading IPS {} from chain", ips_id),
+        ip_storage.ip_storage(&ips_id.0, None),
+    )
+    .await?
+    .ok_or(format!("IPS {ips_id} does not exist"))?
+    .data
+    .0
+    .into_iter()
+    .filter_map(|file| match file {
+        AnyId::IpsId(id) => Some(id),
+        _ => None,
+    })
+    .collect())
+}
+
+/// Walks `path` down the IP Sets nested in `root`, as in `inv4://<root>/<child>/<grandchild>`.
+///
+/// If the last id isn't nested in the IPS before it, it is read as the subasset id of URLs from
+/// before nesting, `inv4://<ips_id>/<subasset_id>`, and returned alongside.
+pub async fn resolve_ips_path(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    root: IpsId,
+    path: &[u32],
+) -> BoxResult<(IpsId, Option<u32>)> {
+    let mut ips_id = root;
+
+    for (i, &child) in path.iter().enumerate() {
+        if ips_child_ids(chain_api, ips_id).await?.contains(&child) {
+            debug!("IPS {} is nested in IPS {}", child, ips_id);
+            ips_id = IpsId(child);
+        } else if i + 1 == path.len() {
+            eprintln!(
+                "Warning: IPS {} has no nested IPS {}, taking it as a subasset id. Write subassets as inv4://{}?subasset={} instead.",
+                ips_id, child, ips_id, child
+            );
+            return Ok((ips_id, Some(child)));
+        } else {
+            error!(format!("IPS {} has no nested IPS {}", ips_id, child))
+        }
+    }
+
+    Ok((ips_id, None))
+}
+
+/// The storage entries of `ipf_ids`, in order, `None` for IPFs that don't exist.
+///
+/// They are read with a single `state_queryStorageAt`, so walking an IPS takes one RPC per
+/// `IPF_BATCH_SIZE` IPFs instead of one per IPF, and sees all of them at the same block.
+pub async fn ipf_infos(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ipf_ids: &[u64],
+) -> BoxResult<Vec<Option<IpfInfo>>> {
+    if ipf_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // `IpfStorage` is a Blake2_128Concat map in the `Ipf` pallet
+    let mut prefix = twox_128(b"Ipf").to_vec();
+    prefix.extend_from_slice(&twox_128(b"IpfStorage"));
+    let keys: Vec<String> = ipf_ids
+        .iter()
+        .map(|id| {
+            let encoded = id.encode();
+            let mut key = prefix.clone();
+            key.extend_from_slice(&blake2_128(&encoded));
+            key.extend_from_slice(&encoded);
+            format!("0x{}", hex::encode(key))
+        })
+        .collect();
+
+    let change_sets: Value = chain_api
+        .client
+        .rpc()
+        .client
+        .request("state_queryStorageAt", rpc_params![keys.clone()])
+        .await?;
+
+    let mut values = HashMap::new();
+    for change in change_sets[0]["changes"].as_array().into_iter().flatten() {
+        if let (Some(key), Some(data)) = (change[0].as_str(), change[1].as_str()) {
+            values.insert(key.to_owned(), data.to_owned());
+        }
+    }
+
+    keys.iter()
+        .map(|key| match values.get(key) {
+            Some(data) => {
+                let bytes = hex::decode(data.trim_start_matches("0x"))?;
+                Ok(Some(IpfInfo::decode(&mut bytes.as_slice())?))
+            }
+            None => Ok(None),
+        })
+        .collect()
+}
+
+/// Finds the IPF called `name` in an IPS.
+///
+/// `IpStorage` only holds the list of ids, so IPF entries are fetched in batches of
+/// `IPF_BATCH_SIZE`, newest first, and the walk stops as soon as `name` turns up.
+pub async fn find_ipf(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    ips_id: IpsId,
+    name: &str,
+) -> BoxResult<Option<(IpfId, Cid)>> {
+    let ipf_ids = ips_ipf_ids(chain_api, ips_id).await?;
+
+    for batch in ipf_ids.rchunks(IPF_BATCH_SIZE) {
+        let ipf_infos = within(
+            Operation::ChainQuery,
+            &format!("reading IPFs of IPS {} from chain", ips_id),
+            ipf_infos(chain_api, batch),
+        )
+        .await?;
+
+        for (id, ipf_info) in batch.iter().zip(ipf_infos).rev() {
+            let ipf_info = ipf_info.ok_or("Internal error: IPF listed from IPS does not exist")?;
+
+            if !is_git_ipf(&ipf_info.metadata.0) {
+                continue;
+            }
+
+            if ipf_metadata_name(&ipf_info.metadata.0)? == name {
+                let (_, cid) = decode_ipf_metadata(&ipf_info.metadata.0, ipf_info.data.0)?;
+                return Ok(Some((IpfId(*id), cid)));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Copies `cid` into the node's MFS as `/inv4-git/<ips_id>/<name>` so it shows up in the web UI.
+///
+/// Failures only produce a warning, the push itself doesn't depend on MFS.
+pub async fn mfs_mirror(ipfs: &mut Ipfs, ips_id: IpsId, name: &str, cid: &Cid) {
+    let dir = format!("/inv4-git/{}", ips_id);
+
+    // RepoData is replaced on every push, so the copy replaces what's there
+    if let Err(e) = ipfs.mfs_copy(&cid.to_string(), &dir, name).await {
+        eprintln!(
+            "Warning: could not mirror {} to MFS at {}/{}: {}",
+            cid, dir, name, e
+        );
+    }
+}
+
+/// The number of the block `block_hash` names.
+pub async fn block_number(
+    chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+    block_hash: H256,
+) -> BoxResult<u32> {
+    Ok(chain_api
+        .client
+        .rpc()
+        .header(Some(block_hash))
+        .await?
+        .ok_or(format!("Block {:?} not found", block_hash))?
+        .number)
 }
@@ -0,0 +1,160 @@
+use crate::{
+    error,
+    exit::ExitCode,
+    maintenance::Fetched,
+    primitives::{BoxResult, RepoData},
+    shallow,
+    types::{GitHash, IpsId},
+};
+use git2::{ObjectType, Oid, Repository};
+use std::collections::HashSet;
+
+/// File mode of the tree entries of submodules, whose commits live in another repository.
+const SUBMODULE_FILE_MODE: i32 = 0o160000;
+
+/// Checks what the fetches of a batch wrote to `repo`, as `fetch.verify` asks for.
+///
+/// Every fetched object is read back and hashed again, and has to be listed in `remote_repo`
+/// with the type and size it has there. From every tip git asked for, the history has to be
+/// complete down to the objects `repo` had before, or to the commits a shallow push cut off.
+/// Every discrepancy is reported with what to do about it before failing.
+pub fn after_fetch(
+    repo: &Repository,
+    remote_repo: &RepoData,
+    fetched: &Fetched,
+    ips_id: IpsId,
+) -> BoxResult<()> {
+    let odb = repo.odb()?;
+    let mut problems = Vec::new();
+
+    for oid in &fetched.objects {
+        let object = match odb.read(*oid) {
+            Ok(object) => object,
+            Err(e) => {
+                problems.push(format!(
+                    "Object {} was fetched but can't be read back: {}. Fetch again once the disk is healthy.",
+                    oid, e
+                ));
+                continue;
+            }
+        };
+
+        let rehashed = Oid::hash_object(object.kind(), object.data())?;
+        if rehashed != *oid {
+            problems.push(format!(
+                "Object {} hashes to {} on disk. Delete .git/objects/{}/{} and fetch again.",
+                oid,
+                rehashed,
+                &oid.to_string()[..2],
+                &oid.to_string()[2..]
+            ));
+            continue;
+        }
+
+        let git_hash = GitHash::from(*oid);
+        if !remote_repo.objects.contains_key(&git_hash) {
+            problems.push(format!(
+                "Object {} was fetched but RepoData of IPS {} doesn't list it. The IPS may have changed during the fetch, run it again.",
+                oid, ips_id
+            ));
+            continue;
+        }
+        if let Some(header) = remote_repo.object_headers.get(&git_hash) {
+            if header.kind.object_type() != object.kind() || header.size != object.len() as u64 {
+                problems.push(format!(
+                    "Object {} is a {} of {} bytes, but RepoData of IPS {} says it's a {} of {} bytes. Check the IPS with `git-remote-inv4 fsck --ips {}`.",
+                    oid,
+                    object.kind(),
+                    object.len(),
+                    ips_id,
+                    header.kind.object_type(),
+                    header.size,
+                    ips_id
+                ));
+            }
+        }
+    }
+
+    let mut cut_off: HashSet<GitHash> = remote_repo.shallow.iter().cloned().collect();
+    cut_off.extend(shallow::boundary(repo)?);
+
+    for missing in unreachable_history(repo, fetched, &cut_off)? {
+        problems.push(format!(
+            "Object {} of the fetched history is missing locally. Fetch again, and if it stays missing check the IPS with `git-remote-inv4 fsck --ips {}`.",
+            missing, ips_id
+        ));
+    }
+
+    if problems.is_empty() {
+        eprintln!(
+            "Verified the {} objects fetched from IPS {}",
+            fetched.objects.len(),
+            ips_id
+        );
+        return Ok(());
+    }
+
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+
+    error!(ExitCode::Verification.wrap(format!(
+        "{} problems found verifying the fetch from IPS {}",
+        problems.len(),
+        ips_id
+    )))
+}
+
+/// The objects the history of the fetched tips points at that `repo` lacks.
+///
+/// Objects `repo` had before the fetch aren't descended into, as git checked them when they
+/// were written.
+fn unreachable_history(
+    repo: &Repository,
+    fetched: &Fetched,
+    cut_off: &HashSet<GitHash>,
+) -> BoxResult<Vec<Oid>> {
+    let odb = repo.odb()?;
+    let mut missing = Vec::new();
+    let mut seen = HashSet::new();
+    let mut stack = fetched.tips.clone();
+
+    while let Some(oid) = stack.pop() {
+        if !seen.insert(oid) {
+            continue;
+        }
+        if odb.read_header(oid).is_err() {
+            missing.push(oid);
+            continue;
+        }
+        if !fetched.objects.contains(&oid) {
+            continue;
+        }
+
+        let object = repo.find_object(oid, None)?;
+        match object.kind() {
+            Some(ObjectType::Commit) => {
+                let commit = object.peel_to_commit()?;
+                stack.push(commit.tree_id());
+                if !cut_off.contains(&GitHash::from(oid)) {
+                    stack.extend(commit.parent_ids());
+                }
+            }
+            Some(ObjectType::Tree) => {
+                let tree = object.peel_to_tree()?;
+                stack.extend(
+                    tree.iter()
+                        .filter(|entry| entry.filemode() != SUBMODULE_FILE_MODE)
+                        .map(|entry| entry.id()),
+                );
+            }
+            Some(ObjectType::Tag) => {
+                let tag = object.peel_to_tag()?;
+                stack.push(tag.target_id());
+            }
+            _ => {}
+        }
+    }
+
+    Ok(missing)
+}
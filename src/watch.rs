@@ -0,0 +1,200 @@
+use crate::{
+    fees::InvArchExtrinsicParams,
+    invarch::{self, runtime_types::pallet_inv4::pallet::AnyId},
+    primitives::{BoxResult, RepoData},
+    set_repo,
+    types::{GitHash, IpfId, IpsId},
+    util::ipf_metadata_name,
+};
+use futures::StreamExt;
+use std::collections::BTreeMap;
+use subxt::{
+    sp_core::{crypto::AccountId32, H256},
+    DefaultConfig,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+/// IPFs appended to an IPS by one `Appended` event.
+pub struct Appended {
+    pub ips_id: IpsId,
+    pub block_hash: H256,
+    pub ipf_ids: Vec<IpfId>,
+}
+
+/// The IPFs appended to IP Sets, one batch per `Appended` event.
+pub struct AppendedIpfs {
+    receiver: mpsc::UnboundedReceiver<Appended>,
+    handle: JoinHandle<()>,
+}
+
+impl AppendedIpfs {
+    /// Subscribes to `Appended` events of `ips_id`, returning once the subscription is live.
+    pub async fn subscribe(
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> BoxResult<Self> {
+        Self::subscribe_to(chain_api, vec![ips_id]).await
+    }
+
+    /// Subscribes to `Appended` events of any of `ips_ids` through one subscription.
+    pub async fn subscribe_to(
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_ids: Vec<IpsId>,
+    ) -> BoxResult<Self> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (ready_tx, ready_rx) = oneshot::channel();
+
+        let task_api = chain_api.clone();
+        let handle = tokio::spawn(async move {
+            let events = task_api.events();
+            let mut appended_events = match events.subscribe().await {
+                Ok(subscription) => {
+                    let _ = ready_tx.send(Ok(()));
+                    subscription.filter_events::<(invarch::inv4::events::Appended,)>()
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e.to_string()));
+                    return;
+                }
+            };
+
+            while let Some(Ok(details)) = appended_events.next().await {
+                let invarch::inv4::events::Appended(_, appended_ips_id, _, assets) = details.event;
+                let ips_id = IpsId(appended_ips_id);
+                if !ips_ids.contains(&ips_id) {
+                    continue;
+                }
+
+                let ipf_ids = assets
+                    .into_iter()
+                    .filter_map(|asset| match asset {
+                        AnyId::IpfId(id) => Some(IpfId(id)),
+                        _ => None,
+                    })
+                    .collect();
+
+                let appended = Appended {
+                    ips_id,
+                    block_hash: details.block_hash,
+                    ipf_ids,
+                };
+
+                if sender.send(appended).is_err() {
+                    return;
+                }
+            }
+        });
+
+        ready_rx
+            .await?
+            .map_err(|e| format!("Could not subscribe to chain events: {}", e))?;
+
+        Ok(Self { receiver, handle })
+    }
+
+    /// Waits for the next batch of appended IPFs.
+    pub async fn next(&mut self) -> Option<Appended> {
+        self.receiver.recv().await
+    }
+
+    /// The IPFs appended since the last call, without waiting.
+    pub fn drain(&mut self) -> Vec<IpfId> {
+        let mut ipf_ids = Vec::new();
+        while let Ok(appended) = self.receiver.try_recv() {
+            ipf_ids.extend(appended.ipf_ids);
+        }
+        ipf_ids
+    }
+}
+
+impl Drop for AppendedIpfs {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Collects IPFs appended to an IPS while a push is running, so another account's
+/// RepoData replacement can be merged before we overwrite it with ours.
+pub struct RepoDataWatcher {
+    ips_id: IpsId,
+    appended: AppendedIpfs,
+}
+
+impl RepoDataWatcher {
+    pub async fn start(
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        ips_id: IpsId,
+    ) -> BoxResult<Self> {
+        Ok(Self {
+            ips_id,
+            appended: AppendedIpfs::subscribe(chain_api, ips_id).await?,
+        })
+    }
+
+    /// The author of the latest RepoData appended by anyone other than `our_account` since the
+    /// last call.
+    async fn concurrent_push(
+        &mut self,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        our_account: &AccountId32,
+    ) -> BoxResult<Option<AccountId32>> {
+        let mut author = None;
+
+        for ipf_id in self.appended.drain() {
+            let ipf_info = match chain_api
+                .storage()
+                .ipf()
+                .ipf_storage(&ipf_id.0, None)
+                .await?
+            {
+                Some(ipf_info) => ipf_info,
+                None => continue,
+            };
+
+            let is_repo_data = ipf_metadata_name(&ipf_info.metadata.0)
+                .map(|name| name == "RepoData")
+                .unwrap_or(false);
+
+            if is_repo_data && ipf_info.author != *our_account {
+                author = Some(ipf_info.author);
+            }
+        }
+
+        Ok(author)
+    }
+
+    /// Merges the RepoData other accounts pushed since `start` into `ours`.
+    ///
+    /// `base_refs` are the refs `ours` was built on and become the refs of the RepoData merged
+    /// last. Fails only if a concurrent push changed a ref this push changes too.
+    pub async fn merge_concurrent_pushes(
+        &mut self,
+        chain_api: &invarch::RuntimeApi<DefaultConfig, InvArchExtrinsicParams<DefaultConfig>>,
+        our_account: &AccountId32,
+        ours: &mut RepoData,
+        base_refs: &mut BTreeMap<String, GitHash>,
+    ) -> BoxResult<()> {
+        while let Some(author) = self.concurrent_push(chain_api, our_account).await? {
+            let theirs = set_repo(self.ips_id, chain_api.clone()).await?;
+
+            ours.merge(base_refs, &theirs).map_err(|e| {
+                format!(
+                    "{} pushed to IPS {} while this push was running: {}",
+                    author, self.ips_id, e
+                )
+            })?;
+
+            eprintln!(
+                "Merged a concurrent push by {} to IPS {}",
+                author, self.ips_id
+            );
+
+            *base_refs = theirs.refs;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use crate::{
+    address::address,
+    config::Config,
+    connect,
+    ipfs::Ipfs,
+    primitives::{BoxResult, RefUpdate, RepoData, RepoDataOps},
+    proxy, set_repo,
+    types::{IpfId, IpsId},
+    util::{decode_ipf_metadata, flag, is_git_ipf},
+    watch::AppendedIpfs,
+};
+use log::debug;
+use serde_json::{json, Value};
+
+/// Subscribes to an IPS and POSTs a JSON summary to `--webhook` every time its RepoData is replaced.
+pub async fn run(config: &Config, args: &[String]) -> BoxResult<()> {
+    let usage = "Usage: git-remote-inv4 watch --ips <id> --webhook <url>";
+    let ips_id: IpsId = config
+        .ips_arg(args)?
+        .ok_or(format!("Missing IPS id. {}", usage))?;
+    let webhook = flag(args, "--webhook").ok_or(format!("Missing webhook URL. {}", usage))?;
+
+    let chain_api = connect(config).await?;
+    let http = proxy::http_client(config)?;
+    let mut ipfs = Ipfs::default();
+
+    let mut appended = AppendedIpfs::subscribe(&chain_api, ips_id).await?;
+    let mut current = set_repo(ips_id, chain_api.clone()).await?;
+
+    eprintln!("Watching IPS {} for pushes", ips_id);
+
+    while let Some(event) = appended.next().await {
+        for ipf_id in event.ipf_ids {
+            let ipf_info = match chain_api
+                .storage()
+                .ipf()
+                .ipf_storage(&ipf_id.0, None)
+                .await?
+            {
+                Some(ipf_info) => ipf_info,
+                None => continue,
+            };
+            if !is_git_ipf(&ipf_info.metadata.0) {
+                continue;
+            }
+
+            let (name, cid) = decode_ipf_metadata(&ipf_info.metadata.0, ipf_info.data.0)?;
+            if name != "RepoData" {
+                continue;
+            }
+
+            let new = RepoData::from_ipfs(cid, &mut ipfs).await?;
+
+            let payload = json!({
+                "ips_id": ips_id.0,
+                "ipf_id": ipf_id.0,
+                "block": format!("{:?}", event.block_hash),
+                "pusher": address(&ipf_info.author),
+                "refs": RefUpdate::between(&current.refs, &new.refs)
+                    .iter()
+                    .map(RefUpdate::to_json)
+                    .collect::<Value>(),
+            });
+
+            deliver(&http, webhook, &payload, ipf_id).await;
+
+            current = new;
+        }
+    }
+
+    Err("The chain event subscription ended".into())
+}
+
+/// POSTs `payload`. A failed delivery only produces a warning so the watch keeps going.
+async fn deliver(http: &reqwest::Client, webhook: &str, payload: &Value, ipf_id: IpfId) {
+    debug!("Delivering {} to {}", payload, webhook);
+
+    let result = http
+        .post(webhook)
+        .json(payload)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+
+    match result {
+        Ok(_) => eprintln!("Delivered RepoData IPF {} to {}", ipf_id, webhook),
+        Err(e) => eprintln!(
+            "Warning: could not deliver RepoData IPF {} to {}: {}",
+            ipf_id, webhook, e
+        ),
+    }
+}